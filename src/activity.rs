@@ -0,0 +1,227 @@
+// Activity -> Append-only record of every completed sync and every individual fetch, so it can be looked up later
+// what went onto which device and when. Like the playlist history it's JSONL in the data dir, each record going out in
+// a single write to a file opened for appending so concurrent writers can't interleave lines. Appending only reads the
+// file's first and last lines, and it's compacted back down to MAX_RECORDS once it reaches twice that, under a lock
+// beside the file so concurrent runs neither reuse an id nor compact away each other's records.
+
+use std::{
+    fs::{OpenOptions, read_to_string, rename, write},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use crate::{
+    audio::{AudioError, AudioInfo, AudioKey},
+    cache::{TrashedAudio, get_data_dir, unix_now},
+    fsutil::{first_line, last_line, lock_beside},
+    source::FetchResult,
+    sync::{SyncOutcome, SyncReport},
+};
+
+// Records kept when compacting, the oldest go first.
+const MAX_RECORDS: u64 = 5000;
+// Compacting rewrites the whole file, so it waits until there are this many records.
+const COMPACT_AT_RECORDS: u64 = 2 * MAX_RECORDS;
+
+pub fn activity_history() -> PathBuf {
+    get_data_dir().join("activity_history.jsonl")
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct FetchRecord {
+    pub key: Option<AudioKey>,
+    pub source: String,
+    pub url: Option<String>,
+    // Why the fetch failed, None when it succeeded.
+    pub error: Option<String>,
+    pub bytes: u64,
+    pub duration_ms: u64,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Activity {
+    Sync { device: String, report: SyncReport },
+    Fetch(FetchRecord),
+    // Unreferenced cached audio moved to the trash by `cache gc`.
+    Gc { removed: Vec<TrashedAudio> },
+    // A `cache gc` put back by `undo`.
+    Undo { of: u64, restored: usize },
+}
+
+impl FetchRecord {
+    /// Describe a finished fetch of info, keyed by what the source learned about it when it succeeded.
+    pub fn new(info: &AudioInfo, source: &str, result: Result<&FetchResult, &AudioError>, started: Instant) -> Self {
+        let (info, url, bytes) = match result {
+            Ok(fetched) => (&fetched.info, fetched.url.clone(), fetched.bytes),
+            Err(_) => (info, info.youtube_url.clone(), 0),
+        };
+        Self {
+            key: AudioKey::from_info(info),
+            source: source.to_string(),
+            url,
+            error: result.err().map(|e| e.to_string()),
+            bytes,
+            duration_ms: started.elapsed().as_millis() as u64,
+        }
+    }
+}
+
+impl Activity {
+    pub fn failed(&self) -> bool {
+        match self {
+            Activity::Sync { report, .. } => report.count(&SyncOutcome::Failed(String::new())) > 0,
+            Activity::Fetch(fetch) => fetch.error.is_some(),
+            Activity::Gc { .. } | Activity::Undo { .. } => false,
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct ActivityRecord {
+    pub id: u64,
+    // Unix seconds.
+    pub timestamp: u64,
+    #[serde(flatten)]
+    pub activity: Activity,
+}
+
+/// Every record, oldest first.
+pub fn load_activity() -> Vec<ActivityRecord> {
+    read_to_string(activity_history())
+        .map(|s| s.lines().filter_map(|line| serde_json::from_str(line).ok()).collect())
+        .unwrap_or_default()
+}
+
+pub fn find_activity(id: u64) -> Option<ActivityRecord> {
+    load_activity().into_iter().find(|r| r.id == id)
+}
+
+/// The newest record `undo` can reverse, one that hasn't been undone already.
+pub fn last_undoable() -> Option<ActivityRecord> {
+    let all = load_activity();
+    let undone: Vec<u64> = all
+        .iter()
+        .filter_map(|r| match r.activity {
+            Activity::Undo { of, .. } => Some(of),
+            _ => None,
+        })
+        .collect();
+    all.into_iter()
+        .rev()
+        .find(|r| matches!(r.activity, Activity::Gc { .. }) && !undone.contains(&r.id))
+}
+
+/// Record an activity, best effort like the playlist history, never failing what's being recorded.
+pub fn record_activity(activity: Activity) {
+    if let Err(e) = append(activity) {
+        println!("Failed to record activity history: {}", e);
+    }
+}
+
+fn append(activity: Activity) -> io::Result<()> {
+    append_to(&activity_history(), activity)
+}
+
+// Just the id of a record, whatever its kind.
+#[derive(serde::Deserialize)]
+struct RecordId {
+    id: u64,
+}
+
+fn record_id(line: Option<String>) -> Option<u64> {
+    serde_json::from_str::<RecordId>(&line?).ok().map(|record| record.id)
+}
+
+fn append_to(path: &Path, activity: Activity) -> io::Result<()> {
+    let _lock = lock_beside(path)?;
+    let last_id = match last_line(path)? {
+        None => 0,
+        // Unreadable, so fall back to reading every id rather than reuse one.
+        line => record_id(line).unwrap_or_else(|| {
+            let contents = read_to_string(path).unwrap_or_default();
+            contents.lines().filter_map(|line| record_id(Some(line.to_string()))).max().unwrap_or_default()
+        }),
+    };
+    let record = ActivityRecord { id: last_id + 1, timestamp: unix_now(), activity };
+    let mut line = serde_json::to_string(&record)?;
+    line.push('\n');
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(line.as_bytes())?;
+
+    // Ids count up by one, so the first and last say how many records there are.
+    let first_id = record_id(first_line(path)?).unwrap_or_default();
+    if record.id - first_id + 1 >= COMPACT_AT_RECORDS {
+        compact(path)?;
+    }
+    Ok(())
+}
+
+// Rewrite the file with only the newest MAX_RECORDS lines, replacing it in one rename so readers never see half of it.
+fn compact(path: &Path) -> io::Result<()> {
+    let contents = read_to_string(path)?;
+    let lines: Vec<&str> = contents.lines().collect();
+    let mut kept = lines[lines.len().saturating_sub(MAX_RECORDS as usize)..].join("\n");
+    kept.push('\n');
+    let tmp = path.with_extension("jsonl.tmp");
+    write(&tmp, kept)?;
+    rename(tmp, path)
+}
+
+/// Parse an age like "7d", "12h", "30m" or "90s" into seconds.
+pub fn parse_age(s: &str) -> Option<u64> {
+    let split = s.find(|c: char| !c.is_ascii_digit())?;
+    let (n, unit) = s.split_at(split);
+    let n: u64 = n.parse().ok()?;
+    match unit {
+        "s" => Some(n),
+        "m" => Some(n * 60),
+        "h" => Some(n * 60 * 60),
+        "d" => Some(n * 24 * 60 * 60),
+        "w" => Some(n * 7 * 24 * 60 * 60),
+        _ => None,
+    }
+}
+
+/// Format unix seconds as a UTC "YYYY-MM-DD HH:MM".
+pub fn format_timestamp(secs: u64) -> String {
+    // Days since the epoch to a civil date, from Howard Hinnant's days_from_civil inverse.
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02} {:02}:{:02}", year, month, day, secs % 86400 / 3600, secs % 3600 / 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compacts_once_past_the_high_water_mark() {
+        let dir = std::env::temp_dir().join(format!("music-man-activity-{}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("activity_history.jsonl");
+        let lines = || read_to_string(&path).unwrap().lines().count() as u64;
+        let undo = || Activity::Undo { of: 0, restored: 0 };
+
+        for _ in 0..COMPACT_AT_RECORDS - 1 {
+            append_to(&path, undo()).unwrap();
+        }
+        assert_eq!(lines(), COMPACT_AT_RECORDS - 1);
+        append_to(&path, undo()).unwrap();
+        assert_eq!(lines(), MAX_RECORDS);
+        // Ids carry on from the newest record kept.
+        append_to(&path, undo()).unwrap();
+        assert_eq!(record_id(last_line(&path).unwrap()), Some(COMPACT_AT_RECORDS + 1));
+        assert_eq!(record_id(first_line(&path).unwrap()), Some(COMPACT_AT_RECORDS - MAX_RECORDS + 1));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
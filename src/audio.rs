@@ -1,4 +1,11 @@
-use std::{fs::DirEntry, path::{Path, PathBuf}};
+use std::{
+    collections::HashMap,
+    fs::DirEntry,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use unicode_normalization::UnicodeNormalization;
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum PlaylistName {
@@ -13,51 +20,120 @@ impl PlaylistName {
             PlaylistName::Uncategorized => "Uncategorized",
         }
     }
+
+    // Inverse of disp_name, for playlist names typed at the REPL.
+    pub fn from_disp_name(name: &str) -> Self {
+        match name {
+            "Uncategorized" => PlaylistName::Uncategorized,
+            _ => PlaylistName::Named(name.to_string()),
+        }
+    }
 }
 
-// A collection of AudioInfo.
+// A collection of AudioInfo. Shared rather than owned, so listing an index that holds its playlists in memory, like
+// the cache, doesn't copy every track.
 pub struct Playlist {
     pub name: PlaylistName,
-    pub audio: Vec<AudioInfo>,
+    pub audio: Arc<Vec<AudioInfo>>,
 }
 
 // A hashable key for indexing audio by artist + title.
-#[derive(Hash, PartialEq, Eq, Clone, Debug)]
+#[derive(Hash, PartialEq, Eq, PartialOrd, Ord, Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct AudioKey {
     pub artist: String,
     pub title: String,
 }
 
 impl AudioKey {
+    // Keyed by the whole artist credit as written, so different acts never share a key. Matching on the primary
+    // artist alone is only a fallback, see find_key.
     pub fn from_info(info: &AudioInfo) -> Option<Self> {
+        let artist = match &info.artist {
+            Some(artist) => artist.clone(),
+            None if !info.artists.is_empty() => info.artists.join(", "),
+            None => return None,
+        };
         Some(Self {
-            artist: info.artist.as_ref()?.to_lowercase(),
-            title: info.title.as_ref()?.to_lowercase(),
+            artist: nfc(&artist).to_lowercase(),
+            title: nfc(info.title.as_ref()?).to_lowercase(),
         })
     }
+
+    // The key's primary credited artist.
+    fn primary_artist(&self) -> String {
+        split_artists(&self.artist).into_iter().next().unwrap_or_else(|| self.artist.clone())
+    }
+}
+
+/// Look up audio by its exact key, falling back to an entry with the same title and primary artist, so that
+/// "A & B - Title" still finds "A - Title" and the other way round.
+pub fn find_key<'a, V>(index: &'a HashMap<AudioKey, V>, key: &AudioKey) -> Option<(&'a AudioKey, &'a V)> {
+    if let Some(entry) = index.get_key_value(key) {
+        return Some(entry);
+    }
+    let primary = key.primary_artist();
+    let mut matches: Vec<(&AudioKey, &V)> =
+        index.iter().filter(|(other, _)| other.title == key.title && other.primary_artist() == primary).collect();
+    // Deterministic whatever the map's order, the shortest credit is the closest to a plain "A - Title".
+    matches.sort_by(|a, b| (a.0.artist.len(), &a.0.artist).cmp(&(b.0.artist.len(), &b.0.artist)));
+    matches.into_iter().next()
+}
+
+/// Normalize to Unicode NFC. macOS hands out filenames in decomposed (NFD) form while most other systems and tags use
+/// composed (NFC), so any filename derived string must be normalized before comparing, or "Björk" != "Björk".
+pub fn nfc(s: &str) -> String {
+    s.nfc().collect()
 }
 
 // AudioInfo -> A structure representing various information about audio. Depending on the information present, it can
 // be used for searching different AudioSource and AudioTarget, to see where the audio resides already.
 #[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
 pub struct AudioInfo {
+    // Every credited artist joined as written, kept for display and for playlists saved before `artists` existed.
     pub artist: Option<String>,
+    // Individually credited artists, primary first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub artists: Vec<String>,
     pub title: Option<String>,
     pub filename: Option<String>,
     pub youtube_url: Option<String>,
     pub isrc: Option<String>,
     pub duration_secs: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub album: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub track_number: Option<u32>,
+    // Part of a various artists compilation, so the album says nothing about the artist.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub compilation: bool,
+    // When the audio was added to a playlist (unix seconds), only set on playlist entries.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub added_at: Option<u64>,
+    // Synced whatever the device's budget for the playlist, only set on playlist entries.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pinned: bool,
+    // Devices this playlist entry is never synced to, by name, e.g. long mixes on a small player.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub skip_on: Vec<String>,
 }
 
 impl AudioInfo {
     pub fn from_filename(filename: impl AsRef<Path>) -> Self {
-        let filename_str = filename.as_ref().to_string_lossy();
-        let stem = filename
+        let filename_str = nfc(&filename.as_ref().to_string_lossy());
+        let full_stem = filename
             .as_ref()
             .file_stem()
-            .map(|s| s.to_string_lossy())
+            .map(|s| nfc(&s.to_string_lossy()))
             .unwrap_or(filename_str.clone());
 
+        // Ignore any track number / ordering prefix, so prefixed copies key the same as the original.
+        let stem = strip_number_prefix(&full_stem);
+        let track_number = if stem.len() < full_stem.len() {
+            full_stem.split(|c: char| !c.is_ascii_digit()).next().and_then(|n| n.parse().ok())
+        } else {
+            None
+        };
+
         // Try to split up the filename to artist + title, if delimiter isn't there just take it all as title.
         let (artist, title) = stem
             .split_once(" - ")
@@ -66,14 +142,126 @@ impl AudioInfo {
             .unwrap_or((None, Some(stem.to_string())));
 
         Self {
+            artists: artist.as_deref().map(split_artists).unwrap_or_default(),
             artist,
             title,
             filename: Some(filename_str.to_string()), // AttachedDevice will always have at least filenames.
             youtube_url: None,
             isrc: None,
             duration_secs: None,
+            album: None,
+            track_number,
+            compilation: false,
+            added_at: None,
+            pinned: false,
+            skip_on: Vec::new(),
+        }
+    }
+
+    /// Credited artists, falling back to splitting the joined artist for entries that predate `artists`.
+    pub fn credited_artists(&self) -> Vec<String> {
+        if !self.artists.is_empty() {
+            return self.artists.clone();
+        }
+        self.artist.as_deref().map(split_artists).unwrap_or_default()
+    }
+
+    pub fn primary_artist(&self) -> Option<String> {
+        match self.artists.first() {
+            Some(artist) => Some(artist.clone()),
+            None => split_artists(self.artist.as_deref()?).into_iter().next().or_else(|| self.artist.clone()),
+        }
+    }
+
+    /// Whether any credited artist is the given artist, ignoring case and normalization form.
+    pub fn credits(&self, artist: &str) -> bool {
+        let artist = nfc(artist.trim()).to_lowercase();
+        self.credited_artists().iter().any(|a| nfc(a).to_lowercase() == artist)
+    }
+
+    /// The ISRC in comparable form, sources differ in case and some hyphenate it e.g. "US-RC1-76-07839".
+    pub fn normalized_isrc(&self) -> Option<String> {
+        self.isrc.as_deref().and_then(normalize_isrc)
+    }
+}
+
+pub fn normalize_isrc(isrc: &str) -> Option<String> {
+    let isrc: String = isrc.chars().filter(|c| c.is_ascii_alphanumeric()).collect::<String>().to_ascii_uppercase();
+    (!isrc.is_empty()).then_some(isrc)
+}
+
+// Separators between credited artists, matched case-insensitively.
+const ARTIST_SEPARATORS: [&str; 4] = [", ", " & ", " x ", " feat. "];
+
+// Acts whose own names contain a separator, never split up. Lowercase.
+const KNOWN_ACTS: [&str; 20] = [
+    "above & beyond",
+    "belle & sebastian",
+    "brooks & dunn",
+    "chase & status",
+    "crosby, stills & nash",
+    "crosby, stills, nash & young",
+    "earth, wind & fire",
+    "echo & the bunnymen",
+    "emerson, lake & palmer",
+    "hall & oates",
+    "huey lewis & the news",
+    "iron & wine",
+    "kool & the gang",
+    "mumford & sons",
+    "peter, paul & mary",
+    "sam & dave",
+    "simon & garfunkel",
+    "sly & the family stone",
+    "the mamas & the papas",
+    "tyler, the creator",
+];
+
+/// Split a joined artist credit like "A, B & C feat. D" into individual artists. Acts in KNOWN_ACTS, like "Earth,
+/// Wind & Fire", stay whole.
+pub fn split_artists(artist: &str) -> Vec<String> {
+    // ASCII lowercasing keeps byte offsets intact, so positions found in `lower` index `artist` too.
+    let lower = artist.to_ascii_lowercase();
+    let mut artists = Vec::new();
+    let mut start = 0;
+    while start < artist.len() {
+        // A known act is taken whole when it's the entire rest of the credit, or followed by a separator.
+        let known = KNOWN_ACTS.iter().filter(|act| lower[start..].starts_with(*act)).map(|act| start + act.len()).find(|end| {
+            *end == artist.len() || ARTIST_SEPARATORS.iter().any(|sep| lower[*end..].starts_with(sep))
+        });
+        if let Some(end) = known {
+            artists.push(artist[start..end].to_string());
+            let sep_len = ARTIST_SEPARATORS.iter().find(|sep| lower[end..].starts_with(*sep)).map_or(0, |sep| sep.len());
+            start = end + sep_len;
+            continue;
         }
+        let next = ARTIST_SEPARATORS
+            .iter()
+            .filter_map(|sep| lower[start..].find(sep).map(|i| (start + i, sep.len())))
+            .min();
+        let (end, sep_len) = next.unwrap_or((artist.len(), 0));
+        let name = artist[start..end].trim();
+        if !name.is_empty() {
+            artists.push(name.to_string());
+        }
+        start = end + sep_len;
     }
+    artists
+}
+
+/// Strip a leading track number prefix like "017 - ", "03. " or "1-" from a filename stem. Only up to three digits
+/// followed by a "-" or "." separator count, and only when what remains still has an artist - title delimiter, so
+/// that artists like "3 Doors Down" or "50 Cent", and the band "311 - Amber", are left alone.
+pub fn strip_number_prefix(stem: &str) -> &str {
+    let digits = stem.len() - stem.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    if !(1..=3).contains(&digits) {
+        return stem;
+    }
+    let Some(stripped) = stem[digits..].trim_start().strip_prefix(['-', '.']) else {
+        return stem;
+    };
+    let stripped = stripped.trim_start();
+    if stripped.contains(" - ") || stripped.contains(" – ") { stripped } else { stem }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -88,6 +276,14 @@ pub enum AudioError {
     Unavailable(String),
     #[error("Export failed: {0}")]
     ExportFailed(String),
+    #[error("Download of {url} failed: {reason}")]
+    DownloadFailed { url: String, reason: String },
+    #[error("Read-only mode, refusing to write")]
+    ReadOnly,
+    #[error("Config error: {0}")]
+    Config(String),
+    #[error("Cancelled")]
+    Cancelled,
     #[error("IO Error: {0}")]
     Io(#[from] std::io::Error),
 }
@@ -109,34 +305,336 @@ impl AudioLocation {
     }
 }
 
-pub fn is_supported_audio_file(entry: &DirEntry) -> bool {
+// Files a download is still writing: yt-dlp's .part and .ytdl state files, its fragments, and the .temp.<ext> files its
+// post-processors write before renaming into place. Shared by everything that writes downloads and everything that
+// indexes or sweeps the cache, so they can't drift apart.
+// Our own copies are named with PARTIAL_DOWNLOAD_SUFFIX until they're complete, for the same reason.
+pub const PARTIAL_DOWNLOAD_SUFFIX: &str = ".part";
+const PARTIAL_DOWNLOAD_SUFFIXES: &[&str] = &[PARTIAL_DOWNLOAD_SUFFIX, ".ytdl"];
+const PARTIAL_DOWNLOAD_INFIXES: &[&str] = &[".part-Frag", ".temp."];
+
+/// Whether a filename belongs to a download still in progress (or one that died mid-way), never a finished track.
+pub fn is_partial_download(name: &str) -> bool {
+    PARTIAL_DOWNLOAD_SUFFIXES.iter().any(|suffix| name.ends_with(suffix))
+        || PARTIAL_DOWNLOAD_INFIXES.iter().any(|infix| name.contains(infix))
+}
+
+/// Whether a directory entry is a finished audio file with one of the given extensions, e.g. those a device accepts.
+pub fn is_audio_file_in(entry: &DirEntry, extensions: &[String]) -> bool {
     if !entry.path().is_file() {
         return false;
     }
 
-    // macOS fork files.
-    if entry.file_name().to_string_lossy().starts_with("._") {
+    // macOS fork files, and downloads still being written.
+    let name = entry.file_name().to_string_lossy().to_string();
+    if name.starts_with("._") || is_partial_download(&name) {
         return false;
     }
 
-    entry
-        .path()
-        .extension()
-        .and_then(|ext| ext.to_str())
-        .map(|ext| {
-            matches!(
-                ext.to_lowercase().as_str(),
-                "mp3" | "flac" | "wma" | "wav" | "aac" | "m4a" | "ape"
-            )
-        })
-        .unwrap_or(false)
+    has_extension_in(&entry.path(), extensions)
+}
+
+/// Extensions the cache indexes unless the config lists its own.
+pub const DEFAULT_AUDIO_EXTENSIONS: [&str; 11] =
+    ["mp3", "flac", "wma", "wav", "aac", "m4a", "ape", "opus", "ogg", "aiff", "wv"];
+
+static AUDIO_EXTENSIONS: Mutex<Option<Vec<String>>> = Mutex::new(None);
+
+/// Replace the recognized audio extensions, None going back to the defaults.
+pub fn set_audio_extensions(extensions: Option<Vec<String>>) {
+    *AUDIO_EXTENSIONS.lock().unwrap_or_else(PoisonError::into_inner) = extensions.map(|e| normalize_extensions(&e));
+}
+
+/// The recognized audio extensions, lowercase and without dots.
+pub fn audio_extensions() -> Vec<String> {
+    AUDIO_EXTENSIONS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+        .unwrap_or_else(|| DEFAULT_AUDIO_EXTENSIONS.iter().map(|ext| ext.to_string()).collect())
+}
+
+/// Extensions as written in config, ".MP3" or "mp3", to the lowercase, dotless form they're compared in.
+pub fn normalize_extensions(extensions: &[String]) -> Vec<String> {
+    let mut normalized: Vec<String> = extensions
+        .iter()
+        .map(|ext| ext.trim().trim_start_matches('.').to_lowercase())
+        .filter(|ext| !ext.is_empty())
+        .collect();
+    normalized.sort();
+    normalized.dedup();
+    normalized
+}
+
+/// Whether a path's extension is one of the recognized audio formats, whether or not the file exists.
+pub fn has_audio_extension(path: &Path) -> bool {
+    has_extension_in(path, &audio_extensions())
+}
+
+/// Whether a path's extension is one of the given ones, compared case-insensitively.
+pub fn has_extension_in(path: &Path, extensions: &[String]) -> bool {
+    extensions.contains(&audio_extension(path))
+}
+
+/// A path's extension, lowercase, empty when it has none.
+pub fn audio_extension(path: &Path) -> String {
+    path.extension().map(|ext| ext.to_string_lossy().to_lowercase()).unwrap_or_default()
 }
 
-/// List all supported audio files in a folder, returning AudioInfo for each.
-pub fn list_audio_in_folder(folder: &Path) -> Result<Vec<AudioInfo>, AudioError> {
-    std::fs::read_dir(folder)?
+/// Check a downloaded audio file is non-empty and fully decodes, using ffmpeg (which yt-dlp already requires).
+pub fn verify_audio_file(path: &Path) -> Result<(), AudioError> {
+    if std::fs::metadata(path)?.len() == 0 {
+        return Err(AudioError::ExportFailed(format!("{} is empty", path.display())));
+    }
+
+    let output = std::process::Command::new("ffmpeg")
+        .args(["-v", "error", "-i"])
+        .arg(path)
+        .args(["-f", "null", "-"])
+        .output()?;
+    if !output.status.success() || !output.stderr.is_empty() {
+        return Err(AudioError::ExportFailed(format!(
+            "{} failed to decode: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+/// Transcode audio to mp3 with ffmpeg, dropping any video stream.
+pub fn transcode_to_mp3(src: &Path, dest: &Path) -> Result<(), AudioError> {
+    let output = std::process::Command::new("ffmpeg")
+        .args(["-v", "error", "-y", "-i"])
+        .arg(src)
+        .args(["-vn", "-codec:a", "libmp3lame", "-q:a", "0"])
+        .arg(dest)
+        .output()?;
+    if !output.status.success() {
+        return Err(AudioError::ExportFailed(format!(
+            "ffmpeg failed to transcode {}: {}",
+            src.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+/// Tag audio in place from its AudioInfo with ffmpeg, copying the streams and keeping any tags the info doesn't set.
+/// Written to a sibling file and renamed over the original, so a failure leaves it as it was.
+pub fn write_tags(path: &Path, info: &AudioInfo) -> Result<(), AudioError> {
+    let tags = [
+        ("artist", info.artist.clone()),
+        ("title", info.title.clone()),
+        ("album", info.album.clone()),
+        ("track", info.track_number.map(|number| number.to_string())),
+        ("isrc", info.isrc.clone()),
+    ];
+    let name = path.file_name().ok_or(AudioError::NotFound)?.to_string_lossy();
+    // ffmpeg picks the container from the extension, so the sibling keeps it.
+    let tagged = path.with_file_name(format!(".tagging.{}", name));
+    let mut command = std::process::Command::new("ffmpeg");
+    command.args(["-v", "error", "-y", "-i"]).arg(path);
+    command.args(["-map", "0", "-map_metadata", "0", "-codec", "copy"]);
+    for (tag, value) in tags {
+        if let Some(value) = value {
+            command.arg("-metadata").arg(format!("{}={}", tag, value));
+        }
+    }
+    let output = command.arg(&tagged).output()?;
+    if !output.status.success() {
+        std::fs::remove_file(&tagged).ok();
+        return Err(AudioError::ExportFailed(format!(
+            "ffmpeg failed to tag {}: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    std::fs::rename(&tagged, path)?;
+    Ok(())
+}
+
+/// List the audio files with one of the given extensions in a folder, returning AudioInfo for each. Folders that look like a compilation
+/// have their audio marked as such, with the folder name as the album.
+pub fn list_audio_in_folder(folder: &Path, extensions: &[String]) -> Result<Vec<AudioInfo>, AudioError> {
+    let mut audio: Vec<AudioInfo> = std::fs::read_dir(folder)?
         .filter_map(|e| e.ok())
-        .filter(|entry| is_supported_audio_file(entry))
-        .map(|entry| Ok(AudioInfo::from_filename(&entry.file_name())))
-        .collect()
-}
\ No newline at end of file
+        .filter(|entry| is_audio_file_in(entry, extensions))
+        .map(|entry| AudioInfo::from_filename(entry.file_name()))
+        .collect();
+    number_by_bare_prefix(&mut audio);
+    if is_compilation(&audio) {
+        let album = folder.file_name().map(|name| nfc(&name.to_string_lossy()));
+        for info in &mut audio {
+            info.compilation = true;
+            info.album = album.clone();
+        }
+    }
+    Ok(audio)
+}
+
+// "NN Artist - Title", numbered with just a space, can't be told apart from an artist like "3 Doors Down" in a single
+// name. A folder of three or more where every file has one and they count up from 1 is numbered though, so they're reparsed as numbers.
+fn number_by_bare_prefix(audio: &mut [AudioInfo]) {
+    let prefixed: Option<Vec<(u32, String)>> = audio
+        .iter()
+        .map(|info| {
+            let filename = info.filename.as_deref()?;
+            let (number, rest) = filename.split_once(' ')?;
+            let is_number = (1..=3).contains(&number.len()) && number.chars().all(|c| c.is_ascii_digit());
+            if !is_number || info.track_number.is_some() {
+                return None;
+            }
+            Some((number.parse().ok()?, rest.to_string()))
+        })
+        .collect();
+    let Some(prefixed) = prefixed else {
+        return;
+    };
+    let mut numbers: Vec<u32> = prefixed.iter().map(|(number, _)| *number).collect();
+    numbers.sort_unstable();
+    if numbers.len() < 3 || !numbers.iter().copied().eq(1..=numbers.len() as u32) {
+        return;
+    }
+    for (info, (number, rest)) in audio.iter_mut().zip(prefixed) {
+        let filename = info.filename.take();
+        *info = AudioInfo::from_filename(rest);
+        info.filename = filename;
+        info.track_number = Some(number);
+    }
+}
+
+// Compilation folders are numbered tracks by (nearly) all different artists. Albums by one artist, and playlists of
+// unnumbered files, never qualify.
+fn is_compilation(audio: &[AudioInfo]) -> bool {
+    if audio.len() < 3 || audio.iter().any(|info| info.track_number.is_none()) {
+        return false;
+    }
+    let artists: std::collections::HashSet<String> = audio
+        .iter()
+        .filter_map(|info| info.primary_artist().map(|a| nfc(&a).to_lowercase()))
+        .collect();
+    artists.len() * 5 >= audio.len() * 4
+}
+
+/// Whether an album artist tag names a compilation rather than a real artist.
+pub fn is_various_artists(artist: &str) -> bool {
+    matches!(artist.trim().to_lowercase().as_str(), "various artists" | "various" | "va")
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_track_number_prefixes() {
+        assert_eq!(strip_number_prefix("017 - Muse - Uprising"), "Muse - Uprising");
+        assert_eq!(strip_number_prefix("03. Muse - Uprising"), "Muse - Uprising");
+        assert_eq!(strip_number_prefix("1-Muse - Uprising"), "Muse - Uprising");
+        assert_eq!(strip_number_prefix("01 - 3 Doors Down - Kryptonite"), "3 Doors Down - Kryptonite");
+    }
+
+    #[test]
+    fn keeps_numeric_artist_names() {
+        assert_eq!(strip_number_prefix("3 Doors Down - Kryptonite"), "3 Doors Down - Kryptonite");
+        assert_eq!(strip_number_prefix("50 Cent - In Da Club"), "50 Cent - In Da Club");
+        assert_eq!(strip_number_prefix("311 - Amber"), "311 - Amber");
+        assert_eq!(strip_number_prefix("2024 - Some Song - Remix"), "2024 - Some Song - Remix");
+    }
+
+    #[test]
+    fn numeric_artists_parse_from_filenames() {
+        let info = AudioInfo::from_filename("3 Doors Down - Kryptonite.mp3");
+        assert_eq!(info.artist.as_deref(), Some("3 Doors Down"));
+        assert_eq!(info.title.as_deref(), Some("Kryptonite"));
+        assert_eq!(info.track_number, None);
+
+        let info = AudioInfo::from_filename("50 Cent - In Da Club.mp3");
+        assert_eq!(info.artist.as_deref(), Some("50 Cent"));
+
+        let info = AudioInfo::from_filename("07 - 50 Cent - In Da Club.mp3");
+        assert_eq!(info.artist.as_deref(), Some("50 Cent"));
+        assert_eq!(info.track_number, Some(7));
+    }
+
+    fn key(filename: &str) -> AudioKey {
+        AudioKey::from_info(&AudioInfo::from_filename(filename)).unwrap()
+    }
+
+    #[test]
+    fn keys_use_the_full_artist_credit() {
+        assert_eq!(key("Earth, Wind & Fire - September.mp3").artist, "earth, wind & fire");
+        assert_eq!(key("Simon & Garfunkel - America.mp3").artist, "simon & garfunkel");
+        assert_ne!(key("Simon & Garfunkel - America.mp3"), key("Simon - America.mp3"));
+    }
+
+    #[test]
+    fn known_acts_are_not_split() {
+        assert_eq!(split_artists("Earth, Wind & Fire"), vec!["Earth, Wind & Fire"]);
+        assert_eq!(split_artists("Simon & Garfunkel feat. Someone"), vec!["Simon & Garfunkel", "Someone"]);
+        assert_eq!(split_artists("Drake & Earth, Wind & Fire"), vec!["Drake", "Earth, Wind & Fire"]);
+        assert_eq!(split_artists("A, B & C"), vec!["A", "B", "C"]);
+    }
+
+    #[test]
+    fn finds_keys_by_primary_artist_as_a_fallback() {
+        let index: HashMap<AudioKey, u32> = HashMap::from([(key("A - Title.mp3"), 1), (key("Simon - America.mp3"), 2)]);
+        assert_eq!(find_key(&index, &key("A - Title.mp3")).map(|(_, v)| *v), Some(1));
+        assert_eq!(find_key(&index, &key("A & B - Title.mp3")).map(|(_, v)| *v), Some(1));
+        assert_eq!(find_key(&index, &key("Simon & Garfunkel - America.mp3")), None);
+
+        let index: HashMap<AudioKey, u32> = HashMap::from([(key("A feat. B - Title.mp3"), 1)]);
+        assert_eq!(find_key(&index, &key("A - Title.mp3")).map(|(_, v)| *v), Some(1));
+    }
+
+    // Lists a fixture folder, named and laid out like a real rip of the album.
+    fn list_fixture(album: &str, files: &[&str]) -> Vec<AudioInfo> {
+        let dir = std::env::temp_dir().join(format!("music-man-audio-{}", std::process::id())).join(album);
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        for file in files {
+            std::fs::write(dir.join(file), b"").unwrap();
+        }
+        let mut audio = list_audio_in_folder(&dir, &["mp3".to_string()]).unwrap();
+        std::fs::remove_dir_all(&dir).ok();
+        audio.sort_by_key(|info| info.track_number);
+        audio
+    }
+
+    #[test]
+    fn compilation_folders_keep_per_track_artists() {
+        let audio = list_fixture(
+            "Trainspotting (Music from the Motion Picture)",
+            &[
+                "01 Iggy Pop - Lust for Life.mp3",
+                "02 Brian Eno - Deep Blue Day.mp3",
+                "03 Primal Scream - Trainspotting.mp3",
+                "04 Sleeper - Atomic.mp3",
+                "05 New Order - Temptation.mp3",
+                "06 Iggy Pop - Nightclubbing.mp3",
+                "07 Blur - Sing.mp3",
+                "08 Lou Reed - Perfect Day.mp3",
+            ],
+        );
+        assert_eq!(audio.len(), 8);
+        assert!(audio.iter().all(|info| info.compilation));
+        assert!(audio.iter().all(|info| info.album.as_deref() == Some("Trainspotting (Music from the Motion Picture)")));
+        assert_eq!(audio[0].artist.as_deref(), Some("Iggy Pop"));
+        assert_eq!(audio[0].title.as_deref(), Some("Lust for Life"));
+        assert_eq!(audio[0].filename.as_deref(), Some("01 Iggy Pop - Lust for Life.mp3"));
+        assert_eq!(audio[7].track_number, Some(8));
+        assert_eq!(audio[7].artist.as_deref(), Some("Lou Reed"));
+    }
+
+    #[test]
+    fn albums_by_one_artist_are_not_compilations() {
+        let audio = list_fixture(
+            "Parachutes",
+            &["01 - Coldplay - Don't Panic.mp3", "02 - Coldplay - Shiver.mp3", "03 - Coldplay - Spies.mp3"],
+        );
+        assert!(audio.iter().all(|info| !info.compilation && info.album.is_none()));
+        // A lone numeric artist isn't mistaken for a track number.
+        let audio = list_fixture("Singles", &["3 Doors Down - Kryptonite.mp3"]);
+        assert_eq!(audio[0].artist.as_deref(), Some("3 Doors Down"));
+        assert_eq!(audio[0].track_number, None);
+    }
+}
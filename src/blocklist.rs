@@ -0,0 +1,102 @@
+// Blocklist -> Source videos never to download from again, e.g. the upload a bad rip came from. Searches skip them and
+// take the next best result, and a track's recorded URL is searched past rather than reused when it's blocked.
+
+use std::{
+    fs::{read_to_string, write},
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::cache::get_data_dir;
+
+pub fn blocklist_path() -> PathBuf {
+    get_data_dir().join("blocked_sources.json")
+}
+
+/// The video ID of a YouTube URL, e.g. from a watch or youtu.be link, or the string itself when it's already an ID.
+pub fn video_id(url: &str) -> &str {
+    let url = url.trim();
+    let id = match url.split_once("v=") {
+        Some((_, query)) => query,
+        None => url.rsplit('/').next().unwrap_or(url),
+    };
+    id.split(['&', '?', '#']).next().unwrap_or(id)
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Blocklist {
+    path: PathBuf,
+    // Video IDs.
+    ids: Vec<String>,
+}
+
+impl Blocklist {
+    pub fn load() -> Self {
+        Self::load_from(&blocklist_path())
+    }
+
+    fn load_from(path: &Path) -> Self {
+        let ids = read_to_string(path).ok().and_then(|s| serde_json::from_str(&s).ok()).unwrap_or_default();
+        Self { path: path.to_path_buf(), ids }
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        write(&self.path, serde_json::to_string_pretty(&self.ids)?)
+    }
+
+    pub fn ids(&self) -> &[String] {
+        &self.ids
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    pub fn contains(&self, url: &str) -> bool {
+        let id = video_id(url);
+        self.ids.iter().any(|blocked| blocked == id)
+    }
+
+    /// Block a video by URL or ID, returning whether it wasn't blocked already.
+    pub fn block(&mut self, url: &str) -> bool {
+        if self.contains(url) {
+            return false;
+        }
+        self.ids.push(video_id(url).to_string());
+        true
+    }
+
+    /// Returns whether the video was blocked.
+    pub fn unblock(&mut self, url: &str) -> bool {
+        let before = self.ids.len();
+        let id = video_id(url);
+        self.ids.retain(|blocked| blocked != id);
+        self.ids.len() != before
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn videos_are_blocked_by_id_whatever_the_url() {
+        assert_eq!(video_id("https://www.youtube.com/watch?v=dQw4w9WgXcQ&t=42"), "dQw4w9WgXcQ");
+        assert_eq!(video_id("https://youtu.be/dQw4w9WgXcQ?si=abc"), "dQw4w9WgXcQ");
+        assert_eq!(video_id("dQw4w9WgXcQ"), "dQw4w9WgXcQ");
+
+        let path = std::env::temp_dir().join(format!("music-man-blocklist-{}.json", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let mut blocklist = Blocklist::load_from(&path);
+        assert!(blocklist.block("https://youtu.be/dQw4w9WgXcQ"));
+        assert!(!blocklist.block("dQw4w9WgXcQ"));
+        blocklist.save().unwrap();
+
+        let mut blocklist = Blocklist::load_from(&path);
+        assert!(blocklist.contains("https://www.youtube.com/watch?v=dQw4w9WgXcQ"));
+        assert!(!blocklist.contains("https://www.youtube.com/watch?v=9bZkp7q19f0"));
+        assert!(blocklist.unblock("https://www.youtube.com/watch?v=dQw4w9WgXcQ"));
+        assert!(blocklist.is_empty());
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,211 @@
+// StateBundle -> Everything that makes up an install except the audio itself, packed into a tar.gz to carry to
+// another machine: config, playlists, history, flags and the cached files' sidecar metadata. The bundle lists the
+// exporting cache's keys, so the new machine can tell what it still has to fetch. Device profiles live on the devices
+// themselves, so they travel with them.
+//
+// Layout: bundle.json, config/, data/, meta/ and playlists.json, which holds every playlist whatever the store layout.
+// Only the files named in CONFIG_FILES and DATA_FILES are carried, in either direction. The config and data dirs can
+// be the same directory (on macOS both are Application Support), which also holds logs, probes, the managed yt-dlp
+// and other state that only means something on the machine that wrote it.
+
+use std::{
+    collections::HashSet,
+    fs::{OpenOptions, copy, create_dir_all, read_dir, read_to_string, write},
+    io::{self, Write},
+    path::Path,
+    process::Command,
+};
+
+use crate::{
+    audio::{AudioError, AudioKey},
+    cache::{LocalCache, StagingDir, get_config_dir, get_data_dir, sidecar_dir, unix_now},
+    playlist_store::PlaylistStore,
+    sort,
+};
+
+/// Bumped whenever the layout changes, bundles from a newer version are refused rather than half imported.
+pub const BUNDLE_VERSION: u32 = 1;
+
+const MANIFEST_FILENAME: &str = "bundle.json";
+const PLAYLISTS_FILENAME: &str = "playlists.json";
+
+const CONFIG_FILES: [&str; 1] = ["config.toml"];
+// Playlists are exported through the store instead.
+const DATA_FILES: [&str; 6] = [
+    "activity_history.jsonl",
+    "playlist_history.jsonl",
+    "flagged.json",
+    "starred_pending.json",
+    "download_queue.json",
+    "blocked_sources.json",
+];
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct BundleManifest {
+    pub version: u32,
+    // Unix seconds.
+    pub created_at: u64,
+    // Every key in the exporting machine's cache.
+    pub cached: Vec<AudioKey>,
+}
+
+/// Write the app state to a tar.gz bundle, returning how many cached keys it lists.
+pub fn export_state(cache: &LocalCache, bundle: &Path) -> Result<usize, AudioError> {
+    let staging = StagingDir::new("bundle")?;
+    let root = staging.path();
+    copy_files(&get_config_dir(), &root.join("config"), &CONFIG_FILES, false)?;
+    copy_files(&get_data_dir(), &root.join("data"), &DATA_FILES, false)?;
+    copy_tree(&sidecar_dir(), &root.join("meta"), false)?;
+    PlaylistStore::Monolithic(root.join(PLAYLISTS_FILENAME)).save(&cache.playlist_store().load(), &[])?;
+
+    let mut cached: Vec<AudioKey> = cache.index_keys().cloned().collect();
+    cached.sort_by(sort::key_cmp);
+    let manifest = BundleManifest {
+        version: BUNDLE_VERSION,
+        created_at: unix_now(),
+        cached,
+    };
+    write_json(&root.join(MANIFEST_FILENAME), &manifest)?;
+
+    // Absolute, so the archive can't be taken as relative to the -C directory.
+    let bundle = std::path::absolute(bundle)?;
+    run_tar(Command::new("tar").arg("-czf").arg(&bundle).arg("-C").arg(root).arg("."))?;
+    Ok(manifest.cached.len())
+}
+
+// An unpacked bundle, removed again when dropped.
+pub struct StateBundle {
+    dir: StagingDir,
+    pub manifest: BundleManifest,
+}
+
+impl StateBundle {
+    /// Unpack a bundle, refusing one written by a newer version.
+    pub fn open(bundle: &Path) -> Result<Self, AudioError> {
+        let not_a_bundle = || AudioError::Config(format!("{} is not a music-man state bundle", bundle.display()));
+        let dir = StagingDir::new("bundle")?;
+        run_tar(Command::new("tar").arg("-xzf").arg(std::path::absolute(bundle)?).arg("-C").arg(dir.path()))?;
+        let manifest = read_to_string(dir.path().join(MANIFEST_FILENAME)).map_err(|_| not_a_bundle())?;
+
+        // Check the version before the rest, a newer layout may not parse at all.
+        let version = serde_json::from_str::<serde_json::Value>(&manifest)
+            .ok()
+            .and_then(|value| value.get("version")?.as_u64())
+            .ok_or_else(not_a_bundle)?;
+        if version > BUNDLE_VERSION as u64 {
+            return Err(AudioError::Config(format!(
+                "{} is a version {} state bundle, but this music-man only imports up to version {}. Upgrade it, then import again",
+                bundle.display(),
+                version,
+                BUNDLE_VERSION
+            )));
+        }
+        let manifest = serde_json::from_str(&manifest).map_err(|_| not_a_bundle())?;
+        Ok(Self { dir, manifest })
+    }
+
+    /// Copy the bundle's state in. Merging keeps existing files, appends history the machine doesn't have yet, and
+    /// adds the playlist entries missing here. Replacing overwrites them and deletes the playlists the bundle doesn't
+    /// have. Returns the playlists that changed; the cache has to be reloaded to see the rest.
+    pub fn apply(&self, cache: &mut LocalCache, replace: bool) -> Result<Vec<String>, AudioError> {
+        cache.ensure_writable()?;
+        let root = self.dir.path();
+        copy_files(&root.join("config"), &get_config_dir(), &CONFIG_FILES, replace)?;
+        copy_files(&root.join("data"), &get_data_dir(), &DATA_FILES, replace)?;
+        copy_tree(&root.join("meta"), &sidecar_dir(), replace)?;
+        let playlists = PlaylistStore::Monolithic(root.join(PLAYLISTS_FILENAME)).load();
+        cache.import_playlists(playlists, replace)
+    }
+
+    /// Keys cached on the exporting machine that aren't cached here.
+    pub fn missing(&self, cache: &LocalCache) -> Vec<&AudioKey> {
+        let cached: HashSet<&AudioKey> = cache.index_keys().collect();
+        self.manifest.cached.iter().filter(|key| !cached.contains(key)).collect()
+    }
+}
+
+/// Ask on the terminal whether to merge with or replace the existing state, None to cancel.
+pub fn prompt_import_mode() -> Option<bool> {
+    loop {
+        print!("Merge the bundle into the existing state, or replace it? [m]erge, [r]eplace, [c]ancel? ");
+        io::stdout().flush().ok();
+        let mut answer = String::new();
+        if io::stdin().read_line(&mut answer).unwrap_or(0) == 0 {
+            // Input closed, never block, and never replace without being asked.
+            return None;
+        }
+        match answer.trim().to_lowercase().as_str() {
+            "m" | "merge" => return Some(false),
+            "r" | "replace" => return Some(true),
+            "c" | "cancel" | "" => return None,
+            _ => println!("Please answer m, r or c."),
+        }
+    }
+}
+
+fn write_json(path: &Path, value: &impl serde::Serialize) -> io::Result<()> {
+    write(path, serde_json::to_string_pretty(value)?)
+}
+
+fn run_tar(command: &mut Command) -> Result<(), AudioError> {
+    let output = command.output()?;
+    if !output.status.success() {
+        return Err(AudioError::Config(format!(
+            "tar failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+// Copy a directory tree. Existing files are kept unless overwrite is set.
+fn copy_tree(from: &Path, to: &Path, overwrite: bool) -> io::Result<()> {
+    if !from.is_dir() {
+        return Ok(());
+    }
+    create_dir_all(to)?;
+    for entry in read_dir(from)? {
+        let entry = entry?;
+        let (src, dst) = (entry.path(), to.join(entry.file_name()));
+        if entry.file_type()?.is_dir() {
+            copy_tree(&src, &dst, overwrite)?;
+        } else {
+            copy_file(&src, &dst, overwrite)?;
+        }
+    }
+    Ok(())
+}
+
+// Copy the named files of a directory, skipping any that aren't there.
+fn copy_files(from: &Path, to: &Path, names: &[&str], overwrite: bool) -> io::Result<()> {
+    create_dir_all(to)?;
+    for name in names {
+        let src = from.join(name);
+        if src.is_file() {
+            copy_file(&src, &to.join(name), overwrite)?;
+        }
+    }
+    Ok(())
+}
+
+// An existing file is kept unless overwrite is set, except history logs (.jsonl) which get the lines they don't have
+// yet appended.
+fn copy_file(src: &Path, dst: &Path, overwrite: bool) -> io::Result<()> {
+    if overwrite || !dst.exists() {
+        copy(src, dst)?;
+    } else if src.extension().is_some_and(|ext| ext == "jsonl") {
+        append_new_lines(src, dst)?;
+    }
+    Ok(())
+}
+
+fn append_new_lines(src: &Path, dst: &Path) -> io::Result<()> {
+    let existing = read_to_string(dst)?;
+    let seen: HashSet<&str> = existing.lines().collect();
+    let new: String = read_to_string(src)?
+        .lines()
+        .filter(|line| !seen.contains(line))
+        .map(|line| format!("{}\n", line))
+        .collect();
+    OpenOptions::new().append(true).open(dst)?.write_all(new.as_bytes())
+}
@@ -8,12 +8,25 @@
 //
 // Cache is an AudioIndex and an AudioSource
 
-use std::{collections::HashMap, path::Path};
-use std::fs::{create_dir_all, read_dir, read_to_string, write};
+use std::{borrow::Cow, collections::{HashMap, HashSet}, path::Path};
+use std::fs::{DirEntry, create_dir_all, read_dir, read_to_string, rename, write};
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
-use crate::audio::list_audio_in_folder;
-use crate::source::AudioSource;
+use crate::audio::{PARTIAL_DOWNLOAD_SUFFIX, audio_extensions, find_key, has_audio_extension, has_extension_in, is_audio_file_in, is_partial_download, list_audio_in_folder, normalize_isrc, transcode_to_mp3, verify_audio_file, write_tags};
+use crate::config::{Config, PlaylistStorage};
+use crate::file_index::{IndexedDir, IndexedFile, ReindexSummary, reindex_dir};
+use crate::fsutil::{format_size, hash_file, on_disk_spelling, smart_copy};
+use crate::fuzzy;
+use crate::http::stage_remote;
+use crate::naming::{DestNaming, FilenameRules};
+use crate::history::{self, PlaylistSnapshot};
+use crate::playlist_store::{PlaylistStore, Playlists};
+use crate::sidecar::Sidecar;
+use crate::sort;
+use crate::source::{AudioSource, FetchResult};
 use crate::{audio::{AudioError, AudioInfo, AudioKey, AudioLocation, Playlist, PlaylistName}, index::AudioIndex};
 
 pub fn setup_app_directories() -> std::io::Result<()> {
@@ -28,6 +41,10 @@ pub fn setup_app_directories() -> std::io::Result<()> {
 
     // Create local file cache for downloaded audio.
     create_dir_all(audio_cache_dir())?;
+    create_dir_all(staging_dir())?;
+    create_dir_all(trash_dir())?;
+    create_dir_all(sidecar_dir())?;
+    create_dir_all(originals_dir())?;
 
     println!("Data dir: {:?}", data_dir);
     println!("Cache dir: {:?}", cache_dir);
@@ -64,6 +81,243 @@ pub fn playlist_cache() -> PathBuf {
     audio_cache_dir().join("playlists.json")
 }
 
+// Temporary download location, kept outside the flat audio dir so partial files are never indexed.
+pub fn staging_dir() -> PathBuf {
+    get_cache_dir().join("staging")
+}
+
+/// A fresh directory under staging_dir() for one fetch, removed along with anything left in it when dropped. Named
+/// <kind>-<pid>-<time>-<n>, so the startup sweep can tell a crashed run's directories from a running one's.
+pub struct StagingDir {
+    path: PathBuf,
+}
+
+impl StagingDir {
+    pub fn new(kind: &str) -> std::io::Result<Self> {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let n = NEXT.fetch_add(1, Ordering::Relaxed);
+        let path = staging_dir().join(format!("{}-{}-{}-{}", kind, std::process::id(), unix_now(), n));
+        create_dir_all(&path)?;
+        Ok(Self { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for StagingDir {
+    fn drop(&mut self) {
+        std::fs::remove_dir_all(&self.path).ok();
+    }
+}
+
+// Whether the process that created a staging directory is still running, None for names not made by StagingDir.
+fn staging_owner_running(name: &str) -> Option<bool> {
+    let pid = name.split('-').nth(1)?.parse::<libc::pid_t>().ok()?;
+    Some(process_running(pid))
+}
+
+pub(crate) fn process_running(pid: libc::pid_t) -> bool {
+    if pid as u32 == std::process::id() {
+        return true;
+    }
+    // Signal 0 only checks the process exists, EPERM means it does but belongs to someone else.
+    let exists = unsafe { libc::kill(pid, 0) == 0 };
+    exists || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+// Held by the one process writing to the cache, holding its pid.
+pub fn cache_lock_path() -> PathBuf {
+    get_cache_dir().join("lock")
+}
+
+/// The pid recorded in the cache lock, if there is one.
+pub fn cache_lock_owner() -> Option<libc::pid_t> {
+    read_to_string(cache_lock_path()).ok()?.trim().parse().ok()
+}
+
+/// Exclusive use of the cache for as long as it's held, so two runs never download into or rewrite the cache at once.
+/// A lock left behind by a process that's no longer running, e.g. one quit with a second Ctrl-C, is taken over.
+pub struct CacheLock(());
+
+impl CacheLock {
+    pub fn acquire() -> Result<Self, AudioError> {
+        create_dir_all(get_cache_dir())?;
+        loop {
+            match std::fs::OpenOptions::new().write(true).create_new(true).open(cache_lock_path()) {
+                Ok(mut file) => {
+                    std::io::Write::write_all(&mut file, std::process::id().to_string().as_bytes())?;
+                    return Ok(Self(()));
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => match cache_lock_owner() {
+                    Some(pid) if process_running(pid) => {
+                        return Err(AudioError::Unavailable(format!("the cache is in use by music-man (pid {})", pid)));
+                    }
+                    owner => {
+                        tracing::warn!("removing stale cache lock left by pid {:?}", owner);
+                        std::fs::remove_file(cache_lock_path())?;
+                    }
+                },
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+impl Drop for CacheLock {
+    fn drop(&mut self) {
+        release_cache_lock();
+    }
+}
+
+/// Remove the cache lock if this process holds it. Called on exit too, since exiting skips destructors.
+pub fn release_cache_lock() {
+    if cache_lock_owner() == Some(std::process::id() as libc::pid_t) {
+        std::fs::remove_file(cache_lock_path()).ok();
+    }
+}
+
+// Replaced or deleted audio is moved here rather than removed outright.
+pub fn trash_dir() -> PathBuf {
+    get_cache_dir().join("trash")
+}
+
+// Kept pre-transcode downloads, never indexed or synced to devices.
+pub fn originals_dir() -> PathBuf {
+    get_cache_dir().join("originals")
+}
+
+// Per-file Sidecar metadata for cached audio.
+pub fn sidecar_dir() -> PathBuf {
+    get_cache_dir().join("meta")
+}
+
+// Filenames last seen in the secondary cache, so its audio is still known about while the volume is unmounted.
+pub fn secondary_index_cache() -> PathBuf {
+    get_data_dir().join("secondary_index.json")
+}
+
+// Keys of the files last indexed in each cache directory, with their sizes and mtimes, so startup only rekeys the ones
+// that changed.
+pub fn file_index_cache() -> PathBuf {
+    get_cache_dir().join("file_index.json")
+}
+
+pub fn flagged_cache() -> PathBuf {
+    get_data_dir().join("flagged.json")
+}
+
+// ISRCs with two or more cached keys, sorted by their first key.
+fn isrc_conflicts_in(
+    isrc_index: &HashMap<String, Vec<AudioKey>>,
+    cached: impl Fn(&AudioKey) -> bool,
+) -> Vec<(String, Vec<AudioKey>)> {
+    let mut conflicts: Vec<(String, Vec<AudioKey>)> = isrc_index
+        .iter()
+        .map(|(isrc, keys)| (isrc.clone(), keys.iter().filter(|key| cached(key)).cloned().collect::<Vec<_>>()))
+        .filter(|(_, keys)| keys.len() > 1)
+        .collect();
+    for (_, keys) in &mut conflicts {
+        keys.sort_by(sort::key_cmp);
+    }
+    conflicts.sort_by(|a, b| sort::key_cmp(&a.1[0], &b.1[0]));
+    conflicts
+}
+
+// File names of audio whose Sidecar has it starred.
+fn starred_sidecars() -> HashSet<String> {
+    read_dir(sidecar_dir())
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().strip_suffix(".json")?.to_string();
+            let sidecar: Sidecar = serde_json::from_str(&read_to_string(entry.path()).ok()?).ok()?;
+            sidecar.starred.then_some(name)
+        })
+        .collect()
+}
+
+// Playlist entries starred before their audio was cached, starred in their Sidecar once it is.
+pub fn pending_stars_cache() -> PathBuf {
+    get_data_dir().join("starred_pending.json")
+}
+
+// ISRC -> keys of the audio cached for it, learned from the entries audio was added with. ISRCs are exact where
+// artist/title keys are fuzzy, so matching prefers them when both sides have one.
+pub fn isrc_index_cache() -> PathBuf {
+    get_data_dir().join("isrc_index.json")
+}
+
+/// Virtual playlist of every starred cached track, listed alongside the real playlists unless one shadows it.
+pub const STARRED_PLAYLIST: &str = "Starred";
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[derive(Debug)]
+pub struct GcCandidate {
+    pub key: AudioKey,
+    pub path: PathBuf,
+    pub bytes: u64,
+    // Unix seconds.
+    pub fetched_at: Option<u64>,
+}
+
+// Cached audio moved to the trash, with where it came from so it can be put back.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct TrashedAudio {
+    pub key: AudioKey,
+    pub path: PathBuf,
+    pub trash_path: PathBuf,
+    pub bytes: u64,
+    // ISRCs it was cached with, given back if it's restored.
+    #[serde(default)]
+    pub isrcs: Vec<String>,
+}
+
+// Trashed audio's sidecar sits next to it in the trash.
+fn trashed_sidecar(trash_path: &Path) -> PathBuf {
+    let mut name = trash_path.as_os_str().to_owned();
+    name.push(".json");
+    PathBuf::from(name)
+}
+
+// Bump when the persisted file index's format or how keys are derived from filenames changes.
+const FILE_INDEX_VERSION: u32 = 2;
+
+// The persisted file index of each cache directory, for the audio extensions it was built with.
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+struct CacheFileIndex {
+    #[serde(default)]
+    version: u32,
+    extensions: Vec<String>,
+    dirs: HashMap<PathBuf, IndexedDir>,
+}
+
+impl CacheFileIndex {
+    // Carry hashes and durations over from an index loaded earlier, for the files that are still the same.
+    fn merge_learned(&mut self, learned: CacheFileIndex) {
+        for (dir, learned) in learned.dirs {
+            let Some(indexed) = self.dirs.get_mut(&dir) else {
+                continue;
+            };
+            let learned: HashMap<String, IndexedFile> = learned.files.into_iter().map(|f| (f.name.clone(), f)).collect();
+            for file in &mut indexed.files {
+                if let Some(known) = learned.get(&file.name).filter(|known| known.size == file.size && known.mtime == file.mtime) {
+                    file.hash = file.hash.take().or_else(|| known.hash.clone());
+                    file.duration_secs = file.duration_secs.or(known.duration_secs);
+                }
+            }
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct LocalCache {
     // flat cache directory for all audio.
@@ -71,44 +325,147 @@ pub struct LocalCache {
     // Maps audio to cache locations.
     index: HashMap<AudioKey, PathBuf>,
     // Overlays the flat cache with playlist mappings.
-    playlists: HashMap<String, Vec<AudioInfo>>,
-    // Path to saved playlists metadata.
-    playlists_path: PathBuf,
+    playlists: Playlists,
+    // Where playlists are persisted.
+    store: PlaylistStore,
+    // Audio flagged for re-download e.g. corrupt or low quality files.
+    flagged: Vec<AudioInfo>,
+    // Starred playlist entries that aren't cached yet.
+    pending_stars: Vec<AudioKey>,
+    // File names of cached audio starred in its Sidecar, read from the sidecars when the index is built so listings
+    // don't read one per track.
+    starred: HashSet<String>,
+    // Normalized ISRC -> keys cached with it. Keys leave it with their audio, trashed audio takes its ISRCs along to
+    // be restored with.
+    isrc_index: HashMap<String, Vec<AudioKey>>,
+    // Optional overflow cache on another volume, which may not always be mounted.
+    secondary_dir: Option<PathBuf>,
+    // Size the primary cache may grow to before new audio goes to the secondary.
+    primary_limit: Option<u64>,
+    // Every mutating operation fails with AudioError::ReadOnly before touching the filesystem.
+    read_only: bool,
+    // Each cache directory's files as last indexed, with the hashes and durations worked out for them since. Shared,
+    // so a sync holding the cache immutably can still record the hashes it computes.
+    file_index: Arc<Mutex<CacheFileIndex>>,
 }
 
 impl LocalCache {
     pub fn new() -> Self {
+        Self::from_config(&Config::load().unwrap_or_default())
+    }
+
+    pub fn from_config(config: &Config) -> Self {
         // Load system state from config + data + cache directories.
         // 1. local file cache, for existing audio.
         // 2. audio lookup map -> mapping (artist, song) -> audio file.
         // 3. playlist map -> mapping (playlist name) -> set of AudioInfo.
-        setup_app_directories().expect("Failed to create app directories.");
+        if !config.read_only {
+            setup_app_directories().expect("Failed to create app directories.");
+        }
         let audio_dir = audio_cache_dir();
-        let playlists_path = playlist_cache();
+        let store = PlaylistStore::from_storage(config.playlist_storage);
         let mut cache = Self {
             audio_dir,
             index: HashMap::new(),
-            playlists: Self::load_playlists(&playlists_path),
-            playlists_path,
+            playlists: store.load(),
+            store,
+            flagged: read_to_string(flagged_cache())
+                .ok()
+                .and_then(|s| serde_json::from_str(&s).ok())
+                .unwrap_or_default(),
+            pending_stars: read_to_string(pending_stars_cache())
+                .ok()
+                .and_then(|s| serde_json::from_str(&s).ok())
+                .unwrap_or_default(),
+            starred: HashSet::new(),
+            isrc_index: read_to_string(isrc_index_cache())
+                .ok()
+                .and_then(|s| serde_json::from_str(&s).ok())
+                .unwrap_or_default(),
+            secondary_dir: config.secondary_cache_dir.clone(),
+            primary_limit: config.primary_cache_limit_bytes().unwrap_or_default(),
+            read_only: config.read_only,
+            file_index: Arc::default(),
         };
+        if !cache.read_only {
+            cache.sweep_partial_downloads();
+            cache.sweep_staging();
+        }
         cache.rebuild_index();
         println!("Initialized Local Cache: {:?}", cache);
         cache
     }
 
-    pub fn search_playlist(&self, playlist_name: &str) -> Result<Vec<(&AudioInfo, AudioLocation)>, AudioError> {
-        let playlist = self.get_playlist(playlist_name).ok_or(AudioError::NotFound)?;
+    // A cache of the audio in audio_dir and the given playlists, without the app directories or anything persisted in
+    // them.
+    #[cfg(test)]
+    pub(crate) fn in_dir(audio_dir: &Path, playlists: HashMap<String, Vec<AudioInfo>>) -> Self {
+        let index = read_dir(audio_dir)
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| Some((AudioKey::from_info(&AudioInfo::from_filename(entry.file_name()))?, entry.path())))
+            .collect();
+        Self {
+            audio_dir: audio_dir.to_path_buf(),
+            index,
+            playlists: playlists.into_iter().map(|(name, tracks)| (name, Arc::new(tracks))).collect(),
+            store: PlaylistStore::Monolithic(audio_dir.join("playlists.json")),
+            flagged: Vec::new(),
+            pending_stars: Vec::new(),
+            starred: HashSet::new(),
+            isrc_index: HashMap::new(),
+            secondary_dir: None,
+            primary_limit: None,
+            read_only: true,
+            file_index: Arc::default(),
+        }
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn ensure_writable(&self) -> Result<(), AudioError> {
+        if self.read_only {
+            return Err(AudioError::ReadOnly);
+        }
+        Ok(())
+    }
+
+    pub fn playlist_store(&self) -> &PlaylistStore {
+        &self.store
+    }
+
+    pub fn search_playlist(&self, playlist_name: &str) -> Result<Vec<(AudioInfo, AudioLocation)>, AudioError> {
+        let playlist = self.playlist_tracks(playlist_name).ok_or(AudioError::NotFound)?;
         playlist
             .iter()
             .map(|info| {
                 let location = self.search(info)?;
-                Ok((info, location))
+                Ok((info.clone(), location))
             })
             .collect()
     }
 
-    pub fn list_playlist_names(&self) -> impl Iterator<Item = &str> {
-        self.playlists.keys().map(|s| s.as_str())
+    pub fn index_keys(&self) -> impl Iterator<Item = &AudioKey> {
+        self.index.keys()
+    }
+
+    pub fn indexed(&self) -> impl Iterator<Item = (&AudioKey, &PathBuf)> {
+        self.index.iter()
+    }
+
+    // Every playlist entry across all playlists, audio may appear more than once.
+    pub fn playlist_entries(&self) -> impl Iterator<Item = &AudioInfo> {
+        self.playlists.values().flat_map(|tracks| tracks.iter())
+    }
+
+    /// Playlist names in display order.
+    pub fn playlist_names(&self) -> impl Iterator<Item = &str> {
+        let mut names: Vec<&str> = self.playlists.keys().map(|s| s.as_str()).collect();
+        names.sort_by(|a, b| sort::natural_cmp(a, b));
+        names.into_iter()
     }
 
     pub fn search(&self, info: &AudioInfo) -> Result<AudioLocation, AudioError> {
@@ -116,19 +473,280 @@ impl LocalCache {
         Ok(AudioLocation::LocalPath(path.to_path_buf()))
     }
 
+    /// Search on whichever of artist and title the AudioInfo has, returning every candidate with its fuzzy score (None
+    /// for exact matches). Offline entries are skipped.
+    pub fn search_partial(&self, info: &AudioInfo) -> Vec<(AudioKey, AudioLocation, Option<f64>)> {
+        fuzzy::partial_matches(info.artist.as_deref(), info.title.as_deref(), self.index.keys(), fuzzy::DEFAULT_THRESHOLD)
+            .into_iter()
+            .filter_map(|(key, score)| {
+                let path = self.index.get(key)?;
+                let offline = self.secondary_dir.as_ref().is_some_and(|s| path.starts_with(s)) && !self.secondary_mounted();
+                (!offline).then(|| (key.clone(), AudioLocation::LocalPath(path.clone()), score))
+            })
+            .collect()
+    }
+
     fn search_path(&self, info: &AudioInfo) -> Result<&PathBuf, AudioError> {
         let key = AudioKey::from_info(info).ok_or(AudioError::MissingInfo)?;
-        self.index.get(&key)
-            .ok_or(AudioError::NotFound)
+        let (_, path) = find_key(&self.index, &key).ok_or(AudioError::NotFound)?;
+        // Known to be in the secondary cache, but the volume it lives on isn't available right now.
+        if let Some(secondary) = &self.secondary_dir
+            && path.starts_with(secondary)
+            && !self.secondary_mounted()
+        {
+            return Err(AudioError::Unavailable(format!(
+                "{} is on secondary cache volume {}, which is not mounted",
+                path.display(),
+                secondary.display()
+            )));
+        }
+        Ok(path)
+    }
+
+    fn secondary_mounted(&self) -> bool {
+        self.secondary_dir.as_ref().is_some_and(|dir| dir.is_dir())
+    }
+
+    // Every audio file in the cache directories, regardless of playlist.
+    fn cached_audio(&self) -> Result<Vec<AudioInfo>, AudioError> {
+        let extensions = audio_extensions();
+        let mut all_cached = list_audio_in_folder(&self.audio_dir, &extensions)?;
+        if let Some(secondary) = self.secondary_dir.as_ref().filter(|_| self.secondary_mounted()) {
+            all_cached.extend(list_audio_in_folder(secondary, &extensions)?);
+        }
+        Ok(all_cached)
+    }
+
+    fn primary_size(&self) -> u64 {
+        read_dir(&self.audio_dir)
+            .map(|entries| {
+                entries
+                    .filter_map(|e| e.ok())
+                    .filter_map(|e| e.metadata().ok())
+                    .filter(|m| m.is_file())
+                    .map(|m| m.len())
+                    .sum()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Where newly fetched audio should go: the primary cache, unless it has reached its size limit and the
+    /// secondary cache is mounted.
+    pub fn download_dir(&self) -> Result<PathBuf, AudioError> {
+        self.ensure_writable()?;
+        Ok(match (&self.secondary_dir, self.primary_limit) {
+            (Some(secondary), Some(limit)) if self.secondary_mounted() && self.primary_size() >= limit => {
+                secondary.clone()
+            }
+            _ => self.audio_dir.clone(),
+        })
+    }
+
+    /// Verify a fetch that landed in a StagingDir, tag it, and move it into the cache directory, so the flat audio dir
+    /// only ever sees complete files. Staging is on the cache volume, so this is a plain rename unless the download
+    /// goes to a secondary cache on another volume. Cached audio is never replaced, that's what redownload is for.
+    /// Doesn't index the file, that's still add_to_cache.
+    pub fn commit_staged(&self, mut result: FetchResult) -> Result<FetchResult, AudioError> {
+        // Remote URLs are staged and moved in by add_to_cache.
+        let AudioLocation::LocalPath(staged) = &result.location else {
+            return Ok(result);
+        };
+        verify_audio_file(staged)?;
+        // A cached file named the same but for case is the same file on a case-insensitive volume, and keeps its name.
+        let dest = on_disk_spelling(&self.download_dir()?.join(staged.file_name().ok_or(AudioError::NotFound)?));
+        if dest.exists() {
+            return Err(AudioError::Io(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{} is already cached, re-download it to replace it", dest.display()),
+            )));
+        }
+        // Untagged audio is still worth keeping.
+        if let Err(e) = write_tags(staged, &result.info) {
+            tracing::warn!("{}", e);
+        }
+        move_file(staged, &dest)?;
+        result.location = AudioLocation::LocalPath(dest);
+        Ok(result)
+    }
+
+    /// Cached audio that `cache gc` could remove: referenced by no playlist (so never pinned either), not starred, not
+    /// in keep e.g. what's on an attached device, and when older_than is set, fetched longer ago than that. Offline
+    /// audio is left alone, largest first.
+    pub fn gc_candidates(&self, older_than: Option<u64>, keep: &HashSet<&AudioKey>) -> Vec<GcCandidate> {
+        let referenced: HashSet<AudioKey> = self.playlist_entries().filter_map(AudioKey::from_info).collect();
+        let now = unix_now();
+        let mut candidates: Vec<GcCandidate> = self
+            .index
+            .iter()
+            .filter(|(key, path)| !referenced.contains(*key) && !keep.contains(key) && !self.is_starred(path))
+            .filter_map(|(key, path)| {
+                let meta = std::fs::metadata(path).ok()?;
+                // Audio cached before sidecars existed falls back to the file's age.
+                let fetched_at = Sidecar::load(path).and_then(|sidecar| sidecar.fetched_at).or_else(|| {
+                    let modified = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+                    Some(modified.as_secs())
+                });
+                if older_than.is_some_and(|age| fetched_at.is_none_or(|fetched| fetched + age > now)) {
+                    return None;
+                }
+                Some(GcCandidate {
+                    key: key.clone(),
+                    path: path.clone(),
+                    bytes: meta.len(),
+                    fetched_at,
+                })
+            })
+            .collect();
+        candidates.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| sort::key_cmp(&a.key, &b.key)));
+        candidates
+    }
+
+    /// Move cached audio and its sidecar to the trash, dropping it from the index.
+    pub fn trash_audio(&mut self, key: &AudioKey) -> Result<TrashedAudio, AudioError> {
+        self.ensure_writable()?;
+        let path = self.index.get(key).ok_or(AudioError::NotFound)?.clone();
+        let bytes = std::fs::metadata(&path)?.len();
+        let name = path.file_name().ok_or(AudioError::NotFound)?.to_string_lossy().to_string();
+        let trash_path = trash_dir().join(format!("{}-{}", unix_now(), name));
+        move_file(&path, &trash_path)?;
+        if let Some(sidecar) = Sidecar::path_for(&path).filter(|sidecar| sidecar.exists()) {
+            move_file(&sidecar, &trashed_sidecar(&trash_path)).ok();
+        }
+        self.note_starred(&path, false);
+        self.index.remove(key);
+        let isrcs = self.forget_isrcs(key);
+        if !isrcs.is_empty() {
+            self.save_isrc_index()?;
+        }
+        if self.secondary_dir.as_ref().is_some_and(|secondary| path.starts_with(secondary)) {
+            self.save_secondary_index()?;
+        }
+        Ok(TrashedAudio {
+            key: key.clone(),
+            path,
+            trash_path,
+            bytes,
+            isrcs,
+        })
+    }
+
+    /// Put trashed audio back where it was, e.g. to undo a `cache gc`.
+    pub fn restore_trashed(&mut self, trashed: &TrashedAudio) -> Result<(), AudioError> {
+        self.ensure_writable()?;
+        if trashed.path.exists() {
+            return Err(AudioError::ExportFailed(format!("{} already exists", trashed.path.display())));
+        }
+        move_file(&trashed.trash_path, &trashed.path)?;
+        if let Some(sidecar) = Sidecar::path_for(&trashed.path) {
+            move_file(&trashed_sidecar(&trashed.trash_path), &sidecar).ok();
+        }
+        self.note_starred(&trashed.path, Sidecar::load(&trashed.path).is_some_and(|sidecar| sidecar.starred));
+        self.index.insert(trashed.key.clone(), trashed.path.clone());
+        for isrc in &trashed.isrcs {
+            let keys = self.isrc_index.entry(isrc.clone()).or_default();
+            if !keys.contains(&trashed.key) {
+                keys.push(trashed.key.clone());
+            }
+        }
+        if !trashed.isrcs.is_empty() {
+            self.save_isrc_index()?;
+        }
+        if self.secondary_dir.as_ref().is_some_and(|secondary| trashed.path.starts_with(secondary)) {
+            self.save_secondary_index()?;
+        }
+        Ok(())
+    }
+
+    /// Make room in the primary cache by moving the least recently modified audio to the secondary cache, until the
+    /// primary is back under its limit. Returns how many files were demoted.
+    pub fn demote_to_secondary(&mut self) -> Result<usize, AudioError> {
+        self.ensure_writable()?;
+        let (Some(secondary), Some(limit)) = (self.secondary_dir.clone(), self.primary_limit) else {
+            return Err(AudioError::Config("No secondary cache and primary_cache_limit configured".to_string()));
+        };
+        if !self.secondary_mounted() {
+            return Err(AudioError::Unavailable(format!(
+                "Secondary cache volume {} is not mounted",
+                secondary.display()
+            )));
+        }
+
+        let mut primary: Vec<(AudioKey, PathBuf, u64, SystemTime)> = self
+            .index
+            .iter()
+            .filter(|(_, path)| path.starts_with(&self.audio_dir))
+            .filter_map(|(key, path)| {
+                let meta = std::fs::metadata(path).ok()?;
+                Some((key.clone(), path.clone(), meta.len(), meta.modified().ok()?))
+            })
+            .collect();
+        primary.sort_by_key(|(_, _, _, modified)| *modified);
+
+        let mut size = self.primary_size();
+        let mut demoted = 0;
+        for (key, path, len, _) in primary {
+            if size < limit {
+                break;
+            }
+            let dest = secondary.join(path.file_name().ok_or(AudioError::NotFound)?);
+            move_file(&path, &dest)?;
+            self.index.insert(key, dest);
+            size = size.saturating_sub(len);
+            demoted += 1;
+        }
+        self.save_secondary_index()?;
+        Ok(demoted)
     }
 
-    /// Add downloaded audio to the cache index, and optionally to a playlist.
-    /// Call this after fetching audio from a source.
-    pub fn add_to_cache(&mut self, info: &AudioInfo, location: &AudioLocation, playlist: Option<&str>) {
+    fn save_secondary_index(&self) -> std::io::Result<()> {
+        let Some(secondary) = &self.secondary_dir else {
+            return Ok(());
+        };
+        let mut filenames: Vec<String> = self
+            .index
+            .values()
+            .filter(|path| path.starts_with(secondary))
+            .filter_map(|path| Some(path.file_name()?.to_string_lossy().to_string()))
+            .collect();
+        filenames.sort();
+        write(secondary_index_cache(), serde_json::to_string_pretty(&filenames)?)
+    }
+
+    /// Add downloaded audio to the cache index, and optionally to a playlist, returning where it's cached.
+    /// Call this after fetching audio from a source. Remote locations are downloaded into the cache first.
+    pub fn add_to_cache(
+        &mut self,
+        info: &AudioInfo,
+        location: &AudioLocation,
+        playlist: Option<&str>,
+    ) -> Result<AudioLocation, AudioError> {
+        self.ensure_writable()?;
+        let location = match location {
+            AudioLocation::RemoteUrl(url) => {
+                let staged = stage_remote(url)?;
+                // Named for the track like any other cached audio, not for whatever the URL ended in.
+                let name = DestNaming::FromInfo
+                    .file_name(info, staged.path(), FilenameRules::Local)
+                    .ok_or(AudioError::NotFound)?;
+                let dest = on_disk_spelling(&self.download_dir()?.join(name));
+                move_file(staged.path(), &dest)?;
+                AudioLocation::LocalPath(dest)
+            }
+            local => local.clone(),
+        };
         // Update the index
-        if let AudioLocation::LocalPath(path) = location {
+        if let AudioLocation::LocalPath(path) = &location {
             if let Some(key) = AudioKey::from_info(info) {
-                self.index.insert(key, path.clone());
+                self.index.insert(key.clone(), path.clone());
+                if self.pending_stars.contains(&key) {
+                    self.set_starred(info, true).ok();
+                }
+                if self.record_isrc(info) {
+                    self.save_isrc_index().ok();
+                }
+            }
+            if self.secondary_dir.as_ref().is_some_and(|secondary| path.starts_with(secondary)) {
+                self.save_secondary_index().ok();
             }
         }
 
@@ -136,54 +754,683 @@ impl LocalCache {
         if let Some(playlist_name) = playlist {
             self.add_to_playlist(playlist_name, info.clone());
         }
+        Ok(location)
     }
 
-    // Iterate the disk cache and build the index of AudioKey -> Audio path.
-    fn rebuild_index(&mut self) {
-        self.index.clear();
+    pub fn save_sidecar(&self, audio_path: &Path, sidecar: &Sidecar) -> Result<(), AudioError> {
+        self.ensure_writable()?;
+        sidecar.save(audio_path)?;
+        Ok(())
+    }
 
-        if let Ok(entries) = read_dir(&self.audio_dir) {
-            for entry in entries.filter_map(|e| e.ok()) {
-                if entry.path().is_file() {
-                    let filename = entry.file_name().to_string_lossy().to_string();
-                    if filename.starts_with("._") {
-                        continue;
-                    }
-                    
-                    let info = AudioInfo::from_filename(&filename);
-                    if let Some(key) = AudioKey::from_info(&info) {
-                        self.index.insert(key, entry.path());
+    pub fn flagged(&self) -> &[AudioInfo] {
+        &self.flagged
+    }
+
+    /// Mark cached audio for re-download, persisting the flag across restarts.
+    pub fn flag(&mut self, info: &AudioInfo) -> Result<(), AudioError> {
+        self.ensure_writable()?;
+        let key = AudioKey::from_info(info).ok_or(AudioError::MissingInfo)?;
+        if !self.flagged.iter().any(|f| AudioKey::from_info(f).as_ref() == Some(&key)) {
+            self.flagged.push(info.clone());
+            self.save_flagged()?;
+        }
+        Ok(())
+    }
+
+    pub fn unflag(&mut self, info: &AudioInfo) -> Result<(), AudioError> {
+        self.ensure_writable()?;
+        let key = AudioKey::from_info(info).ok_or(AudioError::MissingInfo)?;
+        self.flagged.retain(|f| AudioKey::from_info(f).as_ref() != Some(&key));
+        self.save_flagged()?;
+        Ok(())
+    }
+
+    fn save_flagged(&self) -> std::io::Result<()> {
+        let flagged_json = serde_json::to_string_pretty(&self.flagged)?;
+        write(flagged_cache(), flagged_json)
+    }
+
+    pub fn is_starred(&self, audio_path: &Path) -> bool {
+        audio_path.file_name().is_some_and(|name| self.starred.contains(&*name.to_string_lossy()))
+    }
+
+    // Remember whether audio is starred, by the file name its Sidecar is kept under.
+    fn note_starred(&mut self, audio_path: &Path, starred: bool) {
+        let Some(name) = audio_path.file_name().map(|name| name.to_string_lossy().to_string()) else {
+            return;
+        };
+        if starred {
+            self.starred.insert(name);
+        } else {
+            self.starred.remove(&name);
+        }
+    }
+
+    /// Star or unstar audio. Cached audio is marked in its Sidecar, a playlist entry that isn't cached yet is starred
+    /// once it's added to the cache. Returns whether the audio was cached.
+    pub fn set_starred(&mut self, info: &AudioInfo, starred: bool) -> Result<bool, AudioError> {
+        self.ensure_writable()?;
+        let key = AudioKey::from_info(info).ok_or(AudioError::MissingInfo)?;
+        self.pending_stars.retain(|pending| *pending != key);
+        let cached = match self.index.get(&key) {
+            Some(path) => {
+                let path = path.clone();
+                let mut sidecar = Sidecar::load(&path).unwrap_or_default();
+                sidecar.starred = starred;
+                sidecar.save(&path)?;
+                self.note_starred(&path, starred);
+                true
+            }
+            None if !self.playlist_entries().any(|entry| AudioKey::from_info(entry).as_ref() == Some(&key)) => {
+                return Err(AudioError::NotFound);
+            }
+            None => {
+                if starred {
+                    self.pending_stars.push(key);
+                }
+                false
+            }
+        };
+        self.save_pending_stars()?;
+        Ok(cached)
+    }
+
+    // Remember the ISRC audio is cached under, returning whether it wasn't known yet.
+    fn record_isrc(&mut self, info: &AudioInfo) -> bool {
+        let (Some(isrc), Some(key)) = (info.normalized_isrc(), AudioKey::from_info(info)) else {
+            return false;
+        };
+        if !self.index.contains_key(&key) {
+            return false;
+        }
+        let keys = self.isrc_index.entry(isrc).or_default();
+        if keys.contains(&key) {
+            return false;
+        }
+        keys.push(key);
+        true
+    }
+
+    // Drop a key from the ISRC index, returning the ISRCs it had.
+    fn forget_isrcs(&mut self, key: &AudioKey) -> Vec<String> {
+        let mut forgotten = Vec::new();
+        self.isrc_index.retain(|isrc, keys| {
+            if keys.contains(key) {
+                keys.retain(|k| k != key);
+                forgotten.push(isrc.clone());
+            }
+            !keys.is_empty()
+        });
+        forgotten
+    }
+
+    fn save_isrc_index(&self) -> std::io::Result<()> {
+        write(isrc_index_cache(), serde_json::to_string_pretty(&self.isrc_index)?)
+    }
+
+    /// Cached audio with the given ISRC. More than one key is a probable duplicate, see isrc_conflicts.
+    pub fn cached_by_isrc(&self, isrc: &str) -> Vec<&AudioKey> {
+        normalize_isrc(isrc)
+            .and_then(|isrc| self.isrc_index.get(&isrc))
+            .map(|keys| keys.iter().filter(|key| self.index.contains_key(key)).collect())
+            .unwrap_or_default()
+    }
+
+    /// The ISRCs a cached file is known by, usually one.
+    pub fn isrcs_of_file(&self, path: &Path) -> Vec<&str> {
+        let key = path.file_name().and_then(|name| AudioKey::from_info(&AudioInfo::from_filename(name)));
+        self.cached_isrcs().filter(|(_, k)| Some(*k) == key.as_ref()).map(|(isrc, _)| isrc).collect()
+    }
+
+    /// Every known (ISRC, key) pair of cached audio. Audio re-recorded under the same artist and title can have more
+    /// than one ISRC.
+    pub fn cached_isrcs(&self) -> impl Iterator<Item = (&str, &AudioKey)> {
+        self.isrc_index.iter().flat_map(move |(isrc, keys)| {
+            keys.iter().filter(|key| self.index.contains_key(key)).map(move |key| (isrc.as_str(), key))
+        })
+    }
+
+    /// ISRCs cached as two or more different files, e.g. the same recording saved under a different artist spelling.
+    /// These are probable duplicates.
+    pub fn isrc_conflicts(&self) -> Vec<(String, Vec<AudioKey>)> {
+        isrc_conflicts_in(&self.isrc_index, |key| self.index.contains_key(key))
+    }
+
+    fn save_pending_stars(&self) -> std::io::Result<()> {
+        let pending_json = serde_json::to_string_pretty(&self.pending_stars)?;
+        write(pending_stars_cache(), pending_json)
+    }
+
+    /// Every starred cached track, in artist then title order.
+    pub fn starred_audio(&self) -> Vec<AudioInfo> {
+        let mut starred: Vec<(&AudioKey, &PathBuf)> =
+            self.index.iter().filter(|(_, path)| self.is_starred(path)).collect();
+        starred.sort_by(|a, b| sort::key_cmp(a.0, b.0));
+        starred
+            .into_iter()
+            .filter_map(|(_, path)| path.file_name().map(AudioInfo::from_filename))
+            .collect()
+    }
+
+    /// The URL the cached audio was originally fetched from, if we recorded one in its Sidecar or any playlist entry.
+    pub fn provenance_url(&self, info: &AudioInfo) -> Option<String> {
+        if info.youtube_url.is_some() {
+            return info.youtube_url.clone();
+        }
+        if let Some(url) = self
+            .search_path(info)
+            .ok()
+            .and_then(|path| Sidecar::load(path))
+            .and_then(|sidecar| sidecar.source_url)
+        {
+            return Some(url);
+        }
+        let key = AudioKey::from_info(info)?;
+        self.playlists
+            .values()
+            .flat_map(|tracks| tracks.iter())
+            .find(|entry| AudioKey::from_info(entry).as_ref() == Some(&key) && entry.youtube_url.is_some())
+            .and_then(|entry| entry.youtube_url.clone())
+    }
+
+    /// Fetch a fresh copy of cached audio and swap it in place of the existing file. The fresh copy is staged and
+    /// verified before the old file is moved to the trash, so a failed re-download never loses the current copy.
+    pub fn redownload(&mut self, info: &AudioInfo, source: &dyn AudioSource) -> Result<FetchResult, AudioError> {
+        self.ensure_writable()?;
+        // Must already be cached, re-download is only for replacing an existing file.
+        self.search_path(info)?;
+
+        let mut fetch_info = info.clone();
+        fetch_info.youtube_url = self.provenance_url(info);
+
+        let staging = StagingDir::new("redownload")?;
+        // Transcoding from a kept original is both faster and better quality than hitting the network again.
+        let fetched = match self.kept_original(info) {
+            Some(original) => {
+                let dest = staging.path().join(original.with_extension("mp3").file_name().ok_or(AudioError::NotFound)?);
+                transcode_to_mp3(&original, &dest).and_then(|_| {
+                    let bytes = std::fs::metadata(&dest)?.len();
+                    Ok(FetchResult::local(dest, fetch_info.clone(), bytes, "kept original"))
+                })
+            }
+            None => source.fetch(&fetch_info, staging.path().to_path_buf(), &DestNaming::FromInfo),
+        };
+        // The result describes the fetch, but points at where the fresh copy ended up in the cache.
+        let result = fetched.and_then(|mut fetched| {
+            fetched.location = match &fetched.location {
+                AudioLocation::LocalPath(path) => {
+                    verify_audio_file(path)?;
+                    self.replace_cached(info, path)?
+                }
+                AudioLocation::RemoteUrl(url) => {
+                    let staged = stage_remote(url)?;
+                    verify_audio_file(staged.path())?;
+                    self.replace_cached(info, staged.path())?
+                }
+            };
+            Ok(fetched)
+        });
+        drop(staging);
+
+        let result = result?;
+        self.unflag(info)?;
+        Ok(result)
+    }
+
+    /// Move a kept pre-transcode download into the originals directory, named after the cached file it belongs to,
+    /// returning the stored filename for recording in the Sidecar.
+    pub fn store_original(&self, cached: &Path, original: &Path) -> Result<String, AudioError> {
+        self.ensure_writable()?;
+        let stem = cached.file_stem().ok_or(AudioError::NotFound)?;
+        let mut dest = originals_dir().join(stem);
+        if let Some(ext) = original.extension() {
+            dest.set_extension(ext);
+        }
+        move_file(original, &dest)?;
+        Ok(dest.file_name().ok_or(AudioError::NotFound)?.to_string_lossy().to_string())
+    }
+
+    fn kept_original(&self, info: &AudioInfo) -> Option<PathBuf> {
+        let cached = self.search_path(info).ok()?;
+        let original = originals_dir().join(Sidecar::load(cached)?.original?);
+        original.exists().then_some(original)
+    }
+
+    // Replace the cached file for some audio with a new file, keeping the cached filename (and so every playlist
+    // and device reference) unless the extension changed, in which case the index and playlists are updated together.
+    fn replace_cached(&mut self, info: &AudioInfo, new_file: &Path) -> Result<AudioLocation, AudioError> {
+        let key = AudioKey::from_info(info).ok_or(AudioError::MissingInfo)?;
+        let old_path = self.search_path(info)?.clone();
+
+        let mut dest_path = old_path.clone();
+        if let Some(ext) = new_file.extension() {
+            dest_path.set_extension(ext);
+        }
+
+        // The original goes to the trash as a link or a copy, so the cached file stays in place until the new one is
+        // renamed over it, and a failure at any point leaves a playable track.
+        let old_name = old_path.file_name().ok_or(AudioError::NotFound)?.to_string_lossy().to_string();
+        let trash_path = trash_dir().join(format!("{}-{}", unix_now(), old_name));
+        if std::fs::hard_link(&old_path, &trash_path).is_err() {
+            smart_copy(&old_path, &trash_path)?;
+        }
+        if let Err(e) = move_file(new_file, &dest_path) {
+            std::fs::remove_file(&trash_path).ok();
+            return Err(e.into());
+        }
+
+        if dest_path != old_path {
+            // Left behind under the old extension it would only be a stray duplicate, the trash has it anyway.
+            if let Err(e) = std::fs::remove_file(&old_path) {
+                tracing::warn!("failed to remove replaced {}: {}", old_path.display(), e);
+            }
+            let mut changed = Vec::new();
+            if let (Some(old_sidecar), Some(new_sidecar)) = (Sidecar::path_for(&old_path), Sidecar::path_for(&dest_path)) {
+                rename(old_sidecar, new_sidecar).ok();
+            }
+            let starred = self.is_starred(&old_path);
+            self.note_starred(&old_path, false);
+            self.note_starred(&dest_path, starred);
+            let new_name = dest_path.file_name().map(|n| n.to_string_lossy().to_string());
+            for (name, tracks) in self.playlists.iter_mut() {
+                for entry in Arc::make_mut(tracks) {
+                    if AudioKey::from_info(entry).as_ref() == Some(&key) && entry.filename.is_some() {
+                        entry.filename = new_name.clone();
+                        changed.push(name.clone());
                     }
                 }
             }
+            changed.dedup();
+            self.save_playlists(&changed.iter().map(|s| s.as_str()).collect::<Vec<_>>())?;
         }
+        self.index.insert(key, dest_path.clone());
+
+        Ok(AudioLocation::LocalPath(dest_path))
+    }
+
+    /// Bring the index up to date with the cache directories, e.g. after the recognized audio extensions changed.
+    /// Returns what changed since the index was last persisted.
+    pub fn reindex(&mut self) -> ReindexSummary {
+        self.rebuild_index()
     }
 
-    // Reload the on-disk playlists file.
-    fn load_playlists(path: &Path) -> HashMap<String, Vec<AudioInfo>> {
-        read_to_string(path)
+    // Iterate the disk cache and build the index of AudioKey -> Audio path. The primary cache takes precedence when
+    // audio is in both caches. An unmounted secondary is indexed from the filenames we last saw on it. Files unchanged
+    // since the persisted file index keep the keys it recorded.
+    fn rebuild_index(&mut self) -> ReindexSummary {
+        let started = Instant::now();
+        self.index.clear();
+        let extensions = audio_extensions();
+        // An index built for other extensions left out files that may count now, and one from another version keyed
+        // them differently, so nothing in either is reused.
+        let mut persisted = read_to_string(file_index_cache())
             .ok()
-            .and_then(|s| serde_json::from_str(&s).ok())
-            .unwrap_or_default()
+            .and_then(|s| serde_json::from_str::<CacheFileIndex>(&s).ok())
+            .filter(|persisted| persisted.version == FILE_INDEX_VERSION && persisted.extensions == extensions)
+            .unwrap_or_default();
+        // Hashes learned since the file index was loaded, e.g. by a sync, aren't on disk yet.
+        let learned = std::mem::take(&mut *self.file_index.lock().unwrap_or_else(PoisonError::into_inner));
+        persisted.merge_learned(learned);
+        let mut summary = ReindexSummary::default();
+        self.starred = starred_sidecars();
+
+        if let Some(secondary) = self.secondary_dir.clone() {
+            if self.secondary_mounted() {
+                summary.merge(self.index_dir(&secondary, &extensions, &mut persisted));
+                if !self.read_only {
+                    self.save_secondary_index().ok();
+                }
+            } else {
+                let filenames: Vec<String> = read_to_string(secondary_index_cache())
+                    .ok()
+                    .and_then(|s| serde_json::from_str(&s).ok())
+                    .unwrap_or_default();
+                for filename in filenames.into_iter().filter(|name| has_extension_in(Path::new(name), &extensions)) {
+                    if let Some(key) = AudioKey::from_info(&AudioInfo::from_filename(&filename)) {
+                        self.index.insert(key, secondary.join(filename));
+                    }
+                }
+            }
+        }
+
+        let audio_dir = self.audio_dir.clone();
+        summary.merge(self.index_dir(&audio_dir, &extensions, &mut persisted));
+        persisted.version = FILE_INDEX_VERSION;
+        persisted.extensions = extensions;
+        persisted.dirs.retain(|dir, _| *dir == self.audio_dir || self.secondary_dir.as_ref() == Some(dir));
+        *self.file_index.lock().unwrap_or_else(PoisonError::into_inner) = persisted;
+        self.save_file_index();
+        tracing::debug!("indexed the cache in {:?}: {}", started.elapsed(), summary.summary());
+
+        // Playlist entries from an index that has ISRCs, e.g. imported ones, teach us the ISRC of audio cached before
+        // the ISRC index existed.
+        let entries: Vec<AudioInfo> = self.playlist_entries().filter(|info| info.isrc.is_some()).cloned().collect();
+        let learned = entries.iter().filter(|info| self.record_isrc(info)).count();
+        // Audio deleted from the cache directory by hand takes its ISRCs with it too.
+        let index = &self.index;
+        let mut pruned = false;
+        self.isrc_index.retain(|_, keys| {
+            let before = keys.len();
+            keys.retain(|key| index.contains_key(key));
+            pruned |= keys.len() != before;
+            !keys.is_empty()
+        });
+        if (learned > 0 || pruned) && !self.read_only {
+            self.save_isrc_index().ok();
+        }
+        summary
     }
 
-    fn save_playlists(&self) -> std::io::Result<()> {
-        let playlist_json = serde_json::to_string_pretty(&self.playlists)?;
-        write(&self.playlists_path, playlist_json)
+    /// Persist the file index, with whatever hashes and durations were learned since it was loaded.
+    pub fn save_file_index(&self) {
+        if self.read_only {
+            return;
+        }
+        let file_index = self.file_index.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Err(e) = serde_json::to_string(&*file_index).map_err(std::io::Error::from).and_then(|s| write(file_index_cache(), s)) {
+            tracing::warn!("failed to save the cache file index: {}", e);
+        }
+    }
+
+    // Run f on a cached file's index entry, if it has one and the file hasn't changed since it was indexed.
+    fn with_indexed_file<R>(&self, path: &Path, f: impl FnOnce(&mut IndexedFile) -> R) -> Option<R> {
+        let (dir, name) = (path.parent()?, path.file_name()?.to_str()?);
+        let meta = std::fs::metadata(path).ok()?;
+        let mut file_index = self.file_index.lock().unwrap_or_else(PoisonError::into_inner);
+        let file = file_index.dirs.get_mut(dir)?.files.iter_mut().find(|file| file.name == name)?;
+        file.matches(&meta).then(|| f(file))
+    }
+
+    /// The content hash of a cached file, hashing it only when the file index doesn't have it yet.
+    pub fn content_hash(&self, path: &Path) -> std::io::Result<String> {
+        if let Some(hash) = self.with_indexed_file(path, |file| file.hash.clone()).flatten() {
+            return Ok(hash);
+        }
+        let hash = hash_file(path)?;
+        self.with_indexed_file(path, |file| file.hash = Some(hash.clone()));
+        Ok(hash)
     }
 
-    fn get_playlist(&self, name: &str) -> Option<&Vec<AudioInfo>> {
-        self.playlists.get(name)
+    /// How long a cached file plays, from the file index, or its Sidecar the first time.
+    pub fn duration_secs(&self, path: &Path) -> Option<u32> {
+        if let Some(duration) = self.with_indexed_file(path, |file| file.duration_secs).flatten() {
+            return Some(duration);
+        }
+        let duration = Sidecar::load(path)?.duration_secs?;
+        self.with_indexed_file(path, |file| file.duration_secs = Some(duration));
+        Some(duration)
+    }
+
+    fn index_dir(&mut self, dir: &Path, extensions: &[String], persisted: &mut CacheFileIndex) -> ReindexSummary {
+        // Only the recognized audio extensions, not fork files or downloads still in progress.
+        let accept = |entry: &DirEntry| is_audio_file_in(entry, extensions);
+        let reindexed = reindex_dir(dir, persisted.dirs.remove(dir), accept, |name| {
+            AudioKey::from_info(&AudioInfo::from_filename(name))
+        });
+        match reindexed {
+            Ok((indexed, summary)) => {
+                for file in &indexed.files {
+                    self.index.insert(file.key.clone(), dir.join(&file.name));
+                }
+                persisted.dirs.insert(dir.to_path_buf(), indexed);
+                summary
+            }
+            Err(e) => {
+                tracing::warn!("failed to index {}: {}", dir.display(), e);
+                ReindexSummary::default()
+            }
+        }
+    }
+
+    fn save_playlists(&self, changed: &[&str]) -> std::io::Result<()> {
+        self.store.save(&self.playlists, changed)
+    }
+
+    pub fn rename_playlist(&mut self, old: &str, new: &str) -> Result<(), AudioError> {
+        self.ensure_writable()?;
+        if self.playlists.contains_key(new) {
+            return Err(AudioError::ExportFailed(format!("Playlist {} already exists", new)));
+        }
+        let tracks = self.playlists.remove(old).ok_or(AudioError::NotFound)?;
+        self.playlists.insert(new.to_string(), tracks);
+        self.store.rename(&self.playlists, old, new)?;
+        self.record_snapshot(new, &format!("rename from {}", old));
+        Ok(())
+    }
+
+    pub fn delete_playlist(&mut self, name: &str) -> Result<(), AudioError> {
+        self.ensure_writable()?;
+        self.playlists.remove(name).ok_or(AudioError::NotFound)?;
+        self.save_playlists(&[name])?;
+        // Snapshot the now empty playlist, so a delete can be rolled back like any other change.
+        if let Err(e) = history::record_snapshot(name, &[], "delete") {
+            println!("Failed to record playlist history for {}: {}", name, e);
+        }
+        Ok(())
+    }
+
+    /// Bring in playlists from elsewhere, e.g. an imported state bundle. Replacing swaps each playlist wholesale and
+    /// deletes the playlists the import doesn't have, merging appends the entries a playlist doesn't have yet. Returns
+    /// the playlists that changed, deleted ones included.
+    pub fn import_playlists(&mut self, imported: Playlists, replace: bool) -> Result<Vec<String>, AudioError> {
+        self.ensure_writable()?;
+        let mut changed = Vec::new();
+        let mut deleted = Vec::new();
+        if replace {
+            deleted = self.playlists.keys().filter(|name| !imported.contains_key(*name)).cloned().collect();
+            for name in &deleted {
+                self.playlists.remove(name);
+            }
+            changed.extend(deleted.iter().cloned());
+        }
+        for (name, tracks) in imported {
+            let existing = Arc::make_mut(self.playlists.entry(name.clone()).or_default());
+            let before = existing.len();
+            if replace {
+                *existing = Arc::unwrap_or_clone(tracks);
+            } else {
+                let present: HashSet<AudioKey> = existing.iter().filter_map(AudioKey::from_info).collect();
+                existing.extend(
+                    Arc::unwrap_or_clone(tracks)
+                        .into_iter()
+                        .filter(|info| AudioKey::from_info(info).is_none_or(|key| !present.contains(&key))),
+                );
+            }
+            if replace || existing.len() != before {
+                changed.push(name);
+            }
+        }
+        changed.sort_by(|a, b| sort::natural_cmp(a, b));
+        self.save_playlists(&changed.iter().map(String::as_str).collect::<Vec<_>>())?;
+        for name in &changed {
+            if deleted.contains(name) {
+                // Snapshot the now empty playlist, like a delete, so it can be rolled back.
+                if let Err(e) = history::record_snapshot(name, &[], "import state") {
+                    println!("Failed to record playlist history for {}: {}", name, e);
+                }
+            } else {
+                self.record_snapshot(name, "import state");
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Switch playlist persistence to another layout, writing every playlist to the new store.
+    pub fn migrate_playlists(&mut self, storage: PlaylistStorage) -> Result<(), AudioError> {
+        self.ensure_writable()?;
+        let store = PlaylistStore::from_storage(storage);
+        self.store.migrate_to(&store)?;
+        self.store = store;
+        Ok(())
+    }
+
+    /// A playlist's entries, or the virtual Starred playlist when no real playlist has that name.
+    pub fn playlist_tracks(&self, name: &str) -> Option<Cow<'_, [AudioInfo]>> {
+        match self.playlists.get(name) {
+            Some(tracks) => Some(Cow::Borrowed(tracks.as_slice())),
+            None if name == STARRED_PLAYLIST => Some(Cow::Owned(self.starred_audio())),
+            None => None,
+        }
+    }
+
+    /// Change a playlist's entries for some audio, e.g. its pin and skip flags, and save the playlist file.
+    pub fn update_entry(
+        &mut self,
+        playlist_name: &str,
+        info: &AudioInfo,
+        update: impl Fn(&mut AudioInfo),
+    ) -> Result<(), AudioError> {
+        self.ensure_writable()?;
+        let key = AudioKey::from_info(info).ok_or(AudioError::MissingInfo)?;
+        let tracks = Arc::make_mut(self.playlists.get_mut(playlist_name).ok_or(AudioError::NotFound)?);
+        let mut found = false;
+        for entry in tracks.iter_mut().filter(|entry| AudioKey::from_info(entry).as_ref() == Some(&key)) {
+            update(entry);
+            found = true;
+        }
+        if !found {
+            return Err(AudioError::NotFound);
+        }
+        self.save_playlists(&[playlist_name])?;
+        Ok(())
     }
 
     // Add audio to a playlist and save the playlist file.
-    fn add_to_playlist(&mut self, playlist_name: &str, audio: AudioInfo) {
-        self.playlists
-            .entry(playlist_name.to_string())
-            .or_default()
-            .push(audio);
-        self.save_playlists().ok();
+    fn add_to_playlist(&mut self, playlist_name: &str, mut audio: AudioInfo) {
+        audio.added_at = Some(unix_now());
+        let operation = format!(
+            "add {} - {}",
+            audio.artist.as_deref().unwrap_or_default(),
+            audio.title.as_deref().unwrap_or_default()
+        );
+        Arc::make_mut(self.playlists.entry(playlist_name.to_string()).or_default()).push(audio);
+        self.save_playlists(&[playlist_name]).ok();
+        self.record_snapshot(playlist_name, &operation);
+    }
+
+    // Snapshot a playlist after a change, history is best effort and never fails the change itself.
+    fn record_snapshot(&self, playlist_name: &str, operation: &str) {
+        if let Some(tracks) = self.playlists.get(playlist_name)
+            && let Err(e) = history::record_snapshot(playlist_name, tracks, operation)
+        {
+            println!("Failed to record playlist history for {}: {}", playlist_name, e);
+        }
+    }
+
+    /// Restore a playlist to a snapshot's track list. Snapshots only hold keys, so each entry is rebuilt from an
+    /// existing playlist entry or the cached file, and keys with neither are dropped and returned.
+    pub fn rollback_playlist(&mut self, snapshot: &PlaylistSnapshot) -> Result<Vec<AudioKey>, AudioError> {
+        self.ensure_writable()?;
+        let mut tracks = Vec::new();
+        let mut missing = Vec::new();
+        for key in &snapshot.keys {
+            let existing = self
+                .playlist_entries()
+                .find(|entry| AudioKey::from_info(entry).as_ref() == Some(key))
+                .cloned()
+                .or_else(|| {
+                    let filename = self.index.get(key)?.file_name()?.to_string_lossy().to_string();
+                    Some(AudioInfo::from_filename(filename))
+                });
+            match existing {
+                Some(info) => tracks.push(info),
+                None => missing.push(key.clone()),
+            }
+        }
+
+        self.playlists.insert(snapshot.playlist.clone(), Arc::new(tracks));
+        self.save_playlists(&[&snapshot.playlist])?;
+        self.record_snapshot(&snapshot.playlist, &format!("rollback to snapshot {}", snapshot.id));
+        Ok(missing)
+    }
+}
+
+// Partial downloads younger than this may still be being written, by another music-man or a download in progress.
+const STALE_PARTIAL_SECS: u64 = 5 * 60;
+
+impl LocalCache {
+    /// Move partial downloads left behind by crashes and force-kills, and empty audio files, to the trash. Only files
+    /// that haven't been touched for a few minutes, so a download in progress elsewhere is left alone.
+    fn sweep_partial_downloads(&self) {
+        let now = unix_now();
+        let dirs = std::iter::once(self.audio_dir.clone())
+            .chain(self.secondary_dir.clone().filter(|_| self.secondary_mounted()));
+        let (mut swept, mut bytes) = (0, 0);
+        let extensions = audio_extensions();
+        for entry in dirs.filter_map(|dir| read_dir(dir).ok()).flatten().filter_map(|e| e.ok()) {
+            let Ok(meta) = entry.metadata() else {
+                continue;
+            };
+            let name = entry.file_name().to_string_lossy().to_string();
+            let orphaned = is_partial_download(&name) || (meta.len() == 0 && has_extension_in(&entry.path(), &extensions));
+            let modified = meta.modified().ok().and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map(|d| d.as_secs());
+            if !meta.is_file() || !orphaned || modified.is_some_and(|m| m + STALE_PARTIAL_SECS > now) {
+                continue;
+            }
+            let trash_path = trash_dir().join(format!("{}-{}", now, name));
+            match move_file(&entry.path(), &trash_path) {
+                Ok(()) => {
+                    tracing::debug!("moved orphaned partial download {} to the trash", entry.path().display());
+                    swept += 1;
+                    bytes += meta.len();
+                }
+                Err(e) => tracing::warn!("failed to move {} to the trash: {}", entry.path().display(), e),
+            }
+        }
+        if swept > 0 {
+            println!("Moved {} orphaned partial downloads ({}) to the trash", swept, format_size(bytes));
+        }
+    }
+
+    /// Remove staging directories left by runs that crashed or were killed before their StagingDir was dropped.
+    fn sweep_staging(&self) {
+        let mut swept = 0;
+        for entry in read_dir(staging_dir()).into_iter().flatten().filter_map(|e| e.ok()) {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let modified = entry
+                .metadata()
+                .and_then(|meta| meta.modified())
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs());
+            // Anything else in there only goes once it's stale.
+            let running = staging_owner_running(&name)
+                .unwrap_or_else(|| modified.is_none_or(|m| m + STALE_PARTIAL_SECS > unix_now()));
+            if running {
+                continue;
+            }
+            let removed = if entry.path().is_dir() {
+                std::fs::remove_dir_all(entry.path())
+            } else {
+                std::fs::remove_file(entry.path())
+            };
+            match removed {
+                Ok(()) => swept += 1,
+                Err(e) => tracing::warn!("failed to remove staging {}: {}", entry.path().display(), e),
+            }
+        }
+        if swept > 0 {
+            tracing::info!("removed {} stale staging directories", swept);
+        }
+    }
+}
+
+// Rename a file, falling back to copy + delete when the rename crosses filesystems. The copy is written under a
+// partial name and renamed into place, so an interrupted move never leaves a truncated file under the real name.
+fn move_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    if rename(from, to).is_ok() {
+        return Ok(());
+    }
+    let mut partial = to.as_os_str().to_owned();
+    partial.push(PARTIAL_DOWNLOAD_SUFFIX);
+    let partial = PathBuf::from(partial);
+    if let Err(e) = smart_copy(from, &partial) {
+        std::fs::remove_file(&partial).ok();
+        return Err(e);
     }
+    rename(&partial, to)?;
+    std::fs::remove_file(from)
 }
 
 impl AudioIndex for LocalCache {
@@ -192,27 +1439,55 @@ impl AudioIndex for LocalCache {
     }
 
     fn list_playlists(&self) -> Result<Vec<Playlist>, AudioError> {
-        // Build all of the "real" playlists (not uncategorized audio).
+        // Build all of the "real" playlists (not uncategorized audio), sharing their tracks rather than copying them.
         let mut result: Vec<Playlist> = self.playlists
             .iter()
             .map(|(name, tracks)| Playlist {
                 name: PlaylistName::Named(name.clone()),
-                audio: tracks.clone(),
+                audio: Arc::clone(tracks),
             })
             .collect();
         
+        let starred = self.starred_audio();
+        if !starred.is_empty() && !self.playlists.contains_key(STARRED_PLAYLIST) {
+            result.push(Playlist {
+                name: PlaylistName::Named(STARRED_PLAYLIST.to_string()),
+                audio: Arc::new(starred),
+            });
+        }
+
         // Also list all cached files as "Uncategorized"
-        let all_cached = list_audio_in_folder(&self.audio_dir)?;
+        let all_cached = self.cached_audio()?;
         
         if !all_cached.is_empty() {
             result.push(Playlist {
                 name: PlaylistName::Uncategorized,
-                audio: all_cached,
+                audio: Arc::new(all_cached),
             });
         }
-        
+
+        result.sort_by(|a, b| sort::playlist_name_cmp(&a.name, &b.name));
         Ok(result)
     }
+
+    fn list_playlist_names(&self) -> Result<Vec<PlaylistName>, AudioError> {
+        let mut names: Vec<PlaylistName> = self.playlists.keys().cloned().map(PlaylistName::Named).collect();
+        if !self.playlists.contains_key(STARRED_PLAYLIST) && self.index.values().any(|path| self.is_starred(path)) {
+            names.push(PlaylistName::Named(STARRED_PLAYLIST.to_string()));
+        }
+        if !self.index.is_empty() {
+            names.push(PlaylistName::Uncategorized);
+        }
+        names.sort_by(sort::playlist_name_cmp);
+        Ok(names)
+    }
+
+    fn get_playlist(&self, name: &PlaylistName) -> Result<Cow<'_, [AudioInfo]>, AudioError> {
+        match name {
+            PlaylistName::Named(name) => self.playlist_tracks(name).ok_or(AudioError::NotFound),
+            PlaylistName::Uncategorized => Ok(Cow::Owned(self.cached_audio()?)),
+        }
+    }
 }
 
 impl AudioSource for LocalCache {
@@ -231,18 +1506,141 @@ impl AudioSource for LocalCache {
     // on it, but in reality this will just be used to get back the local cache location of the audio.
     // Exception would be if we are trying to fetch to the cache with some AudioInfo that matches a
     // cached path, but the destination path we fetch to is different.
-    fn fetch(&self, info: &AudioInfo, dest: PathBuf) -> Result<AudioLocation, AudioError> {
+    fn fetch(&self, info: &AudioInfo, dest: PathBuf, naming: &DestNaming) -> Result<FetchResult, AudioError> {
         // Already in cache - just return the path
         let cached_path = self.search_path(info)?;
-        
-        // If dest is different from cache dir, copy the file
-        if dest != self.audio_dir {
-            let filename = cached_path.file_name().ok_or(AudioError::NotFound)?;
-            let dest_path = dest.join(filename);
-            std::fs::copy(cached_path, &dest_path)?;
-            Ok(AudioLocation::LocalPath(dest_path))
+
+        // dest is either a directory to copy into, named as asked, or the file to copy to.
+        let dest_path = if is_file_destination(&dest) {
+            dest
         } else {
-            Ok(AudioLocation::LocalPath(cached_path.clone()))
+            let filename = naming.file_name(info, cached_path, FilenameRules::Local).ok_or(AudioError::NotFound)?;
+            dest.join(filename)
+        };
+
+        // Fetching into the cache itself (however the path is spelled) is a no-op.
+        if canonical_destination(&dest_path) == canonical_destination(cached_path) {
+            return Ok(FetchResult::local(cached_path.clone(), info.clone(), 0, AudioSource::name(self)));
+        }
+
+        self.ensure_writable()?;
+        if let Some(parent) = dest_path.parent() {
+            create_dir_all(parent)?;
+        }
+        let bytes = smart_copy(cached_path, &dest_path)?;
+        Ok(FetchResult::local(dest_path, info.clone(), bytes, AudioSource::name(self)))
+    }
+}
+
+// A destination names a file, rather than a directory to copy into, when it isn't an existing directory, isn't spelled
+// with a trailing separator, and has an audio extension.
+fn is_file_destination(dest: &Path) -> bool {
+    let trailing_separator = dest.as_os_str().to_string_lossy().ends_with(std::path::is_separator);
+    !dest.is_dir() && !trailing_separator && has_audio_extension(dest)
+}
+
+// Resolve symlinks and redundant components in a path that may not exist yet, by canonicalizing its deepest existing
+// ancestor and re-appending the rest.
+fn canonical_destination(path: &Path) -> PathBuf {
+    let mut existing = path;
+    let mut rest = Vec::new();
+    loop {
+        if let Ok(canonical) = existing.canonicalize() {
+            return rest.iter().rev().fold(canonical, |path, component| path.join(component));
+        }
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                rest.push(name.to_os_string());
+                existing = parent;
+            }
+            _ => return path.to_path_buf(),
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(artist: &str, title: &str, isrc: &str) -> AudioInfo {
+        AudioInfo {
+            artist: Some(artist.to_string()),
+            title: Some(title.to_string()),
+            isrc: Some(isrc.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn listing_playlists_shares_their_tracks() {
+        let dir = std::env::temp_dir().join(format!("music-man-list-shared-{}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        create_dir_all(&dir).unwrap();
+        let road = vec![AudioInfo::from_filename("Muse - Uprising.mp3")];
+        let cache = LocalCache::in_dir(&dir, HashMap::from([("Road".to_string(), road)]));
+        let (first, second) = (cache.list_playlists().unwrap(), cache.list_playlists().unwrap());
+        assert!(Arc::ptr_eq(&first[0].audio, &second[0].audio));
+        assert!(Arc::ptr_eq(&first[0].audio, &cache.playlists["Road"]));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn fetch_compares_whole_destinations_and_copies_to_files_or_dirs() {
+        let dir = std::env::temp_dir().join(format!("music-man-fetch-dest-{}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        let audio_dir = dir.join("audio");
+        create_dir_all(&audio_dir).unwrap();
+        write(audio_dir.join("Muse - Uprising.mp3"), b"uprising").unwrap();
+        let mut cache = LocalCache::in_dir(&audio_dir, HashMap::new());
+        cache.read_only = false;
+        let info = AudioInfo::from_filename("Muse - Uprising.mp3");
+        let fetch = |dest: PathBuf| AudioSource::fetch(&cache, &info, dest, &DestNaming::KeepSource).unwrap();
+        let local_path = |result: FetchResult| match result.location {
+            AudioLocation::LocalPath(path) => (path, result.bytes),
+            other => panic!("not a local path: {:?}", other),
+        };
+
+        // The cache itself, however it's spelled, is never copied onto.
+        let cached = audio_dir.join("Muse - Uprising.mp3");
+        assert_eq!(local_path(fetch(PathBuf::from(format!("{}/", audio_dir.display())))), (cached.clone(), 0));
+        assert_eq!(local_path(fetch(dir.join("audio/../audio/."))), (cached.clone(), 0));
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(&audio_dir, dir.join("linked")).unwrap();
+            assert_eq!(local_path(fetch(dir.join("linked"))), (cached.clone(), 0));
+        }
+
+        // A directory, existing or spelled with a trailing separator, keeps the filename. A file path is used as is,
+        // parents and all.
+        create_dir_all(dir.join("export")).unwrap();
+        assert_eq!(local_path(fetch(dir.join("export"))), (dir.join("export/Muse - Uprising.mp3"), 8));
+        let into = PathBuf::from(format!("{}/", dir.join("new/dir").display()));
+        assert_eq!(local_path(fetch(into)).0, dir.join("new/dir/Muse - Uprising.mp3"));
+        assert_eq!(local_path(fetch(dir.join("new/file/song.mp3"))).0, dir.join("new/file/song.mp3"));
+        assert_eq!(std::fs::read(dir.join("new/file/song.mp3")).unwrap(), b"uprising");
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn only_one_isrc_under_several_keys_is_a_conflict() {
+        let cached = [
+            // The same recording cached twice under different artist spellings.
+            track("Beyonce", "Halo", "USSM10804556"),
+            track("Beyoncé", "Halo", "US-SM1-08-04556"),
+            // A re-recording under the same key, which isn't a duplicate.
+            track("Kate Bush", "Running Up That Hill", "GBAYE8500077"),
+            track("Kate Bush", "Running Up That Hill", "GBAYE1200411"),
+        ];
+        let mut isrc_index: HashMap<String, Vec<AudioKey>> = HashMap::new();
+        for info in &cached {
+            isrc_index.entry(info.normalized_isrc().unwrap()).or_default().push(AudioKey::from_info(info).unwrap());
+        }
+
+        let conflicts = isrc_conflicts_in(&isrc_index, |_| true);
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].0, "USSM10804556");
+        assert_eq!(conflicts[0].1.len(), 2);
+        // Once one of them has left the cache, there's nothing left to dedupe.
+        let beyonce = AudioKey::from_info(&cached[0]).unwrap();
+        assert!(isrc_conflicts_in(&isrc_index, |key| *key != beyonce).is_empty());
+    }
+}
@@ -0,0 +1,102 @@
+// Cancel -> Cooperative cancellation of long running work: downloads, copies and syncs check a CancelToken between
+// steps, and clean up after themselves when it's cancelled rather than leaving partial files behind. Ctrl-C cancels
+// the process wide interrupt token, which is what everything uses unless given its own, so library users can also
+// cancel programmatically from another thread.
+
+use std::{
+    io,
+    sync::{
+        Arc, OnceLock,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use crate::audio::AudioError;
+
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Err(AudioError::Cancelled) once cancelled, for checking between steps with `?`.
+    pub fn check(&self) -> Result<(), AudioError> {
+        if self.is_cancelled() {
+            return Err(AudioError::Cancelled);
+        }
+        Ok(())
+    }
+
+    /// Make the token usable again, e.g. for the next command once a cancelled one has unwound.
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+// Carried by cancelled_io_error, so a copy stopped by its token is told apart from a merely interrupted syscall.
+#[derive(Debug)]
+struct Cancelled;
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// The error copies fail with when cancelled mid-way, mapped back to AudioError::Cancelled by is_cancelled.
+pub fn cancelled_io_error() -> io::Error {
+    io::Error::new(io::ErrorKind::Interrupted, Cancelled)
+}
+
+impl AudioError {
+    /// Whether this error is a cancellation, either directly or from a copy that was interrupted.
+    pub fn is_cancelled(&self) -> bool {
+        match self {
+            AudioError::Cancelled => true,
+            AudioError::Io(e) => e.get_ref().is_some_and(|inner| inner.is::<Cancelled>()),
+            _ => false,
+        }
+    }
+}
+
+static INTERRUPT: OnceLock<CancelToken> = OnceLock::new();
+
+/// The token Ctrl-C cancels.
+pub fn interrupt_token() -> CancelToken {
+    INTERRUPT.get_or_init(CancelToken::new).clone()
+}
+
+/// Route Ctrl-C to the interrupt token rather than killing the process outright. A second Ctrl-C before the token is
+/// reset exits immediately.
+pub fn install_interrupt_handler() {
+    interrupt_token();
+    #[cfg(unix)]
+    unsafe {
+        libc::signal(libc::SIGINT, on_interrupt as *const () as libc::sighandler_t);
+    }
+}
+
+// Only async-signal-safe calls in here: the token's atomic, write and _exit.
+#[cfg(unix)]
+extern "C" fn on_interrupt(_: libc::c_int) {
+    let Some(token) = INTERRUPT.get() else {
+        return;
+    };
+    if token.0.swap(true, Ordering::SeqCst) {
+        unsafe { libc::_exit(130) };
+    }
+    let message = b"\nCancelling, press Ctrl-C again to quit immediately\n";
+    unsafe { libc::write(libc::STDERR_FILENO, message.as_ptr().cast(), message.len()) };
+}
@@ -0,0 +1,210 @@
+// DeviceChecksums -> Content hashes of every file music-man placed on a device, kept next to the device manifest, so
+// that files which have since rotted on the card can be found by rereading them, and repaired from the cache.
+
+use std::{
+    collections::HashMap,
+    fs::{create_dir_all, read_to_string, write},
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    audio::{AudioError, AudioInfo, AudioLocation},
+    cache::{LocalCache, unix_now},
+    device::AttachedDevice,
+    fsutil::{hash_file, smart_copy},
+    manifest::{manifest_dir, relative_path},
+    sync::shuffle_in_place,
+};
+
+fn checksums_path(device_root: &Path) -> PathBuf {
+    manifest_dir(device_root).join("checksums.json")
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ChecksumEntry {
+    pub size: u64,
+    pub hash: String,
+    // Unix seconds the device copy was last known to match the hash.
+    pub verified_at: u64,
+}
+
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct DeviceChecksums {
+    // Device relative path -> expected content.
+    #[serde(default)]
+    pub files: HashMap<String, ChecksumEntry>,
+    // Device relative paths that failed verification, awaiting repair.
+    #[serde(default)]
+    pub flagged: Vec<String>,
+}
+
+impl DeviceChecksums {
+    pub fn load(device_root: &Path) -> Self {
+        read_to_string(checksums_path(device_root))
+            .ok()
+            .and_then(|s| serde_json::from_str(&s).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, device_root: &Path) -> io::Result<()> {
+        create_dir_all(manifest_dir(device_root))?;
+        write(checksums_path(device_root), serde_json::to_string_pretty(self)?)
+    }
+
+    /// Record the expected content of a file just written to the device. The hash is the one already computed for the
+    /// copy's source, so the device file is never reread for this.
+    pub fn record(&mut self, device_root: &Path, path: &Path, size: u64, hash: String) {
+        let rel_path = relative_path(device_root, path);
+        self.flagged.retain(|p| *p != rel_path);
+        self.files.insert(rel_path, ChecksumEntry { size, hash, verified_at: unix_now() });
+    }
+
+    pub fn forget(&mut self, device_root: &Path, path: &Path) {
+        let rel_path = relative_path(device_root, path);
+        self.flagged.retain(|p| *p != rel_path);
+        self.files.remove(&rel_path);
+    }
+
+    pub fn rename_path(&mut self, device_root: &Path, from: &Path, to: &Path) {
+        let (from, to) = (relative_path(device_root, from), relative_path(device_root, to));
+        if let Some(entry) = self.files.remove(&from) {
+            self.files.insert(to.clone(), entry);
+        }
+        for path in self.flagged.iter_mut().filter(|p| **p == from) {
+            *path = to.clone();
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize)]
+pub enum VerifyProblem {
+    Missing,
+    SizeMismatch { expected: u64, actual: u64 },
+    HashMismatch,
+    ReadError(String),
+}
+
+#[derive(Debug, Default, serde::Serialize)]
+pub struct VerifyReport {
+    pub checked: usize,
+    pub total: usize,
+    pub problems: Vec<(String, VerifyProblem)>,
+}
+
+impl VerifyReport {
+    pub fn print(&self) {
+        for (path, problem) in &self.problems {
+            println!("{:?}: {}", problem, path);
+        }
+        println!(
+            "Verified {} of {} files, {} problems{}",
+            self.checked,
+            self.total,
+            self.problems.len(),
+            if self.problems.is_empty() { "" } else { ", run 'repair-device' to re-copy them from the cache" }
+        );
+    }
+}
+
+/// Reread files music-man placed on the device and compare them against their recorded checksums, optionally only a
+/// random sample_percent of them. Failing files are flagged for repair_device.
+pub fn verify_device(device: &AttachedDevice, sample_percent: Option<u8>) -> Result<VerifyReport, AudioError> {
+    let mut checksums = DeviceChecksums::load(&device.path);
+    let mut paths: Vec<String> = checksums.files.keys().cloned().collect();
+    let total = paths.len();
+    if let Some(percent) = sample_percent {
+        shuffle_in_place(&mut paths);
+        paths.truncate((total * percent.min(100) as usize).div_ceil(100));
+    }
+
+    let mut report = VerifyReport { total, ..Default::default() };
+    for rel_path in paths {
+        let expected = &checksums.files[&rel_path];
+        let path = device.path.join(&rel_path);
+        let problem = match std::fs::metadata(&path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Some(VerifyProblem::Missing),
+            Err(e) => Some(VerifyProblem::ReadError(e.to_string())),
+            Ok(meta) if meta.len() != expected.size => Some(VerifyProblem::SizeMismatch {
+                expected: expected.size,
+                actual: meta.len(),
+            }),
+            Ok(_) => match hash_file(&path) {
+                Ok(hash) if hash == expected.hash => None,
+                Ok(_) => Some(VerifyProblem::HashMismatch),
+                Err(e) => Some(VerifyProblem::ReadError(e.to_string())),
+            },
+        };
+        report.checked += 1;
+        match problem {
+            Some(problem) => {
+                if !checksums.flagged.contains(&rel_path) {
+                    checksums.flagged.push(rel_path.clone());
+                }
+                report.problems.push((rel_path, problem));
+            }
+            None => {
+                checksums.files.get_mut(&rel_path).unwrap().verified_at = unix_now();
+            }
+        }
+    }
+
+    // Verification is read-only with respect to audio, but recording the results needs a writable device.
+    if device.ensure_writable().is_ok() {
+        checksums.save(&device.path)?;
+    }
+    Ok(report)
+}
+
+#[derive(Debug, Default, serde::Serialize)]
+pub struct RepairReport {
+    pub repaired: Vec<String>,
+    // Device path and why it couldn't be repaired.
+    pub failed: Vec<(String, String)>,
+}
+
+impl RepairReport {
+    pub fn print(&self) {
+        for (path, e) in &self.failed {
+            println!("FAILED {}: {}", path, e);
+        }
+        println!("Repaired {} files, {} failed", self.repaired.len(), self.failed.len());
+    }
+}
+
+/// Re-copy every flagged file from the cache, checking the new copy before clearing its flag.
+pub fn repair_device(cache: &LocalCache, device: &AttachedDevice) -> Result<RepairReport, AudioError> {
+    device.ensure_writable()?;
+    let mut checksums = DeviceChecksums::load(&device.path);
+    let mut report = RepairReport::default();
+    for rel_path in checksums.flagged.clone() {
+        let path = device.path.join(&rel_path);
+        match repair_file(cache, &path) {
+            Ok((size, hash)) => {
+                checksums.record(&device.path, &path, size, hash);
+                report.repaired.push(rel_path);
+            }
+            Err(e) => report.failed.push((rel_path, e.to_string())),
+        }
+    }
+    checksums.save(&device.path)?;
+    Ok(report)
+}
+
+fn repair_file(cache: &LocalCache, path: &Path) -> Result<(u64, String), AudioError> {
+    let filename = path.file_name().ok_or(AudioError::NotFound)?;
+    let AudioLocation::LocalPath(source) = cache.search(&AudioInfo::from_filename(filename))? else {
+        return Err(AudioError::Unexpected);
+    };
+    // From the cache's file index when it's been hashed before, the fresh copy is the only file necessarily read back.
+    let hash = cache.content_hash(&source)?;
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+    }
+    let size = smart_copy(&source, path)?;
+    // The whole point is that this device corrupts files, so check the fresh copy rather than trusting it.
+    if hash_file(path)? != hash {
+        return Err(AudioError::ExportFailed(format!("{} still differs after re-copy", path.display())));
+    }
+    Ok((size, hash))
+}
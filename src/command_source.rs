@@ -0,0 +1,239 @@
+// CommandSource -> An external downloader (bandcamp-dl, scdl, a custom script) plugged in as an AudioSource from
+// config.toml, without first-class support:
+//
+//   [[source]]
+//   name = "bandcamp"
+//   search = "bandcamp-search {artist} {title}"
+//   fetch = "bandcamp-dl --base-dir {dest} {url}"
+//   priority = 10
+//
+// Templates are run with `sh -c`, placeholders are {artist}, {title}, {url} and {dest}, and every substituted value is
+// single-quoted, so metadata can never inject shell syntax. search prints the URL to fetch, fetch prints the path of
+// the file it wrote (relative paths are taken relative to {dest}), both as the last non-empty line of stdout.
+
+use std::{path::PathBuf, process::Stdio, time::Duration};
+
+use crate::{
+    audio::{AudioError, AudioInfo},
+    cancel::{CancelToken, interrupt_token},
+    naming::{DestNaming, FilenameRules},
+    process::{run_with_timeout, shell_command},
+    source::{AudioSource, FetchResult},
+};
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct CommandSourceConfig {
+    pub name: String,
+    // Prints a URL for {artist} and {title}. Without one, only tracks that already have a URL can be fetched.
+    pub search: Option<String>,
+    pub fetch: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    // Sources are tried lowest priority first, yt-dlp is at YTDLP_PRIORITY.
+    #[serde(default = "default_priority")]
+    pub priority: i32,
+    #[serde(default = "default_search_timeout")]
+    pub search_timeout_secs: u64,
+    #[serde(default = "default_fetch_timeout")]
+    pub fetch_timeout_secs: u64,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_priority() -> i32 {
+    100
+}
+
+fn default_search_timeout() -> u64 {
+    30
+}
+
+fn default_fetch_timeout() -> u64 {
+    600
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Placeholder {
+    Artist,
+    Title,
+    Url,
+    Dest,
+}
+
+#[derive(Clone, Debug)]
+enum Segment {
+    Literal(String),
+    Value(Placeholder),
+}
+
+// A command template parsed once at config load, so a typo'd placeholder fails there rather than mid-fetch.
+#[derive(Clone, Debug)]
+struct CommandTemplate {
+    segments: Vec<Segment>,
+}
+
+impl CommandTemplate {
+    fn parse(template: &str, allowed: &[Placeholder]) -> Result<Self, String> {
+        if template.trim().is_empty() {
+            return Err("empty command".to_string());
+        }
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' => {
+                    let mut name = String::new();
+                    let mut closed = false;
+                    for c in chars.by_ref() {
+                        if c == '}' {
+                            closed = true;
+                            break;
+                        }
+                        name.push(c);
+                    }
+                    if !closed {
+                        return Err("unmatched {".to_string());
+                    }
+                    let placeholder = match name.as_str() {
+                        "artist" => Placeholder::Artist,
+                        "title" => Placeholder::Title,
+                        "url" => Placeholder::Url,
+                        "dest" => Placeholder::Dest,
+                        _ => return Err(format!("unknown placeholder {{{}}}", name)),
+                    };
+                    if !allowed.contains(&placeholder) {
+                        return Err(format!("{{{}}} can't be used here", name));
+                    }
+                    segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    segments.push(Segment::Value(placeholder));
+                }
+                '}' => return Err("unmatched }".to_string()),
+                c => literal.push(c),
+            }
+        }
+        segments.push(Segment::Literal(literal));
+        Ok(Self { segments })
+    }
+
+    // The shell command line, with each value quoted.
+    fn expand(&self, value: impl Fn(Placeholder) -> Option<String>) -> Result<String, AudioError> {
+        let mut command = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(literal) => command.push_str(literal),
+                Segment::Value(placeholder) => command.push_str(&shell_quote(&value(*placeholder).ok_or(AudioError::MissingInfo)?)),
+            }
+        }
+        Ok(command)
+    }
+}
+
+// Single-quote a value for sh, where nothing inside single quotes is special except the closing quote itself.
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+pub struct CommandSource {
+    pub name: String,
+    pub priority: i32,
+    search: Option<CommandTemplate>,
+    fetch: CommandTemplate,
+    search_timeout: Duration,
+    fetch_timeout: Duration,
+    // Kills a running command, defaults to the Ctrl-C token.
+    pub cancel: CancelToken,
+}
+
+impl CommandSource {
+    pub fn from_config(config: &CommandSourceConfig) -> Result<Self, AudioError> {
+        let invalid = |field: &str, e: String| AudioError::Config(format!("source {}: invalid {}: {}", config.name, field, e));
+        let search = config
+            .search
+            .as_deref()
+            .map(|search| CommandTemplate::parse(search, &[Placeholder::Artist, Placeholder::Title]))
+            .transpose()
+            .map_err(|e| invalid("search", e))?;
+        let all = [Placeholder::Artist, Placeholder::Title, Placeholder::Url, Placeholder::Dest];
+        let fetch = CommandTemplate::parse(&config.fetch, &all).map_err(|e| invalid("fetch", e))?;
+        Ok(Self {
+            name: config.name.clone(),
+            priority: config.priority,
+            search,
+            fetch,
+            search_timeout: Duration::from_secs(config.search_timeout_secs),
+            fetch_timeout: Duration::from_secs(config.fetch_timeout_secs),
+            cancel: interrupt_token(),
+        })
+    }
+
+    fn run(&self, command_line: String, timeout: Duration) -> Result<String, AudioError> {
+        tracing::debug!("source {} running: {}", self.name, command_line);
+        let mut command = shell_command(&command_line);
+        command.stdout(Stdio::piped()).stderr(Stdio::inherit());
+        let output = run_with_timeout(&mut command, &self.name, timeout, &self.cancel)?;
+        if !output.status.success() {
+            return Err(AudioError::ExportFailed(format!("{} exited with status: {}", self.name, output.status)));
+        }
+        output
+            .stdout
+            .lines()
+            .rev()
+            .map(str::trim)
+            .find(|line| !line.is_empty())
+            .map(str::to_string)
+            .ok_or(AudioError::NotFound)
+    }
+}
+
+impl AudioSource for CommandSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn search(&self, info: &AudioInfo) -> Result<AudioInfo, AudioError> {
+        let search = self
+            .search
+            .as_ref()
+            .ok_or_else(|| AudioError::Unavailable(format!("{} has no search command", self.name)))?;
+        let command_line = search.expand(|placeholder| match placeholder {
+            Placeholder::Artist => info.artist.clone(),
+            Placeholder::Title => info.title.clone(),
+            _ => None,
+        })?;
+        let url = self.run(command_line, self.search_timeout)?;
+        let mut extended_info = info.clone();
+        extended_info.youtube_url = Some(url);
+        Ok(extended_info)
+    }
+
+    fn fetch(&self, info: &AudioInfo, dest: PathBuf, naming: &DestNaming) -> Result<FetchResult, AudioError> {
+        let command_line = self.fetch.expand(|placeholder| match placeholder {
+            Placeholder::Artist => info.artist.clone(),
+            Placeholder::Title => info.title.clone(),
+            Placeholder::Url => info.youtube_url.clone(),
+            Placeholder::Dest => Some(dest.display().to_string()),
+        })?;
+        let mut path = dest.join(self.run(command_line, self.fetch_timeout)?);
+        if !path.is_file() {
+            return Err(AudioError::ExportFailed(format!(
+                "{} printed {}, which isn't a file",
+                self.name,
+                path.display()
+            )));
+        }
+        // The command names its own output, rename it afterwards if something else was asked for.
+        if *naming != DestNaming::KeepSource
+            && let Some(file_name) = naming.file_name(info, &path, FilenameRules::Local)
+            && let Some(renamed) = path.parent().map(|dir| dir.join(file_name))
+            && renamed != path
+        {
+            std::fs::rename(&path, &renamed)?;
+            path = renamed;
+        }
+        let bytes = std::fs::metadata(&path)?.len();
+        Ok(FetchResult::local(path, info.clone(), bytes, &self.name))
+    }
+}
@@ -0,0 +1,144 @@
+// Config -> User configuration, loaded from config.toml in the app config dir. Every field has a default, so a missing
+// or partial config file is valid, but a config file that fails to parse is an error rather than silently ignored.
+
+use std::{
+    fs::{create_dir_all, read_to_string, write},
+    io::ErrorKind,
+    path::PathBuf,
+};
+
+use crate::{
+    audio::{AudioError, normalize_extensions},
+    cache::get_config_dir,
+    command_source::{CommandSource, CommandSourceConfig},
+    cue::CuePregap,
+    fsutil::parse_size,
+    hooks::HookConfig,
+    remote::{LastFmConfig, SpotifyConfig, SubsonicConfig},
+    ytdlp::managed_binary,
+};
+
+pub fn config_path() -> PathBuf {
+    get_config_dir().join("config.toml")
+}
+
+// How playlists are persisted, see PlaylistStore.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PlaylistStorage {
+    // A single playlists.json holding every playlist.
+    #[default]
+    Monolithic,
+    // One file per playlist, friendlier to keeping playlists in version control.
+    PerFile,
+}
+
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub playlist_storage: PlaylistStorage,
+    // Flat audio directory on another volume, used once the primary cache reaches primary_cache_limit.
+    pub secondary_cache_dir: Option<PathBuf>,
+    // Size the primary cache may grow to before downloads overflow to the secondary, e.g. "20G".
+    pub primary_cache_limit: Option<String>,
+    // Refuse every write to the cache, playlists, and devices. Also set by the --read-only flag.
+    pub read_only: bool,
+    // yt-dlp binary to run, defaults to the one `ytdlp update` downloaded, then finding yt-dlp on PATH.
+    pub ytdlp_path: Option<PathBuf>,
+    // Seconds a yt-dlp search may take, defaults to 30.
+    pub ytdlp_search_timeout_secs: Option<u64>,
+    // Seconds a yt-dlp download may go without printing anything before it's killed as stalled, defaults to 600. A
+    // slow download that's still making progress is never killed.
+    pub ytdlp_stall_timeout_secs: Option<u64>,
+    // Proxy for every download, yt-dlp and plain HTTP alike, e.g. "socks5://127.0.0.1:1080".
+    pub proxy: Option<String>,
+    // Country code yt-dlp pretends to download from, for region-locked tracks.
+    pub geo_bypass_country: Option<String>,
+    // Cap on download speed, yt-dlp and plain HTTP alike, e.g. "2M" for 2 MiB a second.
+    pub limit_rate: Option<String>,
+    // Command that plays preview clips, given the clip as its last argument, defaults to afplay.
+    pub player: Option<String>,
+    // Device directory offered by default at startup.
+    pub default_target: Option<PathBuf>,
+    // Extensions the cache indexes as audio, e.g. ["mp3", "flac", "opus"], defaults to DEFAULT_AUDIO_EXTENSIONS.
+    pub audio_extensions: Option<Vec<String>>,
+    // Which track a pregap goes to when splitting with split-cue, "append" (the previous one) or "prepend".
+    pub cue_pregap: CuePregap,
+    // Commands run around syncs and fetches.
+    pub hooks: HookConfig,
+    // Remote indexes `missing` can compare with the cache, as [subsonic], [spotify] and [lastfm] tables.
+    pub subsonic: Option<SubsonicConfig>,
+    pub spotify: Option<SpotifyConfig>,
+    pub lastfm: Option<LastFmConfig>,
+    // External downloader commands tried as sources alongside yt-dlp, as [[source]] tables.
+    #[serde(rename = "source", skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<CommandSourceConfig>,
+}
+
+impl Config {
+    pub fn load() -> Result<Self, AudioError> {
+        match read_to_string(config_path()) {
+            Ok(contents) => {
+                let config: Self = toml::from_str(&contents)
+                    .map_err(|e| AudioError::Config(format!("{}: {}", config_path().display(), e)))?;
+                config.validate()?;
+                Ok(config)
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Catch bad values at load time, rather than whenever they're first used.
+    fn validate(&self) -> Result<(), AudioError> {
+        self.primary_cache_limit_bytes()?;
+        self.limit_rate_bytes()?;
+        if let Some(extensions) = &self.audio_extensions
+            && normalize_extensions(extensions).is_empty()
+        {
+            return Err(AudioError::Config("audio_extensions must list at least one extension".to_string()));
+        }
+        for (i, source) in self.sources.iter().enumerate() {
+            CommandSource::from_config(source)?;
+            if source.name == "ytdlp" || self.sources[..i].iter().any(|s| s.name == source.name) {
+                return Err(AudioError::Config(format!("Duplicate source name: {}", source.name)));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn primary_cache_limit_bytes(&self) -> Result<Option<u64>, AudioError> {
+        self.primary_cache_limit
+            .as_deref()
+            .map(|limit| {
+                parse_size(limit)
+                    .ok_or_else(|| AudioError::Config(format!("Invalid primary_cache_limit: {}", limit)))
+            })
+            .transpose()
+    }
+
+    pub fn limit_rate_bytes(&self) -> Result<Option<u64>, AudioError> {
+        self.limit_rate
+            .as_deref()
+            .map(|rate| {
+                parse_size(rate)
+                    .filter(|rate| *rate > 0)
+                    .ok_or_else(|| AudioError::Config(format!("Invalid limit_rate: {}", rate)))
+            })
+            .transpose()
+    }
+
+    pub fn ytdlp_binary(&self) -> PathBuf {
+        self.ytdlp_path.clone().unwrap_or_else(|| {
+            let managed = managed_binary();
+            if managed.is_file() { managed } else { PathBuf::from("yt-dlp") }
+        })
+    }
+
+    pub fn save(&self) -> Result<(), AudioError> {
+        let contents = toml::to_string_pretty(self).map_err(|e| AudioError::Config(e.to_string()))?;
+        create_dir_all(get_config_dir())?;
+        write(config_path(), contents)?;
+        Ok(())
+    }
+}
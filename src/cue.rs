@@ -0,0 +1,293 @@
+// CueSheet -> Splitting single-file album rips (one big FLAC plus a .cue) into a file per track. The cue sheet says where
+// each track starts, ffmpeg cuts and tags the pieces, and they're cached like any other audio.
+//
+// Cue sheets predate UTF-8 being the norm, so ones that aren't valid UTF-8 are read as Latin-1. Tracks may have a
+// pregap (INDEX 00 before INDEX 01), which goes to the end of the previous track unless configured otherwise, so no
+// audio is ever dropped between tracks.
+
+use std::{
+    fs::read,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::{
+    audio::{AudioError, AudioInfo, has_audio_extension},
+    naming::{FilenameRules, sanitize},
+};
+
+// Cue times are MM:SS:FF, with 75 frames a second.
+const FRAMES_PER_SEC: u64 = 75;
+
+// Which track a pregap's audio belongs to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CuePregap {
+    // The end of the previous track, the way it plays from the disc.
+    #[default]
+    Append,
+    // The start of its own track, from INDEX 00.
+    Prepend,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CueTrack {
+    pub number: u32,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub isrc: Option<String>,
+    // The FILE the track is in, as the cue sheet names it.
+    pub file: String,
+    // INDEX 00 and INDEX 01, in frames.
+    pub pregap: Option<u64>,
+    pub start: u64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CueSheet {
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub tracks: Vec<CueTrack>,
+}
+
+// A track's piece of its file, in seconds. No end for the file's last track.
+#[derive(Clone, Debug)]
+pub struct CueSplit {
+    pub track: CueTrack,
+    pub audio: PathBuf,
+    pub start_secs: f64,
+    pub end_secs: Option<f64>,
+}
+
+impl CueSheet {
+    /// Read a cue sheet, as UTF-8 (with or without a BOM) if it is, otherwise as Latin-1.
+    pub fn load(path: &Path) -> Result<Self, AudioError> {
+        let bytes = read(path)?;
+        let text = match String::from_utf8(bytes) {
+            Ok(text) => text.trim_start_matches('\u{feff}').to_string(),
+            // Every byte is a Latin-1 character of the same value.
+            Err(e) => e.into_bytes().iter().map(|b| *b as char).collect(),
+        };
+        Self::parse(&text).map_err(|e| AudioError::Config(format!("{}: {}", path.display(), e)))
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut sheet = CueSheet::default();
+        let mut file: Option<String> = None;
+        // Only AUDIO tracks are kept, data tracks on enhanced CDs have nothing to split.
+        let mut current: Option<CueTrack> = None;
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            let (command, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let rest = rest.trim();
+            let error = |what: &str| format!("line {}: {}", n + 1, what);
+            match command.to_ascii_uppercase().as_str() {
+                // FILE "name" TYPE, the name only quoted when it has spaces.
+                "FILE" if rest.starts_with('"') => file = Some(unquote(rest).to_string()),
+                "FILE" => file = Some(rest.rsplit_once(char::is_whitespace).map_or(rest, |(name, _)| name).to_string()),
+                "TRACK" => {
+                    sheet.tracks.extend(current.take());
+                    let (number, kind) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+                    let number = number.parse().map_err(|_| error("invalid track number"))?;
+                    if kind.trim().eq_ignore_ascii_case("AUDIO") {
+                        let file = file.clone().ok_or_else(|| error("TRACK before any FILE"))?;
+                        current = Some(CueTrack { number, file, ..Default::default() });
+                    }
+                }
+                "TITLE" | "PERFORMER" => {
+                    let value = Some(unquote(rest).to_string()).filter(|value| !value.is_empty());
+                    let (title, performer) = match &mut current {
+                        Some(track) => (&mut track.title, &mut track.performer),
+                        None => (&mut sheet.title, &mut sheet.performer),
+                    };
+                    if command.eq_ignore_ascii_case("TITLE") {
+                        *title = value;
+                    } else {
+                        *performer = value;
+                    }
+                }
+                "ISRC" => {
+                    if let Some(track) = &mut current {
+                        track.isrc = Some(rest.to_string());
+                    }
+                }
+                "INDEX" => {
+                    let Some(track) = &mut current else {
+                        continue;
+                    };
+                    let (index, time) = rest.split_once(char::is_whitespace).ok_or_else(|| error("invalid INDEX"))?;
+                    let frames = parse_time(time.trim()).ok_or_else(|| error("invalid INDEX time"))?;
+                    match index.parse::<u32>() {
+                        Ok(0) => track.pregap = Some(frames),
+                        Ok(1) => track.start = frames,
+                        // Later indexes mark points within the track.
+                        Ok(_) => {}
+                        Err(_) => return Err(error("invalid INDEX number")),
+                    }
+                }
+                _ => {}
+            }
+        }
+        sheet.tracks.extend(current);
+        if sheet.tracks.is_empty() {
+            return Err("no audio tracks".to_string());
+        }
+        Ok(sheet)
+    }
+
+    /// Where each track is, in its audio file relative to the cue sheet. Files that aren't where the sheet says are
+    /// looked for under the same name with another audio extension, since rips are often converted after the cue
+    /// sheet was written, e.g. a .wav reference to what's now a .flac.
+    pub fn splits(&self, cue_path: &Path, pregap: CuePregap) -> Result<Vec<CueSplit>, AudioError> {
+        let dir = cue_path.parent().unwrap_or(Path::new("."));
+        let track_start = |track: &CueTrack| match pregap {
+            CuePregap::Append => track.start,
+            CuePregap::Prepend => track.pregap.unwrap_or(track.start),
+        };
+        let mut splits = Vec::new();
+        for (i, track) in self.tracks.iter().enumerate() {
+            let audio = find_audio(dir, &track.file).ok_or_else(|| {
+                AudioError::Config(format!("{} references {}, which doesn't exist", cue_path.display(), track.file))
+            })?;
+            // A track runs until the next track in the same file starts, the last one to the end of the file.
+            let end = self.tracks.get(i + 1).filter(|next| next.file == track.file).map(track_start);
+            let start = track_start(track);
+            splits.push(CueSplit {
+                track: track.clone(),
+                audio,
+                start_secs: start as f64 / FRAMES_PER_SEC as f64,
+                end_secs: end.map(|end| end as f64 / FRAMES_PER_SEC as f64),
+            });
+        }
+        Ok(splits)
+    }
+
+    /// The AudioInfo for a track, falling back to the album's performer and a "Track NN (Album)" title, so untitled
+    /// tracks of different albums don't share a key.
+    pub fn info(&self, track: &CueTrack) -> AudioInfo {
+        let artist = track.performer.clone().or_else(|| self.performer.clone());
+        let untitled = || match &self.title {
+            Some(album) => format!("Track {:02} ({})", track.number, album),
+            None => format!("Track {:02}", track.number),
+        };
+        AudioInfo {
+            artists: artist.as_deref().map(crate::audio::split_artists).unwrap_or_default(),
+            artist,
+            title: Some(track.title.clone().unwrap_or_else(untitled)),
+            isrc: track.isrc.clone(),
+            album: self.title.clone(),
+            track_number: Some(track.number),
+            ..Default::default()
+        }
+    }
+}
+
+impl CueSplit {
+    /// The split file's name, "NN - Artist - Title.ext". Formats ffmpeg can't write are split to FLAC.
+    pub fn file_name(&self, info: &AudioInfo) -> String {
+        let ext = self.audio.extension().map(|ext| ext.to_string_lossy().to_lowercase()).unwrap_or_default();
+        let ext = match ext.as_str() {
+            "flac" | "wav" | "mp3" | "m4a" | "ogg" | "opus" | "aiff" => ext.as_str(),
+            _ => "flac",
+        };
+        format!(
+            "{:02} - {} - {}.{}",
+            self.track.number,
+            sanitize(info.artist.as_deref().unwrap_or("Unknown Artist"), FilenameRules::Local),
+            sanitize(info.title.as_deref().unwrap_or_default(), FilenameRules::Local),
+            ext
+        )
+    }
+
+    /// Cut the track out of its file with ffmpeg, tagged from the cue sheet. Re-encodes to the same format, copying
+    /// would cut at the nearest frame rather than the exact sample.
+    pub fn split(&self, info: &AudioInfo, total_tracks: usize, dest: &Path) -> Result<(), AudioError> {
+        let mut command = Command::new("ffmpeg");
+        command.args(["-v", "error", "-y", "-i"]).arg(&self.audio);
+        command.args(["-ss", &format!("{:.3}", self.start_secs)]);
+        if let Some(end) = self.end_secs {
+            command.args(["-t", &format!("{:.3}", end - self.start_secs)]);
+        }
+        // The rip's own tags are the album's, not the track's.
+        command.args(["-map", "0:a", "-map_metadata", "-1"]);
+        let tags = [
+            ("artist", info.artist.clone()),
+            ("title", info.title.clone()),
+            ("album", info.album.clone()),
+            ("track", Some(format!("{}/{}", self.track.number, total_tracks))),
+            ("isrc", info.isrc.clone()),
+        ];
+        for (tag, value) in tags {
+            if let Some(value) = value {
+                command.arg("-metadata").arg(format!("{}={}", tag, value));
+            }
+        }
+        let output = command.arg(dest).output()?;
+        if !output.status.success() {
+            return Err(AudioError::ExportFailed(format!(
+                "ffmpeg failed to split track {} from {}: {}",
+                self.track.number,
+                self.audio.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(())
+    }
+}
+
+// A FILE reference, or the audio file with the same stem when it's been converted since.
+fn find_audio(dir: &Path, file: &str) -> Option<PathBuf> {
+    let referenced = dir.join(file.replace('\\', "/"));
+    if referenced.is_file() {
+        return Some(referenced);
+    }
+    let stem = referenced.file_stem()?.to_owned();
+    let mut candidates: Vec<PathBuf> = std::fs::read_dir(referenced.parent()?)
+        .ok()?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.file_stem() == Some(stem.as_os_str()) && has_audio_extension(path))
+        .collect();
+    candidates.sort();
+    candidates.into_iter().next()
+}
+
+// Values are quoted when they have spaces, and sometimes when they don't.
+fn unquote(value: &str) -> &str {
+    match value.strip_prefix('"') {
+        Some(rest) => rest.rfind('"').map_or(rest, |end| &rest[..end]),
+        None => value,
+    }
+}
+
+// MM:SS:FF -> frames. Minutes go past 59 on long discs.
+fn parse_time(time: &str) -> Option<u64> {
+    let mut parts = time.split(':').map(|part| part.parse::<u64>().ok());
+    let (minutes, seconds, frames) = (parts.next()??, parts.next()??, parts.next()??);
+    if parts.next().is_some() || seconds >= 60 || frames >= FRAMES_PER_SEC {
+        return None;
+    }
+    Some((minutes * 60 + seconds) * FRAMES_PER_SEC + frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::AudioKey;
+
+    fn sheet(album: &str) -> CueSheet {
+        CueSheet::parse(&format!(
+            "PERFORMER \"Band\"\nTITLE \"{}\"\nFILE \"rip.flac\" WAVE\n  TRACK 01 AUDIO\n    INDEX 01 00:00:00\n",
+            album
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn untitled_tracks_of_different_albums_key_apart() {
+        let (first, second) = (sheet("First"), sheet("Second"));
+        let first = first.info(&first.tracks[0]);
+        let second = second.info(&second.tracks[0]);
+        assert_eq!(first.title.as_deref(), Some("Track 01 (First)"));
+        assert_ne!(AudioKey::from_info(&first), AudioKey::from_info(&second));
+    }
+}
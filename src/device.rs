@@ -1,10 +1,23 @@
 use crate::{
-    audio::{AudioError, AudioInfo, AudioKey, AudioLocation, PlaylistName},
+    audio::{AudioError, AudioInfo, AudioKey, AudioLocation, PlaylistName, find_key},
+    cancel::{CancelToken, interrupt_token},
+    device_index::DeviceIndexCache,
+    file_index::ReindexSummary,
+    fsutil::{CopyOptions, guess_case_insensitive, is_case_insensitive},
+    fuzzy,
     index::AudioIndex,
+    junk::{JunkReport, clean_junk},
+    layout::{DeviceLayout, M3U_EXTENSION},
+    manifest::{manifest_dir, relative_path},
+    naming::{FilenameRules, sanitize},
+    profile::DeviceProfile,
 };
 use std::{
     collections::HashMap,
-    path::PathBuf,
+    fs::{create_dir_all, read_dir, write},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::Instant,
 };
 
 // An attached device e.g. mp3 player, hard drive etc,
@@ -12,47 +25,236 @@ use std::{
 pub struct AttachedDevice {
     pub name: String,
     pub path: PathBuf,
+    pub profile: DeviceProfile,
     index: HashMap<AudioKey, AudioLocation>,
+    // The index as last persisted to the device, reused on attach.
+    index_cache: DeviceIndexCache,
+    // Whether index_cache has changes not yet persisted to the device.
+    index_unsaved: bool,
+    // Every write to the device fails with AudioError::ReadOnly before touching the filesystem.
+    read_only: bool,
+    // Detected from the volume the first time it's needed, the profile's setting takes precedence.
+    detected_case_insensitive: OnceLock<bool>,
+    // Cancels copies to the device and syncs, defaults to the Ctrl-C token.
+    pub cancel: CancelToken,
 }
 
 impl AttachedDevice {
-    pub fn new(name: String, path: PathBuf) -> Self {
+    pub fn new(name: String, path: PathBuf) -> Result<Self, AudioError> {
         let mut device = Self {
             name,
+            profile: DeviceProfile::load(&path)?,
+            index_cache: DeviceIndexCache::load(&path),
+            index_unsaved: false,
+            detected_case_insensitive: OnceLock::new(),
             path,
             index: HashMap::new(),
+            read_only: false,
+            cancel: interrupt_token(),
         };
-        // Iterate the device to construct a local index.
-        let playlists = device.list_playlists().unwrap();
-        for playlist in &playlists {
-            // We treat all files stored in the root of an AttachedDevice as being part of a special ""
-            let dirpath = match &playlist.name {
-                PlaylistName::Uncategorized => device.path.clone(),
-                PlaylistName::Named(name) => device.path.join(name),
-            };
+        device.build_index(false)?;
+        println!("Added new attached device: {:?}", device);
+
+        Ok(device)
+    }
+
+    // Build the key -> location index from the persisted index cache, reindexing the directories that changed since,
+    // or every directory when full is set. Returns what changed since the persisted index.
+    fn build_index(&mut self, full: bool) -> Result<ReindexSummary, AudioError> {
+        let started = Instant::now();
+        let dirs = self.indexed_dirs()?;
+        let summary = self.index_cache.refresh(&self.path, &dirs, self.profile.layout, &self.profile.extensions(), full)?;
+        tracing::debug!(
+            "indexed {} directories of {} in {:?}: {}",
+            dirs.len(),
+            self.path.display(),
+            started.elapsed(),
+            summary.summary()
+        );
+        self.index_unsaved |= !summary.is_empty();
+        self.index.clear();
+        for (dir, _) in &dirs {
+            for file in self.index_cache.files(&self.path, dir) {
+                self.index.insert(file.key.clone(), AudioLocation::LocalPath(dir.join(&file.name)));
+            }
+        }
+        Ok(summary)
+    }
 
-            for audio in &playlist.audio {
-                if let Some(audiokey) = AudioKey::from_info(audio) {
-                    let audiopath: PathBuf = dirpath.join(
-                        audio
-                            .filename
-                            .as_ref()
-                            .expect("AttachedDevice must have audio filenames."),
-                    );
-                    device
-                        .index
-                        .insert(audiokey, AudioLocation::LocalPath(audiopath));
+    // The directories audio lives in, each with the layout its files are named by. Besides the device's layout's own
+    // directories, audio synced under another layout before `device layout` changed it is indexed where that layout
+    // put it, in the root, playlist directories or Artist/Album directories, so the next sync doesn't copy it again.
+    fn indexed_dirs(&self) -> Result<Vec<(PathBuf, DeviceLayout)>, AudioError> {
+        let layout = self.profile.layout;
+        let current = match layout {
+            DeviceLayout::PlaylistDirs => self.list_playlist_names()?.iter().map(|name| self.playlist_dir(name)).collect(),
+            DeviceLayout::Flat => vec![self.path.clone()],
+            DeviceLayout::ArtistAlbum => {
+                let mut dirs = Vec::new();
+                for artist in visible_subdirs(&self.path)? {
+                    dirs.extend(visible_subdirs(&artist)?);
                 }
+                dirs
             }
+        };
+        // Other layouts' directories go first, so a track in both places is found where the device's layout puts it.
+        let mut dirs = vec![(self.path.clone(), DeviceLayout::Flat)];
+        for dir in visible_subdirs(&self.path)? {
+            let albums = visible_subdirs(&dir)?;
+            dirs.push((dir, DeviceLayout::PlaylistDirs));
+            dirs.extend(albums.into_iter().map(|album| (album, DeviceLayout::ArtistAlbum)));
         }
-        println!("Added new attached device: {:?}", device);
+        dirs.retain(|(dir, _)| !current.contains(dir));
+        dirs.extend(current.into_iter().map(|dir| (dir, layout)));
+        Ok(dirs)
+    }
+
+    /// Reindex every directory on the device, rather than trusting directory mtimes to say which changed. Files whose
+    /// size and mtime are unchanged keep their persisted keys.
+    pub fn reindex(&mut self) -> Result<ReindexSummary, AudioError> {
+        let summary = self.build_index(true)?;
+        self.save_index();
+        Ok(summary)
+    }
+
+    /// Refresh the index after the device changed, e.g. after a sync, and persist it if anything did.
+    pub fn refresh_index(&mut self) {
+        if let Err(e) = self.build_index(false) {
+            tracing::warn!("failed to refresh the device index for {}: {}", self.path.display(), e);
+        }
+        self.save_index();
+    }
+
+    /// Persist the index as last built, when it has changes the device doesn't have yet. Best effort, an index that
+    /// can't be saved only means a slower next attach.
+    pub fn save_index(&mut self) {
+        if !self.index_unsaved || self.ensure_writable().is_err() {
+            return;
+        }
+        match self.index_cache.save(&self.path) {
+            Ok(()) => self.index_unsaved = false,
+            Err(e) => tracing::warn!("failed to save the device index for {}: {}", self.path.display(), e),
+        }
+    }
+
+    /// Whether names differing only by case are the same file on the device, so paths are compared case folded.
+    pub fn case_insensitive(&self) -> bool {
+        self.profile.case_insensitive.unwrap_or_else(|| {
+            *self.detected_case_insensitive.get_or_init(|| {
+                // Probed with a scratch file in the manifest area, unless the device mustn't be written at all.
+                let probe_dir = manifest_dir(&self.path);
+                if !self.read_only && create_dir_all(&probe_dir).is_ok() {
+                    is_case_insensitive(&probe_dir)
+                } else {
+                    guess_case_insensitive(&self.path)
+                }
+            })
+        })
+    }
+
+    /// Directory a playlist lives in on the device, honoring the profile's destination overrides.
+    pub fn playlist_dir(&self, playlist: &PlaylistName) -> PathBuf {
+        match playlist {
+            PlaylistName::Uncategorized => self.path.clone(),
+            PlaylistName::Named(name) => self.path.join(self.profile.playlist_destination(name)),
+        }
+    }
+
+    /// Remove junk like AppleDouble files from the device, per its profile's patterns. A dry run only reports it.
+    pub fn clean_junk(&self, dry_run: bool) -> Result<JunkReport, AudioError> {
+        if !dry_run {
+            self.ensure_writable()?;
+        }
+        Ok(clean_junk(&self.path, &self.profile.junk_patterns(), dry_run)?)
+    }
+
+    /// The M3U file a playlist is written to when the layout has no playlist directories.
+    pub fn m3u_path(&self, playlist: &str) -> PathBuf {
+        self.path.join(format!("{}.{}", sanitize(playlist, FilenameRules::Fat), M3U_EXTENSION))
+    }
+
+    /// Write a playlist's M3U file, listing the tracks that are on the device in playlist order, with paths relative
+    /// to the device root. Returns how many tracks it lists.
+    pub fn write_m3u(&self, playlist: &str, tracks: &[AudioInfo]) -> Result<usize, AudioError> {
+        self.ensure_writable()?;
+        let mut m3u = String::from("#EXTM3U\n");
+        let mut listed = 0;
+        for info in tracks {
+            let Ok(AudioLocation::LocalPath(path)) = self.search(info) else {
+                continue;
+            };
+            let duration = info.duration_secs.map_or(-1, i64::from);
+            let artist = info.artist.as_deref().unwrap_or_default();
+            let title = info.title.as_deref().unwrap_or_default();
+            m3u.push_str(&format!("#EXTINF:{},{} - {}\n{}\n", duration, artist, title, relative_path(&self.path, path)));
+            listed += 1;
+        }
+        write(self.m3u_path(playlist), m3u)?;
+        Ok(listed)
+    }
+
+    /// Whether a playlist entry is marked to never be synced here. Entries name the device as it was attached, or by
+    /// just its directory name e.g. "CLIP" for /Volumes/CLIP, so the mount point can move.
+    pub fn skips(&self, info: &AudioInfo) -> bool {
+        let dir_name = self.path.file_name().map(|name| name.to_string_lossy());
+        info.skip_on
+            .iter()
+            .any(|device| *device == self.name || dir_name.as_deref() == Some(device.as_str()))
+    }
 
-        device
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    /// How copies to the device are written, per its profile, cancelled by the device's token.
+    pub fn copy_options(&self) -> CopyOptions {
+        CopyOptions { cancel: self.cancel.clone(), ..self.profile.copy_options() }
+    }
+
+    pub fn ensure_writable(&self) -> Result<(), AudioError> {
+        if self.read_only {
+            return Err(AudioError::ReadOnly);
+        }
+        Ok(())
+    }
+
+    pub fn save_profile(&self) -> Result<(), AudioError> {
+        self.ensure_writable()?;
+        self.profile.save(&self.path)?;
+        Ok(())
     }
 
     pub fn search(&self, info: &AudioInfo) -> Result<&AudioLocation, AudioError> {
-        let key = AudioKey::from_info(info).ok_or(AudioError::MissingInfo)?;
-        self.index.get(&key).ok_or(AudioError::NotFound)
+        let key = self.profile.layout.key(info).ok_or(AudioError::MissingInfo)?;
+        find_key(&self.index, &key).map(|(_, location)| location).ok_or(AudioError::NotFound)
+    }
+
+    /// Search on whichever of artist and title the AudioInfo has, returning every candidate with its fuzzy score (None
+    /// for exact matches).
+    pub fn search_partial(&self, info: &AudioInfo) -> Vec<(&AudioKey, &AudioLocation, Option<f64>)> {
+        fuzzy::partial_matches(info.artist.as_deref(), info.title.as_deref(), self.index.keys(), fuzzy::DEFAULT_THRESHOLD)
+            .into_iter()
+            .map(|(key, score)| (key, &self.index[key], score))
+            .collect()
+    }
+
+    pub fn index_keys(&self) -> impl Iterator<Item = &AudioKey> {
+        self.index.keys()
+    }
+
+    pub fn location(&self, key: &AudioKey) -> Option<&AudioLocation> {
+        self.index.get(key)
+    }
+
+    // Drop whatever is indexed at a path, e.g. a file just moved to the device trash.
+    pub fn remove_location(&mut self, path: &Path) {
+        self.index.retain(|_, location| !matches!(location, AudioLocation::LocalPath(p) if p == path));
+    }
+
+    pub fn remove_from_index(&mut self, info: &AudioInfo) {
+        if let Some(audiokey) = self.profile.layout.key(info) {
+            self.index.remove(&audiokey);
+        }
     }
 
     pub fn update_index(
@@ -61,7 +263,7 @@ impl AttachedDevice {
         location: &AudioLocation,
     ) -> Result<(), AudioError> {
         if let AudioLocation::LocalPath(_) = location {
-            if let Some(audiokey) = AudioKey::from_info(info) {
+            if let Some(audiokey) = self.profile.layout.key(info) {
                 self.index.insert(audiokey, location.clone());
                 return Ok(());
             }
@@ -70,3 +272,76 @@ impl AttachedDevice {
     }
 
 }
+
+/// Subdirectories of a device directory, leaving out hidden ones like the manifest area and the ones the OS keeps.
+pub fn visible_subdirs(dir: &Path) -> Result<Vec<PathBuf>, AudioError> {
+    let mut dirs = Vec::new();
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if entry.file_type()?.is_dir() && !name.starts_with('.') && name != "System Volume Information" {
+            dirs.push(entry.path());
+        }
+    }
+    Ok(dirs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::remove_dir_all;
+
+    fn info(artist: &str, title: &str) -> AudioInfo {
+        AudioInfo { artist: Some(artist.to_string()), title: Some(title.to_string()), ..Default::default() }
+    }
+
+    #[test]
+    fn audio_stays_indexed_after_the_layout_changes() {
+        let root = std::env::temp_dir().join(format!("music-man-device-layout-{}", std::process::id()));
+        remove_dir_all(&root).ok();
+        create_dir_all(root.join("Road")).unwrap();
+        write(root.join("Road/Muse - Uprising.mp3"), b"audio").unwrap();
+        create_dir_all(root.join("Muse/The Resistance")).unwrap();
+        write(root.join("Muse/The Resistance/02 - Resistance.mp3"), b"audio").unwrap();
+        let found = |device: &AttachedDevice, info: &AudioInfo| match device.search(info) {
+            Ok(AudioLocation::LocalPath(path)) => relative_path(&root, path),
+            other => panic!("{:?} not found: {:?}", info, other),
+        };
+
+        let mut device = AttachedDevice::new("test".to_string(), root.clone()).unwrap();
+        assert_eq!(found(&device, &info("Muse", "Uprising")), "Road/Muse - Uprising.mp3");
+        // Synced under artist-album before the layout went back to playlist directories.
+        assert_eq!(found(&device, &info("Muse", "Resistance")), "Muse/The Resistance/02 - Resistance.mp3");
+
+        device.profile.layout = DeviceLayout::ArtistAlbum;
+        device.reindex().unwrap();
+        assert_eq!(found(&device, &info("Muse", "Uprising")), "Road/Muse - Uprising.mp3");
+        assert_eq!(found(&device, &info("Muse", "Resistance")), "Muse/The Resistance/02 - Resistance.mp3");
+        remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn an_unchanged_device_keeps_its_persisted_index() {
+        let root = std::env::temp_dir().join(format!("music-man-device-index-{}", std::process::id()));
+        remove_dir_all(&root).ok();
+        create_dir_all(root.join("Road")).unwrap();
+        write(root.join("Road/Muse - Uprising.mp3"), b"audio").unwrap();
+        let index_path = manifest_dir(&root).join("index.json");
+
+        let saved_at = || std::fs::metadata(&index_path).and_then(|meta| meta.modified()).unwrap();
+
+        let mut device = AttachedDevice::new("test".to_string(), root.clone()).unwrap();
+        device.save_index();
+        let first_save = saved_at();
+
+        // Nothing changed since, so attaching again has nothing to write.
+        let mut device = AttachedDevice::new("test".to_string(), root.clone()).unwrap();
+        device.save_index();
+        assert_eq!(saved_at(), first_save);
+
+        write(root.join("Road/Muse - Resistance.mp3"), b"audio").unwrap();
+        device.refresh_index();
+        assert_ne!(saved_at(), first_save);
+        remove_dir_all(&root).ok();
+    }
+}
@@ -0,0 +1,141 @@
+// DeviceIndexCache -> The device index persisted next to the device manifest, so attaching a big card doesn't mean
+// rereading every directory on it. Each playlist directory is stored with its mtime and its files' keys, sizes and
+// mtimes. On attach only directories whose mtime changed are reindexed, and a small random sample of the reused files
+// is checked, reindexing every directory if any of them changed without their directory noticing. Reindexing only
+// rekeys the files whose size or mtime changed, see reindex_dir.
+
+use std::{
+    collections::HashMap,
+    fs::{create_dir_all, metadata, read_to_string, write},
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    audio::is_audio_file_in,
+    cache::unix_now,
+    file_index::{IndexedDir, IndexedFile, NANOS_PER_SEC, ReindexSummary, mtime_nanos, reindex_dir},
+    layout::DeviceLayout,
+    manifest::{manifest_dir, relative_path},
+    sync::shuffle_in_place,
+};
+
+// Bump when the stored format or how keys are derived from filenames changes, forcing every file to be rekeyed.
+const INDEX_CACHE_VERSION: u32 = 4;
+// Reused files statted on attach to catch changes the directory mtimes missed.
+const CONSISTENCY_SAMPLE: usize = 8;
+// FAT stores mtimes at 2 second resolution, so a directory changed just before saving may look unchanged after.
+const MTIME_SLACK_SECS: u64 = 2;
+
+fn index_cache_path(device_root: &Path) -> PathBuf {
+    manifest_dir(device_root).join("index.json")
+}
+
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct DeviceIndexCache {
+    pub version: u32,
+    // Unix seconds the cache was saved.
+    pub saved_at: u64,
+    // The layout the files were keyed for, keys from another layout are never reused.
+    #[serde(default)]
+    pub layout: DeviceLayout,
+    // The extensions the device accepted when it was scanned, files of other formats weren't indexed.
+    #[serde(default)]
+    pub extensions: Vec<String>,
+    // Device relative directory, "" for the root -> its audio files.
+    pub dirs: HashMap<String, IndexedDir>,
+    // Device relative directory -> the layout its files were named by, which can be another than the device's, e.g.
+    // for audio synced before `device layout` changed it.
+    #[serde(default)]
+    pub dir_layouts: HashMap<String, DeviceLayout>,
+}
+
+impl DeviceIndexCache {
+    /// A missing, unreadable, or outdated cache is just an empty one, meaning a full scan.
+    pub fn load(device_root: &Path) -> Self {
+        read_to_string(index_cache_path(device_root))
+            .ok()
+            .and_then(|s| serde_json::from_str::<Self>(&s).ok())
+            .filter(|cache| cache.version == INDEX_CACHE_VERSION)
+            .unwrap_or_default()
+    }
+
+    pub fn save(&mut self, device_root: &Path) -> io::Result<()> {
+        self.version = INDEX_CACHE_VERSION;
+        self.saved_at = unix_now();
+        create_dir_all(manifest_dir(device_root))?;
+        write(index_cache_path(device_root), serde_json::to_string(self)?)
+    }
+
+    /// Bring the cache up to date with the given directories, each with the layout its files are named by, reindexing
+    /// the ones that changed, or all of them when full is set. Files are keyed for the device's layout, and only
+    /// rekeyed when they changed, or every file when the layout or accepted extensions did. Directories no longer
+    /// listed are dropped, along with their files.
+    pub fn refresh(
+        &mut self,
+        device_root: &Path,
+        dirs: &[(PathBuf, DeviceLayout)],
+        layout: DeviceLayout,
+        extensions: &[String],
+        full: bool,
+    ) -> io::Result<ReindexSummary> {
+        let rekey = layout != self.layout || extensions != self.extensions;
+        self.layout = layout;
+        self.extensions = extensions.to_vec();
+        let mut refreshed = HashMap::new();
+        let mut dir_layouts = HashMap::new();
+        let mut reused = Vec::new();
+        let mut summary = ReindexSummary::default();
+        for (dir, named_by) in dirs {
+            let rel_dir = relative_path(device_root, dir);
+            let mtime = mtime_nanos(&metadata(dir)?);
+            let renamed = self.dir_layouts.get(&rel_dir) != Some(named_by);
+            let previous = self.dirs.remove(&rel_dir).filter(|_| !rekey && !renamed);
+            let indexed = match previous {
+                Some(previous)
+                    if !full && previous.mtime == mtime && mtime / NANOS_PER_SEC + MTIME_SLACK_SECS < self.saved_at =>
+                {
+                    reused.push(rel_dir.clone());
+                    previous
+                }
+                previous => {
+                    let (indexed, changed) = reindex_dir(
+                        dir,
+                        previous,
+                        |entry| is_audio_file_in(entry, extensions),
+                        |name| layout.key(&named_by.info_from_path(&Path::new(&rel_dir).join(name))),
+                    )?;
+                    summary.merge(changed);
+                    indexed
+                }
+            };
+            dir_layouts.insert(rel_dir.clone(), *named_by);
+            refreshed.insert(rel_dir, indexed);
+        }
+        summary.removed += self.dirs.values().map(|indexed| indexed.files.len()).sum::<usize>();
+        self.dirs = refreshed;
+        self.dir_layouts = dir_layouts;
+
+        if !full && !reused.is_empty() && !self.sample_consistent(device_root, &reused) {
+            tracing::info!("device index cache for {} is stale, reindexing every directory", device_root.display());
+            summary.merge(self.refresh(device_root, dirs, layout, extensions, true)?);
+        }
+        Ok(summary)
+    }
+
+    // Stat a random sample of files from reused directories, checking they're still as recorded.
+    fn sample_consistent(&self, device_root: &Path, reused: &[String]) -> bool {
+        let mut files: Vec<(&String, &IndexedFile)> =
+            reused.iter().flat_map(|dir| self.dirs[dir].files.iter().map(move |file| (dir, file))).collect();
+        shuffle_in_place(&mut files);
+        files.iter().take(CONSISTENCY_SAMPLE).all(|(dir, file)| {
+            metadata(device_root.join(dir).join(&file.name))
+                .is_ok_and(|meta| file.matches(&meta))
+        })
+    }
+
+    /// The indexed files of a directory.
+    pub fn files(&self, device_root: &Path, dir: &Path) -> &[IndexedFile] {
+        self.dirs.get(&relative_path(device_root, dir)).map_or(&[], |indexed| &indexed.files)
+    }
+}
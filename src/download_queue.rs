@@ -0,0 +1,103 @@
+// DownloadQueue -> Tracks waiting to be downloaded into the cache, persisted so a long batch like a whole discography
+// carries on where it left off after a cancel or a restart. Entries leave the queue once downloaded, ones that failed
+// stay queued for the next run.
+
+use std::{
+    fs::{read_to_string, write},
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    audio::{AudioInfo, AudioKey},
+    cache::get_data_dir,
+};
+
+pub fn download_queue_path() -> PathBuf {
+    get_data_dir().join("download_queue.json")
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct QueuedDownload {
+    pub info: AudioInfo,
+    // Cache playlist the track is added to once it's downloaded.
+    pub playlist: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct DownloadQueue {
+    path: PathBuf,
+    entries: Vec<QueuedDownload>,
+}
+
+impl DownloadQueue {
+    pub fn load() -> Self {
+        Self::load_from(&download_queue_path())
+    }
+
+    fn load_from(path: &Path) -> Self {
+        let entries = read_to_string(path).ok().and_then(|s| serde_json::from_str(&s).ok()).unwrap_or_default();
+        Self { path: path.to_path_buf(), entries }
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        write(&self.path, serde_json::to_string_pretty(&self.entries)?)
+    }
+
+    pub fn entries(&self) -> &[QueuedDownload] {
+        &self.entries
+    }
+
+    /// Queue downloads behind those already waiting, skipping tracks that are queued already. Returns how many were
+    /// added.
+    pub fn push(&mut self, downloads: impl IntoIterator<Item = QueuedDownload>) -> usize {
+        let before = self.entries.len();
+        for download in downloads {
+            let key = AudioKey::from_info(&download.info);
+            if key.is_none() || !self.entries.iter().any(|queued| AudioKey::from_info(&queued.info) == key) {
+                self.entries.push(download);
+            }
+        }
+        self.entries.len() - before
+    }
+
+    pub fn remove(&mut self, info: &AudioInfo) {
+        let key = AudioKey::from_info(info);
+        self.entries.retain(|queued| AudioKey::from_info(&queued.info) != key);
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queued(artist: &str, title: &str, playlist: &str) -> QueuedDownload {
+        QueuedDownload {
+            info: AudioInfo { artist: Some(artist.to_string()), title: Some(title.to_string()), ..Default::default() },
+            playlist: Some(playlist.to_string()),
+        }
+    }
+
+    #[test]
+    fn queued_tracks_survive_a_restart_once_each() {
+        let path = std::env::temp_dir().join(format!("music-man-download-queue-{}.json", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let mut queue = DownloadQueue::load_from(&path);
+        assert_eq!(queue.push([queued("Muse", "Uprising", "Muse - The Resistance")]), 1);
+        // Queued again under another spelling, e.g. by a second download-artist.
+        let added = queue.push([queued("muse", "uprising", "Muse - The Resistance"), queued("Muse", "Resistance", "Muse - The Resistance")]);
+        assert_eq!(added, 1);
+        queue.save().unwrap();
+
+        let mut queue = DownloadQueue::load_from(&path);
+        let titles: Vec<_> = queue.entries().iter().map(|queued| queued.info.title.clone().unwrap()).collect();
+        assert_eq!(titles, ["Uprising", "Resistance"]);
+        queue.remove(&AudioInfo { artist: Some("MUSE".to_string()), title: Some("Uprising".to_string()), ..Default::default() });
+        assert_eq!(queue.entries().len(), 1);
+        std::fs::remove_file(&path).ok();
+    }
+}
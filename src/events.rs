@@ -0,0 +1,150 @@
+// Events -> Structured progress for tools wrapping music-man, e.g. a GUI. Long running operations report through a
+// ProgressReporter, which by default only shows download progress on a terminal, or with `--events jsonl` writes one
+// JSON object per line to stderr (or with `--events-to <path>`, to a file or named pipe). Human output on stdout is
+// never affected.
+//
+// Schema, version EVENT_SCHEMA_VERSION. Every line is an object with:
+//   "v"     schema version, bumped on any incompatible change
+//   "ts"    unix seconds
+//   "event" one of the Event variant names in snake_case, with that variant's fields alongside:
+//     plan_computed     playlist, tracks, in_budget
+//     track_started     playlist, index, info
+//     transcoding       playlist, index, info, format (what it's transcoded to), when the device doesn't play the
+//                       cached format, before the track's copy starts
+//     download_progress percent (0-100), bytes_per_sec (current speed, null until known)
+//     copy_progress     copied_bytes, total_bytes (cumulative over the sync), bytes_per_sec (average so far)
+//     conflict          playlist, info, path, kind (differs, fuzzy_match, or duration_mismatch with expected_secs
+//                       and cached_secs), before it's resolved by policy or prompt; the resolution is in the report
+//     track_finished    playlist, index, info, outcome
+//     sync_finished     report
+// Adding an event is adding a variant, consumers should ignore events they don't know.
+
+use std::{
+    fs::OpenOptions,
+    io::{IsTerminal, Write, stderr, stdout},
+    path::Path,
+};
+
+use crate::{
+    audio::AudioInfo,
+    cache::unix_now,
+    fsutil::format_size,
+    sync::{ConflictKind, SyncOutcome, SyncReport},
+};
+
+pub const EVENT_SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, serde::Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    PlanComputed { playlist: &'a str, tracks: usize, in_budget: usize },
+    TrackStarted { playlist: &'a str, index: usize, info: &'a AudioInfo },
+    Transcoding { playlist: &'a str, index: usize, info: &'a AudioInfo, format: &'a str },
+    DownloadProgress { percent: f32, bytes_per_sec: Option<u64> },
+    CopyProgress { copied_bytes: u64, total_bytes: u64, bytes_per_sec: u64 },
+    Conflict { playlist: &'a str, info: &'a AudioInfo, path: &'a Path, kind: ConflictKind },
+    TrackFinished { playlist: &'a str, index: usize, info: &'a AudioInfo, outcome: &'a SyncOutcome },
+    SyncFinished { report: &'a SyncReport },
+}
+
+#[derive(serde::Serialize)]
+struct Envelope<'a> {
+    v: u32,
+    ts: u64,
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+}
+
+pub trait ProgressReporter {
+    fn report(&mut self, event: Event);
+
+    /// Whether events go to stderr, so tools' own stderr output mustn't be echoed there between them.
+    fn on_stderr(&self) -> bool {
+        false
+    }
+}
+
+// Reports nothing, for when no one is listening.
+pub struct NoProgress;
+
+impl ProgressReporter for NoProgress {
+    fn report(&mut self, _event: Event) {}
+}
+
+// Shows download progress and speed on one line, rewritten in place, so a capped --limit-rate is visible as it
+// happens. Piped output is left alone, since there's no line to rewrite.
+pub struct HumanProgress {
+    terminal: bool,
+}
+
+impl HumanProgress {
+    pub fn stdout() -> Self {
+        Self { terminal: stdout().is_terminal() }
+    }
+}
+
+impl ProgressReporter for HumanProgress {
+    fn report(&mut self, event: Event) {
+        let Event::DownloadProgress { percent, bytes_per_sec } = event else {
+            return;
+        };
+        if !self.terminal {
+            return;
+        }
+        let speed = bytes_per_sec.map(|speed| format!(" at {}/s", format_size(speed))).unwrap_or_default();
+        print!("\rDownloading {:5.1}%{:<16}", percent, speed);
+        if percent >= 100.0 {
+            println!();
+        }
+        stdout().flush().ok();
+    }
+}
+
+pub struct JsonlEvents {
+    writer: Box<dyn Write>,
+    on_stderr: bool,
+}
+
+impl JsonlEvents {
+    pub fn stderr() -> Self {
+        Self { writer: Box::new(stderr()), on_stderr: true }
+    }
+
+    /// Append events to a file, or write them to a named pipe.
+    pub fn to_path(path: &Path) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { writer: Box::new(file), on_stderr: false })
+    }
+}
+
+impl ProgressReporter for JsonlEvents {
+    fn report(&mut self, event: Event) {
+        let envelope = Envelope { v: EVENT_SCHEMA_VERSION, ts: unix_now(), event: &event };
+        // A reader going away must never fail the operation being reported on.
+        if let Ok(line) = serde_json::to_string(&envelope) {
+            writeln!(self.writer, "{}", line).ok();
+            self.writer.flush().ok();
+        }
+    }
+
+    fn on_stderr(&self) -> bool {
+        self.on_stderr
+    }
+}
+
+/// Pick the reporter from the command line: `--events jsonl` for stderr, plus `--events-to <path>` to redirect.
+pub fn reporter_from_args(args: &[String]) -> Result<Box<dyn ProgressReporter>, String> {
+    let value = |flag: &str| args.iter().position(|a| a == flag).map(|i| args.get(i + 1));
+    match (value("--events"), value("--events-to")) {
+        (None, None) => Ok(Box::new(HumanProgress::stdout())),
+        (Some(Some(format)), path) if format == "jsonl" => match path {
+            Some(Some(path)) => JsonlEvents::to_path(Path::new(path))
+                .map(|events| Box::new(events) as Box<dyn ProgressReporter>)
+                .map_err(|e| format!("Failed to open event stream {}: {}", path, e)),
+            Some(None) => Err("--events-to needs a path".to_string()),
+            None => Ok(Box::new(JsonlEvents::stderr())),
+        },
+        (Some(_), _) => Err("Unsupported --events format, expected: --events jsonl".to_string()),
+        (None, Some(_)) => Err("--events-to requires --events jsonl".to_string()),
+    }
+}
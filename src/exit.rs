@@ -0,0 +1,101 @@
+// Exit status contract for scripted use. Every command's outcome is mapped here, so the codes mean the same thing
+// everywhere:
+//   0 full success
+//   1 usage or config error
+//   2 environment missing, e.g. yt-dlp/ffmpeg not installed or the device not mounted
+//   3 partial failure, some tracks in a batch failed
+//   4 hard failure
+//   130 cancelled with Ctrl-C
+
+use std::io;
+
+use crate::{audio::AudioError, cache::release_cache_lock, logging::flush};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExitStatus {
+    #[default]
+    Success,
+    Usage,
+    Environment,
+    Partial,
+    Failure,
+    Cancelled,
+}
+
+impl ExitStatus {
+    pub fn code(self) -> i32 {
+        match self {
+            ExitStatus::Success => 0,
+            ExitStatus::Usage => 1,
+            ExitStatus::Environment => 2,
+            ExitStatus::Partial => 3,
+            ExitStatus::Failure => 4,
+            ExitStatus::Cancelled => 130,
+        }
+    }
+
+    pub fn from_error(error: &AudioError) -> Self {
+        match error {
+            e if e.is_cancelled() => ExitStatus::Cancelled,
+            AudioError::Config(_) | AudioError::MissingInfo | AudioError::ReadOnly => ExitStatus::Usage,
+            AudioError::Unavailable(_) => ExitStatus::Environment,
+            // Spawning a tool that isn't installed, or reading a device that isn't there.
+            AudioError::Io(e) if e.kind() == io::ErrorKind::NotFound => ExitStatus::Environment,
+            _ => ExitStatus::Failure,
+        }
+    }
+
+    pub fn from_result<T>(result: &Result<T, AudioError>) -> Self {
+        match result {
+            Ok(_) => ExitStatus::Success,
+            Err(e) => Self::from_error(e),
+        }
+    }
+
+    /// Map a batch of tracks, printing a greppable "FAILED n/m tracks" summary on stderr when any failed.
+    pub fn from_batch(failed: usize, total: usize) -> Self {
+        if failed == 0 {
+            return ExitStatus::Success;
+        }
+        eprintln!("FAILED {}/{} tracks", failed, total);
+        if failed < total { ExitStatus::Partial } else { ExitStatus::Failure }
+    }
+
+    // How bad a status is, when combining several commands' statuses into one.
+    fn severity(self) -> u8 {
+        match self {
+            ExitStatus::Success => 0,
+            ExitStatus::Partial => 1,
+            ExitStatus::Usage => 2,
+            ExitStatus::Environment => 3,
+            ExitStatus::Failure => 4,
+            ExitStatus::Cancelled => 5,
+        }
+    }
+
+    pub fn worst(self, other: Self) -> Self {
+        if other.severity() > self.severity() { other } else { self }
+    }
+
+    pub fn exit(self) -> ! {
+        tracing::debug!("exiting with status {}", self.code());
+        release_cache_lock();
+        flush();
+        std::process::exit(self.code())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batches_fail_partially_until_every_track_fails() {
+        assert_eq!(ExitStatus::from_batch(0, 52), ExitStatus::Success);
+        assert_eq!(ExitStatus::from_batch(3, 52).code(), 3);
+        assert_eq!(ExitStatus::from_batch(52, 52).code(), 4);
+        assert_eq!(ExitStatus::Partial.worst(ExitStatus::Usage), ExitStatus::Usage);
+        assert_eq!(ExitStatus::Cancelled.worst(ExitStatus::Failure), ExitStatus::Cancelled);
+        assert_eq!(ExitStatus::from_error(&AudioError::Unavailable("no ffmpeg".to_string())).code(), 2);
+    }
+}
@@ -0,0 +1,143 @@
+// Export -> Writing cache playlists out in formats other players understand.
+
+use std::{fs::write, path::Path};
+
+use crate::{
+    audio::{AudioError, AudioInfo, AudioLocation},
+    cache::LocalCache,
+    sidecar::Sidecar,
+};
+
+// Where each XSPF track location should point.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum XspfLocation {
+    // file:// URI of the cached file.
+    Cache,
+    // "<playlist>/<filename>", matching where AttachedDevice imports the playlist.
+    DeviceRelative,
+}
+
+/// Export a cache playlist as an XSPF document, returning the number of tracks written. Tracks missing from the
+/// cache are still listed with their metadata, just without a location.
+pub fn export_xspf(
+    cache: &LocalCache,
+    playlist: &str,
+    out: &Path,
+    location: XspfLocation,
+) -> Result<usize, AudioError> {
+    cache.ensure_writable()?;
+    let tracks = cache.playlist_tracks(playlist).ok_or(AudioError::NotFound)?;
+    write(out, xspf_document(cache, playlist, &tracks, location))?;
+    Ok(tracks.len())
+}
+
+fn xspf_document(cache: &LocalCache, playlist: &str, tracks: &[AudioInfo], location: XspfLocation) -> String {
+    let mut xml = String::new();
+    xml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str("<playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n");
+    xml.push_str(&format!("  <title>{}</title>\n", xml_escape(playlist)));
+    xml.push_str("  <trackList>\n");
+    for info in tracks.iter() {
+        xml.push_str("    <track>\n");
+        if let Ok(AudioLocation::LocalPath(path)) = cache.search(info) {
+            let uri = match location {
+                XspfLocation::Cache => {
+                    let absolute = path.canonicalize().unwrap_or(path.clone());
+                    format!("file://{}", percent_encode_path(&absolute.to_string_lossy()))
+                }
+                XspfLocation::DeviceRelative => {
+                    let filename = path.file_name().map(|f| f.to_string_lossy()).unwrap_or_default();
+                    percent_encode_path(&format!("{}/{}", playlist, filename))
+                }
+            };
+            xml.push_str(&format!("      <location>{}</location>\n", xml_escape(&uri)));
+
+            if let Some(secs) = info
+                .duration_secs
+                .or_else(|| Sidecar::load(&path).and_then(|s| s.duration_secs))
+            {
+                xml.push_str(&format!("      <duration>{}</duration>\n", u64::from(secs) * 1000));
+            }
+        }
+        if let Some(title) = &info.title {
+            xml.push_str(&format!("      <title>{}</title>\n", xml_escape(title)));
+        }
+        if let Some(artist) = &info.artist {
+            xml.push_str(&format!("      <creator>{}</creator>\n", xml_escape(artist)));
+        }
+        xml.push_str("    </track>\n");
+    }
+    xml.push_str("  </trackList>\n");
+    xml.push_str("</playlist>\n");
+    xml
+}
+
+// Escape text for XML content and attributes. Control characters aren't allowed in XML 1.0 at all, so drop them.
+fn xml_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if c.is_control() => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Percent-encode a path for use in a URI, keeping unreserved characters and path separators as is. Non-ASCII
+// characters are encoded byte by byte as UTF-8, per RFC 3986.
+fn percent_encode_path(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn nasty_titles_are_escaped_and_locations_encoded() {
+        assert_eq!(xml_escape("Rock & Roll <Live> \"Tom's\""), "Rock &amp; Roll &lt;Live&gt; &quot;Tom&apos;s&quot;");
+        assert_eq!(xml_escape("Jóga\u{0}\u{1b}"), "Jóga");
+        assert_eq!(percent_encode_path("/Music/Björk - Jóga #1?.mp3"), "/Music/Bj%C3%B6rk%20-%20J%C3%B3ga%20%231%3F.mp3");
+
+        let dir = std::env::temp_dir().join(format!("music-man-xspf-{}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("Simon & Garfunkel - Cecilia.mp3"), b"audio").unwrap();
+        let track = |artist: &str, title: &str| AudioInfo {
+            artist: Some(artist.to_string()),
+            title: Some(title.to_string()),
+            duration_secs: Some(175),
+            ..Default::default()
+        };
+        let tracks = vec![track("Simon & Garfunkel", "Cecilia"), track("Björk", "Jóga <Remix>")];
+        let cache = LocalCache::in_dir(&dir, HashMap::from([("R&B".to_string(), tracks.clone())]));
+
+        let xml = xspf_document(&cache, "R&B", &tracks, XspfLocation::DeviceRelative);
+        assert!(xml.contains("<title>R&amp;B</title>"));
+        assert!(xml.contains("<location>R%26B/Simon%20%26%20Garfunkel%20-%20Cecilia.mp3</location>"));
+        assert!(xml.contains("<duration>175000</duration>"));
+        assert!(xml.contains("<creator>Simon &amp; Garfunkel</creator>"));
+        // Not cached, so listed without a location.
+        assert!(xml.contains("<title>Jóga &lt;Remix&gt;</title>"));
+        assert_eq!(xml.matches("<location>").count(), 1);
+
+        let xml = xspf_document(&cache, "R&B", &tracks, XspfLocation::Cache);
+        assert!(xml.contains("<location>file:///"));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
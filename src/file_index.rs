@@ -0,0 +1,166 @@
+// FileIndex -> Incremental reindexing, shared by the cache and devices. A directory is walked once, and each file's
+// size and mtime (in nanoseconds, so a rewrite within the same second still shows) compared with what was recorded for
+// it last time. Unchanged files keep their recorded key, along with their content hash and duration once something has
+// computed them, so those are only ever worked out once per version of a file. New and changed files are parsed again,
+// and files that have gone are dropped.
+
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    fs::{DirEntry, Metadata, metadata, read_dir},
+    io,
+    path::Path,
+    time::UNIX_EPOCH,
+};
+
+use crate::audio::AudioKey;
+
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct IndexedFile {
+    pub name: String,
+    pub key: AudioKey,
+    pub size: u64,
+    // Unix nanoseconds.
+    pub mtime: u64,
+    // Filled in by whoever first needs them, and dropped when the file changes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_secs: Option<u32>,
+}
+
+impl IndexedFile {
+    /// Whether the file on disk is still the one recorded.
+    pub fn matches(&self, meta: &Metadata) -> bool {
+        meta.len() == self.size && mtime_nanos(meta) == self.mtime
+    }
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct IndexedDir {
+    // Unix nanoseconds.
+    pub mtime: u64,
+    pub files: Vec<IndexedFile>,
+}
+
+// What a reindex changed, summed over every directory it walked.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize)]
+pub struct ReindexSummary {
+    pub added: usize,
+    pub updated: usize,
+    pub removed: usize,
+}
+
+impl ReindexSummary {
+    pub fn merge(&mut self, other: ReindexSummary) {
+        self.added += other.added;
+        self.updated += other.updated;
+        self.removed += other.removed;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn summary(&self) -> String {
+        format!("{} added, {} updated, {} removed", self.added, self.updated, self.removed)
+    }
+}
+
+pub const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+pub fn mtime_nanos(meta: &Metadata) -> u64 {
+    meta.modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default()
+}
+
+/// Bring a directory's recorded files up to date. Only entries `accept` passes are indexed, keyed by `key` from their
+/// file name, which is only called for files that are new or whose size or mtime changed. Files without a key, e.g.
+/// ones with no artist in their name, are left out, as are files that vanish mid-walk.
+pub fn reindex_dir(
+    dir: &Path,
+    previous: Option<IndexedDir>,
+    accept: impl Fn(&DirEntry) -> bool,
+    key: impl Fn(&OsStr) -> Option<AudioKey>,
+) -> io::Result<(IndexedDir, ReindexSummary)> {
+    let mut previous: HashMap<String, IndexedFile> = previous
+        .into_iter()
+        .flat_map(|indexed| indexed.files)
+        .map(|file| (file.name.clone(), file))
+        .collect();
+    // Taken before walking, so a file added mid-walk leaves the directory looking changed next time.
+    let dir_mtime = mtime_nanos(&metadata(dir)?);
+    let mut summary = ReindexSummary::default();
+    let mut files = Vec::new();
+    for entry in read_dir(dir)?.filter_map(|e| e.ok()) {
+        if !accept(&entry) {
+            continue;
+        }
+        let name = entry.file_name().to_string_lossy().to_string();
+        // Deleted since it was listed, which leaves it to be counted as removed below.
+        let Ok(meta) = entry.metadata() else {
+            continue;
+        };
+        let (size, mtime) = (meta.len(), mtime_nanos(&meta));
+        match previous.remove(&name) {
+            Some(file) if file.matches(&meta) => files.push(file),
+            recorded => match (key(&entry.file_name()), recorded.is_some()) {
+                (Some(key), true) => {
+                    summary.updated += 1;
+                    files.push(IndexedFile { name, key, size, mtime, hash: None, duration_secs: None });
+                }
+                (Some(key), false) => {
+                    summary.added += 1;
+                    files.push(IndexedFile { name, key, size, mtime, hash: None, duration_secs: None });
+                }
+                // Renamed in place to something that no longer names a track.
+                (None, true) => summary.removed += 1,
+                (None, false) => {}
+            },
+        }
+    }
+    summary.removed += previous.len();
+    Ok((IndexedDir { mtime: dir_mtime, files }, summary))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::AudioInfo;
+
+    fn key(name: &OsStr) -> Option<AudioKey> {
+        AudioKey::from_info(&AudioInfo::from_filename(name))
+    }
+
+    #[test]
+    fn unchanged_files_keep_their_hash_and_duration() {
+        let dir = std::env::temp_dir().join(format!("music-man-file-index-{}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("A - Kept.mp3"), b"kept").unwrap();
+        std::fs::write(dir.join("B - Changed.mp3"), b"before").unwrap();
+        std::fs::write(dir.join("C - Removed.mp3"), b"removed").unwrap();
+
+        let (mut indexed, summary) = reindex_dir(&dir, None, |_| true, key).unwrap();
+        assert_eq!(summary, ReindexSummary { added: 3, updated: 0, removed: 0 });
+        for file in &mut indexed.files {
+            file.hash = Some(format!("hash of {}", file.name));
+            file.duration_secs = Some(60);
+        }
+
+        std::fs::write(dir.join("B - Changed.mp3"), b"after, and longer").unwrap();
+        std::fs::remove_file(dir.join("C - Removed.mp3")).unwrap();
+        std::fs::write(dir.join("D - Added.mp3"), b"added").unwrap();
+        let (indexed, summary) = reindex_dir(&dir, Some(indexed), |_| true, key).unwrap();
+        assert_eq!(summary, ReindexSummary { added: 1, updated: 1, removed: 1 });
+        let file = |name: &str| indexed.files.iter().find(|file| file.name == name).unwrap();
+        assert_eq!(file("A - Kept.mp3").hash.as_deref(), Some("hash of A - Kept.mp3"));
+        assert_eq!(file("A - Kept.mp3").duration_secs, Some(60));
+        assert_eq!(file("B - Changed.mp3").hash, None);
+        assert_eq!(file("D - Added.mp3").duration_secs, None);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
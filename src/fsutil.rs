@@ -0,0 +1,543 @@
+// Filesystem helpers shared by the cache, devices, and sync.
+
+use std::{
+    fs::{File, OpenOptions, metadata, read_dir},
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    audio::{PARTIAL_DOWNLOAD_SUFFIX, nfc},
+    cancel::{CancelToken, cancelled_io_error, interrupt_token},
+};
+
+const HASH_BUFFER_SIZE: usize = 1024 * 1024;
+const COPY_BUFFER_SIZE: usize = 1024 * 1024;
+// Removable targets are usually FAT over USB, where fewer, larger writes make the most difference.
+const REMOVABLE_COPY_BUFFER_SIZE: usize = 4 * 1024 * 1024;
+
+// How chunked copies write.
+#[derive(Clone, Debug)]
+pub struct CopyOptions {
+    pub buffer_size: usize,
+    // Size the destination up front, so the filesystem can allocate it in one go rather than per chunk.
+    pub preallocate: bool,
+    // Checked between chunks, a cancelled copy removes its partial file and leaves the destination as it was. Defaults
+    // to the Ctrl-C token.
+    pub cancel: CancelToken,
+}
+
+impl Default for CopyOptions {
+    fn default() -> Self {
+        Self { buffer_size: COPY_BUFFER_SIZE, preallocate: false, cancel: interrupt_token() }
+    }
+}
+
+impl CopyOptions {
+    pub fn removable() -> Self {
+        Self { buffer_size: REMOVABLE_COPY_BUFFER_SIZE, preallocate: true, cancel: interrupt_token() }
+    }
+}
+
+/// Copy a file, cloning it (APFS clonefile, Linux FICLONE reflink) when source and destination share a filesystem
+/// that supports it, and otherwise falling back to a chunked byte copy e.g. cross-device or to FAT targets.
+/// Returns the number of bytes at the destination.
+pub fn smart_copy(src: &Path, dst: &Path) -> io::Result<u64> {
+    smart_copy_with(src, dst, CopyOptions::default())
+}
+
+/// smart_copy, with the options used when it falls back to a chunked copy.
+pub fn smart_copy_with(src: &Path, dst: &Path, options: CopyOptions) -> io::Result<u64> {
+    smart_copy_hashed(src, dst, options).map(|copied| copied.bytes)
+}
+
+// What a copy wrote.
+#[derive(Clone, Debug)]
+pub struct CopiedFile {
+    pub bytes: u64,
+    // blake3 hash of the contents as hash_file gives it, when the copy read them anyway. None for clones, which never
+    // read the file.
+    pub hash: Option<String>,
+}
+
+/// smart_copy_with, also returning the hash of what a chunked copy wrote, so callers needn't reread either file.
+pub fn smart_copy_hashed(src: &Path, dst: &Path, options: CopyOptions) -> io::Result<CopiedFile> {
+    refuse_same_file(src, dst)?;
+    if same_filesystem(src, dst) && clone_over(src, dst).is_ok() {
+        return Ok(CopiedFile { bytes: metadata(dst)?.len(), hash: None });
+    }
+    chunked_copy_hashed(src, dst, options)
+}
+
+// Clone to a temporary file beside the destination and rename it over, like chunked_copy, so a failed clone leaves an
+// existing destination as it was.
+fn clone_over(src: &Path, dst: &Path) -> io::Result<()> {
+    let partial = partial_copy_path(dst);
+    let result = clone_file(src, &partial).and_then(|_| std::fs::rename(&partial, dst));
+    if result.is_err() {
+        std::fs::remove_file(&partial).ok();
+    }
+    result
+}
+
+/// Plain buffered copy, replacing any existing destination. Written to a temporary file beside the destination and
+/// renamed over it once complete, so a failed or cancelled copy never touches an existing destination. The file is
+/// fsynced once at the end, never per chunk.
+pub fn chunked_copy(src: &Path, dst: &Path, options: CopyOptions) -> io::Result<u64> {
+    chunked_copy_hashed(src, dst, options).map(|copied| copied.bytes)
+}
+
+fn chunked_copy_hashed(src: &Path, dst: &Path, options: CopyOptions) -> io::Result<CopiedFile> {
+    refuse_same_file(src, dst)?;
+    let partial = partial_copy_path(dst);
+    let result = copy_chunks(src, &partial, &options).and_then(|copied| std::fs::rename(&partial, dst).map(|_| copied));
+    if result.is_err() {
+        std::fs::remove_file(&partial).ok();
+    }
+    result
+}
+
+// Each chunk is hashed as it's written, which costs far less than reading the file again to hash it.
+fn copy_chunks(src: &Path, dst: &Path, options: &CopyOptions) -> io::Result<CopiedFile> {
+    let mut reader = File::open(src)?;
+    let mut writer = File::create(dst)?;
+    if options.preallocate {
+        writer.set_len(reader.metadata()?.len())?;
+    }
+    let mut buffer = vec![0; options.buffer_size.max(4096)];
+    let mut hasher = blake3::Hasher::new();
+    let mut total = 0;
+    loop {
+        if options.cancel.is_cancelled() {
+            return Err(cancelled_io_error());
+        }
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        writer.write_all(&buffer[..read])?;
+        hasher.update(&buffer[..read]);
+        total += read as u64;
+    }
+    // The source may have shrunk since it was sized up front.
+    if options.preallocate {
+        writer.set_len(total)?;
+    }
+    writer.sync_all()?;
+    Ok(CopiedFile { bytes: total, hash: Some(hasher.finalize().to_hex().to_string()) })
+}
+
+// Hidden, and with a partial download suffix so neither the cache nor a device ever indexes it.
+fn partial_copy_path(dst: &Path) -> PathBuf {
+    let name = dst.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+    dst.with_file_name(format!(".{}{}", name, PARTIAL_DOWNLOAD_SUFFIX))
+}
+
+/// Flush a directory's entries, so newly created files survive a yanked cable. Once per directory after a batch of
+/// copies is enough, the files themselves are already synced.
+pub fn sync_dir(dir: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+// On a case-insensitive volume "a.mp3" and "A.mp3" are one file, and copying it over itself would truncate or remove
+// the only copy before reading it.
+fn refuse_same_file(src: &Path, dst: &Path) -> io::Result<()> {
+    if same_file(src, dst) {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} and {} are the same file", src.display(), dst.display()),
+        ));
+    }
+    Ok(())
+}
+
+/// Whether two paths name the same existing file, e.g. spellings differing only by case on a case-insensitive volume.
+#[cfg(unix)]
+pub fn same_file(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (metadata(a), metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+pub fn same_file(a: &Path, b: &Path) -> bool {
+    matches!((a.canonicalize(), b.canonicalize()), (Ok(a), Ok(b)) if a == b)
+}
+
+/// Whether a directory's volume treats names differing only by case as the same file, as APFS and FAT do by default.
+/// Probed by creating a file in `dir` and looking it up with its name's case swapped, removing it again. Falls back to
+/// guess_case_insensitive when `dir` can't be written.
+pub fn is_case_insensitive(dir: &Path) -> bool {
+    let name = format!(".case-probe-{}", std::process::id());
+    let probe = dir.join(&name);
+    if File::create(&probe).is_err() {
+        return guess_case_insensitive(dir);
+    }
+    let insensitive = metadata(dir.join(name.to_uppercase())).is_ok();
+    std::fs::remove_file(&probe).ok();
+    insensitive
+}
+
+/// is_case_insensitive without writing anything, told from an existing entry. Assumed for an empty directory, since
+/// treating a case-sensitive volume as insensitive only makes path comparisons stricter.
+pub fn guess_case_insensitive(dir: &Path) -> bool {
+    // The name with its ASCII letters' case swapped, None without any letters to swap.
+    let swapped = |name: &str| {
+        let swapped: String = name
+            .chars()
+            .map(|c| if c.is_ascii_lowercase() { c.to_ascii_uppercase() } else { c.to_ascii_lowercase() })
+            .collect();
+        (swapped != name).then_some(swapped)
+    };
+    let Ok(entries) = read_dir(dir) else {
+        return true;
+    };
+    for entry in entries.filter_map(|e| e.ok()) {
+        if let Some(other) = swapped(&entry.file_name().to_string_lossy()) {
+            return same_file(&entry.path(), &dir.join(other));
+        }
+    }
+    true
+}
+
+/// The path an existing file is actually spelled with, when `path` names it with a different case on a
+/// case-insensitive volume, otherwise `path` itself. Moving onto the existing spelling keeps the file's name, and
+/// everything recorded under it, rather than respelling it underneath them.
+pub fn on_disk_spelling(path: &Path) -> PathBuf {
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+        return path.to_path_buf();
+    };
+    if !path.exists() {
+        return path.to_path_buf();
+    }
+    let folded = path_key(&name.to_string_lossy(), true);
+    read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|e| e.ok())
+        .find(|entry| {
+            entry.file_name() != name
+                && path_key(&entry.file_name().to_string_lossy(), true) == folded
+                && same_file(&entry.path(), path)
+        })
+        .map(|entry| entry.path())
+        .unwrap_or_else(|| path.to_path_buf())
+}
+
+/// A path or name as compared on a volume, case folded when the volume is case-insensitive. Always NFC, since macOS
+/// may hand back either normalization form for the same name.
+pub fn path_key(path: &str, case_insensitive: bool) -> String {
+    let normalized = nfc(path);
+    if case_insensitive { normalized.to_lowercase() } else { normalized }
+}
+
+#[cfg(unix)]
+fn same_filesystem(src: &Path, dst: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    // The destination may not exist yet, so compare against the directory it will be created in.
+    let dst_dir = match dst.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    match (metadata(src), metadata(dst_dir)) {
+        (Ok(src_meta), Ok(dst_meta)) => src_meta.dev() == dst_meta.dev(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn same_filesystem(_src: &Path, _dst: &Path) -> bool {
+    false
+}
+
+#[cfg(target_os = "macos")]
+fn clone_file(src: &Path, dst: &Path) -> io::Result<()> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let to_cstring = |p: &Path| {
+        CString::new(p.as_os_str().as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    };
+    let (src_c, dst_c) = (to_cstring(src)?, to_cstring(dst)?);
+
+    // clonefile refuses to replace an existing file, e.g. a temporary one left by a crash.
+    if dst.exists() {
+        std::fs::remove_file(dst)?;
+    }
+    // SAFETY: both paths are valid NUL terminated strings for the duration of the call.
+    if unsafe { libc::clonefile(src_c.as_ptr(), dst_c.as_ptr(), 0) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn clone_file(src: &Path, dst: &Path) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let reader = File::open(src)?;
+    let writer = File::create(dst)?;
+    // SAFETY: both descriptors are open for the duration of the call.
+    if unsafe { libc::ioctl(writer.as_raw_fd(), libc::FICLONE, reader.as_raw_fd()) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "linux")))]
+fn clone_file(_src: &Path, _dst: &Path) -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+/// Streaming blake3 hash of a file's contents, as a hex string.
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = blake3::Hasher::new();
+    let mut buffer = vec![0; HASH_BUFFER_SIZE];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// Compare two files cheaply, by size first and only hashing when the sizes match. Each side is hashed through its own
+/// hasher so callers can serve it from a cache (e.g. the cache's file index or the device manifest) instead of
+/// rereading the file.
+pub fn files_identical(
+    src: &Path,
+    dst: &Path,
+    src_hasher: &mut dyn FnMut(&Path) -> io::Result<String>,
+    dst_hasher: &mut dyn FnMut(&Path) -> io::Result<String>,
+) -> io::Result<bool> {
+    if metadata(src)?.len() != metadata(dst)?.len() {
+        return Ok(false);
+    }
+    Ok(src_hasher(src)? == dst_hasher(dst)?)
+}
+
+/// Take an exclusive lock that's held until the returned file is closed, on a ".lock" file beside `path` rather than
+/// `path` itself, since files that are rewritten by renaming over them would take their lock with them.
+pub fn lock_beside(path: &Path) -> io::Result<File> {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".lock");
+    let file = OpenOptions::new().create(true).truncate(false).write(true).open(path.with_file_name(name))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::io::AsRawFd;
+
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(file)
+}
+
+/// The first line of a file, without its newline. None when the file is missing or empty.
+pub fn first_line(path: &Path) -> io::Result<Option<String>> {
+    let file = match File::open(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        file => file?,
+    };
+    let mut line = String::new();
+    BufReader::new(file).read_line(&mut line)?;
+    let line = line.trim_end_matches('\n');
+    Ok((!line.is_empty()).then(|| line.to_string()))
+}
+
+/// The last line of a file, without its newline, read back from the end so a long log isn't read whole. None when the
+/// file is missing or empty.
+pub fn last_line(path: &Path) -> io::Result<Option<String>> {
+    const CHUNK: u64 = 8192;
+    let mut file = match File::open(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        file => file?,
+    };
+    let mut start = file.metadata()?.len();
+    let mut tail = Vec::new();
+    loop {
+        let chunk = CHUNK.min(start);
+        start -= chunk;
+        let mut read = vec![0; chunk as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut read)?;
+        read.extend_from_slice(&tail);
+        tail = read;
+        let line = tail.strip_suffix(b"\n").unwrap_or(&tail);
+        match line.iter().rposition(|&b| b == b'\n') {
+            Some(newline) => return Ok(Some(String::from_utf8_lossy(&line[newline + 1..]).into_owned())),
+            None if start == 0 => return Ok((!line.is_empty()).then(|| String::from_utf8_lossy(line).into_owned())),
+            None => {}
+        }
+    }
+}
+
+/// Parse a human size like "1G", "500MB", "1.5GiB" or a plain byte count. Units are binary (1K = 1024 bytes).
+pub fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: f64 = number.parse().ok()?;
+    let multiplier: u64 = match unit.trim().to_uppercase().trim_end_matches("IB").trim_end_matches('B') {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return None,
+    };
+    Some((number * multiplier as f64) as u64)
+}
+
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("music-man-{}-{}", name, std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn cancelled_copy_leaves_the_destination_alone() {
+        let dir = scratch_dir("cancelled-copy");
+        let (src, dst) = (dir.join("new.mp3"), dir.join("old.mp3"));
+        std::fs::write(&src, b"new audio").unwrap();
+        std::fs::write(&dst, b"old audio").unwrap();
+        let options = CopyOptions { cancel: CancelToken::new(), ..CopyOptions::default() };
+        options.cancel.cancel();
+
+        let e = chunked_copy(&src, &dst, options).unwrap_err();
+        assert!(crate::audio::AudioError::Io(e).is_cancelled());
+        assert_eq!(std::fs::read(&dst).unwrap(), b"old audio");
+        assert!(!partial_copy_path(&dst).exists());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn copy_replaces_the_destination() {
+        let dir = scratch_dir("replacing-copy");
+        let (src, dst) = (dir.join("new.mp3"), dir.join("old.mp3"));
+        std::fs::write(&src, b"new audio").unwrap();
+        std::fs::write(&dst, b"old").unwrap();
+
+        assert_eq!(chunked_copy(&src, &dst, CopyOptions::default()).unwrap(), 9);
+        assert_eq!(std::fs::read(&dst).unwrap(), b"new audio");
+        assert!(!partial_copy_path(&dst).exists());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn chunked_copies_hash_what_they_write() {
+        let dir = scratch_dir("hashed-copy");
+        let (src, dst) = (dir.join("song.mp3"), dir.join("copy.mp3"));
+        // Spans several chunks of the smallest buffer.
+        std::fs::write(&src, vec![7; 10_000]).unwrap();
+        let options = CopyOptions { buffer_size: 4096, ..CopyOptions::default() };
+
+        let copied = chunked_copy_hashed(&src, &dst, options).unwrap();
+        assert_eq!(copied.bytes, 10_000);
+        assert_eq!(copied.hash.unwrap(), hash_file(&dst).unwrap());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn failed_copies_leave_the_destination_alone() {
+        let dir = scratch_dir("failed-copy");
+        let (src, dst) = (dir.join("missing.mp3"), dir.join("old.mp3"));
+        std::fs::write(&dst, b"old audio").unwrap();
+
+        assert!(smart_copy(&src, &dst).is_err());
+        assert_eq!(std::fs::read(&dst).unwrap(), b"old audio");
+        assert!(!partial_copy_path(&dst).exists());
+
+        std::fs::write(&src, b"new audio").unwrap();
+        assert_eq!(smart_copy(&src, &dst).unwrap(), 9);
+        assert_eq!(std::fs::read(&dst).unwrap(), b"new audio");
+        assert!(!partial_copy_path(&dst).exists());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn copying_a_file_over_itself_is_refused() {
+        let dir = scratch_dir("same-file");
+        let (path, alias) = (dir.join("song.mp3"), dir.join("alias.mp3"));
+        std::fs::write(&path, b"only copy").unwrap();
+        std::fs::hard_link(&path, &alias).unwrap();
+
+        for copy in [smart_copy(&path, &alias), chunked_copy(&alias, &path, CopyOptions::default())] {
+            assert_eq!(copy.unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        }
+        assert_eq!(std::fs::read(&path).unwrap(), b"only copy");
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn interrupted_io_is_not_a_cancel() {
+        let e = io::Error::from(io::ErrorKind::Interrupted);
+        assert!(!crate::audio::AudioError::Io(e).is_cancelled());
+    }
+
+    #[test]
+    fn reads_first_and_last_lines() {
+        let dir = scratch_dir("lines");
+        let path = dir.join("log.jsonl");
+        assert_eq!(last_line(&path).unwrap(), None);
+        assert_eq!(first_line(&path).unwrap(), None);
+        // Longer than a chunk, so the last line spans several reads back from the end.
+        let long = "x".repeat(20_000);
+        std::fs::write(&path, format!("first\n{}\n", long)).unwrap();
+        assert_eq!(first_line(&path).unwrap().as_deref(), Some("first"));
+        assert_eq!(last_line(&path).unwrap(), Some(long.clone()));
+        std::fs::write(&path, format!("{}\nlast", long)).unwrap();
+        assert_eq!(last_line(&path).unwrap().as_deref(), Some("last"));
+        std::fs::write(&path, "only\n").unwrap();
+        assert_eq!(last_line(&path).unwrap().as_deref(), Some("only"));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn case_probe_cleans_up_after_itself() {
+        let dir = scratch_dir("case-probe");
+        std::fs::write(dir.join("Track.mp3"), b"audio").unwrap();
+        // Whatever the volume, the probe reaches the same answer as looking at an existing entry.
+        assert_eq!(is_case_insensitive(&dir), guess_case_insensitive(&dir));
+        let names: Vec<_> = read_dir(&dir).unwrap().map(|e| e.unwrap().file_name()).collect();
+        assert_eq!(names, vec![std::ffi::OsString::from("Track.mp3")]);
+        // The file's own spelling, or the path as given when there's no such file.
+        let spelled = on_disk_spelling(&dir.join("track.mp3"));
+        assert!(spelled == dir.join("track.mp3") || spelled == dir.join("Track.mp3"));
+        assert_eq!(on_disk_spelling(&dir.join("Track.mp3")), dir.join("Track.mp3"));
+        assert_eq!(on_disk_spelling(&dir.join("Missing.mp3")), dir.join("Missing.mp3"));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
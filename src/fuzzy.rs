@@ -0,0 +1,95 @@
+// Fuzzy matching of AudioKey, for when an exact key lookup misses because of typos, punctuation or "feat." noise.
+
+use crate::audio::{AudioKey, nfc};
+
+// Similarity below which a candidate isn't considered a match.
+pub const DEFAULT_THRESHOLD: f64 = 0.75;
+
+/// Similarity of two strings in 0.0..=1.0, from the Levenshtein distance normalized by the longer length.
+pub fn similarity(a: &str, b: &str) -> f64 {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let longest = a.len().max(b.len());
+    if longest == 0 {
+        return 1.0;
+    }
+    1.0 - levenshtein(&a, &b) as f64 / longest as f64
+}
+
+fn levenshtein(a: &[char], b: &[char]) -> usize {
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+    for (i, ca) in a.iter().enumerate() {
+        current[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != cb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    previous[b.len()]
+}
+
+/// Score a key against a query, both already lowercased. Artist and title are compared separately so a close title
+/// can't make up for a completely different artist.
+pub fn key_similarity(query: &AudioKey, candidate: &AudioKey) -> f64 {
+    similarity(&query.artist, &candidate.artist).min(similarity(&query.title, &candidate.title))
+}
+
+/// The best candidates scoring at least threshold, best first, without duplicates.
+pub fn best_matches<'a>(
+    query: &AudioKey,
+    candidates: impl IntoIterator<Item = &'a AudioKey>,
+    threshold: f64,
+    limit: usize,
+) -> Vec<(AudioKey, f64)> {
+    let mut matches: Vec<(AudioKey, f64)> = Vec::new();
+    for candidate in candidates {
+        if matches.iter().any(|(key, _)| key == candidate) {
+            continue;
+        }
+        let score = key_similarity(query, candidate);
+        if score >= threshold {
+            matches.push((candidate.clone(), score));
+        }
+    }
+    matches.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    matches.truncate(limit);
+    matches
+}
+
+/// Match keys on whichever of artist and title are given, e.g. a bare title. Exact field matches win when there are
+/// any, otherwise fuzzy ones scoring at least threshold are returned, best first with their score.
+pub fn partial_matches<'a>(
+    artist: Option<&str>,
+    title: Option<&str>,
+    keys: impl IntoIterator<Item = &'a AudioKey>,
+    threshold: f64,
+) -> Vec<(&'a AudioKey, Option<f64>)> {
+    let artist = artist.map(|a| nfc(a).to_lowercase());
+    let title = title.map(|t| nfc(t).to_lowercase());
+    let score = |key: &AudioKey| {
+        let artist_score = artist.as_ref().map_or(1.0, |a| similarity(a, &key.artist));
+        let title_score = title.as_ref().map_or(1.0, |t| similarity(t, &key.title));
+        artist_score.min(title_score)
+    };
+
+    let mut exact = Vec::new();
+    let mut fuzzy = Vec::new();
+    for key in keys {
+        if exact.iter().chain(&fuzzy).any(|(k, _)| *k == key) {
+            continue;
+        }
+        match score(key) {
+            s if s >= 1.0 => exact.push((key, None)),
+            s if s >= threshold => fuzzy.push((key, Some(s))),
+            _ => {}
+        }
+    }
+    if !exact.is_empty() {
+        exact.sort_by(|a: &(&AudioKey, Option<f64>), b| a.0.cmp(b.0));
+        return exact;
+    }
+    fuzzy.sort_by(|a, b| b.1.unwrap_or_default().total_cmp(&a.1.unwrap_or_default()).then_with(|| a.0.cmp(b.0)));
+    fuzzy
+}
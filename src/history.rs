@@ -0,0 +1,181 @@
+// PlaylistHistory -> Append-only snapshots of playlist contents, recorded every time a playlist changes, so changes
+// can be reviewed, diffed, and rolled back. Snapshots only hold AudioKeys rather than full AudioInfo to stay small,
+// and the history is capped per playlist. Recording only appends, reading back just the last line for the next id,
+// and once the file passes MAX_HISTORY_BYTES it's compacted to the newest snapshots of each playlist, well under that
+// again so the rewrite isn't repeated on every recording that follows.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{OpenOptions, read_to_string, rename, write},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    audio::{AudioInfo, AudioKey},
+    cache::{get_data_dir, unix_now},
+    fsutil::{last_line, lock_beside},
+};
+
+// Snapshots kept per playlist, older snapshots are dropped when compacting.
+const MAX_SNAPSHOTS_PER_PLAYLIST: usize = 50;
+// Compact the history file once it grows past this many bytes.
+const MAX_HISTORY_BYTES: u64 = 4 * 1024 * 1024;
+// What compacting brings it down to, dropping the oldest snapshots beyond each playlist's newest if need be.
+const COMPACT_TO_BYTES: u64 = MAX_HISTORY_BYTES / 2;
+
+pub fn playlist_history() -> PathBuf {
+    get_data_dir().join("playlist_history.jsonl")
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct PlaylistSnapshot {
+    pub id: u64,
+    pub playlist: String,
+    pub keys: Vec<AudioKey>,
+    // Unix seconds.
+    pub timestamp: u64,
+    // What changed, e.g. "add artist - title".
+    pub operation: String,
+}
+
+// Tracks added and removed between two snapshots.
+#[derive(Debug, Default)]
+pub struct SnapshotDiff {
+    pub added: Vec<AudioKey>,
+    pub removed: Vec<AudioKey>,
+}
+
+impl SnapshotDiff {
+    pub fn between(from: &PlaylistSnapshot, to: &PlaylistSnapshot) -> Self {
+        let from_keys: HashSet<&AudioKey> = from.keys.iter().collect();
+        let to_keys: HashSet<&AudioKey> = to.keys.iter().collect();
+        Self {
+            added: to.keys.iter().filter(|k| !from_keys.contains(k)).cloned().collect(),
+            removed: from.keys.iter().filter(|k| !to_keys.contains(k)).cloned().collect(),
+        }
+    }
+}
+
+fn load_all() -> Vec<PlaylistSnapshot> {
+    load_from(&playlist_history())
+}
+
+/// All snapshots of a playlist, oldest first.
+pub fn snapshots(playlist: &str) -> Vec<PlaylistSnapshot> {
+    load_all().into_iter().filter(|s| s.playlist == playlist).collect()
+}
+
+pub fn find_snapshot(playlist: &str, id: u64) -> Option<PlaylistSnapshot> {
+    snapshots(playlist).into_iter().find(|s| s.id == id)
+}
+
+/// Append a snapshot of a playlist's current track list.
+pub fn record_snapshot(playlist: &str, tracks: &[AudioInfo], operation: &str) -> io::Result<()> {
+    record_snapshot_in(&playlist_history(), playlist, tracks, operation)
+}
+
+fn record_snapshot_in(path: &Path, playlist: &str, tracks: &[AudioInfo], operation: &str) -> io::Result<()> {
+    // Held across numbering, appending and compacting, so concurrent runs neither reuse an id nor lose a snapshot.
+    let _lock = lock_beside(path)?;
+    // Snapshots are appended in id order, so the last has the highest.
+    let last_id = match last_line(path)? {
+        None => 0,
+        Some(line) => match serde_json::from_str::<PlaylistSnapshot>(&line) {
+            Ok(last) => last.id,
+            Err(_) => load_from(path).iter().map(|s| s.id).max().unwrap_or_default(),
+        },
+    };
+    let snapshot = PlaylistSnapshot {
+        id: last_id + 1,
+        playlist: playlist.to_string(),
+        keys: tracks.iter().filter_map(AudioKey::from_info).collect(),
+        timestamp: unix_now(),
+        operation: operation.to_string(),
+    };
+
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", serde_json::to_string(&snapshot)?)?;
+    if file.metadata()?.len() > MAX_HISTORY_BYTES {
+        compact(path)?;
+    }
+    Ok(())
+}
+
+fn load_from(path: &Path) -> Vec<PlaylistSnapshot> {
+    read_to_string(path)
+        .map(|s| s.lines().filter_map(|line| serde_json::from_str(line).ok()).collect())
+        .unwrap_or_default()
+}
+
+// Rewrite the history keeping only the newest snapshots of each playlist, then if that's still over COMPACT_TO_BYTES
+// the oldest of those, short of any playlist's newest. Replaced in one rename so readers never see half of it.
+fn compact(path: &Path) -> io::Result<()> {
+    let all = load_from(path);
+    let mut per_playlist = HashMap::<&str, usize>::new();
+    let mut kept = Vec::new();
+    for snapshot in all.iter().rev() {
+        let count = per_playlist.entry(&snapshot.playlist).or_default();
+        if *count < MAX_SNAPSHOTS_PER_PLAYLIST {
+            *count += 1;
+            // Newest first, so the first seen of each playlist is its current contents.
+            kept.push((*count == 1, serde_json::to_string(snapshot)?));
+        }
+    }
+    kept.reverse();
+
+    let mut size: u64 = kept.iter().map(|(_, line)| line.len() as u64 + 1).sum();
+    let mut contents = String::new();
+    for (newest, line) in kept {
+        if size > COMPACT_TO_BYTES && !newest {
+            size -= line.len() as u64 + 1;
+            continue;
+        }
+        contents.push_str(&line);
+        contents.push('\n');
+    }
+    let tmp = path.with_extension("jsonl.tmp");
+    write(&tmp, contents)?;
+    rename(tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(n: usize) -> AudioInfo {
+        AudioInfo { artist: Some("Artist".to_string()), title: Some(format!("Title {}", n)), ..Default::default() }
+    }
+
+    #[test]
+    fn compacts_past_the_high_water_mark_keeping_each_playlists_newest() {
+        let dir = std::env::temp_dir().join(format!("music-man-history-{}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("playlist_history.jsonl");
+
+        // Recording past the per-playlist cap only appends, until the file grows past MAX_HISTORY_BYTES.
+        record_snapshot_in(&path, "small", &[track(0)], "add").unwrap();
+        let tracks: Vec<AudioInfo> = (0..1000).map(track).collect();
+        let size = || std::fs::metadata(&path).unwrap().len();
+        let mut recorded = 1;
+        loop {
+            let before = size();
+            record_snapshot_in(&path, "big", &tracks, "add").unwrap();
+            recorded += 1;
+            if size() < before {
+                break;
+            }
+        }
+        // The first shrink came only once the file was full, well past the per-playlist cap.
+        assert!(recorded > MAX_SNAPSHOTS_PER_PLAYLIST + 1);
+
+        let after: Vec<PlaylistSnapshot> = load_from(&path);
+        assert!(size() <= COMPACT_TO_BYTES);
+        assert!(after.iter().filter(|s| s.playlist == "big").count() <= MAX_SNAPSHOTS_PER_PLAYLIST);
+        // The only snapshot of a quiet playlist survives, and ids carry on.
+        assert_eq!(after.iter().filter(|s| s.playlist == "small").count(), 1);
+        assert_eq!(after.last().unwrap().id, recorded as u64);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
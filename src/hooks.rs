@@ -0,0 +1,174 @@
+// Hooks -> User commands run around syncs and fetches, e.g. pinging a server after a sync or checking a VPN before
+// fetching, configured in config.toml:
+//
+//   [hooks]
+//   pre_fetch = "vpn-status --quiet"
+//   post_sync = "curl -fsS https://home.example/synced"
+//   on_pre_hook_failure = "abort"
+//   timeout_secs = 60
+//
+// Hooks run with `sh -c` and get what they're about through environment variables:
+//   MUSIC_MAN_HOOK      pre_sync, post_sync, pre_fetch or post_fetch
+//   MUSIC_MAN_DEVICE    device name (syncs)
+//   MUSIC_MAN_PLAYLIST  playlist being synced, or the fetch's target playlist if any
+//   MUSIC_MAN_ARTIST, MUSIC_MAN_TITLE, MUSIC_MAN_URL  track being fetched (fetches)
+//   MUSIC_MAN_OUTCOME   success or failure (post hooks)
+//   MUSIC_MAN_ERROR     why it failed (post hooks, on failure)
+//   MUSIC_MAN_TOTAL, MUSIC_MAN_COPIED, MUSIC_MAN_FAILED  track counts from the sync report (post_sync)
+//   MUSIC_MAN_PATH      where the fetched file went (post_fetch, on success)
+// Their output goes to the log rather than the console. A failing pre hook aborts the sync or fetch, or with
+// on_pre_hook_failure = "warn" only warns. A failing post hook only ever warns.
+
+use std::{process::Stdio, time::Duration};
+
+use crate::{
+    audio::{AudioError, AudioInfo, AudioLocation},
+    cancel::{CancelToken, interrupt_token},
+    process::{run_with_timeout, shell_command},
+    sync::{SyncOutcome, SyncReport},
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HookFailurePolicy {
+    #[default]
+    Abort,
+    Warn,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct HookConfig {
+    pub pre_sync: Option<String>,
+    pub post_sync: Option<String>,
+    pub pre_fetch: Option<String>,
+    pub post_fetch: Option<String>,
+    pub on_pre_hook_failure: HookFailurePolicy,
+    pub timeout_secs: u64,
+}
+
+impl Default for HookConfig {
+    fn default() -> Self {
+        Self {
+            pre_sync: None,
+            post_sync: None,
+            pre_fetch: None,
+            post_fetch: None,
+            on_pre_hook_failure: HookFailurePolicy::default(),
+            timeout_secs: 60,
+        }
+    }
+}
+
+/// Run one hook with its environment, logging its output. Fails if it can't run, exits non-zero, times out, or is
+/// cancelled.
+pub fn run_hook(
+    name: &str,
+    command_line: &str,
+    env: &[(&str, String)],
+    timeout: Duration,
+    cancel: &CancelToken,
+) -> Result<(), AudioError> {
+    tracing::debug!("hook {} running: {}", name, command_line);
+    let mut command = shell_command(command_line);
+    command.env("MUSIC_MAN_HOOK", name).envs(env.iter().map(|(k, v)| (k, v))).stdin(Stdio::null());
+    command.stdout(Stdio::piped()).stderr(Stdio::piped());
+    let output = run_with_timeout(&mut command, &format!("{} hook", name), timeout, cancel)?;
+    for line in output.stdout.lines() {
+        tracing::info!("hook {} stdout: {}", name, line);
+    }
+    for line in output.stderr.lines() {
+        tracing::info!("hook {} stderr: {}", name, line);
+    }
+    if !output.status.success() {
+        return Err(AudioError::ExportFailed(format!("{} hook exited with status: {}", name, output.status)));
+    }
+    Ok(())
+}
+
+impl HookConfig {
+    fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+
+    // A pre hook either lets the operation go ahead, or fails it, depending on the failure policy.
+    fn run_pre(&self, name: &str, command_line: Option<&String>, env: &[(&str, String)]) -> Result<(), AudioError> {
+        let Some(command_line) = command_line else {
+            return Ok(());
+        };
+        match (run_hook(name, command_line, env, self.timeout(), &interrupt_token()), self.on_pre_hook_failure) {
+            (Ok(()), _) => Ok(()),
+            (Err(e), _) if e.is_cancelled() => Err(e),
+            (Err(e), HookFailurePolicy::Abort) => Err(AudioError::Unavailable(e.to_string())),
+            (Err(e), HookFailurePolicy::Warn) => {
+                println!("Warning: {}", e);
+                Ok(())
+            }
+        }
+    }
+
+    // Post hooks still run after a cancelled sync or fetch, so aren't cancelled themselves.
+    fn run_post(&self, name: &str, command_line: Option<&String>, env: &[(&str, String)]) {
+        if let Some(command_line) = command_line
+            && let Err(e) = run_hook(name, command_line, env, self.timeout(), &CancelToken::new())
+        {
+            println!("Warning: {}", e);
+        }
+    }
+
+    pub fn pre_sync(&self, device: &str, playlist: &str) -> Result<(), AudioError> {
+        let env = [("MUSIC_MAN_DEVICE", device.to_string()), ("MUSIC_MAN_PLAYLIST", playlist.to_string())];
+        self.run_pre("pre_sync", self.pre_sync.as_ref(), &env)
+    }
+
+    pub fn post_sync(&self, device: &str, playlist: &str, result: Result<&SyncReport, &AudioError>) {
+        let mut env = vec![("MUSIC_MAN_DEVICE", device.to_string()), ("MUSIC_MAN_PLAYLIST", playlist.to_string())];
+        match result {
+            Ok(report) => {
+                let failed = report.count(&SyncOutcome::Failed(String::new()));
+                env.push(("MUSIC_MAN_OUTCOME", if failed == 0 { "success" } else { "failure" }.to_string()));
+                env.push(("MUSIC_MAN_TOTAL", report.tracks.len().to_string()));
+                env.push(("MUSIC_MAN_COPIED", report.count(&SyncOutcome::Copied).to_string()));
+                env.push(("MUSIC_MAN_FAILED", failed.to_string()));
+            }
+            Err(e) => {
+                env.push(("MUSIC_MAN_OUTCOME", "failure".to_string()));
+                env.push(("MUSIC_MAN_ERROR", e.to_string()));
+            }
+        }
+        self.run_post("post_sync", self.post_sync.as_ref(), &env);
+    }
+
+    fn fetch_env(info: &AudioInfo, playlist: Option<&str>) -> Vec<(&'static str, String)> {
+        [
+            ("MUSIC_MAN_ARTIST", info.artist.clone()),
+            ("MUSIC_MAN_TITLE", info.title.clone()),
+            ("MUSIC_MAN_URL", info.youtube_url.clone()),
+            ("MUSIC_MAN_PLAYLIST", playlist.map(str::to_string)),
+        ]
+        .into_iter()
+        .filter_map(|(key, value)| value.map(|value| (key, value)))
+        .collect()
+    }
+
+    pub fn pre_fetch(&self, info: &AudioInfo, playlist: Option<&str>) -> Result<(), AudioError> {
+        self.run_pre("pre_fetch", self.pre_fetch.as_ref(), &Self::fetch_env(info, playlist))
+    }
+
+    pub fn post_fetch(&self, info: &AudioInfo, playlist: Option<&str>, result: Result<&AudioLocation, &AudioError>) {
+        let mut env = Self::fetch_env(info, playlist);
+        match result {
+            Ok(location) => {
+                env.push(("MUSIC_MAN_OUTCOME", "success".to_string()));
+                if let AudioLocation::LocalPath(path) = location {
+                    env.push(("MUSIC_MAN_PATH", path.display().to_string()));
+                }
+            }
+            Err(e) => {
+                env.push(("MUSIC_MAN_OUTCOME", "failure".to_string()));
+                env.push(("MUSIC_MAN_ERROR", e.to_string()));
+            }
+        }
+        self.run_post("post_fetch", self.post_fetch.as_ref(), &env);
+    }
+}
@@ -0,0 +1,140 @@
+// HTTP downloader, used to materialize AudioLocation::RemoteUrl into a local file before it's imported anywhere.
+// Downloads are done with curl, the same way yt-dlp and ffmpeg are shelled out to.
+
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{Mutex, PoisonError},
+};
+
+use crate::{
+    audio::AudioError,
+    cache::StagingDir,
+};
+
+/// A downloaded file in its own staging directory, which is removed when this is dropped.
+pub struct StagedFile {
+    _dir: StagingDir,
+    path: PathBuf,
+}
+
+impl StagedFile {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Download a URL into a fresh staging directory.
+pub fn stage_remote(url: &str) -> Result<StagedFile, AudioError> {
+    let dir = StagingDir::new("remote")?;
+    let path = dir.path().join(filename_from_url(url));
+    // Construct the guard before downloading, so a failed download cleans up after itself too.
+    let staged = StagedFile { _dir: dir, path };
+    download(url, &staged.path)?;
+    Ok(staged)
+}
+
+static PROXY: Mutex<Option<String>> = Mutex::new(None);
+
+/// Route every HTTP download through a proxy, any curl understands, e.g. "socks5://127.0.0.1:1080".
+pub fn set_proxy(proxy: Option<String>) {
+    *PROXY.lock().unwrap_or_else(PoisonError::into_inner) = proxy;
+}
+
+static LIMIT_RATE: Mutex<Option<u64>> = Mutex::new(None);
+
+/// Cap every HTTP download at a number of bytes per second. API calls, like MusicBrainz lookups, aren't capped, their
+/// responses are small and a rate limit only makes their own rate limits more likely to be hit.
+pub fn set_limit_rate(limit: Option<u64>) {
+    *LIMIT_RATE.lock().unwrap_or_else(PoisonError::into_inner) = limit;
+}
+
+// Identifies us to APIs that ask clients to, like MusicBrainz.
+const USER_AGENT: &str = concat!("music-man/", env!("CARGO_PKG_VERSION"), " ( https://github.com/AashrayAnand/music-man )");
+
+fn curl() -> Command {
+    let mut command = Command::new("curl");
+    command.args(["--fail", "--silent", "--show-error", "--location", "--user-agent", USER_AGENT]);
+    if let Some(proxy) = PROXY.lock().unwrap_or_else(PoisonError::into_inner).as_ref() {
+        command.args(["--proxy", proxy]);
+    }
+    command
+}
+
+/// Download a URL to a local path, failing on HTTP errors rather than saving the error page.
+pub fn download(url: &str, dest: &Path) -> Result<u64, AudioError> {
+    let mut command = curl();
+    if let Some(limit) = *LIMIT_RATE.lock().unwrap_or_else(PoisonError::into_inner) {
+        command.arg("--limit-rate").arg(limit.to_string());
+    }
+    let output = command
+        .arg("--output")
+        .arg(dest)
+        .arg(url)
+        .output()
+        .map_err(|e| AudioError::DownloadFailed {
+            url: url.to_string(),
+            reason: e.to_string(),
+        })?;
+    if !output.status.success() {
+        return Err(AudioError::DownloadFailed {
+            url: url.to_string(),
+            reason: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        });
+    }
+    Ok(std::fs::metadata(dest)?.len())
+}
+
+/// GET a URL's body, e.g. from a JSON API, with the query parameters URL-encoded.
+pub fn get_text(url: &str, params: &[(&str, &str)]) -> Result<String, AudioError> {
+    get_text_with_headers(url, params, &[])
+}
+
+/// get_text, also sending headers, e.g. "Authorization: Bearer <token>".
+pub fn get_text_with_headers(url: &str, params: &[(&str, &str)], headers: &[String]) -> Result<String, AudioError> {
+    let mut command = curl();
+    command.arg("--get");
+    for header in headers {
+        command.arg("--header").arg(header);
+    }
+    request(command, url, params)
+}
+
+/// POST URL-encoded form fields and return the response body, with basic auth when user is "<name>:<password>".
+pub fn post_form(url: &str, params: &[(&str, &str)], user: Option<&str>) -> Result<String, AudioError> {
+    let mut command = curl();
+    if let Some(user) = user {
+        command.arg("--user").arg(user);
+    }
+    request(command, url, params)
+}
+
+fn request(mut command: Command, url: &str, params: &[(&str, &str)]) -> Result<String, AudioError> {
+    for (name, value) in params {
+        command.arg("--data-urlencode").arg(format!("{}={}", name, value));
+    }
+    let failed = |reason: String| AudioError::DownloadFailed { url: url.to_string(), reason };
+    let output = command.arg(url).output().map_err(|e| failed(e.to_string()))?;
+    if !output.status.success() {
+        return Err(failed(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+// Name the staged file after the last URL path segment, which for stream URLs is usually the track file.
+fn filename_from_url(url: &str) -> String {
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+    let segment = path.rsplit('/').next().unwrap_or_default();
+    let name: String = segment
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' | '%' => '_',
+            _ => c,
+        })
+        .collect();
+    if name.is_empty() || name.starts_with('.') {
+        "download".to_string()
+    } else {
+        name
+    }
+}
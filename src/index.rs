@@ -1,14 +1,31 @@
-use crate::audio::{AudioError, Playlist, PlaylistName, list_audio_in_folder};
+use std::{borrow::Cow, fs::read_to_string, path::Path, sync::Arc};
+
+use crate::audio::{AudioError, AudioInfo, Playlist, PlaylistName, is_audio_file_in, list_audio_in_folder};
 use crate::device::AttachedDevice;
+use crate::layout::M3U_EXTENSION;
+use crate::sort;
 
 // TRAIT: AudioIndex, e.g. an attached mp3 device, a streaming platform, etc.
 // AudioIndex impls are able to specify an index of AudioInfo. They may not necessarily be AudioSource or AudioTarget that we can read/write,
 // but they at least provide an index of information about audio e.g. Spotify can provide an index of the user's spotify playlists and music.
 // 1. list -> returns a corresponding AudioCollection describing the music on the device.
-// 2.
+// 2. list_playlist_names / get_playlist -> cheaper lookups for callers that only need names or a single playlist. The
+//    defaults go through list_playlists, large indexes should implement them natively.
 pub trait AudioIndex {
     fn name(&self) -> &str;
     fn list_playlists(&self) -> Result<Vec<Playlist>, AudioError>;
+
+    fn list_playlist_names(&self) -> Result<Vec<PlaylistName>, AudioError> {
+        Ok(self.list_playlists()?.into_iter().map(|playlist| playlist.name).collect())
+    }
+
+    fn get_playlist(&self, name: &PlaylistName) -> Result<Cow<'_, [AudioInfo]>, AudioError> {
+        self.list_playlists()?
+            .into_iter()
+            .find(|playlist| &playlist.name == name)
+            .map(|playlist| Cow::Owned(Arc::unwrap_or_clone(playlist.audio)))
+            .ok_or(AudioError::NotFound)
+    }
 }
 
 impl AudioIndex for AttachedDevice {
@@ -17,39 +34,86 @@ impl AudioIndex for AttachedDevice {
     }
 
     fn list_playlists(&self) -> Result<Vec<Playlist>, AudioError> {
-        // Iterate device directories, list out all directories
-        let mut playlists = Vec::new();
+        self.list_playlist_names()?
+            .into_iter()
+            .map(|name| {
+                let audio = Arc::new(self.get_playlist(&name)?.into_owned());
+                Ok(Playlist { name, audio })
+            })
+            .collect()
+    }
 
-        // Add a playlist entry per-directory, and an uncategorized playlist for all root files.
-        let playlist_directories = std::fs::read_dir(&self.path).unwrap().filter_map(|entry| {
-            let entry = entry.as_ref().unwrap();
+    fn list_playlist_names(&self) -> Result<Vec<PlaylistName>, AudioError> {
+        let mut names = Vec::new();
+        let mut has_root_audio = false;
+
+        // Without playlist directories, playlists are the M3U files in the root.
+        if !self.profile.layout.has_playlist_dirs() {
+            for entry in std::fs::read_dir(&self.path)? {
+                let path = entry?.path();
+                if path.is_file()
+                    && path.extension().is_some_and(|ext| ext == M3U_EXTENSION)
+                    && let Some(stem) = path.file_stem()
+                {
+                    names.push(PlaylistName::Named(stem.to_string_lossy().to_string()));
+                }
+            }
+            names.sort_by(sort::playlist_name_cmp);
+            return Ok(names);
+        }
+
+        // A playlist per-directory, and an uncategorized playlist for all root files.
+        let extensions = self.profile.extensions();
+        for entry in std::fs::read_dir(&self.path)? {
+            let entry = entry?;
             let file_name = entry.file_name().to_string_lossy().to_string();
-            if entry.file_type().unwrap().is_dir()
-                && !file_name.starts_with('.')
-                && file_name != "System Volume Information"
-            {
-                return Some((entry.path(), file_name));
+            if entry.file_type()?.is_dir() {
+                if !file_name.starts_with('.')
+                    && file_name != "System Volume Information"
+                    && !self.profile.is_mapped_destination(&file_name)
+                {
+                    names.push(PlaylistName::Named(file_name));
+                }
+            } else if is_audio_file_in(&entry, &extensions) {
+                has_root_audio = true;
             }
-            None
-        });
-
-        for (directory, dirname) in playlist_directories {
-            let audio = list_audio_in_folder(&directory)?;
-            playlists.push(Playlist {
-                name: PlaylistName::Named(dirname),
-                audio,
-            });
         }
 
-        if let Ok(root_playlist) = list_audio_in_folder(&self.path) {
-            if !root_playlist.is_empty() {
-                playlists.push(Playlist {
-                    name: PlaylistName::Uncategorized,
-                    audio: root_playlist,
-                });
+        // Playlists mapped elsewhere on the device are listed under their playlist name.
+        for playlist in self.profile.destinations.keys() {
+            let name = PlaylistName::Named(playlist.clone());
+            if self.playlist_dir(&name).is_dir() {
+                names.push(name);
             }
         }
 
-        Ok(playlists)
+        if has_root_audio {
+            names.push(PlaylistName::Uncategorized);
+        }
+        names.sort_by(sort::playlist_name_cmp);
+        Ok(names)
+    }
+
+    fn get_playlist(&self, name: &PlaylistName) -> Result<Cow<'_, [AudioInfo]>, AudioError> {
+        if !self.profile.layout.has_playlist_dirs() {
+            let PlaylistName::Named(name) = name else {
+                return Err(AudioError::NotFound);
+            };
+            let m3u = read_to_string(self.m3u_path(name)).map_err(|_| AudioError::NotFound)?;
+            // Entries whose file has since gone are left out, like a directory that no longer has them.
+            let layout = self.profile.layout;
+            return Ok(Cow::Owned(
+                m3u.lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#') && self.path.join(line).is_file())
+                    .map(|line| layout.info_from_path(Path::new(line)))
+                    .collect(),
+            ));
+        }
+        let directory = self.playlist_dir(name);
+        if !directory.is_dir() {
+            return Err(AudioError::NotFound);
+        }
+        Ok(Cow::Owned(list_audio_in_folder(&directory, &self.profile.extensions())?))
     }
 }
@@ -0,0 +1,124 @@
+// Junk -> Files macOS and other systems leave on a device that players show as unplayable tracks: AppleDouble "._"
+// files, Finder's .DS_Store, and the Spotlight and fsevents directories. Only names matching a known junk pattern are
+// ever removed, and never anything in music-man's own manifest area, which holds the device trash.
+
+use std::{
+    fs::{read_dir, remove_dir_all, remove_file, symlink_metadata},
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::{fsutil::format_size, manifest::manifest_dir};
+
+/// Name patterns removed by `device clean-junk` unless the device profile lists its own. A "*" matches any run of
+/// characters, anything else must match exactly.
+pub const DEFAULT_JUNK_PATTERNS: [&str; 4] = ["._*", ".DS_Store", ".Spotlight-V100", ".fseventsd"];
+
+#[derive(Debug, Default, serde::Serialize)]
+pub struct JunkReport {
+    // Removed, or with dry_run what would be, and the bytes each took.
+    pub removed: Vec<(PathBuf, u64)>,
+    // Junk that couldn't be removed, e.g. a file the OS still has open, with why.
+    pub failed: Vec<(PathBuf, String)>,
+}
+
+impl JunkReport {
+    pub fn bytes(&self) -> u64 {
+        self.removed.iter().map(|(_, bytes)| bytes).sum()
+    }
+
+    /// A dry run lists what it found, a real clean only the total and what it couldn't remove.
+    pub fn print(&self, dry_run: bool) {
+        if dry_run {
+            for (path, bytes) in &self.removed {
+                println!("{} ({})", path.display(), format_size(*bytes));
+            }
+        }
+        for (path, e) in &self.failed {
+            println!("Couldn't remove {}: {}", path.display(), e);
+        }
+        println!(
+            "{} {} junk files, {}",
+            if dry_run { "Would remove" } else { "Removed" },
+            self.removed.len(),
+            format_size(self.bytes())
+        );
+    }
+}
+
+/// Remove junk from a device, everywhere under its root. Junk directories are removed whole. A file that can't be
+/// removed is recorded and skipped, the rest are still cleaned.
+pub fn clean_junk(device_root: &Path, patterns: &[String], dry_run: bool) -> io::Result<JunkReport> {
+    let mut report = JunkReport::default();
+    clean_dir(device_root, &manifest_dir(device_root), patterns, dry_run, &mut report)?;
+    report.removed.sort();
+    Ok(report)
+}
+
+fn clean_dir(dir: &Path, skip: &Path, patterns: &[String], dry_run: bool, report: &mut JunkReport) -> io::Result<()> {
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if path == skip {
+            continue;
+        }
+        // Never follows symlinks, a link to somewhere else on the system is only ever removed itself.
+        let file_type = entry.file_type()?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if patterns.iter().any(|pattern| matches(pattern, &name)) {
+            let bytes = size_of(&path);
+            let removed = match (dry_run, file_type.is_dir()) {
+                (true, _) => Ok(()),
+                (false, true) => remove_dir_all(&path),
+                (false, false) => remove_file(&path),
+            };
+            match removed {
+                Ok(()) => report.removed.push((path, bytes)),
+                Err(e) => report.failed.push((path, e.to_string())),
+            }
+        } else if file_type.is_dir()
+            && let Err(e) = clean_dir(&path, skip, patterns, dry_run, report)
+        {
+            // An unreadable directory, e.g. one the OS protects, doesn't stop the rest being cleaned.
+            report.failed.push((path, e.to_string()));
+        }
+    }
+    Ok(())
+}
+
+// Bytes a file, or everything under a directory, takes up.
+fn size_of(path: &Path) -> u64 {
+    match symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => read_dir(path)
+            .map(|entries| entries.filter_map(|e| e.ok()).map(|e| size_of(&e.path())).sum())
+            .unwrap_or_default(),
+        Ok(meta) => meta.len(),
+        Err(_) => 0,
+    }
+}
+
+// Whether a name matches a pattern, where "*" matches any run of characters.
+fn matches(pattern: &str, name: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == name,
+        Some((prefix, rest)) => {
+            let Some(mut remaining) = name.strip_prefix(prefix) else {
+                return false;
+            };
+            if rest.is_empty() {
+                return true;
+            }
+            // Try every place the rest of the pattern could start.
+            loop {
+                if matches(rest, remaining) {
+                    return true;
+                }
+                let mut chars = remaining.chars();
+                if chars.next().is_none() {
+                    return false;
+                }
+                remaining = chars.as_str();
+            }
+        }
+    }
+}
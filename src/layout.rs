@@ -0,0 +1,200 @@
+// DeviceLayout -> How tracks are arranged in a device's directories. Playlist directories are how devices have always
+// been synced, and suit players that browse by folder as a stand-in for playlists. Players that index by folder
+// structure want Artist/Album instead, and then playlists are M3U files in the device root, since directories no
+// longer say what's in which playlist.
+
+use std::path::{Path, PathBuf};
+
+use crate::{
+    audio::{AudioInfo, AudioKey},
+    naming::{DestNaming, FilenameRules, sanitize},
+};
+
+pub const UNKNOWN_ARTIST: &str = "Unknown Artist";
+pub const UNKNOWN_ALBUM: &str = "Unknown Album";
+pub const VARIOUS_ARTISTS: &str = "Various Artists";
+pub const M3U_EXTENSION: &str = "m3u8";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DeviceLayout {
+    // Every track in the device root.
+    Flat,
+    // A directory per playlist, uncategorized audio in the root.
+    #[default]
+    PlaylistDirs,
+    // Artist/Album/NN - Title.ext, from the track's primary artist, album and track number. Compilations go under
+    // Various Artists/Album/NN - Artist - Title.ext instead, so each track keeps its own artist.
+    ArtistAlbum,
+}
+
+impl DeviceLayout {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "flat" => Some(DeviceLayout::Flat),
+            "playlist-dirs" => Some(DeviceLayout::PlaylistDirs),
+            "artist-album" => Some(DeviceLayout::ArtistAlbum),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            DeviceLayout::Flat => "flat",
+            DeviceLayout::PlaylistDirs => "playlist-dirs",
+            DeviceLayout::ArtistAlbum => "artist-album",
+        }
+    }
+
+    /// Whether playlists are directories, otherwise they're M3U files.
+    pub fn has_playlist_dirs(self) -> bool {
+        self == DeviceLayout::PlaylistDirs
+    }
+
+    /// Device relative directory a track goes in, None when that's its playlist's directory.
+    pub fn track_dir(self, info: &AudioInfo) -> Option<PathBuf> {
+        match self {
+            DeviceLayout::Flat => Some(PathBuf::new()),
+            DeviceLayout::PlaylistDirs => None,
+            DeviceLayout::ArtistAlbum => {
+                let (artist, album) = artist_album(info);
+                Some(Path::new(&artist).join(album))
+            }
+        }
+    }
+
+    /// How a track is named in this layout. Artist/Album files are named after the title, or artist and title for
+    /// compilations, with the track number first when known. Other layouts keep the given naming.
+    pub fn naming(self, info: &AudioInfo, naming: &DestNaming) -> DestNaming {
+        if self != DeviceLayout::ArtistAlbum {
+            return naming.clone();
+        }
+        let base = if info.compilation { DestNaming::FromInfo } else { DestNaming::Title };
+        match info.track_number {
+            Some(number) => DestNaming::Prefixed { prefix: format!("{:02} - ", number), base: Box::new(base) },
+            None => base,
+        }
+    }
+
+    /// The audio a device file stands for, from its device relative path. Artist/Album files only name the title, the
+    /// artist and album come from their directories, except for compilations which name their artist like any file.
+    pub fn info_from_path(self, rel_path: &Path) -> AudioInfo {
+        let Some(filename) = rel_path.file_name() else {
+            return AudioInfo::default();
+        };
+        let mut info = AudioInfo::from_filename(filename);
+        let mut dirs = rel_path.parent().into_iter().flat_map(Path::iter).map(|dir| dir.to_string_lossy().to_string());
+        if let (DeviceLayout::ArtistAlbum, Some(artist), Some(album)) = (self, dirs.next(), dirs.next()) {
+            if artist == VARIOUS_ARTISTS {
+                info.album = Some(album);
+                info.compilation = true;
+                return info;
+            }
+            let stem = crate::audio::nfc(&Path::new(filename).file_stem().unwrap_or_default().to_string_lossy());
+            let (track_number, title) = match stem.split_once(" - ") {
+                Some((number, title)) if number.chars().all(|c| c.is_ascii_digit()) => (number.parse().ok(), title),
+                _ => (None, stem.as_str()),
+            };
+            info.artists = vec![artist.clone()];
+            info.artist = Some(artist);
+            info.title = Some(title.to_string());
+            info.album = (album != UNKNOWN_ALBUM).then_some(album);
+            info.track_number = track_number;
+        }
+        info
+    }
+
+    /// How audio is keyed on a device with this layout. Artist/Album keys come from directory and file names, so
+    /// they're keyed on the names as written, after sanitizing for FAT. Compilation tracks key on their own artist,
+    /// never Various Artists, so songs sharing a title on different compilations stay apart.
+    pub fn key(self, info: &AudioInfo) -> Option<AudioKey> {
+        match self {
+            DeviceLayout::ArtistAlbum if info.compilation => {
+                let fat = |name: &str| sanitize(name, FilenameRules::Fat);
+                AudioKey::from_info(&AudioInfo {
+                    artist: info.artist.as_deref().map(fat),
+                    artists: info.artists.iter().map(|artist| fat(artist)).collect(),
+                    title: Some(fat(info.title.as_deref()?)),
+                    ..Default::default()
+                })
+            }
+            DeviceLayout::ArtistAlbum => {
+                let (artist, _) = artist_album(info);
+                let title = sanitize(info.title.as_deref()?, FilenameRules::Fat);
+                AudioKey::from_info(&AudioInfo {
+                    artist: Some(artist),
+                    title: Some(title),
+                    ..Default::default()
+                })
+            }
+            _ => AudioKey::from_info(info),
+        }
+    }
+}
+
+// The artist and album directory names of a track.
+fn artist_album(info: &AudioInfo) -> (String, String) {
+    let dir_name = |name: Option<String>, unknown: &str| {
+        let name = sanitize(name.as_deref().unwrap_or_default().trim(), FilenameRules::Fat);
+        // FAT drops trailing dots and spaces, which would leave the directory unfindable by its name.
+        let name = name.trim_end_matches(['.', ' ']);
+        if name.is_empty() { unknown.to_string() } else { name.to_string() }
+    };
+    let artist = if info.compilation { VARIOUS_ARTISTS.to_string() } else { dir_name(info.primary_artist(), UNKNOWN_ARTIST) };
+    (artist, dir_name(info.album.clone(), UNKNOWN_ALBUM))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(artist: &str, title: &str, album: &str, number: u32, compilation: bool) -> AudioInfo {
+        AudioInfo {
+            artist: Some(artist.to_string()),
+            title: Some(title.to_string()),
+            album: Some(album.to_string()),
+            track_number: Some(number),
+            compilation,
+            ..Default::default()
+        }
+    }
+
+    // Where the layout puts a track, relative to the device root.
+    fn placed(info: &AudioInfo) -> PathBuf {
+        let layout = DeviceLayout::ArtistAlbum;
+        let naming = layout.naming(info, &DestNaming::KeepSource);
+        let name = naming.file_name(info, Path::new("source.mp3"), FilenameRules::Fat).unwrap();
+        layout.track_dir(info).unwrap().join(name)
+    }
+
+    #[test]
+    fn compilations_go_under_various_artists() {
+        let info = track("Lou Reed", "Perfect Day", "Trainspotting", 8, true);
+        let path = placed(&info);
+        assert_eq!(path, Path::new("Various Artists/Trainspotting/08 - Lou Reed - Perfect Day.mp3"));
+
+        let parsed = DeviceLayout::ArtistAlbum.info_from_path(&path);
+        assert_eq!(parsed.artist.as_deref(), Some("Lou Reed"));
+        assert_eq!(parsed.title.as_deref(), Some("Perfect Day"));
+        assert_eq!(parsed.album.as_deref(), Some("Trainspotting"));
+        assert_eq!(parsed.track_number, Some(8));
+        assert!(parsed.compilation);
+        assert_eq!(DeviceLayout::ArtistAlbum.key(&parsed), DeviceLayout::ArtistAlbum.key(&info));
+
+        let album = track("Lou Reed", "Perfect Day", "Transformer", 3, false);
+        assert_eq!(placed(&album), Path::new("Lou Reed/Transformer/03 - Perfect Day.mp3"));
+    }
+
+    #[test]
+    fn compilation_tracks_key_on_their_own_artist() {
+        let layout = DeviceLayout::ArtistAlbum;
+        // Two different songs called Intro, on different compilations.
+        let first = track("The xx", "Intro", "Mixmag Presents", 1, true);
+        let second = track("M83", "Intro", "Indie Anthems", 1, true);
+        assert_ne!(layout.key(&first), layout.key(&second));
+        // The same song on a compilation and its own album is still one track.
+        let compiled = track("Iggy Pop", "Lust for Life", "Trainspotting", 1, true);
+        let original = track("Iggy Pop", "Lust for Life", "Lust for Life", 1, false);
+        assert_eq!(layout.key(&compiled), layout.key(&original));
+    }
+}
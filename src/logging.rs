@@ -0,0 +1,118 @@
+// Logging -> Everything traced at debug level goes to a daily rolling file under data_dir/logs, named
+// music-man.<date>.log, keeping the last LOG_FILES_KEPT days. This is independent of what's printed to the console,
+// so a failed unattended sync can be looked into afterwards with `logs`.
+
+use std::{
+    fs::{File, create_dir_all, read_dir},
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom},
+    path::PathBuf,
+    sync::Mutex,
+    thread::sleep,
+    time::Duration,
+};
+
+use tracing::Level;
+use tracing_appender::{
+    non_blocking::WorkerGuard,
+    rolling::{RollingFileAppender, Rotation},
+};
+
+use crate::cache::get_data_dir;
+
+const LOG_PREFIX: &str = "music-man";
+const LOG_SUFFIX: &str = "log";
+const LOG_FILES_KEPT: usize = 7;
+
+pub fn logs_dir() -> PathBuf {
+    get_data_dir().join("logs")
+}
+
+// Flushes lines still queued for the writer thread when dropped.
+static GUARD: Mutex<Option<WorkerGuard>> = Mutex::new(None);
+
+/// Start writing the log file from a background thread, so logging never blocks on disk. Logging is best effort, a
+/// missing or unwritable log directory only prints a warning.
+pub fn init() {
+    let dir = logs_dir();
+    let appender = create_dir_all(&dir).map_err(|e| e.to_string()).and_then(|_| {
+        RollingFileAppender::builder()
+            .rotation(Rotation::DAILY)
+            .filename_prefix(LOG_PREFIX)
+            .filename_suffix(LOG_SUFFIX)
+            .max_log_files(LOG_FILES_KEPT)
+            .build(&dir)
+            .map_err(|e| e.to_string())
+    });
+    let appender = match appender {
+        Ok(appender) => appender,
+        Err(e) => {
+            eprintln!("Logging disabled, can't write to {}: {}", dir.display(), e);
+            return;
+        }
+    };
+
+    let (writer, guard) = tracing_appender::non_blocking(appender);
+    tracing_subscriber::fmt()
+        .with_writer(writer)
+        .with_ansi(false)
+        .with_max_level(Level::DEBUG)
+        .init();
+    *GUARD.lock().unwrap() = Some(guard);
+}
+
+/// Write out everything logged so far, before exiting.
+pub fn flush() {
+    GUARD.lock().unwrap().take();
+}
+
+// The log file currently being written, the dated names sort chronologically.
+fn current_log() -> Option<PathBuf> {
+    let mut logs: Vec<PathBuf> = read_dir(logs_dir())
+        .ok()?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with(LOG_PREFIX) && name.ends_with(LOG_SUFFIX))
+        })
+        .collect();
+    logs.sort();
+    logs.pop()
+}
+
+/// Print the last `tail` lines of the current log, then with follow keep printing new lines until interrupted.
+pub fn print_logs(tail: usize, follow: bool) -> io::Result<()> {
+    let Some(path) = current_log() else {
+        println!("No logs yet in {}", logs_dir().display());
+        return Ok(());
+    };
+    let mut file = File::open(&path)?;
+    let lines: Vec<String> = BufReader::new(&mut file).lines().collect::<Result<_, _>>()?;
+    for line in &lines[lines.len().saturating_sub(tail)..] {
+        println!("{}", line);
+    }
+    if !follow {
+        return Ok(());
+    }
+
+    let mut path = path;
+    let mut offset = file.seek(SeekFrom::End(0))?;
+    loop {
+        sleep(Duration::from_millis(500));
+        // Rotation at midnight moves logging on to a new file.
+        if let Some(newest) = current_log()
+            && newest != path
+        {
+            path = newest;
+            offset = 0;
+        }
+        let mut file = File::open(&path)?;
+        if file.metadata()?.len() <= offset {
+            continue;
+        }
+        file.seek(SeekFrom::Start(offset))?;
+        let mut new = String::new();
+        offset += file.read_to_string(&mut new)? as u64;
+        print!("{}", new);
+    }
+}
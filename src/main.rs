@@ -1,27 +1,362 @@
+pub mod activity;
+pub mod cancel;
 pub mod cache;
 pub mod audio;
+pub mod blocklist;
+pub mod bundle;
+pub mod checksums;
+pub mod command_source;
+pub mod config;
+pub mod cue;
 pub mod device;
+pub mod device_index;
+pub mod doctor;
+pub mod download_queue;
+pub mod events;
+pub mod exit;
+pub mod export;
+pub mod file_index;
+pub mod fsutil;
+pub mod fuzzy;
+pub mod history;
+pub mod hooks;
+pub mod http;
 pub mod index;
+pub mod junk;
+pub mod layout;
+pub mod logging;
+pub mod manifest;
+pub mod musicbrainz;
+pub mod naming;
+pub mod playlist_store;
+pub mod preview;
+pub mod probe;
+pub mod process;
+pub mod profile;
+pub mod remote;
+pub mod report;
+pub mod sidecar;
+pub mod sort;
 pub mod source;
+pub mod sync;
+pub mod table;
 pub mod target;
+pub mod ytdlp;
 
-use std::{io::stdin, path::PathBuf};
+use std::{collections::HashSet, io::{IsTerminal, Write, stdin}, path::{Path, PathBuf}, time::Instant};
 
 use crate::{
-    cache::{get_cache_dir, audio_cache_dir, setup_app_directories, LocalCache},
-    audio::{AudioError, AudioInfo, PlaylistName},
+    activity::{
+        Activity, FetchRecord, find_activity, format_timestamp, last_undoable, load_activity, parse_age, record_activity,
+    },
+    cancel::{CancelToken, install_interrupt_handler, interrupt_token},
+    bundle::StateBundle,
+    cache::{audio_cache_dir, setup_app_directories, unix_now, CacheLock, LocalCache, StagingDir, STARRED_PLAYLIST},
+    audio::{AudioError, AudioInfo, AudioKey, AudioLocation, PlaylistName, audio_extensions, normalize_extensions, set_audio_extensions},
+    checksums::{repair_device, verify_device},
+    config::{Config, PlaylistStorage},
+    cue::CueSheet,
     device::AttachedDevice,
+    doctor::{CheckStatus, print_checks, run_checks},
+    download_queue::{DownloadQueue, QueuedDownload},
+    events::{ProgressReporter, reporter_from_args},
+    exit::ExitStatus,
+    export::{XspfLocation, export_xspf},
+    fsutil::{format_size, parse_size},
+    history::{SnapshotDiff, find_snapshot, snapshots},
     index::AudioIndex,
-    source::{AudioSource, YtDlpSource},
+    layout::DeviceLayout,
+    logging::print_logs,
+    manifest::DeviceManifest,
+    musicbrainz::MbArtist,
+    naming::DestNaming,
+    preview::{DEFAULT_PLAYER, PREVIEW_CANDIDATES, choose_by_preview},
+    probe::ProbeCache,
+    profile::{MAX_COPY_BUFFER, parse_copy_buffer},
+    report::{ArtistTracksReport, ArtistsReport, DEFAULT_MIN_KBPS, DupesReport, IndexKind, MissingReport, QualityReport, WhereReport},
+    sidecar::Sidecar,
+    source::{AudioSource, FetchResult, NetworkOptions, SourceChain},
+    sync::{CollisionPolicy, ConflictChoice, SyncOutcome, apply_shuffle_order, prompt_on_stdin, sync_playlist},
+    table::{OutputOptions, Table, format_duration},
     target::AudioTarget,
 };
 
+// Ask a yes/no question on the terminal, anything but yes (or closed input) is a no.
+fn confirm(question: &str) -> bool {
+    print!("{} [y/N] ", question);
+    std::io::stdout().flush().ok();
+    let mut answer = String::new();
+    stdin().read_line(&mut answer).unwrap_or(0) > 0 && matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+// Parse "<artist> - <title>" from REPL args, also accepting the plain "<artist> <title>" two argument form.
+fn parse_artist_title(args: &[&str]) -> Option<AudioInfo> {
+    let joined = args.join(" ");
+    let (artist, title) = match joined.split_once(" - ") {
+        Some((artist, title)) => (artist.trim().to_string(), title.trim().to_string()),
+        None if args.len() == 2 => (args[0].to_string(), args[1].to_string()),
+        None => return None,
+    };
+    if artist.is_empty() || title.is_empty() {
+        return None;
+    }
+    Some(AudioInfo {
+        artist: Some(artist),
+        title: Some(title),
+        ..Default::default()
+    })
+}
+
+// Parse a possibly partial track query: "<artist> - <title>", "--artist <artist>", or otherwise a bare title.
+fn parse_partial_query(args: &[&str]) -> Option<AudioInfo> {
+    let joined = args.join(" ");
+    let info = if let Some(artist) = joined.strip_prefix("--artist") {
+        AudioInfo {
+            artist: Some(artist.trim().to_string()),
+            ..Default::default()
+        }
+    } else if let Some((artist, title)) = joined.split_once(" - ") {
+        AudioInfo {
+            artist: Some(artist.trim().to_string()),
+            title: Some(title.trim().to_string()),
+            ..Default::default()
+        }
+    } else {
+        AudioInfo {
+            title: Some(joined.trim().to_string()),
+            ..Default::default()
+        }
+    };
+    let empty = |field: &Option<String>| field.as_ref().is_some_and(|f| f.is_empty());
+    if empty(&info.artist) || empty(&info.title) {
+        return None;
+    }
+    Some(info)
+}
+
+// Download audio from the first source that has it into the cache, adding it to playlist if given.
+// `only` fetches from that one source, e.g. yt-dlp for a URL picked by previewing, rather than trying each in turn.
+fn download_to_cache(
+    info: &AudioInfo,
+    playlist: Option<&str>,
+    config: &Config,
+    sources: &SourceChain,
+    only: Option<&dyn AudioSource>,
+    cache: &mut LocalCache,
+    reporter: &mut dyn ProgressReporter,
+) -> ExitStatus {
+    if let Err(e) = config.hooks.pre_fetch(info, playlist) {
+        println!("Download aborted: {}", e);
+        return ExitStatus::from_error(&e);
+    }
+    // Said up front, so a slow download isn't mistaken for a stalled one.
+    if let Some(limit) = sources.ytdlp.network.limit_rate {
+        println!("Downloads limited to {}/s", format_size(limit));
+    }
+    // Try each source in priority order until one has it. Each fetch goes to its own staging directory and is only moved
+    // into the cache once it's verified, a bad file falls through to the next source. The staging directory lives until
+    // the end of the download, kept originals are moved out of it below.
+    let mut fetched = Err(AudioError::NotFound);
+    let mut staging = None;
+    let from = match only {
+        Some(source) => vec![source],
+        None => sources.ordered(),
+    };
+    for source in from {
+        let started = Instant::now();
+        fetched = cache
+            .download_dir()
+            .and_then(|_| {
+                let staging = staging.insert(StagingDir::new("download")?);
+                source.fetch_with_progress(info, staging.path().to_path_buf(), &DestNaming::FromInfo, reporter)
+            })
+            .and_then(|result| cache.commit_staged(result));
+        record_activity(Activity::Fetch(FetchRecord::new(info, source.name(), fetched.as_ref(), started)));
+        match &fetched {
+            Ok(_) => break,
+            Err(e) if e.is_cancelled() => break,
+            Err(e) => tracing::info!("source {} couldn't fetch {:?}: {}", source.name(), info, e),
+        }
+    }
+    config.hooks.post_fetch(
+        fetched.as_ref().map_or(info, |result| &result.info),
+        playlist,
+        fetched.as_ref().map(|result| &result.location),
+    );
+    match fetched {
+        Ok(result) => {
+            let info = result.info;
+            let location = match cache.add_to_cache(&info, &result.location, playlist) {
+                Ok(cached) => cached,
+                Err(e) => {
+                    println!("Failed to add {:?} to the cache: {}", result.location, e);
+                    result.location
+                }
+            };
+            if let (AudioLocation::LocalPath(path), Some(metadata)) = (&location, result.metadata) {
+                let original = metadata.original_filepath.as_ref().and_then(|original| {
+                    cache
+                        .store_original(path, original)
+                        .inspect_err(|e| println!("Failed to keep original {:?}: {}", original, e))
+                        .ok()
+                });
+                let sidecar = Sidecar {
+                    source: Some(result.source_name),
+                    source_url: result.url,
+                    source_title: metadata.title,
+                    uploader: metadata.uploader,
+                    upload_date: metadata.upload_date,
+                    duration_secs: metadata.duration_secs,
+                    fetched_at: Some(unix_now()),
+                    original,
+                    // A pending star was applied as the audio was added.
+                    starred: cache.is_starred(path),
+                };
+                if let Err(e) = cache.save_sidecar(path, &sidecar) {
+                    println!("Failed to write metadata for {:?}: {}", path, e);
+                }
+            }
+            println!("Downloaded to cache: {:?}", location);
+            if let Some(p) = playlist {
+                println!("Added to playlist: {}", p);
+            }
+            ExitStatus::Success
+        }
+        Err(e) => {
+            println!("Download failed: {}", e);
+            ExitStatus::from_error(&e)
+        }
+    }
+}
+
+// Download everything queued, in order, saving the queue after each track so a cancel or a crash loses nothing. Tracks
+// cached some other way since they were queued are dropped, failed ones stay queued for the next `queue run`.
+fn run_download_queue(
+    queue: &mut DownloadQueue,
+    config: &Config,
+    sources: &SourceChain,
+    cache: &mut LocalCache,
+    reporter: &mut dyn ProgressReporter,
+    interrupt: &CancelToken,
+) -> ExitStatus {
+    let pending = queue.entries().to_vec();
+    let (mut status, mut downloaded) = (ExitStatus::Success, 0);
+    for queued in &pending {
+        if interrupt.is_cancelled() {
+            status = status.worst(ExitStatus::Cancelled);
+            break;
+        }
+        if cache.search(&queued.info).is_err() {
+            let fetched = download_to_cache(&queued.info, queued.playlist.as_deref(), config, sources, None, cache, reporter);
+            status = status.worst(fetched);
+            if fetched != ExitStatus::Success {
+                continue;
+            }
+            downloaded += 1;
+        }
+        queue.remove(&queued.info);
+        if let Err(e) = queue.save() {
+            println!("Failed to save the download queue: {}", e);
+        }
+    }
+    println!("Downloaded {} of {} queued tracks, {} left in the queue", downloaded, pending.len(), queue.entries().len());
+    status
+}
+
+// Sync a playlist to one device, with its hooks, printing and recording the report. Returns the summary line too.
+fn sync_to_device(
+    cache: &LocalCache,
+    device: &mut AttachedDevice,
+    playlist_name: &str,
+    policy: CollisionPolicy,
+    shuffle: bool,
+    config: &Config,
+    reporter: &mut dyn ProgressReporter,
+) -> (ExitStatus, Option<String>) {
+    if let Err(e) = config.hooks.pre_sync(&device.name, playlist_name) {
+        println!("Sync of {} aborted: {}", playlist_name, e);
+        return (ExitStatus::from_error(&e), None);
+    }
+    let result = sync_playlist(cache, device, playlist_name, policy, &mut prompt_on_stdin, reporter);
+    config.hooks.post_sync(&device.name, playlist_name, result.as_ref());
+    let (mut status, summary) = match result {
+        Ok(report) => {
+            report.print();
+            let status = if report.cancelled() {
+                ExitStatus::Cancelled
+            } else {
+                ExitStatus::from_batch(report.count(&SyncOutcome::Failed(String::new())), report.tracks.len())
+            };
+            let summary = report.summary();
+            record_activity(Activity::Sync { device: device.name.clone(), report });
+            (status, Some(summary))
+        }
+        // Nothing to put in shuffle order after a sync that didn't happen.
+        Err(e) => {
+            println!("Failed to sync {} with error: {}", playlist_name, e);
+            return (ExitStatus::from_error(&e), None);
+        }
+    };
+    if status == ExitStatus::Cancelled {
+        return (status, summary);
+    }
+    match apply_shuffle_order(device, playlist_name, shuffle) {
+        Ok(0) => {}
+        Ok(renamed) => println!("Renamed {} files for shuffle order", renamed),
+        Err(e) => {
+            println!("Failed to apply shuffle order to {}: {}", playlist_name, e);
+            status = status.worst(ExitStatus::from_error(&e));
+        }
+    }
+    // Best effort, leftover junk never fails a sync.
+    if device.profile.clean_junk_after_sync {
+        match device.clean_junk(false) {
+            Ok(report) if report.removed.is_empty() && report.failed.is_empty() => {}
+            Ok(report) => report.print(false),
+            Err(e) => println!("Failed to clean junk off {}: {}", device.name, e),
+        }
+    }
+    (status, summary)
+}
+
 fn main() {
+    logging::init();
+    // Ctrl-C cancels the running command, rather than killing us mid-write.
+    install_interrupt_handler();
+    let interrupt = interrupt_token();
+    let config = Config::load();
+    // The cache and devices only index audio with the configured extensions.
+    if let Ok(config) = &config {
+        set_audio_extensions(config.audio_extensions.clone());
+    }
+
+    // doctor runs before anything else touches the environment, and reports through the exit code for scripts.
+    if std::env::args().nth(1).as_deref() == Some("doctor") {
+        let cache = config.as_ref().ok().map(LocalCache::from_config);
+        let checks = run_checks(config.as_ref().map_err(|e| e.to_string()), cache.as_ref());
+        print_checks(&checks);
+        let failed = checks.iter().any(|c| c.status == CheckStatus::Fail);
+        if failed { ExitStatus::Environment } else { ExitStatus::Success }.exit();
+    }
+
+    let mut config = config.unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        ExitStatus::Usage.exit();
+    });
+    if std::env::args().any(|arg| arg == "--read-only") {
+        config.read_only = true;
+    }
+    let mut reporter = reporter_from_args(&std::env::args().collect::<Vec<_>>()).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        ExitStatus::Usage.exit();
+    });
+
     // 1. Get user device to download audio to.
+    let default_target = config.default_target.clone().unwrap_or_else(audio_cache_dir);
     let dirpath = {
         println!(
             "Provide a directory (empty -> {})",
-            audio_cache_dir().to_string_lossy()
+            default_target.to_string_lossy()
         );
         let mut directory = String::new();
         stdin()
@@ -29,54 +364,194 @@ fn main() {
             .expect("Failed to read line");
 
         if directory.trim().is_empty() {
-            get_cache_dir().join("audio")
+            default_target
         } else {
             PathBuf::from(&directory.trim())
         }
     };
 
-    let source = YtDlpSource {
-        name: "ytdlp".to_string(),
-    };
-    let mut cache = LocalCache::new();
-    let target = AttachedDevice::new(dirpath.display().to_string(), dirpath);
+    let mut sources = SourceChain::from_config(&config);
+    if let Some(warning) = ytdlp::version_warning(&sources.ytdlp.binary) {
+        println!("{}", warning);
+    }
+    // Held until exit, read-only runs never write to the cache so don't need it.
+    let _cache_lock = (!config.read_only).then(CacheLock::acquire).transpose().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        ExitStatus::from_error(&e).exit();
+    });
+    let mut cache = LocalCache::from_config(&config);
+    let mut target = AttachedDevice::new(dirpath.display().to_string(), dirpath).unwrap_or_else(|e| {
+        eprintln!("Failed to attach device: {}", e);
+        ExitStatus::from_error(&e).worst(ExitStatus::Environment).exit();
+    });
+    target.set_read_only(config.read_only);
+    target.save_index();
 
     // Iterate sources in order, until we find one that contains the AudioInfo.
     // Fetch from the source to the local file cache, will mean we cache the audio there for a future look up.
+    // With commands piped in (script mode), the worst status of any command is the exit status once input ends.
+    let script_mode = !stdin().is_terminal();
+    let mut status = ExitStatus::Success;
+    let mut last = ExitStatus::Success;
     loop {
+        if last != ExitStatus::Success {
+            tracing::warn!("command finished with status {}", last.code());
+        }
+        status = status.worst(last);
+        // A cancelled script stops there, rather than carrying on with the next command.
+        if script_mode && last == ExitStatus::Cancelled {
+            status.exit();
+        }
+        last = ExitStatus::Success;
         print!("> ");
         let mut buffer = String::new();
-        stdin()
-            .read_line(&mut buffer)
-            .expect("Failed to read input");
+        if stdin().read_line(&mut buffer).unwrap_or(0) == 0 {
+            if script_mode { status } else { ExitStatus::Success }.exit();
+        }
 
+        interrupt.reset();
         let mut split = buffer.trim().split_whitespace();
-        let cmd = split.next().expect("No command provided.");
-        let args = split.collect::<Vec<_>>();
+        let Some(cmd) = split.next() else {
+            continue;
+        };
+        let mut args = split.collect::<Vec<_>>();
+        tracing::debug!("command: {}", buffer.trim());
+        // --no-pager / --plain apply to any command with tabular output.
+        let output = OutputOptions::take_from(&mut args);
+        // --proxy / --geo-bypass-country / --limit-rate override the config for any downloads this command makes.
+        match NetworkOptions::from_config(&config).take_from(&mut args) {
+            Ok(network) => {
+                http::set_proxy(network.proxy.clone());
+                http::set_limit_rate(network.limit_rate);
+                sources.ytdlp.network = network;
+            }
+            Err(e) => {
+                println!("{}", e);
+                last = ExitStatus::Usage;
+                continue;
+            }
+        }
         match cmd {
             "list" => {
-                for playlist in target.list_playlists().unwrap() {
-                    println!("Playlist {}", playlist.name.disp_name());
-                    for audio in &playlist.audio {
-                        println!("{:?}", audio);
+                // list -> playlist names and track counts, list <playlist> -> the playlist's tracks,
+                // list [playlist] --artist <artist> -> tracks crediting the artist, including featured artists.
+                let artist = args.iter().position(|a| *a == "--artist").map(|i| {
+                    let artist = args[i + 1..].join(" ");
+                    args.truncate(i);
+                    artist
+                });
+                let playlist_names = match (args.first(), &artist) {
+                    (Some(playlist_name), _) => vec![PlaylistName::from_disp_name(playlist_name)],
+                    (None, Some(_)) => target.list_playlist_names().unwrap_or_default(),
+                    (None, None) => {
+                        match target.list_playlist_names() {
+                            Ok(names) => {
+                                let mut table = Table::new(&["Playlist", "Tracks"]);
+                                for name in names {
+                                    let count = target.get_playlist(&name).map(|audio| audio.len()).unwrap_or_default();
+                                    table.row(vec![name.disp_name().to_string(), count.to_string()]);
+                                }
+                                table.print(output);
+                            }
+                            Err(e) => {
+                                println!("Failed to list playlists: {}", e);
+                                last = ExitStatus::from_error(&e);
+                            }
+                        }
+                        continue;
+                    }
+                };
+
+                let mut table = Table::new(&["#", "Artist", "Title", "Duration", "Playlist", "Flags"]);
+                for playlist_name in playlist_names {
+                    let audio = match target.get_playlist(&playlist_name) {
+                        Ok(audio) => audio,
+                        Err(e) => {
+                            println!("Failed to list {}: {}", playlist_name.disp_name(), e);
+                            last = ExitStatus::from_error(&e);
+                            continue;
+                        }
+                    };
+                    // Pin and skip flags live on the cache's playlist entries, not the device's files.
+                    let entries = cache.playlist_tracks(playlist_name.disp_name()).unwrap_or_default();
+                    let mut matching: Vec<&AudioInfo> =
+                        audio.iter().filter(|a| artist.as_ref().is_none_or(|artist| a.credits(artist))).collect();
+                    matching.sort_by(|a, b| sort::track_cmp(a, b));
+                    for (i, audio) in matching.into_iter().enumerate() {
+                        let key = AudioKey::from_info(audio);
+                        let flags = entries
+                            .iter()
+                            .find(|entry| key.is_some() && AudioKey::from_info(entry) == key)
+                            .map(|entry| {
+                                let mut flags = Vec::new();
+                                if entry.pinned {
+                                    flags.push("pinned".to_string());
+                                }
+                                flags.extend(entry.skip_on.iter().map(|device| format!("skip on {}", device)));
+                                flags.join(", ")
+                            })
+                            .unwrap_or_default();
+                        table.row(vec![
+                            (i + 1).to_string(),
+                            audio.artist.clone().unwrap_or_default(),
+                            audio.title.clone().unwrap_or_default(),
+                            format_duration(audio.duration_secs),
+                            playlist_name.disp_name().to_string(),
+                            flags,
+                        ]);
                     }
                 }
+                table.print(output);
             }
             "search" => {
-                let artist = args.get(0).expect("Usage: search '<artist>' '<title>'");
-                let title = args.get(1).expect("Usage: search '<artist>' '<title>'");
-                let info = AudioInfo {
-                    artist: Some(artist.to_string()),
-                    title: Some(title.to_string()),
-                    ..Default::default()
+                // search <artist> - <title> | search <title> | search --artist <artist>
+                let Some(query) = parse_partial_query(&args) else {
+                    println!("Usage: search <artist> - <title> | search <title> | search --artist <artist>");
+                    last = ExitStatus::Usage;
+                    continue;
                 };
+                // The legacy two word form "search <artist> <title>" is tried exactly first.
+                let exact = parse_artist_title(&args).map(|info| cache.search(&info).map(|loc| (info, loc)));
+                match exact {
+                    Some(Ok((info, AudioLocation::LocalPath(path)))) => {
+                        let mut table = Table::new(&["Artist", "Title", "Location"]);
+                        table.row(vec![
+                            info.artist.unwrap_or_default(),
+                            info.title.unwrap_or_default(),
+                            path.display().to_string(),
+                        ]);
+                        table.print(output);
+                        continue;
+                    }
+                    Some(Err(AudioError::Unavailable(reason))) => {
+                        println!("Found {} in the local file cache, but offline: {}", args.join(" "), reason);
+                        last = ExitStatus::Environment;
+                        continue;
+                    }
+                    _ => {}
+                }
 
-                if let Ok(loc) = cache.search(&info) {
-                    println!(
-                        "Found {}: {} in the local file cache at {:?}.",
-                        artist, title, loc
-                    );
+                let candidates = cache.search_partial(&query);
+                if candidates.is_empty() {
+                    println!("No matches for {} in the local file cache.", args.join(" "));
+                    last = ExitStatus::Failure;
+                    continue;
+                }
+                if candidates.len() > 1 {
+                    println!("{} matches, narrow it down with <artist> - <title>:", candidates.len());
                 }
+                let mut table = Table::new(&["#", "Artist", "Title", "Match", "Location"]);
+                for (i, (key, location, score)) in candidates.into_iter().enumerate() {
+                    let AudioLocation::LocalPath(path) = location else { continue };
+                    table.row(vec![
+                        (i + 1).to_string(),
+                        key.artist,
+                        key.title,
+                        score.map(|s| format!("fuzzy {:.2}", s)).unwrap_or("exact".to_string()),
+                        path.display().to_string(),
+                    ]);
+                }
+                table.print(output);
             }
             "download" => {
                 // Parse: download <url> [playlist] OR download <artist> <title> [playlist]
@@ -92,33 +567,300 @@ fn main() {
                     (info, playlist)
                 } else {
                     // download <artist> <title> [playlist]
+                    let (Some(artist), Some(title)) = (args.first(), args.get(1)) else {
+                        println!("Usage: download <url> [playlist] OR download <artist> <title> [playlist]");
+                        last = ExitStatus::Usage;
+                        continue;
+                    };
                     let info = AudioInfo {
-                        artist: Some(args.get(0).expect("Usage: download <url> [playlist] OR download <artist> <title> [playlist]").to_string()),
-                        title: Some(args.get(1).expect("Usage: download <url> [playlist] OR download <artist> <title> [playlist]").to_string()),
+                        artist: Some(artist.to_string()),
+                        title: Some(title.to_string()),
                         ..Default::default()
                     };
                     let playlist = args.get(2).map(|s| s.to_string());
                     (info, playlist)
                 };
 
-                match source.fetch(&info, audio_cache_dir()) {
-                    Ok(location) => {
-                        cache.add_to_cache(&info, &location, playlist.as_deref());
-                        println!("Downloaded to cache: {:?}", location);
-                        if let Some(p) = &playlist {
-                            println!("Added to playlist: {}", p);
+                last = download_to_cache(&info, playlist.as_deref(), &config, &sources, None, &mut cache, reporter.as_mut());
+            }
+            "preview" => {
+                let Some(info) = parse_partial_query(&args) else {
+                    println!("Usage: preview <artist> - <title>");
+                    last = ExitStatus::Usage;
+                    continue;
+                };
+                let candidates = match sources.ytdlp.search_candidates(&info, PREVIEW_CANDIDATES) {
+                    Ok(candidates) if candidates.is_empty() => {
+                        println!("No results for {:?}", info);
+                        last = ExitStatus::from_error(&AudioError::NotFound);
+                        continue;
+                    }
+                    Ok(candidates) => candidates,
+                    Err(e) => {
+                        println!("Search failed: {}", e);
+                        last = ExitStatus::from_error(&e);
+                        continue;
+                    }
+                };
+                for (i, (_, title)) in candidates.iter().enumerate() {
+                    println!("{}. {}", i + 1, title);
+                }
+                let player = config.player.as_deref().unwrap_or(DEFAULT_PLAYER);
+                last = match choose_by_preview(&sources.ytdlp, &candidates, player) {
+                    // The picked video, not whatever another source would find for the same artist and title.
+                    Ok(Some(info)) => {
+                        download_to_cache(&info, None, &config, &sources, Some(&sources.ytdlp), &mut cache, reporter.as_mut())
+                    }
+                    Ok(None) => {
+                        println!("Aborted, nothing downloaded");
+                        ExitStatus::Success
+                    }
+                    Err(e) => {
+                        println!("Preview failed: {}", e);
+                        ExitStatus::from_error(&e)
+                    }
+                };
+            }
+            "download-artist" => {
+                // download-artist <artist> [--top N | --albums] -> the artist's tracks (every studio album by default)
+                // that aren't cached yet, from MusicBrainz, after confirming. They're added to the download queue and
+                // the queue is run, so a cancelled discography carries on with `queue run`. Each album goes into a
+                // playlist named "<artist> - <album>", top tracks into "<artist> Top Tracks".
+                let usage = "Usage: download-artist <artist> [--top N | --albums]";
+                let top = args.iter().position(|a| *a == "--top").map(|i| {
+                    let count = args.get(i + 1).and_then(|n| n.parse::<usize>().ok());
+                    args.drain(i..(i + 2).min(args.len()));
+                    count
+                });
+                args.retain(|a| *a != "--albums");
+                let name = args.join(" ").trim_matches('"').to_string();
+                if name.is_empty() || matches!(top, Some(None)) {
+                    println!("{}", usage);
+                    last = ExitStatus::Usage;
+                    continue;
+                }
+                let artists = match musicbrainz::search_artists(&name) {
+                    Ok(artists) => artists,
+                    Err(e) => {
+                        println!("MusicBrainz lookup failed: {}", e);
+                        last = ExitStatus::from_error(&e);
+                        continue;
+                    }
+                };
+                // An exact name match wins over artists that only resemble it.
+                let exact: Vec<MbArtist> = artists.iter().filter(|a| a.name.to_lowercase() == name.to_lowercase()).cloned().collect();
+                let candidates = if exact.is_empty() { artists } else { exact };
+                let artist = match candidates.as_slice() {
+                    [] => {
+                        println!("No artist named {} on MusicBrainz", name);
+                        last = ExitStatus::from_error(&AudioError::NotFound);
+                        continue;
+                    }
+                    [only] => only,
+                    several => match musicbrainz::choose_artist(several) {
+                        Some(artist) => artist,
+                        None => {
+                            println!("Cancelled, nothing downloaded");
+                            continue;
                         }
                     },
-                    Err(e) => println!("Download failed: {:?}", e),
+                };
+                let tracks = match top.flatten() {
+                    Some(count) => musicbrainz::top_tracks(artist, count),
+                    None => musicbrainz::album_tracks(artist),
+                };
+                let tracks = match tracks {
+                    Ok(tracks) => tracks,
+                    Err(e) => {
+                        println!("MusicBrainz lookup failed: {}", e);
+                        last = ExitStatus::from_error(&e);
+                        continue;
+                    }
+                };
+                let total = tracks.len();
+                // A track on several albums is only fetched once, for the oldest.
+                let mut seen = HashSet::new();
+                let missing: Vec<AudioInfo> = tracks
+                    .into_iter()
+                    .filter(|info| cache.search(info).is_err() && AudioKey::from_info(info).is_none_or(|key| seen.insert(key)))
+                    .collect();
+                if missing.is_empty() {
+                    println!("All {} tracks by {} are already cached", total, artist.describe());
+                    continue;
+                }
+                let mut table = Table::new(&["Album", "#", "Title"]);
+                for info in &missing {
+                    table.row(vec![
+                        info.album.clone().unwrap_or_default(),
+                        info.track_number.map(|n| n.to_string()).unwrap_or_default(),
+                        info.title.clone().unwrap_or_default(),
+                    ]);
+                }
+                table.print(output);
+                if !confirm(&format!("Download {} of {} tracks by {}?", missing.len(), total, artist.describe())) {
+                    continue;
+                }
+                // Named with the artist, so albums of the same name by different artists, or a playlist of the user's,
+                // aren't merged.
+                let playlist = |info: &AudioInfo| match &info.album {
+                    Some(album) => format!("{} - {}", artist.name, album),
+                    None => format!("{} Top Tracks", artist.name),
+                };
+                let mut queue = DownloadQueue::load();
+                let queued = queue.push(missing.iter().map(|info| QueuedDownload { info: info.clone(), playlist: Some(playlist(info)) }));
+                if let Err(e) = queue.save() {
+                    println!("Failed to save the download queue: {}", e);
+                    last = ExitStatus::from_error(&e.into());
+                    continue;
                 }
+                println!("Queued {} tracks by {}", queued, artist.name);
+                last = run_download_queue(&mut queue, &config, &sources, &mut cache, reporter.as_mut(), &interrupt);
+            }
+            "queue" => {
+                // queue -> downloads waiting in the download queue, queue run -> download them, queue clear -> empty it.
+                let mut queue = DownloadQueue::load();
+                match args.first() {
+                    None => {
+                        let mut table = Table::new(&["Artist", "Title", "Playlist"]);
+                        for queued in queue.entries() {
+                            table.row(vec![
+                                queued.info.artist.clone().unwrap_or_default(),
+                                queued.info.title.clone().unwrap_or_default(),
+                                queued.playlist.clone().unwrap_or_default(),
+                            ]);
+                        }
+                        table.print(output);
+                    }
+                    Some(&"run") => {
+                        last = run_download_queue(&mut queue, &config, &sources, &mut cache, reporter.as_mut(), &interrupt);
+                    }
+                    Some(&"clear") => {
+                        let cleared = queue.entries().len();
+                        queue.clear();
+                        match queue.save() {
+                            Ok(()) => println!("Cleared {} queued downloads", cleared),
+                            Err(e) => {
+                                println!("Failed to save the download queue: {}", e);
+                                last = ExitStatus::from_error(&e.into());
+                            }
+                        }
+                    }
+                    Some(_) => {
+                        println!("Usage: queue [run | clear]");
+                        last = ExitStatus::Usage;
+                    }
+                }
+            }
+            "split-cue" => {
+                // split-cue <file.cue> [--into <playlist>] -> split a single-file album rip into a cached file per
+                // track, in a playlist in track order named after the album unless --into says otherwise.
+                let into = args.iter().position(|a| *a == "--into").map(|i| {
+                    let playlist = args.get(i + 1).map(|p| p.trim_matches('"').to_string());
+                    args.drain(i..(i + 2).min(args.len()));
+                    playlist
+                });
+                let cue_path = PathBuf::from(args.join(" ").trim_matches('"'));
+                if args.is_empty() || matches!(into, Some(None)) {
+                    println!("Usage: split-cue <file.cue> [--into <playlist>]");
+                    last = ExitStatus::Usage;
+                    continue;
+                }
+                let splits = CueSheet::load(&cue_path)
+                    .and_then(|sheet| Ok((sheet.splits(&cue_path, config.cue_pregap)?, sheet)))
+                    .and_then(|splits| Ok((splits, StagingDir::new("cue")?)));
+                let ((splits, sheet), staging) = match splits {
+                    Ok(splits) => splits,
+                    Err(e) => {
+                        println!("Failed to read {}: {}", cue_path.display(), e);
+                        last = ExitStatus::from_error(&e);
+                        continue;
+                    }
+                };
+                let playlist = into.flatten().or_else(|| sheet.title.clone()).unwrap_or_else(|| {
+                    cue_path.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default()
+                });
+                let listed: HashSet<AudioKey> = cache
+                    .playlist_tracks(&playlist)
+                    .map(|tracks| tracks.iter().filter_map(AudioKey::from_info).collect())
+                    .unwrap_or_default();
+
+                // A track that fails to split is reported, and the rest carry on.
+                let (mut failed, mut done) = (0, 0);
+                let cue_name = cue_path.file_name().map(|name| name.to_string_lossy().to_string());
+                for split in &splits {
+                    if interrupt.is_cancelled() {
+                        last = last.worst(ExitStatus::Cancelled);
+                        break;
+                    }
+                    let mut info = sheet.info(&split.track);
+                    info.duration_secs = split.end_secs.map(|end| (end - split.start_secs).round() as u32);
+                    // Only this cue's own output for the track counts as already split, not other audio under the
+                    // same key, e.g. a download of it.
+                    let already = cache.search(&info).and_then(|location| match &location {
+                        AudioLocation::LocalPath(path)
+                            if path.file_name().is_some_and(|name| *name == *split.file_name(&info))
+                                && Sidecar::load(path).is_some_and(|sidecar| sidecar.source_title == cue_name) =>
+                        {
+                            Ok(location)
+                        }
+                        _ => Err(AudioError::NotFound),
+                    });
+                    let result = match &already {
+                        // Split before, only the playlist may still need it.
+                        Ok(location) if AudioKey::from_info(&info).is_some_and(|key| listed.contains(&key)) => {
+                            Ok(location.clone())
+                        }
+                        Ok(location) => cache.add_to_cache(&info, location, Some(&playlist)),
+                        Err(_) => {
+                            let staged = staging.path().join(split.file_name(&info));
+                            split
+                                .split(&info, splits.len(), &staged)
+                                .and_then(|_| {
+                                    let bytes = std::fs::metadata(&staged)?.len();
+                                    cache.commit_staged(FetchResult::local(staged, info.clone(), bytes, "cue"))
+                                })
+                                .and_then(|result| cache.add_to_cache(&info, &result.location, Some(&playlist)))
+                        }
+                    };
+                    match result {
+                        Ok(location) => {
+                            if let (AudioLocation::LocalPath(path), Err(_)) = (&location, &already) {
+                                let sidecar = Sidecar {
+                                    source: Some("cue".to_string()),
+                                    source_title: cue_name.clone(),
+                                    fetched_at: Some(unix_now()),
+                                    starred: cache.is_starred(path),
+                                    ..Default::default()
+                                };
+                                if let Err(e) = cache.save_sidecar(path, &sidecar) {
+                                    println!("Failed to write metadata for {:?}: {}", path, e);
+                                }
+                            }
+                            done += 1;
+                            let verb = if already.is_ok() { "Already cached" } else { "Split" };
+                            println!(
+                                "{} {:02} {} - {}",
+                                verb,
+                                split.track.number,
+                                info.artist.as_deref().unwrap_or("?"),
+                                info.title.as_deref().unwrap_or_default()
+                            );
+                        }
+                        Err(e) => {
+                            println!("Failed to split track {:02}: {}", split.track.number, e);
+                            failed += 1;
+                        }
+                    }
+                }
+                println!("{} of {} tracks in playlist {}", done, splits.len(), playlist);
+                last = last.worst(ExitStatus::from_batch(failed, splits.len()));
             }
             "import" => {
-                let artist = args
-                    .get(0)
-                    .expect("Usage: import <artist> <title> [playlist]");
-                let title = args
-                    .get(1)
-                    .expect("Usage: import <artist> <title> [playlist]");
+                let (Some(artist), Some(title)) = (args.first(), args.get(1)) else {
+                    println!("Usage: import <artist> <title> [playlist]");
+                    last = ExitStatus::Usage;
+                    continue;
+                };
 
                 // If playlist is provided, import to it, otherwise will assume the audio can be uncategorized.
                 let playlist = args.get(2).map(|s| PlaylistName::Named(s.to_string()));
@@ -129,10 +871,17 @@ fn main() {
                 };
 
                 let location = cache.search(&info);
+                last = ExitStatus::from_result(&location);
                 match location {
-                    Ok(_) => match target.import(&location.unwrap(), &info, playlist) {
-                        Ok(loc) => println!("Imported to target: {:?}", loc),
-                        Err(e) => println!("Import failed {:?}", e),
+                    Ok(location) => match target.import(&location, &info, playlist, &DestNaming::KeepSource) {
+                        Ok(imported) => {
+                            println!("Imported to target: {:?}", imported.location);
+                            target.refresh_index();
+                        }
+                        Err(e) => {
+                            println!("Import failed {:?}", e);
+                            last = ExitStatus::from_error(&e);
+                        }
                     },
                     Err(e) => match e {
                         AudioError::MissingInfo => println!(
@@ -143,43 +892,1163 @@ fn main() {
                             "Failed to find {:?} in cache, need to run 'download' first.",
                             info
                         ),
-                        _ => panic!("Unexpected error during import."),
+                        e => println!("Failed to find {:?} in cache: {}", info, e),
                     },
                 }
             }
+            "ytdlp" => match args.first() {
+                // ytdlp version -> the yt-dlp in use, and whether it's new enough.
+                Some(&"version") => match ytdlp::installed_version(&sources.ytdlp.binary) {
+                    Ok(version) if ytdlp::is_outdated(&version) => println!(
+                        "yt-dlp {} ({}) is older than {}, run ytdlp update",
+                        version,
+                        sources.ytdlp.binary.display(),
+                        ytdlp::MIN_YTDLP_VERSION
+                    ),
+                    Ok(version) => println!("yt-dlp {} ({})", version, sources.ytdlp.binary.display()),
+                    Err(e) => {
+                        println!("Failed to run yt-dlp: {}", e);
+                        last = ExitStatus::from_error(&e);
+                    }
+                },
+                // ytdlp update -> update in place, or download the latest release to use instead of the one on PATH.
+                Some(&"update") => match ytdlp::update(&sources.ytdlp.binary) {
+                    Ok(binary) => {
+                        let version = ytdlp::installed_version(&binary).unwrap_or_default();
+                        println!("yt-dlp {} ({})", version, binary.display());
+                        if let Some(configured) = config.ytdlp_path.as_ref().filter(|path| **path != binary) {
+                            println!("ytdlp_path in config.toml still points at {}, remove it to keep using this one", configured.display());
+                        }
+                        sources.ytdlp.binary = binary;
+                    }
+                    Err(e) => {
+                        println!("Failed to update yt-dlp: {}", e);
+                        last = ExitStatus::from_error(&e);
+                    }
+                },
+                _ => {
+                    println!("Usage: ytdlp version | ytdlp update");
+                    last = ExitStatus::Usage;
+                }
+            },
+            "keep_original" => {
+                // keep_original on|off -> keep the pre-transcode download alongside the mp3 in the cache.
+                match args.first() {
+                    Some(&"on") => sources.ytdlp.keep_original = true,
+                    Some(&"off") => sources.ytdlp.keep_original = false,
+                    _ => {}
+                }
+                println!("Keep original downloads: {}", sources.ytdlp.keep_original);
+            }
+            "query_template" => {
+                // Show the current search query template, or replace it e.g. query_template {artist} - {title} audio
+                if args.is_empty() {
+                    println!("{}", sources.ytdlp.query_template);
+                } else {
+                    sources.ytdlp.query_template = args.join(" ");
+                    println!("Search query template set to: {}", sources.ytdlp.query_template);
+                }
+            }
+            "flag" => {
+                // flag <artist> - <title> [--bad-source] -> mark for re-download, --bad-source also blocks the video the
+                // cached copy came from, so the re-download searches for another.
+                let bad_source = args.contains(&"--bad-source");
+                args.retain(|a| *a != "--bad-source");
+                let Some(info) = parse_artist_title(&args) else {
+                    println!("Usage: flag <artist> - <title> [--bad-source]");
+                    last = ExitStatus::Usage;
+                    continue;
+                };
+                match cache.flag(&info) {
+                    Ok(()) => println!("Flagged {:?} for re-download.", info),
+                    Err(e) => {
+                        println!("Failed to flag {:?}: {}", info, e);
+                        last = ExitStatus::from_error(&e);
+                        continue;
+                    }
+                }
+                if bad_source {
+                    match cache.provenance_url(&info) {
+                        Some(url) => {
+                            sources.ytdlp.blocked.block(&url);
+                            match sources.ytdlp.blocked.save() {
+                                Ok(()) => println!("Blocked {}", url),
+                                Err(e) => {
+                                    println!("Failed to save the blocklist: {}", e);
+                                    last = ExitStatus::from_error(&e.into());
+                                }
+                            }
+                        }
+                        None => println!("No source recorded for {:?}, nothing to block", info),
+                    }
+                }
+            }
+            "block" | "unblock" => {
+                // block <url|id> -> never download from that video again, searches take the next best result instead.
+                // unblock <url|id> undoes it, block on its own lists what's blocked.
+                let Some(url) = args.first() else {
+                    if cmd == "block" {
+                        for id in sources.ytdlp.blocked.ids() {
+                            println!("{}", id);
+                        }
+                    } else {
+                        println!("Usage: unblock <url|id>");
+                        last = ExitStatus::Usage;
+                    }
+                    continue;
+                };
+                let changed = if cmd == "block" { sources.ytdlp.blocked.block(url) } else { sources.ytdlp.blocked.unblock(url) };
+                match sources.ytdlp.blocked.save() {
+                    Ok(()) if !changed => println!("{} was already {}ed", url, cmd),
+                    Ok(()) => println!("{}ed {}", if cmd == "block" { "Block" } else { "Unblock" }, url),
+                    Err(e) => {
+                        println!("Failed to save the blocklist: {}", e);
+                        last = ExitStatus::from_error(&e.into());
+                    }
+                }
+            }
+            "verify-device" => {
+                // verify-device [--sample N%] -> reread files synced to the device and compare against their checksums.
+                let sample = match args.iter().position(|a| *a == "--sample") {
+                    Some(i) => match args.get(i + 1).map(|n| n.trim_end_matches('%').parse::<u8>()) {
+                        Some(Ok(percent)) if (1..=100).contains(&percent) => Some(percent),
+                        _ => {
+                            println!("Usage: verify-device [--sample N%]");
+                            last = ExitStatus::Usage;
+                            continue;
+                        }
+                    },
+                    None => None,
+                };
+                match verify_device(&target, sample) {
+                    Ok(report) => {
+                        report.print();
+                        last = ExitStatus::from_batch(report.problems.len(), report.checked);
+                    }
+                    Err(e) => {
+                        println!("Failed to verify device: {}", e);
+                        last = ExitStatus::from_error(&e);
+                    }
+                }
+            }
+            "repair-device" => match repair_device(&cache, &target) {
+                Ok(report) => {
+                    report.print();
+                    last = ExitStatus::from_batch(report.failed.len(), report.failed.len() + report.repaired.len());
+                }
+                Err(e) => {
+                    println!("Failed to repair device: {}", e);
+                    last = ExitStatus::from_error(&e);
+                }
+            },
+            "where" => {
+                // where <artist> - <title> | <title> | --artist <artist> [--json] -> every place the track exists, nothing
+                // is modified.
+                let json = args.contains(&"--json");
+                args.retain(|a| *a != "--json");
+                let Some(query) = parse_partial_query(&args) else {
+                    println!("Usage: where <artist> - <title> | where <title> | where --artist <artist> [--json]");
+                    last = ExitStatus::Usage;
+                    continue;
+                };
+                let report = WhereReport::build(&cache, &target, &query);
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&report).unwrap());
+                } else {
+                    report.print(output);
+                }
+            }
+            "artists" => {
+                // artists [--min-tracks N] [--json] -> every credited artist in the cache, most tracks first.
+                // artists show <artist> [--json] -> the artist's tracks and everywhere each of them is.
+                let usage = "Usage: artists [--min-tracks N] [--json] | artists show <artist> [--json]";
+                let json = args.contains(&"--json");
+                args.retain(|a| *a != "--json");
+                let min_tracks = match args.iter().position(|a| *a == "--min-tracks") {
+                    None => Some(0),
+                    Some(i) => {
+                        let min_tracks = args.get(i + 1).and_then(|n| n.parse().ok());
+                        args.drain(i..(i + 2).min(args.len()));
+                        min_tracks
+                    }
+                };
+                match (args.first(), min_tracks) {
+                    (None, Some(min_tracks)) => {
+                        let report = ArtistsReport::build(&cache, min_tracks);
+                        if json {
+                            println!("{}", serde_json::to_string_pretty(&report).unwrap());
+                        } else {
+                            report.print(output);
+                        }
+                    }
+                    (Some(&"show"), _) if args.len() > 1 => {
+                        // The REPL splits on whitespace, so a quoted name arrives with its quotes.
+                        let artist = args[1..].join(" ");
+                        let report = ArtistTracksReport::build(&cache, &target, artist.trim_matches('"'));
+                        if json {
+                            println!("{}", serde_json::to_string_pretty(&report).unwrap());
+                        } else {
+                            report.print(output);
+                        }
+                    }
+                    _ => {
+                        println!("{}", usage);
+                        last = ExitStatus::Usage;
+                    }
+                }
+            }
+            "export-state" => {
+                // export-state <bundle.tar.gz> -> config, playlists, history and metadata, without any audio.
+                let Some(bundle) = args.first() else {
+                    println!("Usage: export-state <bundle.tar.gz>");
+                    last = ExitStatus::Usage;
+                    continue;
+                };
+                match bundle::export_state(&cache, Path::new(bundle)) {
+                    Ok(cached) => println!("Exported state to {}, listing {} cached tracks to re-fetch", bundle, cached),
+                    Err(e) => {
+                        println!("Failed to export state: {}", e);
+                        last = ExitStatus::from_error(&e);
+                    }
+                }
+            }
+            "import-state" => {
+                // import-state <bundle> [--merge | --replace] -> bring in another machine's state, asking whether to
+                // merge with or replace this one's unless told.
+                let mode = match (args.contains(&"--merge"), args.contains(&"--replace")) {
+                    (true, true) => None,
+                    (merge, replace) => Some((merge || replace).then_some(replace)),
+                };
+                args.retain(|a| *a != "--merge" && *a != "--replace");
+                let (Some(bundle), Some(mode)) = (args.first(), mode) else {
+                    println!("Usage: import-state <bundle> [--merge | --replace]");
+                    last = ExitStatus::Usage;
+                    continue;
+                };
+                let state = match StateBundle::open(Path::new(bundle)) {
+                    Ok(state) => state,
+                    Err(e) => {
+                        println!("Failed to import state: {}", e);
+                        last = ExitStatus::from_error(&e);
+                        continue;
+                    }
+                };
+                let Some(replace) = mode.or_else(bundle::prompt_import_mode) else {
+                    println!("Import cancelled, nothing changed");
+                    continue;
+                };
+                match state.apply(&mut cache, replace) {
+                    Ok(changed) => {
+                        // Flags, stars and the playlists themselves are only read when the cache is loaded.
+                        cache = LocalCache::from_config(&config);
+                        println!("Imported state from {}, {} playlists changed", bundle, changed.len());
+                        println!("Restart to pick up any imported config changes");
+                        let missing = state.missing(&cache);
+                        println!(
+                            "{} of {} tracks cached on the exporting machine aren't cached here",
+                            missing.len(),
+                            state.manifest.cached.len()
+                        );
+                        for name in cache.playlist_names() {
+                            let uncached = cache
+                                .playlist_tracks(name)
+                                .unwrap_or_default()
+                                .iter()
+                                .filter(|info| cache.search(info).is_err())
+                                .count();
+                            if uncached > 0 {
+                                println!("  {}: {} not cached, see missing cache:{}", name, uncached, name);
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        println!("Failed to import state: {}", e);
+                        last = ExitStatus::from_error(&e);
+                    }
+                }
+            }
+            "missing" => {
+                // missing <index>:<playlist> [--json] -> which of another index's playlist would need downloading.
+                let json = args.contains(&"--json");
+                args.retain(|a| *a != "--json");
+                let joined = args.join(" ");
+                let Some((index_name, playlist)) = joined.split_once(':').filter(|(_, p)| !p.is_empty()) else {
+                    println!("Usage: missing <index>:<playlist> [--json], e.g. missing device:Road or missing spotify:<playlist URL>");
+                    last = ExitStatus::Usage;
+                    continue;
+                };
+                let remote;
+                let index: &dyn AudioIndex = match index_name.to_lowercase().as_str() {
+                    "device" => &target,
+                    "cache" => &cache,
+                    other => match remote::remote_index(&config, other) {
+                        Some(Ok(index)) => {
+                            remote = index;
+                            remote.as_ref()
+                        }
+                        Some(Err(e)) => {
+                            println!("Can't use {}: {}", other, e);
+                            last = ExitStatus::from_error(&e);
+                            continue;
+                        }
+                        None => {
+                            println!("Unknown index {}, expected device, cache, subsonic, spotify or lastfm", other);
+                            last = ExitStatus::Usage;
+                            continue;
+                        }
+                    },
+                };
+                match MissingReport::build(&cache, index, &PlaylistName::from_disp_name(playlist)) {
+                    Ok(report) if json => println!("{}", serde_json::to_string_pretty(&report).unwrap()),
+                    Ok(report) => report.print(output),
+                    Err(e) => {
+                        println!("Failed to list {}: {}", joined, e);
+                        last = ExitStatus::from_error(&e);
+                    }
+                }
+            }
+            "re-download" => {
+                let targets = if args.first() == Some(&"--all-flagged") {
+                    cache.flagged().to_vec()
+                } else if let Some(info) = parse_artist_title(&args) {
+                    vec![info]
+                } else {
+                    println!("Usage: re-download <artist> - <title> OR re-download --all-flagged");
+                    last = ExitStatus::Usage;
+                    continue;
+                };
+
+                let mut failed = 0;
+                for (done, info) in targets.iter().enumerate() {
+                    if interrupt.is_cancelled() {
+                        println!("Cancelled, {} of {} re-downloaded", done - failed, targets.len());
+                        break;
+                    }
+                    let started = Instant::now();
+                    let result = config.hooks.pre_fetch(info, None).and_then(|_| cache.redownload(info, &sources.ytdlp));
+                    record_activity(Activity::Fetch(FetchRecord::new(info, sources.ytdlp.name(), result.as_ref(), started)));
+                    config.hooks.post_fetch(info, None, result.as_ref().map(|result| &result.location));
+                    match result {
+                        Ok(result) => println!("Re-downloaded {:?} to {:?}", info, result.location),
+                        Err(e) => {
+                            println!("Re-download of {:?} failed: {}", info, e);
+                            failed += 1;
+                        }
+                    }
+                }
+                last = if interrupt.is_cancelled() {
+                    ExitStatus::Cancelled
+                } else {
+                    ExitStatus::from_batch(failed, targets.len())
+                };
+            }
+            "dupes" => {
+                // dupes [--across cache,device] [--detailed] [--json]
+                let across = match args.iter().position(|a| *a == "--across") {
+                    Some(i) => match args.get(i + 1).map(|list| IndexKind::parse_list(list)) {
+                        Some(Ok(across)) => across,
+                        Some(Err(e)) => {
+                            println!("{}", e);
+                            last = ExitStatus::Usage;
+                            continue;
+                        }
+                        None => {
+                            println!("Usage: dupes [--across cache,device] [--detailed] [--json]");
+                            last = ExitStatus::Usage;
+                            continue;
+                        }
+                    },
+                    None => vec![IndexKind::Cache, IndexKind::Device],
+                };
+
+                let report = DupesReport::build(&cache, &target, &DeviceManifest::load(&target.path, target.case_insensitive()), &across);
+                if args.contains(&"--json") {
+                    println!("{}", serde_json::to_string_pretty(&report).unwrap());
+                } else {
+                    report.print(args.contains(&"--detailed"), output);
+                }
+            }
+            "sync" => {
+                let usage = "Usage: sync <playlist> [--force | --yes | --on-conflict skip|overwrite|keep-both] [--shuffle-order] \
+                             [--target <dir>]...";
+                // --target <dir>, repeatable, syncs to those devices instead of the attached one.
+                let mut targets = Vec::new();
+                while let Some(i) = args.iter().position(|a| *a == "--target") {
+                    targets.push(args.get(i + 1).map(PathBuf::from));
+                    args.drain(i..(i + 2).min(args.len()));
+                }
+                let (Some(playlist_name), Some(targets)) = (args.first().copied(), targets.into_iter().collect::<Option<Vec<_>>>())
+                else {
+                    println!("{}", usage);
+                    last = ExitStatus::Usage;
+                    continue;
+                };
+                // Collisions are asked about interactively, unless a flag decides them up front so scripts never block.
+                let on_conflict = args.iter().position(|a| *a == "--on-conflict").map(|i| args.get(i + 1));
+                let policy = match on_conflict {
+                    Some(choice) => match choice.and_then(|c| ConflictChoice::parse(c)) {
+                        Some(choice) => CollisionPolicy::Always(choice),
+                        None => {
+                            println!("{}", usage);
+                            last = ExitStatus::Usage;
+                            continue;
+                        }
+                    },
+                    None if args.contains(&"--force") => CollisionPolicy::Always(ConflictChoice::Overwrite),
+                    None if args.contains(&"--yes") || !stdin().is_terminal() => CollisionPolicy::default(),
+                    None => CollisionPolicy::Ask,
+                };
+                let shuffle = args.contains(&"--shuffle-order");
+                if targets.is_empty() {
+                    last = sync_to_device(&cache, &mut target, playlist_name, policy, shuffle, &config, reporter.as_mut()).0;
+                    continue;
+                }
+                // Tracks not cached yet are fetched once up front, then every device imports them from the cache. A
+                // failure on one device doesn't stop the others.
+                let missing: Vec<AudioInfo> = cache
+                    .playlist_tracks(playlist_name)
+                    .unwrap_or_default()
+                    .iter()
+                    .filter(|info| matches!(cache.search(info), Err(AudioError::NotFound)))
+                    .cloned()
+                    .collect();
+                if !missing.is_empty() {
+                    println!("Fetching {} tracks of {} that aren't cached yet", missing.len(), playlist_name);
+                }
+                for info in &missing {
+                    if interrupt.is_cancelled() {
+                        break;
+                    }
+                    last = last.worst(download_to_cache(info, None, &config, &sources, None, &mut cache, reporter.as_mut()));
+                }
+                if interrupt.is_cancelled() {
+                    last = ExitStatus::Cancelled;
+                    continue;
+                }
+                let mut summaries = Vec::new();
+                for path in targets {
+                    let mut device = match AttachedDevice::new(path.display().to_string(), path.clone()) {
+                        Ok(device) => device,
+                        Err(e) => {
+                            println!("Failed to attach {}: {}", path.display(), e);
+                            last = last.worst(ExitStatus::from_error(&e).worst(ExitStatus::Environment));
+                            summaries.push((path.display().to_string(), format!("not attached: {}", e)));
+                            continue;
+                        }
+                    };
+                    device.set_read_only(config.read_only);
+                    println!("== {} ==", device.name);
+                    let (status, summary) =
+                        sync_to_device(&cache, &mut device, playlist_name, policy, shuffle, &config, reporter.as_mut());
+                    device.save_index();
+                    last = last.worst(status);
+                    summaries.push((device.name.clone(), summary.unwrap_or_else(|| "failed".to_string())));
+                    if status == ExitStatus::Cancelled {
+                        break;
+                    }
+                }
+                for (device, summary) in summaries {
+                    println!("{}: {}", device, summary);
+                }
+            }
+            "quality" => {
+                // quality [--playlist <name>] [--min-kbps N] [--flag]
+                let flag_value = |flag: &str| {
+                    args.iter().position(|a| *a == flag).and_then(|i| args.get(i + 1)).copied()
+                };
+                let playlist = flag_value("--playlist");
+                let min_kbps = match flag_value("--min-kbps").map(str::parse) {
+                    Some(Ok(kbps)) => kbps,
+                    Some(Err(_)) => {
+                        println!("Usage: quality [--playlist <name>] [--min-kbps N] [--flag]");
+                        last = ExitStatus::Usage;
+                        continue;
+                    }
+                    None => DEFAULT_MIN_KBPS,
+                };
+
+                let mut probes = ProbeCache::load();
+                let report = QualityReport::build(&cache, &mut probes, playlist, min_kbps);
+                // Probe results are only a cache, so in read-only mode just don't persist them.
+                if !cache.is_read_only()
+                    && let Err(e) = probes.save()
+                {
+                    println!("Failed to save probe results: {}", e);
+                }
+                match report {
+                    Ok(report) => {
+                        report.print(output);
+                        if args.contains(&"--flag") {
+                            let mut all_flagged = true;
+                            for info in report.flagged_info() {
+                                if let Err(e) = cache.flag(&info) {
+                                    println!("Failed to flag {:?}: {}", info, e);
+                                    last = ExitStatus::from_error(&e);
+                                    all_flagged = false;
+                                }
+                            }
+                            if all_flagged {
+                                println!("Flagged for re-download, run 're-download --all-flagged' to replace them.");
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        println!("Failed to build quality report: {}", e);
+                        last = ExitStatus::from_error(&e);
+                    }
+                }
+            }
+            "pin" | "unpin" => {
+                // pin <playlist> <artist> - <title> -> always sync it, whatever the device's budget.
+                let (Some(playlist_name), Some(info)) = (args.first(), parse_artist_title(args.get(1..).unwrap_or_default())) else {
+                    println!("Usage: {} <playlist> <artist> - <title>", cmd);
+                    last = ExitStatus::Usage;
+                    continue;
+                };
+                let pinned = cmd == "pin";
+                match cache.update_entry(playlist_name, &info, |entry| entry.pinned = pinned) {
+                    Ok(()) => println!("{} {:?} in {}", if pinned { "Pinned" } else { "Unpinned" }, info, playlist_name),
+                    Err(e) => {
+                        println!("Failed to {} {:?} in {}: {}", cmd, info, playlist_name, e);
+                        last = ExitStatus::from_error(&e);
+                    }
+                }
+            }
+            "star" | "unstar" => {
+                // star <artist> - <title> -> list it in the virtual Starred playlist, which syncs like any other.
+                let Some(info) = parse_artist_title(&args) else {
+                    println!("Usage: {} <artist> - <title>", cmd);
+                    last = ExitStatus::Usage;
+                    continue;
+                };
+                let starred = cmd == "star";
+                match cache.set_starred(&info, starred) {
+                    Ok(true) => println!("{} {:?}", if starred { "Starred" } else { "Unstarred" }, info),
+                    Ok(false) if starred => println!("Starred {:?}, it will show up in {} once it's cached", info, STARRED_PLAYLIST),
+                    Ok(false) => println!("Unstarred {:?}", info),
+                    Err(e) => {
+                        println!("Failed to {} {:?}: {}", cmd, info, e);
+                        last = ExitStatus::from_error(&e);
+                    }
+                }
+            }
+            "skip-on" => {
+                // skip-on <device> <playlist> <artist> - <title> [--remove] -> keep it in the playlist, but never sync it
+                // to that device.
+                let remove = args.contains(&"--remove");
+                args.retain(|a| *a != "--remove");
+                let (Some(device), Some(playlist_name), Some(info)) =
+                    (args.first(), args.get(1), parse_artist_title(args.get(2..).unwrap_or_default()))
+                else {
+                    println!("Usage: skip-on <device> <playlist> <artist> - <title> [--remove]");
+                    last = ExitStatus::Usage;
+                    continue;
+                };
+                let result = cache.update_entry(playlist_name, &info, |entry| {
+                    entry.skip_on.retain(|d| d != device);
+                    if !remove {
+                        entry.skip_on.push(device.to_string());
+                    }
+                });
+                match result {
+                    Ok(()) if remove => println!("{:?} in {} will sync to {} again", info, playlist_name, device),
+                    Ok(()) => println!("{:?} in {} won't sync to {}", info, playlist_name, device),
+                    Err(e) => {
+                        println!("Failed to update {:?} in {}: {}", info, playlist_name, e);
+                        last = ExitStatus::from_error(&e);
+                    }
+                }
+            }
+            "playlist" => {
+                // playlist history <name> | playlist diff <name> <snap-a> <snap-b> | playlist rollback <name> <snap>
+                let usage = "Usage: playlist history <name> | playlist diff <name> <snap-a> <snap-b> | playlist rollback <name> <snap> | playlist rename <old> <new> | playlist delete <name> | playlist migrate monolithic|per-file";
+                let snapshot_arg = |i: usize| -> Option<u64> { args.get(i)?.parse().ok() };
+                match (args.first(), args.get(1)) {
+                    (Some(&"history"), Some(name)) => {
+                        for snapshot in snapshots(name) {
+                            println!(
+                                "{:>5}  {}  {} tracks  {}",
+                                snapshot.id,
+                                snapshot.timestamp,
+                                snapshot.keys.len(),
+                                snapshot.operation
+                            );
+                        }
+                    }
+                    (Some(&"diff"), Some(name)) => {
+                        let (Some(a), Some(b)) = (snapshot_arg(2), snapshot_arg(3)) else {
+                            println!("{}", usage);
+                            last = ExitStatus::Usage;
+                            continue;
+                        };
+                        match (find_snapshot(name, a), find_snapshot(name, b)) {
+                            (Some(a), Some(b)) => {
+                                let diff = SnapshotDiff::between(&a, &b);
+                                for key in &diff.added {
+                                    println!("+ {} - {}", key.artist, key.title);
+                                }
+                                for key in &diff.removed {
+                                    println!("- {} - {}", key.artist, key.title);
+                                }
+                            }
+                            _ => {
+                                println!("No such snapshot for playlist {}", name);
+                                last = ExitStatus::Usage;
+                            }
+                        }
+                    }
+                    (Some(&"rollback"), Some(name)) => {
+                        let Some(snapshot) = snapshot_arg(2).and_then(|id| find_snapshot(name, id)) else {
+                            println!("No such snapshot for playlist {}", name);
+                            last = ExitStatus::Usage;
+                            continue;
+                        };
+                        match cache.rollback_playlist(&snapshot) {
+                            Ok(missing) => {
+                                println!("Rolled back {} to snapshot {}", name, snapshot.id);
+                                for key in missing {
+                                    println!("Dropped (no cached file): {} - {}", key.artist, key.title);
+                                }
+                            }
+                            Err(e) => {
+                                println!("Failed to roll back {}: {}", name, e);
+                                last = ExitStatus::from_error(&e);
+                            }
+                        }
+                    }
+                    (Some(&"rename"), Some(old)) => {
+                        let Some(new) = args.get(2) else {
+                            println!("{}", usage);
+                            last = ExitStatus::Usage;
+                            continue;
+                        };
+                        match cache.rename_playlist(old, new) {
+                            Ok(()) => println!("Renamed playlist {} to {}", old, new),
+                            Err(e) => {
+                                println!("Failed to rename {}: {}", old, e);
+                                last = ExitStatus::from_error(&e);
+                            }
+                        }
+                    }
+                    (Some(&"delete"), Some(name)) => match cache.delete_playlist(name) {
+                        Ok(()) => println!("Deleted playlist {}", name),
+                        Err(e) => {
+                            println!("Failed to delete {}: {}", name, e);
+                            last = ExitStatus::from_error(&e);
+                        }
+                    },
+                    (Some(&"migrate"), Some(layout)) => {
+                        let storage = match *layout {
+                            "monolithic" => PlaylistStorage::Monolithic,
+                            "per-file" => PlaylistStorage::PerFile,
+                            _ => {
+                                println!("{}", usage);
+                                last = ExitStatus::Usage;
+                                continue;
+                            }
+                        };
+                        config.playlist_storage = storage;
+                        match cache.migrate_playlists(storage).and_then(|_| config.save()) {
+                            Ok(()) => println!("Playlists now stored as {}", layout),
+                            Err(e) => {
+                                println!("Failed to migrate playlists: {}", e);
+                                last = ExitStatus::from_error(&e);
+                            }
+                        }
+                    }
+                    _ => {
+                        println!("{}", usage);
+                        last = ExitStatus::Usage;
+                    }
+                }
+            }
+            "export-xspf" => {
+                let (Some(playlist_name), Some(out)) = (args.first(), args.get(1)) else {
+                    println!("Usage: export-xspf <playlist> <out.xspf> [--relative]");
+                    last = ExitStatus::Usage;
+                    continue;
+                };
+                let location = if args.contains(&"--relative") {
+                    XspfLocation::DeviceRelative
+                } else {
+                    XspfLocation::Cache
+                };
+                match export_xspf(&cache, playlist_name, &PathBuf::from(out), location) {
+                    Ok(count) => println!("Exported {} tracks to {}", count, out),
+                    Err(e) => {
+                        println!("Failed to export {} with error: {}", playlist_name, e);
+                        last = ExitStatus::from_error(&e);
+                    }
+                }
+            }
+            "device" => {
+                // device budget <playlist> <size|none> -> cap how much of a playlist is synced to this device.
+                // device map <playlist> <path|none> -> sync a playlist to a device relative path.
+                // device parallel <n|default> -> how many copies a sync runs at once.
+                // device copy-buffer <size|default>, device preallocate <on|off|default> -> tune copies to the device.
+                // device layout <flat|playlist-dirs|artist-album> -> how tracks are arranged on the device, playlists
+                // are M3U files in the root for layouts other than playlist-dirs.
+                // device clean-junk [--dry-run] -> remove AppleDouble files, .DS_Store and the like from the device.
+                // device clean-junk-after-sync <on|off> -> also clean at the end of every sync.
+                // device extensions <ext,...|default> -> the formats the device plays, others are transcoded to mp3 on
+                // sync. Defaults to every extension the cache indexes.
+                // device case-insensitive <on|off|auto> -> whether names differing only by case are the same file on
+                // the device, auto detects it from the volume.
+                // device reindex -> reindex every directory on the device, not only those whose mtime changed.
+                if args.first() == Some(&"clean-junk") {
+                    let dry_run = args.contains(&"--dry-run");
+                    match target.clean_junk(dry_run) {
+                        Ok(report) => {
+                            report.print(dry_run);
+                            if !report.failed.is_empty() {
+                                last = ExitStatus::Failure;
+                            }
+                        }
+                        Err(e) => {
+                            println!("Failed to clean junk off the device: {}", e);
+                            last = ExitStatus::from_error(&e);
+                        }
+                    }
+                    continue;
+                }
+                if args.first() == Some(&"reindex") {
+                    match target.reindex() {
+                        Ok(summary) => println!("Reindexed device: {}, {} tracks", summary.summary(), target.index_keys().count()),
+                        Err(e) => {
+                            println!("Failed to reindex device: {}", e);
+                            last = ExitStatus::from_error(&e);
+                        }
+                    }
+                    continue;
+                }
+                let usage = "Usage: device budget <playlist> <size|none> | device map <playlist> <path|none> | device parallel <n|default> | device copy-buffer <size|default> | device preallocate <on|off|default> | device layout <flat|playlist-dirs|artist-album> | device clean-junk [--dry-run] | device clean-junk-after-sync <on|off> | device extensions <ext,...|default> | device case-insensitive <on|off|auto> | device reindex";
+                let old_layout = target.profile.layout;
+                let old_extensions = target.profile.extensions();
+                match (args.first(), args.get(1), args.get(2)) {
+                    (Some(&"layout"), Some(layout), None) => match DeviceLayout::parse(layout) {
+                        Some(layout) => target.profile.layout = layout,
+                        None => {
+                            println!("Unknown layout {}, expected flat, playlist-dirs or artist-album", layout);
+                            last = ExitStatus::Usage;
+                            continue;
+                        }
+                    },
+                    (Some(&"copy-buffer"), Some(&"default"), None) => target.profile.copy_buffer = None,
+                    (Some(&"copy-buffer"), Some(size), None) if parse_copy_buffer(size).is_some() => {
+                        target.profile.copy_buffer = Some(size.to_string());
+                    }
+                    (Some(&"copy-buffer"), Some(size), None) => {
+                        println!("Invalid copy buffer {}, expected a size up to {}", size, format_size(MAX_COPY_BUFFER));
+                        last = ExitStatus::Usage;
+                        continue;
+                    }
+                    (Some(&"preallocate"), Some(&"on"), None) => target.profile.preallocate = Some(true),
+                    (Some(&"preallocate"), Some(&"off"), None) => target.profile.preallocate = Some(false),
+                    (Some(&"preallocate"), Some(&"default"), None) => target.profile.preallocate = None,
+                    (Some(&"clean-junk-after-sync"), Some(&"on"), None) => target.profile.clean_junk_after_sync = true,
+                    (Some(&"clean-junk-after-sync"), Some(&"off"), None) => target.profile.clean_junk_after_sync = false,
+                    (Some(&"case-insensitive"), Some(&"on"), None) => target.profile.case_insensitive = Some(true),
+                    (Some(&"case-insensitive"), Some(&"off"), None) => target.profile.case_insensitive = Some(false),
+                    (Some(&"case-insensitive"), Some(&"auto"), None) => target.profile.case_insensitive = None,
+                    (Some(&"extensions"), Some(&"default"), None) => target.profile.extensions = None,
+                    (Some(&"extensions"), Some(extensions), None) => {
+                        let mut profile = target.profile.clone();
+                        profile.extensions = Some(extensions.split(',').map(str::to_string).collect());
+                        if let Err(e) = profile.validate() {
+                            println!("{}", e);
+                            last = ExitStatus::Usage;
+                            continue;
+                        }
+                        target.profile = profile;
+                    }
+                    (Some(&"parallel"), Some(&"default"), None) => target.profile.parallel_imports = None,
+                    (Some(&"parallel"), Some(n), None) => match n.parse() {
+                        Ok(n @ 1..=16) => target.profile.parallel_imports = Some(n),
+                        _ => {
+                            println!("Invalid number of parallel imports {}, expected 1 to 16", n);
+                            last = ExitStatus::Usage;
+                            continue;
+                        }
+                    },
+                    (Some(&"map"), Some(playlist_name), Some(&"none")) => {
+                        target.profile.destinations.remove(*playlist_name);
+                    }
+                    (Some(&"map"), Some(playlist_name), Some(destination)) => {
+                        let mut profile = target.profile.clone();
+                        profile.destinations.insert(playlist_name.to_string(), destination.to_string());
+                        if let Err(e) = profile.validate().and_then(|_| profile.check_destinations(cache.playlist_names())) {
+                            println!("{}", e);
+                            last = ExitStatus::Usage;
+                            continue;
+                        }
+                        target.profile = profile;
+                    }
+                    (Some(&"budget"), Some(playlist_name), Some(&"none")) => {
+                        target.profile.budgets.remove(*playlist_name);
+                    }
+                    (Some(&"budget"), Some(playlist_name), Some(size)) => match parse_size(size) {
+                        Some(bytes) => {
+                            target.profile.budgets.insert(playlist_name.to_string(), bytes);
+                        }
+                        None => {
+                            println!("Invalid size {}, expected e.g. 500M or 1G", size);
+                            last = ExitStatus::Usage;
+                            continue;
+                        }
+                    },
+                    _ => {
+                        println!("{}", usage);
+                        last = ExitStatus::Usage;
+                        continue;
+                    }
+                }
+                match target.save_profile() {
+                    Ok(()) => {
+                        let mut budgets: Vec<_> = target.profile.budgets.iter().collect();
+                        budgets.sort_by(|a, b| sort::natural_cmp(a.0, b.0));
+                        for (playlist_name, budget) in budgets {
+                            println!("{}: budget {}", playlist_name, format_size(*budget));
+                        }
+                        let mut destinations: Vec<_> = target.profile.destinations.iter().collect();
+                        destinations.sort_by(|a, b| sort::natural_cmp(a.0, b.0));
+                        for (playlist_name, destination) in destinations {
+                            println!("{}: synced to {}", playlist_name, destination);
+                        }
+                        println!("Parallel imports: {}", target.profile.parallel_imports());
+                        let copy = target.profile.copy_options();
+                        println!("Copy buffer: {}, preallocate: {}", format_size(copy.buffer_size as u64), copy.preallocate);
+                        println!("Layout: {}", target.profile.layout.name());
+                        println!("Clean junk after sync: {}", target.profile.clean_junk_after_sync);
+                        println!("Accepts: {}", target.profile.extensions().join(", "));
+                        println!(
+                            "Case-insensitive: {}{}",
+                            target.case_insensitive(),
+                            if target.profile.case_insensitive.is_none() { " (detected)" } else { "" }
+                        );
+                        // Files already on the device stay where they are, only new syncs use the new layout. The
+                        // index has to follow, since the layout decides how files are keyed and the extensions which
+                        // files are indexed at all.
+                        if (target.profile.layout != old_layout || target.profile.extensions() != old_extensions)
+                            && let Err(e) = target.reindex()
+                        {
+                            println!("Failed to reindex device: {}", e);
+                            last = ExitStatus::from_error(&e);
+                        }
+                    }
+                    Err(e) => {
+                        println!("Failed to save device profile: {}", e);
+                        last = ExitStatus::from_error(&e);
+                    }
+                }
+            }
+            "cache" => match args.first() {
+                // cache demote -> move least recently used audio to the secondary cache until the primary fits.
+                Some(&"demote") => match cache.demote_to_secondary() {
+                    Ok(demoted) => println!("Demoted {} files to the secondary cache", demoted),
+                    Err(e) => {
+                        println!("Failed to demote: {}", e);
+                        last = ExitStatus::from_error(&e);
+                    }
+                },
+                // cache gc [--older-than 90d] [--dry-run] [--include-devices] -> move cached audio no playlist
+                // references, and that isn't starred, to the trash. Audio on the attached device is kept unless
+                // --include-devices, its cache copy is what verify and repair work from.
+                Some(&"gc") => {
+                    let usage = "Usage: cache gc [--older-than 90d] [--dry-run] [--include-devices]";
+                    let older_than = args.iter().position(|a| *a == "--older-than").map(|i| args.get(i + 1).and_then(|age| parse_age(age)));
+                    let Some(older_than) = older_than.map_or(Some(None), |age| age.map(Some)) else {
+                        println!("{}", usage);
+                        last = ExitStatus::Usage;
+                        continue;
+                    };
+                    let keep: HashSet<&AudioKey> = if args.contains(&"--include-devices") {
+                        HashSet::new()
+                    } else {
+                        target.index_keys().collect()
+                    };
+                    let candidates = cache.gc_candidates(older_than, &keep);
+                    if candidates.is_empty() {
+                        println!("Nothing to collect");
+                        continue;
+                    }
+                    let mut table = Table::new(&["Artist", "Title", "Size", "Fetched", "Path"]);
+                    for candidate in &candidates {
+                        table.row(vec![
+                            candidate.key.artist.clone(),
+                            candidate.key.title.clone(),
+                            format_size(candidate.bytes),
+                            candidate.fetched_at.map(format_timestamp).unwrap_or_default(),
+                            candidate.path.display().to_string(),
+                        ]);
+                    }
+                    table.print(output);
+                    let reclaimable = format_size(candidates.iter().map(|c| c.bytes).sum());
+                    println!("{} unreferenced tracks, {} reclaimable", candidates.len(), reclaimable);
+                    if args.contains(&"--dry-run") || !confirm(&format!("Move them to the trash, freeing {}?", reclaimable)) {
+                        continue;
+                    }
+                    let mut removed = Vec::new();
+                    for candidate in &candidates {
+                        match cache.trash_audio(&candidate.key) {
+                            Ok(trashed) => removed.push(trashed),
+                            Err(e) => {
+                                println!("Failed to remove {}: {}", candidate.path.display(), e);
+                                last = ExitStatus::from_error(&e);
+                            }
+                        }
+                    }
+                    println!(
+                        "Moved {} tracks ({}) to the trash, undo puts them back",
+                        removed.len(),
+                        format_size(removed.iter().map(|t| t.bytes).sum())
+                    );
+                    record_activity(Activity::Gc { removed });
+                }
+                // cache reindex -> bring the cache index up to date with the cache directories.
+                Some(&"reindex") => {
+                    let started = Instant::now();
+                    let summary = cache.reindex();
+                    println!(
+                        "Reindexed cache in {:.2}s: {}, {} tracks",
+                        started.elapsed().as_secs_f64(),
+                        summary.summary(),
+                        cache.index_keys().count()
+                    );
+                }
+                // cache extensions [<ext,...>|default] -> show or set the extensions the cache indexes as audio, then
+                // reindex so newly included formats show up.
+                Some(&"extensions") => {
+                    match args.get(1) {
+                        None => {
+                            println!("Audio extensions: {}", audio_extensions().join(", "));
+                            continue;
+                        }
+                        Some(&"default") => config.audio_extensions = None,
+                        Some(extensions) => {
+                            let extensions = normalize_extensions(&extensions.split(',').map(str::to_string).collect::<Vec<_>>());
+                            if extensions.is_empty() {
+                                println!("Usage: cache extensions [<ext,...>|default]");
+                                last = ExitStatus::Usage;
+                                continue;
+                            }
+                            config.audio_extensions = Some(extensions);
+                        }
+                    }
+                    if let Err(e) = config.save() {
+                        println!("Failed to save config: {}", e);
+                        last = ExitStatus::from_error(&e);
+                        continue;
+                    }
+                    set_audio_extensions(config.audio_extensions.clone());
+                    println!("Audio extensions: {}", audio_extensions().join(", "));
+                    println!("Reindexed cache: {}", cache.reindex().summary());
+                    // Devices without their own extensions accept whatever the cache indexes.
+                    if target.profile.extensions.is_none()
+                        && let Err(e) = target.reindex()
+                    {
+                        println!("Failed to reindex device: {}", e);
+                        last = ExitStatus::from_error(&e);
+                    }
+                }
+                _ => {
+                    println!("Usage: cache demote | cache gc [--older-than 90d] [--dry-run] [--include-devices] | cache reindex | cache extensions [<ext,...>|default]");
+                    last = ExitStatus::Usage;
+                }
+            },
+            "undo" => {
+                // undo -> put back what the last cache gc moved to the trash.
+                let Some(record) = last_undoable() else {
+                    println!("Nothing to undo");
+                    continue;
+                };
+                let Activity::Gc { removed } = &record.activity else {
+                    continue;
+                };
+                let mut restored = 0;
+                for trashed in removed {
+                    match cache.restore_trashed(trashed) {
+                        Ok(()) => restored += 1,
+                        Err(e) => {
+                            println!("Failed to restore {}: {}", trashed.path.display(), e);
+                            last = ExitStatus::from_error(&e);
+                        }
+                    }
+                }
+                println!("Restored {} of {} tracks removed by cache gc #{}", restored, removed.len(), record.id);
+                record_activity(Activity::Undo { of: record.id, restored });
+            }
+            "history" => {
+                // history [--device <name>] [--since 7d] [--failures] -> past syncs and fetches, newest first.
+                // history show <id> -> one record in full.
+                let usage = "Usage: history [--device <name>] [--since 7d] [--failures] | history show <id>";
+                if args.first() == Some(&"show") {
+                    match args.get(1).and_then(|id| id.parse().ok()).and_then(find_activity) {
+                        Some(record) => println!("{}", serde_json::to_string_pretty(&record).unwrap()),
+                        None => {
+                            println!("{}", usage);
+                            last = ExitStatus::Usage;
+                        }
+                    }
+                    continue;
+                }
+                let flag = |name: &str| args.iter().position(|a| *a == name).map(|i| args.get(i + 1).copied());
+                let device = flag("--device");
+                let since = flag("--since").map(|age| age.and_then(parse_age));
+                if matches!(device, Some(None)) || matches!(since, Some(None)) {
+                    println!("{}", usage);
+                    last = ExitStatus::Usage;
+                    continue;
+                }
+                let (device, since) = (device.flatten(), since.flatten());
+                let failures = args.contains(&"--failures");
+
+                let mut table = Table::new(&["ID", "When", "Kind", "Device", "What", "Result"]);
+                for record in load_activity().iter().rev() {
+                    if since.is_some_and(|age| record.timestamp + age < unix_now()) || failures && !record.activity.failed() {
+                        continue;
+                    }
+                    let (kind, record_device, what, result) = match &record.activity {
+                        Activity::Sync { device, report } => (
+                            "sync",
+                            device.as_str(),
+                            report.playlist.clone(),
+                            format!(
+                                "{} tracks, {} copied, {} failed",
+                                report.tracks.len(),
+                                report.count(&SyncOutcome::Copied),
+                                report.count(&SyncOutcome::Failed(String::new()))
+                            ),
+                        ),
+                        Activity::Fetch(fetch) => (
+                            "fetch",
+                            "",
+                            fetch
+                                .key
+                                .as_ref()
+                                .map(|k| format!("{} - {}", k.artist, k.title))
+                                .or_else(|| fetch.url.clone())
+                                .unwrap_or_default(),
+                            match &fetch.error {
+                                Some(e) => format!("failed: {}", e),
+                                None => format!("{} in {:.1}s", format_size(fetch.bytes), fetch.duration_ms as f64 / 1000.0),
+                            },
+                        ),
+                        Activity::Gc { removed } => (
+                            "gc",
+                            "",
+                            format!("{} unreferenced tracks", removed.len()),
+                            format!("{} moved to the trash", format_size(removed.iter().map(|t| t.bytes).sum())),
+                        ),
+                        Activity::Undo { of, restored } => ("undo", "", format!("#{}", of), format!("{} restored", restored)),
+                    };
+                    if device.is_some_and(|device| device != record_device) {
+                        continue;
+                    }
+                    table.row(vec![
+                        record.id.to_string(),
+                        format_timestamp(record.timestamp),
+                        kind.to_string(),
+                        record_device.to_string(),
+                        what,
+                        result,
+                    ]);
+                }
+                if table.is_empty() {
+                    println!("No matching history");
+                } else {
+                    table.print(output);
+                }
+            }
+            "logs" => {
+                // logs [--tail N] [--follow] -> the end of the current log file, optionally following it.
+                let tail = match args.iter().position(|a| *a == "--tail").map(|i| args.get(i + 1).map(|n| n.parse())) {
+                    None => 50,
+                    Some(Some(Ok(tail))) => tail,
+                    Some(_) => {
+                        println!("Usage: logs [--tail N] [--follow]");
+                        last = ExitStatus::Usage;
+                        continue;
+                    }
+                };
+                if let Err(e) = print_logs(tail, args.contains(&"--follow")) {
+                    println!("Failed to read logs: {}", e);
+                    last = ExitStatus::Failure;
+                }
+            }
+            "doctor" => {
+                let checks = run_checks(Ok(&config), Some(&cache));
+                print_checks(&checks);
+                if checks.iter().any(|c| c.status == CheckStatus::Fail) {
+                    last = ExitStatus::Environment;
+                }
+            }
             "list_playlists" => {
-                for name in cache.list_playlist_names() {
+                for name in cache.playlist_names() {
                     println!("{}", name);
                 }
             }
             "show_playlist" => {
-                let playlist_name = args
-                    .get(0)
-                    .expect("Usage: show_playlist <playlist>");
+                let Some(playlist_name) = args.first() else {
+                    println!("Usage: show_playlist <playlist>");
+                    last = ExitStatus::Usage;
+                    continue;
+                };
                 match cache.search_playlist(playlist_name) {
                     Ok(playlist_contents) => {
                         for (info, location) in &playlist_contents {
                             println!("{:?}, {:?}", info, location)
                         }
                     },
-                    Err(e) => println!("Failed to show playlist {} with error: {}", playlist_name, e),
+                    Err(e) => {
+                        println!("Failed to show playlist {} with error: {}", playlist_name, e);
+                        last = ExitStatus::from_error(&e);
+                    }
                 }
             }
             "import_playlist" => {
-                let playlist_name = args
-                    .get(0)
-                    .expect("Usage: import_playlist <playlist>");
+                let Some(playlist_name) = args.first() else {
+                    println!("Usage: import_playlist <playlist>");
+                    last = ExitStatus::Usage;
+                    continue;
+                };
 
                 match cache.search_playlist(playlist_name) {
                     Ok(playlist_contents) => {
+                        let (mut failed, mut imported) = (0, 0);
                         for (info, location) in &playlist_contents {
-                            target.import(location, info, Some(PlaylistName::Named(playlist_name.to_string()))).expect("Failed to import_playlist.");
+                            if interrupt.is_cancelled() {
+                                println!("Cancelled, {} of {} imported", imported, playlist_contents.len());
+                                break;
+                            }
+                            if let Err(e) = target.import(
+                                location,
+                                info,
+                                Some(PlaylistName::Named(playlist_name.to_string())),
+                                &DestNaming::KeepSource,
+                            ) {
+                                println!("Failed to import {:?}: {}", info, e);
+                                failed += 1;
+                            } else {
+                                imported += 1;
+                            }
                         }
+                        last = if interrupt.is_cancelled() {
+                            ExitStatus::Cancelled
+                        } else {
+                            ExitStatus::from_batch(failed, playlist_contents.len())
+                        };
+                        target.refresh_index();
                     },
-                    Err(e) => println!("Failed to import_playlist {} with error: {}", playlist_name, e),
+                    Err(e) => {
+                        println!("Failed to import_playlist {} with error: {}", playlist_name, e);
+                        last = ExitStatus::from_error(&e);
+                    }
                 }
             }
-            _ => {}
+            _ => {
+                println!("Unknown command: {}", cmd);
+                last = ExitStatus::Usage;
+            }
         }
     }
 }
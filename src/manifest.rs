@@ -0,0 +1,341 @@
+// DeviceManifest -> State music-man persists on an AttachedDevice itself, so that it follows the device between hosts.
+// It lives in a hidden directory at the device root, which playlist listing skips like any other dot directory.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{create_dir_all, metadata, read_to_string, write},
+    io,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use crate::fsutil::{hash_file, path_key};
+
+pub fn manifest_dir(device_root: &Path) -> PathBuf {
+    device_root.join(".music-man")
+}
+
+// Files music-man removes from a device are moved here rather than deleted outright.
+pub fn device_trash_dir(device_root: &Path) -> PathBuf {
+    manifest_dir(device_root).join("trash")
+}
+
+/// Move a file on the device into the device's trash. Always a rename, since the trash is on the same volume.
+pub fn trash_on_device(device_root: &Path, path: &Path) -> io::Result<PathBuf> {
+    let trash = device_trash_dir(device_root);
+    create_dir_all(&trash)?;
+    let filename = path.file_name().ok_or(io::ErrorKind::InvalidInput)?;
+    let mut dest = trash.join(filename);
+    let mut suffix = 1;
+    while dest.exists() {
+        dest = trash.join(format!("{}.{}", filename.to_string_lossy(), suffix));
+        suffix += 1;
+    }
+    std::fs::rename(path, &dest)?;
+    Ok(dest)
+}
+
+fn manifest_path(device_root: &Path) -> PathBuf {
+    manifest_dir(device_root).join("manifest.json")
+}
+
+// A file hash, valid as long as the file's size and mtime are unchanged.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct CachedHash {
+    pub size: u64,
+    pub mtime: u64,
+    pub hash: String,
+}
+
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct DeviceManifest {
+    // Device relative path (compared form) -> last computed hash.
+    #[serde(default)]
+    pub hashes: HashMap<String, CachedHash>,
+    // Playlist name -> device relative paths music-man synced for it, so rotation only ever prunes our own files.
+    #[serde(default)]
+    pub synced: HashMap<String, Vec<String>>,
+    // Playlists whose files currently carry shuffle order prefixes.
+    #[serde(default)]
+    pub shuffled: Vec<String>,
+    // Device relative path (compared form) -> numbered copies a sync kept alongside it, so the next sync finds them
+    // rather than keeping yet another.
+    #[serde(default)]
+    pub kept_copies: HashMap<String, Vec<String>>,
+    // Device relative path (compared form) -> content hash of the cached file it was transcoded from, since a
+    // transcode can't be compared with its source directly.
+    #[serde(default)]
+    pub transcoded_from: HashMap<String, String>,
+    // Device relative path (compared form) -> ISRC of the cached audio it was synced from, so a re-recording cached
+    // since under the same artist and title isn't taken for the same track.
+    #[serde(default)]
+    pub isrcs: HashMap<String, String>,
+    // Whether the device's volume is case-insensitive, so paths differing only by case are the same file.
+    #[serde(skip)]
+    pub case_insensitive: bool,
+    // Playlist -> the compared forms of its synced paths, so lookups don't scan the lists.
+    #[serde(skip)]
+    synced_keys: HashMap<String, HashSet<String>>,
+}
+
+impl DeviceManifest {
+    // A missing or unreadable manifest just means we start from scratch. Paths are compared case folded when the
+    // device is case-insensitive, since a file renamed in case only is still the file we synced.
+    pub fn load(device_root: &Path, case_insensitive: bool) -> Self {
+        let manifest: Self = read_to_string(manifest_path(device_root))
+            .ok()
+            .and_then(|s| serde_json::from_str(&s).ok())
+            .unwrap_or_default();
+        // Keys written before the volume was known to be case-insensitive, or by another host, are brought into the
+        // compared form.
+        let hashes = manifest.hashes.into_iter().map(|(path, hash)| (path_key(&path, case_insensitive), hash)).collect();
+        let kept_copies =
+            manifest.kept_copies.into_iter().map(|(path, copies)| (path_key(&path, case_insensitive), copies)).collect();
+        let transcoded_from =
+            manifest.transcoded_from.into_iter().map(|(path, hash)| (path_key(&path, case_insensitive), hash)).collect();
+        let isrcs = manifest.isrcs.into_iter().map(|(path, isrc)| (path_key(&path, case_insensitive), isrc)).collect();
+        let synced_keys = manifest
+            .synced
+            .iter()
+            .map(|(playlist, paths)| {
+                (playlist.clone(), paths.iter().map(|path| path_key(path, case_insensitive)).collect())
+            })
+            .collect();
+        Self { hashes, kept_copies, transcoded_from, isrcs, case_insensitive, synced_keys, ..manifest }
+    }
+
+    // The device relative form a path is compared in.
+    fn key(&self, device_root: &Path, path: &Path) -> String {
+        path_key(&relative_path(device_root, path), self.case_insensitive)
+    }
+
+    pub fn save(&self, device_root: &Path) -> io::Result<()> {
+        create_dir_all(manifest_dir(device_root))?;
+        let manifest_json = serde_json::to_string_pretty(self)?;
+        write(manifest_path(device_root), manifest_json)
+    }
+
+    pub fn record_synced(&mut self, device_root: &Path, playlist: &str, path: &Path) {
+        let key = self.key(device_root, path);
+        if self.synced_keys.entry(playlist.to_string()).or_default().insert(key) {
+            self.synced.entry(playlist.to_string()).or_default().push(relative_path(device_root, path));
+        }
+    }
+
+    pub fn was_synced(&self, device_root: &Path, playlist: &str, path: &Path) -> bool {
+        self.synced_keys.get(playlist).is_some_and(|keys| keys.contains(&self.key(device_root, path)))
+    }
+
+    pub fn forget_synced(&mut self, device_root: &Path, playlist: &str, path: &Path) {
+        let key = self.key(device_root, path);
+        self.forget_synced_key(playlist, &key);
+        self.hashes.remove(&key);
+        self.transcoded_from.remove(&key);
+        self.isrcs.remove(&key);
+    }
+
+    // Drop a path from a playlist's synced files, by its compared form.
+    fn forget_synced_key(&mut self, playlist: &str, key: &str) {
+        if self.synced_keys.get_mut(playlist).is_some_and(|keys| keys.remove(key))
+            && let Some(synced) = self.synced.get_mut(playlist)
+        {
+            let case_insensitive = self.case_insensitive;
+            synced.retain(|p| path_key(p, case_insensitive) != key);
+        }
+    }
+
+    // Forget everything recorded about a file removed from the device.
+    pub fn forget_path(&mut self, device_root: &Path, path: &Path) {
+        let key = self.key(device_root, path);
+        let playlists: Vec<String> = self.synced_keys.keys().cloned().collect();
+        for playlist in playlists {
+            self.forget_synced_key(&playlist, &key);
+        }
+        self.hashes.remove(&key);
+        self.transcoded_from.remove(&key);
+        self.isrcs.remove(&key);
+        self.kept_copies.remove(&key);
+        let case_insensitive = self.case_insensitive;
+        for copies in self.kept_copies.values_mut() {
+            copies.retain(|p| path_key(p, case_insensitive) != key);
+        }
+    }
+
+    pub fn record_kept_copy(&mut self, device_root: &Path, original: &Path, copy: &Path) {
+        let key = self.key(device_root, original);
+        self.kept_copies.entry(key).or_default().push(relative_path(device_root, copy));
+    }
+
+    /// The copies kept alongside a device file by earlier syncs.
+    pub fn kept_copies(&self, device_root: &Path, original: &Path) -> Vec<PathBuf> {
+        self.kept_copies
+            .get(&self.key(device_root, original))
+            .into_iter()
+            .flatten()
+            .map(|copy| device_root.join(copy))
+            .collect()
+    }
+
+    // Follow a file rename on the device, keeping its synced status and cached hash. A rename in case only updates
+    // the recorded spelling.
+    pub fn rename_path(&mut self, device_root: &Path, from: &Path, to: &Path) {
+        let (from_key, to_key) = (self.key(device_root, from), self.key(device_root, to));
+        let to = relative_path(device_root, to);
+        let case_insensitive = self.case_insensitive;
+        for (playlist, keys) in &mut self.synced_keys {
+            if !keys.remove(&from_key) {
+                continue;
+            }
+            keys.insert(to_key.clone());
+            if let Some(synced) = self.synced.get_mut(playlist) {
+                for path in synced.iter_mut().filter(|p| path_key(p, case_insensitive) == from_key) {
+                    *path = to.clone();
+                }
+            }
+        }
+        if let Some(source_hash) = self.transcoded_from.remove(&from_key) {
+            self.transcoded_from.insert(to_key.clone(), source_hash);
+        }
+        if let Some(isrc) = self.isrcs.remove(&from_key) {
+            self.isrcs.insert(to_key.clone(), isrc);
+        }
+        if let Some(hash) = self.hashes.remove(&from_key) {
+            self.hashes.insert(to_key, hash);
+        }
+    }
+
+    pub fn record_transcode(&mut self, device_root: &Path, path: &Path, source_hash: String) {
+        self.transcoded_from.insert(self.key(device_root, path), source_hash);
+    }
+
+    /// The content hash of the cached file a device file was transcoded from, when it was recorded.
+    pub fn transcoded_from(&self, device_root: &Path, path: &Path) -> Option<&str> {
+        self.transcoded_from.get(&self.key(device_root, path)).map(String::as_str)
+    }
+
+    /// Remember the ISRC of the cached audio a device file was synced from, or that it had none.
+    pub fn record_isrc(&mut self, device_root: &Path, path: &Path, isrc: Option<&str>) {
+        let key = self.key(device_root, path);
+        match isrc {
+            Some(isrc) => self.isrcs.insert(key, isrc.to_string()),
+            None => self.isrcs.remove(&key),
+        };
+    }
+
+    /// The ISRC of the cached audio a device file was synced from, when it had one.
+    pub fn isrc(&self, device_root: &Path, path: &Path) -> Option<&str> {
+        self.isrcs.get(&self.key(device_root, path)).map(String::as_str)
+    }
+
+    /// Remember a hash computed elsewhere (e.g. of the copy's source) for a file just written to the device.
+    pub fn record_hash(&mut self, device_root: &Path, path: &Path, hash: String) -> io::Result<()> {
+        let (size, mtime) = size_and_mtime(path)?;
+        self.hashes.insert(self.key(device_root, path), CachedHash { size, mtime, hash });
+        Ok(())
+    }
+
+    /// Hash a file on the device, reusing the cached hash when the file's size and mtime haven't changed.
+    pub fn hash_file(&mut self, device_root: &Path, path: &Path) -> io::Result<String> {
+        let (size, mtime) = size_and_mtime(path)?;
+        let key = self.key(device_root, path);
+
+        if let Some(cached) = self.hashes.get(&key)
+            && cached.size == size
+            && cached.mtime == mtime
+        {
+            return Ok(cached.hash.clone());
+        }
+
+        let hash = hash_file(path)?;
+        self.hashes.insert(key, CachedHash { size, mtime, hash: hash.clone() });
+        Ok(hash)
+    }
+}
+
+fn size_and_mtime(path: &Path) -> io::Result<(u64, u64)> {
+    let meta = metadata(path)?;
+    let mtime = meta
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    Ok((meta.len(), mtime))
+}
+
+pub fn relative_path(device_root: &Path, path: &Path) -> String {
+    path.strip_prefix(device_root)
+        .unwrap_or(path)
+        .to_string_lossy()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("music-man-manifest-{}-{}", name, std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(dir.join("Road Trip")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn case_only_renames_keep_synced_status_and_hash() {
+        let root = device_dir("rename");
+        let (from, to) = (root.join("Road Trip/muse - uprising.mp3"), root.join("Road Trip/Muse - Uprising.mp3"));
+        std::fs::write(&from, b"audio").unwrap();
+        let mut manifest = DeviceManifest::load(&root, true);
+        manifest.record_synced(&root, "Road Trip", &from);
+        manifest.record_hash(&root, &from, "hash".to_string()).unwrap();
+
+        std::fs::rename(&from, &to).unwrap();
+        manifest.rename_path(&root, &from, &to);
+        assert!(manifest.was_synced(&root, "Road Trip", &to));
+        assert_eq!(manifest.synced["Road Trip"], vec!["Road Trip/Muse - Uprising.mp3".to_string()]);
+        assert_eq!(manifest.hash_file(&root, &to).unwrap(), "hash");
+        // Recording the other spelling again is the same file, not a second one to prune later.
+        manifest.record_synced(&root, "Road Trip", &from);
+        assert_eq!(manifest.synced["Road Trip"].len(), 1);
+
+        // Survives a save and reload.
+        manifest.save(&root).unwrap();
+        let mut manifest = DeviceManifest::load(&root, true);
+        assert!(manifest.was_synced(&root, "Road Trip", &from));
+        manifest.forget_synced(&root, "Road Trip", &from);
+        assert!(!manifest.was_synced(&root, "Road Trip", &to));
+        assert!(manifest.synced["Road Trip"].is_empty());
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn case_sensitive_volumes_keep_spellings_apart() {
+        let root = device_dir("sensitive");
+        let (lower, upper) = (root.join("Road Trip/muse - uprising.mp3"), root.join("Road Trip/Muse - Uprising.mp3"));
+        let mut manifest = DeviceManifest::load(&root, false);
+        manifest.record_synced(&root, "Road Trip", &lower);
+        assert!(!manifest.was_synced(&root, "Road Trip", &upper));
+        manifest.record_synced(&root, "Road Trip", &upper);
+        manifest.forget_path(&root, &lower);
+        assert_eq!(manifest.synced["Road Trip"], vec!["Road Trip/Muse - Uprising.mp3".to_string()]);
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn transcodes_remember_their_source_across_renames() {
+        let root = device_dir("transcode");
+        let (from, to) = (root.join("Road Trip/Muse - Uprising.mp3"), root.join("Road Trip/Muse - Uprising (2).mp3"));
+        let mut manifest = DeviceManifest::load(&root, false);
+        manifest.record_transcode(&root, &from, "flac hash".to_string());
+        manifest.save(&root).unwrap();
+        let mut manifest = DeviceManifest::load(&root, false);
+        assert_eq!(manifest.transcoded_from(&root, &from), Some("flac hash"));
+
+        manifest.rename_path(&root, &from, &to);
+        assert_eq!(manifest.transcoded_from(&root, &from), None);
+        assert_eq!(manifest.transcoded_from(&root, &to), Some("flac hash"));
+        manifest.forget_path(&root, &to);
+        assert_eq!(manifest.transcoded_from(&root, &to), None);
+        std::fs::remove_dir_all(&root).ok();
+    }
+}
@@ -0,0 +1,251 @@
+// MusicBrainz -> Artist discographies from the MusicBrainz web service, for `download-artist`. Only metadata comes from
+// here, the audio is still resolved through the source chain. MusicBrainz asks clients to keep to one request a
+// second, so requests are spaced out rather than retried.
+
+use std::{
+    io::Write,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    audio::{AudioError, AudioInfo},
+    http::get_text,
+};
+
+const API: &str = "https://musicbrainz.org/ws/2";
+const REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+// Search scores (0-100) at or above which a result is a plausible match for the name searched for.
+const PLAUSIBLE_SCORE: u32 = 90;
+// The most results MusicBrainz returns per request, more are fetched a page at a time with an offset.
+const PAGE_SIZE: usize = 100;
+// Recordings looked at to rank top tracks, at a request a second.
+const TOP_TRACK_CANDIDATES: usize = 300;
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct MbArtist {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub disambiguation: String,
+    pub country: Option<String>,
+    #[serde(default)]
+    pub score: u32,
+}
+
+impl MbArtist {
+    pub fn describe(&self) -> String {
+        let details: Vec<&str> = [self.disambiguation.as_str(), self.country.as_deref().unwrap_or_default()]
+            .into_iter()
+            .filter(|detail| !detail.is_empty())
+            .collect();
+        if details.is_empty() {
+            self.name.clone()
+        } else {
+            format!("{} ({})", self.name, details.join(", "))
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct ArtistSearch {
+    artists: Vec<MbArtist>,
+}
+
+#[derive(serde::Deserialize)]
+struct ReleaseGroup {
+    id: String,
+    title: String,
+    #[serde(rename = "first-release-date", default)]
+    first_release_date: String,
+    // Live, Compilation, Remix etc. Studio albums have none.
+    #[serde(rename = "secondary-types", default)]
+    secondary_types: Vec<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct ReleaseGroupBrowse {
+    #[serde(rename = "release-groups")]
+    release_groups: Vec<ReleaseGroup>,
+    #[serde(rename = "release-group-count", default)]
+    release_group_count: usize,
+}
+
+#[derive(serde::Deserialize)]
+struct ReleaseBrowse {
+    releases: Vec<Release>,
+}
+
+#[derive(serde::Deserialize)]
+struct Release {
+    #[serde(default)]
+    media: Vec<Medium>,
+}
+
+#[derive(serde::Deserialize)]
+struct Medium {
+    #[serde(default)]
+    tracks: Vec<Track>,
+}
+
+#[derive(serde::Deserialize)]
+struct Track {
+    position: u32,
+    title: String,
+}
+
+#[derive(serde::Deserialize)]
+struct RecordingSearch {
+    recordings: Vec<Recording>,
+    #[serde(default)]
+    count: usize,
+}
+
+#[derive(serde::Deserialize)]
+struct Recording {
+    title: String,
+    // Every release the recording is on, only counted.
+    #[serde(default)]
+    releases: Vec<serde::de::IgnoredAny>,
+}
+
+// GET an API path as JSON, at most one request per REQUEST_INTERVAL.
+fn get<T: serde::de::DeserializeOwned>(path: &str, params: &[(&str, &str)]) -> Result<T, AudioError> {
+    static LAST_REQUEST: Mutex<Option<Instant>> = Mutex::new(None);
+    let mut last = LAST_REQUEST.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Some(wait) = last.map(|at| REQUEST_INTERVAL.saturating_sub(at.elapsed())) {
+        std::thread::sleep(wait);
+    }
+    let url = format!("{}/{}", API, path);
+    let mut params = params.to_vec();
+    params.push(("fmt", "json"));
+    let body = get_text(&url, &params);
+    *last = Some(Instant::now());
+    serde_json::from_str(&body?).map_err(|e| AudioError::Unavailable(format!("Unexpected response from {}: {}", url, e)))
+}
+
+/// Artists matching a name, best match first, keeping only plausible matches.
+pub fn search_artists(name: &str) -> Result<Vec<MbArtist>, AudioError> {
+    let query = format!("artist:\"{}\"", name.replace('"', ""));
+    let search: ArtistSearch = get("artist", &[("query", &query), ("limit", "10")])?;
+    Ok(search.artists.into_iter().filter(|artist| artist.score >= PLAUSIBLE_SCORE).collect())
+}
+
+/// Every track of the artist's studio albums, oldest album first, with the album and track number filled in.
+pub fn album_tracks(artist: &MbArtist) -> Result<Vec<AudioInfo>, AudioError> {
+    let mut albums: Vec<ReleaseGroup> = Vec::new();
+    let mut offset = 0;
+    loop {
+        let (limit, offset_param) = (PAGE_SIZE.to_string(), offset.to_string());
+        let browse: ReleaseGroupBrowse = get(
+            "release-group",
+            &[("artist", &artist.id), ("type", "album"), ("limit", &limit), ("offset", &offset_param)],
+        )?;
+        offset += browse.release_groups.len();
+        let last_page = browse.release_groups.is_empty() || offset >= browse.release_group_count;
+        albums.extend(browse.release_groups.into_iter().filter(|group| group.secondary_types.is_empty()));
+        if last_page {
+            break;
+        }
+    }
+    albums.sort_by(|a, b| a.first_release_date.cmp(&b.first_release_date));
+
+    let mut tracks = Vec::new();
+    for album in albums {
+        // Any official release of the album will do for its track list.
+        let releases: ReleaseBrowse = get(
+            "release",
+            &[("release-group", &album.id), ("status", "official"), ("inc", "recordings"), ("limit", "1")],
+        )?;
+        let Some(release) = releases.releases.into_iter().next() else {
+            continue;
+        };
+        for track in release.media.into_iter().flat_map(|medium| medium.tracks) {
+            tracks.push(AudioInfo {
+                artist: Some(artist.name.clone()),
+                title: Some(track.title),
+                album: Some(album.title.clone()),
+                track_number: Some(track.position),
+                ..Default::default()
+            });
+        }
+    }
+    Ok(tracks)
+}
+
+/// Up to count of the artist's best known tracks. MusicBrainz has no play counts, so tracks are ranked by how many
+/// official releases they're on, singles, compilations and live albums included, which is where hits end up again and
+/// again.
+pub fn top_tracks(artist: &MbArtist, count: usize) -> Result<Vec<AudioInfo>, AudioError> {
+    let query = format!("arid:{} AND status:official", artist.id);
+    let mut recordings = Vec::new();
+    while recordings.len() < TOP_TRACK_CANDIDATES {
+        let (limit, offset) = (PAGE_SIZE.to_string(), recordings.len().to_string());
+        let search: RecordingSearch = get("recording", &[("query", &query), ("limit", &limit), ("offset", &offset)])?;
+        let last_page = search.recordings.is_empty() || recordings.len() + search.recordings.len() >= search.count;
+        recordings.extend(search.recordings);
+        if last_page {
+            break;
+        }
+    }
+    Ok(rank_by_releases(recordings, count)
+        .into_iter()
+        .map(|title| AudioInfo { artist: Some(artist.name.clone()), title: Some(title), ..Default::default() })
+        .collect())
+}
+
+// Titles by how many releases their recordings are on between them, most first. A song is usually several recordings,
+// e.g. the album version and a live one, so they're added up by title. Ties keep search order.
+fn rank_by_releases(recordings: Vec<Recording>, count: usize) -> Vec<String> {
+    let mut ranked: Vec<(String, usize)> = Vec::new();
+    for recording in recordings {
+        let title = recording.title.to_lowercase();
+        match ranked.iter_mut().find(|(ranked_title, _)| ranked_title.to_lowercase() == title) {
+            Some((_, releases)) => *releases += recording.releases.len(),
+            None => ranked.push((recording.title, recording.releases.len())),
+        }
+    }
+    ranked.sort_by_key(|(_, releases)| std::cmp::Reverse(*releases));
+    ranked.into_iter().take(count).map(|(title, _)| title).collect()
+}
+
+/// Pick between several artists sharing a name on the terminal. None if cancelled.
+pub fn choose_artist(artists: &[MbArtist]) -> Option<&MbArtist> {
+    for (i, artist) in artists.iter().enumerate() {
+        println!("{:>2}. {}", i + 1, artist.describe());
+    }
+    loop {
+        print!("Several artists match, which one? [1-{}, c to cancel] ", artists.len());
+        std::io::stdout().flush().ok();
+        let mut answer = String::new();
+        if std::io::stdin().read_line(&mut answer).unwrap_or(0) == 0 {
+            return None;
+        }
+        match answer.trim() {
+            "c" | "" => return None,
+            n => match n.parse::<usize>() {
+                Ok(n) if (1..=artists.len()).contains(&n) => return Some(&artists[n - 1]),
+                _ => println!("Please answer 1-{} or c.", artists.len()),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn top_tracks_are_the_ones_on_the_most_releases() {
+        let search: RecordingSearch = serde_json::from_str(
+            r#"{"count": 4, "recordings": [
+                {"title": "Deep Cut", "releases": [{"id": "a"}]},
+                {"title": "Hit", "releases": [{"id": "a"}, {"id": "b"}]},
+                {"title": "hit", "releases": [{"id": "c"}, {"id": "d"}]},
+                {"title": "Single", "releases": [{"id": "e"}, {"id": "f"}, {"id": "g"}]}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(rank_by_releases(search.recordings, 2), ["Hit", "Single"]);
+    }
+}
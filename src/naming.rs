@@ -0,0 +1,107 @@
+// DestNaming -> What a file is called where it lands. The cache wants "Artist - Title.ext", device syncs may want a
+// track number or shuffle prefix, raw URL downloads want the video title, and copies between places usually just keep
+// the name they already have. Sources and targets take one alongside the destination, and sanitize the name for the
+// filesystem they write to.
+
+use std::path::Path;
+
+use crate::audio::{AudioInfo, nfc};
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum DestNaming {
+    /// The source's own name, for yt-dlp the video title.
+    #[default]
+    KeepSource,
+    /// "Artist - Title", falling back to the source's name when either is missing.
+    FromInfo,
+    /// The title alone, falling back to the source's name when it's missing. For directories that already say who the
+    /// artist is.
+    Title,
+    /// A prefix, e.g. "017 - ", in front of whatever name base gives.
+    Prefixed { prefix: String, base: Box<DestNaming> },
+}
+
+/// Which characters a filesystem refuses in a name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FilenameRules {
+    // The cache and other local directories. Names still avoid Windows' reserved characters, since cache filenames
+    // are how tracks are keyed and end up copied to devices.
+    Local,
+    // FAT and exFAT devices, which also reject control characters and trailing dots or spaces, and count their 255
+    // character limit in UTF-16 units. Names are written NFC, whatever form the source used.
+    Fat,
+}
+
+const MAX_NAME_LEN: usize = 255;
+
+impl FilenameRules {
+    fn name_len(self, name: &str) -> usize {
+        match self {
+            FilenameRules::Local => name.len(),
+            FilenameRules::Fat => name.encode_utf16().count(),
+        }
+    }
+}
+
+/// Make one path component safe to write under the given rules. Doesn't limit the length, see DestNaming::file_name.
+pub fn sanitize(s: &str, rules: FilenameRules) -> String {
+    let replaced: String = s
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() && rules == FilenameRules::Fat => '_',
+            _ => c,
+        })
+        .collect();
+    match rules {
+        FilenameRules::Local => replaced,
+        FilenameRules::Fat => nfc(&replaced),
+    }
+}
+
+impl DestNaming {
+    // The destination stem, with source_stem standing in for the source's own name.
+    fn stem(&self, info: &AudioInfo, source_stem: &str, rules: FilenameRules) -> String {
+        match self {
+            DestNaming::KeepSource => source_stem.to_string(),
+            DestNaming::FromInfo => match (&info.artist, &info.title) {
+                (Some(artist), Some(title)) => format!("{} - {}", sanitize(artist, rules), sanitize(title, rules)),
+                _ => source_stem.to_string(),
+            },
+            DestNaming::Title => match &info.title {
+                Some(title) => sanitize(title, rules),
+                None => source_stem.to_string(),
+            },
+            DestNaming::Prefixed { prefix, base } => {
+                format!("{}{}", sanitize(prefix, rules), base.stem(info, source_stem, rules))
+            }
+        }
+    }
+
+    /// The filename for a copy of source, keeping its extension. The stem is trimmed to fit the filesystem's length
+    /// limit, and for FAT of trailing dots and spaces. None if source has no file name.
+    pub fn file_name(&self, info: &AudioInfo, source: &Path, rules: FilenameRules) -> Option<String> {
+        let source_stem = sanitize(&source.file_stem()?.to_string_lossy(), rules);
+        let mut stem = self.stem(info, &source_stem, rules);
+        let ext = source.extension().map(|ext| format!(".{}", sanitize(&ext.to_string_lossy(), rules)));
+        let ext = ext.unwrap_or_default();
+
+        while !stem.is_empty() && rules.name_len(&stem) + rules.name_len(&ext) > MAX_NAME_LEN {
+            stem.pop();
+        }
+        if rules == FilenameRules::Fat {
+            stem.truncate(stem.trim_end_matches(['.', ' ']).len());
+        }
+        if stem.is_empty() {
+            stem = "_".to_string();
+        }
+        Some(format!("{}{}", stem, ext))
+    }
+
+    /// A yt-dlp output template (relative to the output directory) naming the download, before yt-dlp knows its
+    /// title or extension. yt-dlp sanitizes the title it substitutes itself, and a literal % has to be doubled.
+    pub fn ytdlp_template(&self, info: &AudioInfo, rules: FilenameRules) -> String {
+        let stem = self.stem(info, "\0", rules).replace('%', "%%").replace('\0', "%(title)s");
+        format!("{}.%(ext)s", stem)
+    }
+}
@@ -0,0 +1,251 @@
+// PlaylistStore -> Persistence for LocalCache's playlists, in one of two layouts:
+// 1. Monolithic -> every playlist in a single playlists.json.
+// 2. PerFile -> each playlist in its own pretty-printed file under data_dir/playlists/, with a manifest mapping
+//    playlist names to filenames. Only changed playlists are rewritten, so the directory diffs cleanly under git.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{DirEntry, create_dir_all, read_dir, read_to_string, remove_file, rename, write},
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::{
+    audio::AudioInfo,
+    cache::{get_data_dir, playlist_cache},
+    config::PlaylistStorage,
+};
+
+// Playlist name -> tracks, shared so listings can hand them out without copying.
+pub type Playlists = HashMap<String, Arc<Vec<AudioInfo>>>;
+
+pub fn per_file_playlists_dir() -> PathBuf {
+    get_data_dir().join("playlists")
+}
+
+const MANIFEST_FILENAME: &str = "manifest.json";
+
+// A single playlist file in the PerFile layout.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct PlaylistFile {
+    name: String,
+    tracks: Vec<AudioInfo>,
+}
+
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+struct Manifest {
+    // Playlist name -> filename, ordered so the manifest itself diffs cleanly.
+    playlists: BTreeMap<String, String>,
+}
+
+#[derive(Clone, Debug)]
+pub enum PlaylistStore {
+    Monolithic(PathBuf),
+    PerFile(PathBuf),
+}
+
+impl PlaylistStore {
+    pub fn from_storage(storage: PlaylistStorage) -> Self {
+        match storage {
+            PlaylistStorage::Monolithic => PlaylistStore::Monolithic(playlist_cache()),
+            PlaylistStorage::PerFile => PlaylistStore::PerFile(per_file_playlists_dir()),
+        }
+    }
+
+    // Unreadable playlist files are skipped, rather than losing every playlist.
+    pub fn load(&self) -> Playlists {
+        match self {
+            PlaylistStore::Monolithic(path) => read_to_string(path)
+                .ok()
+                .and_then(|s| serde_json::from_str::<HashMap<String, Vec<AudioInfo>>>(&s).ok())
+                .map(|playlists| playlists.into_iter().map(|(name, tracks)| (name, Arc::new(tracks))).collect())
+                .unwrap_or_default(),
+            // Load every playlist file, not just those in the manifest, so files added by e.g. a git merge appear.
+            PlaylistStore::PerFile(dir) => read_dir(dir)
+                .map(|entries| {
+                    entries
+                        .filter_map(|e| e.ok())
+                        .filter(|e| is_stored_json(e) && e.file_name() != MANIFEST_FILENAME)
+                        .filter_map(|e| read_to_string(e.path()).ok())
+                        .filter_map(|s| serde_json::from_str::<PlaylistFile>(&s).ok())
+                        .map(|file| (file.name, Arc::new(file.tracks)))
+                        .collect()
+                })
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Strictly parse the stored playlists, returning how many there are, or a description of the first problem.
+    /// load() skips anything unreadable, so this is how we tell a healthy store from a silently broken one.
+    pub fn validate(&self) -> Result<usize, String> {
+        match self {
+            PlaylistStore::Monolithic(path) => match read_to_string(path) {
+                Ok(s) => serde_json::from_str::<HashMap<String, Vec<AudioInfo>>>(&s)
+                    .map(|playlists| playlists.len())
+                    .map_err(|e| format!("{}: {}", path.display(), e)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+                Err(e) => Err(format!("{}: {}", path.display(), e)),
+            },
+            PlaylistStore::PerFile(dir) => {
+                let Ok(entries) = read_dir(dir) else {
+                    return Ok(0);
+                };
+                let mut count = 0;
+                for entry in entries.filter_map(|e| e.ok()).filter(is_stored_json) {
+                    let path = entry.path();
+                    let contents = read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+                    if entry.file_name() == MANIFEST_FILENAME {
+                        serde_json::from_str::<Manifest>(&contents).map_err(|e| format!("{}: {}", path.display(), e))?;
+                    } else {
+                        serde_json::from_str::<PlaylistFile>(&contents)
+                            .map_err(|e| format!("{}: {}", path.display(), e))?;
+                        count += 1;
+                    }
+                }
+                Ok(count)
+            }
+        }
+    }
+
+    /// Persist the named playlists. Monolithic storage always rewrites everything, PerFile only rewrites (or removes,
+    /// when no longer present) the changed playlists' files.
+    pub fn save(&self, playlists: &Playlists, changed: &[&str]) -> io::Result<()> {
+        match self {
+            PlaylistStore::Monolithic(path) => {
+                let ordered: BTreeMap<_, _> = playlists.iter().map(|(name, tracks)| (name, tracks.as_slice())).collect();
+                write(path, serde_json::to_string_pretty(&ordered)?)
+            }
+            PlaylistStore::PerFile(dir) => {
+                create_dir_all(dir)?;
+                let mut manifest = load_manifest(dir);
+                for name in changed {
+                    match playlists.get(*name) {
+                        Some(tracks) => {
+                            let filename = manifest_filename(&mut manifest, name);
+                            write_playlist_file(&dir.join(filename), name, tracks)?;
+                        }
+                        None => {
+                            if let Some(filename) = manifest.playlists.remove(*name) {
+                                remove_existing(&dir.join(filename))?;
+                            }
+                        }
+                    }
+                }
+                save_manifest(dir, &manifest)
+            }
+        }
+    }
+
+    /// Persist a playlist rename, moving its file in the PerFile layout.
+    pub fn rename(&self, playlists: &Playlists, old: &str, new: &str) -> io::Result<()> {
+        match self {
+            PlaylistStore::Monolithic(_) => self.save(playlists, &[old, new]),
+            PlaylistStore::PerFile(dir) => {
+                create_dir_all(dir)?;
+                let mut manifest = load_manifest(dir);
+                let old_filename = manifest.playlists.remove(old);
+                let new_filename = manifest_filename(&mut manifest, new);
+                if let Some(old_filename) = old_filename {
+                    rename(dir.join(old_filename), dir.join(&new_filename))?;
+                }
+                if let Some(tracks) = playlists.get(new) {
+                    write_playlist_file(&dir.join(new_filename), new, tracks)?;
+                }
+                save_manifest(dir, &manifest)
+            }
+        }
+    }
+
+    /// Write every playlist to another store, e.g. when switching layouts.
+    pub fn migrate_to(&self, other: &PlaylistStore) -> io::Result<Playlists> {
+        let playlists = self.load();
+        let names: Vec<&str> = playlists.keys().map(|s| s.as_str()).collect();
+        other.save(&playlists, &names)?;
+        Ok(playlists)
+    }
+}
+
+// Whether a directory entry is one of the store's files, a playlist or the manifest. Anything else, like a .git
+// directory or Finder's .DS_Store, isn't ours to read.
+fn is_stored_json(entry: &DirEntry) -> bool {
+    entry.file_type().is_ok_and(|t| t.is_file()) && entry.path().extension().is_some_and(|ext| ext == "json")
+}
+
+fn write_playlist_file(path: &Path, name: &str, tracks: &[AudioInfo]) -> io::Result<()> {
+    let file = PlaylistFile {
+        name: name.to_string(),
+        tracks: tracks.to_vec(),
+    };
+    let mut contents = serde_json::to_string_pretty(&file)?;
+    contents.push('\n');
+    write(path, contents)
+}
+
+fn remove_existing(path: &Path) -> io::Result<()> {
+    match remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn load_manifest(dir: &Path) -> Manifest {
+    read_to_string(dir.join(MANIFEST_FILENAME))
+        .ok()
+        .and_then(|s| serde_json::from_str(&s).ok())
+        .unwrap_or_default()
+}
+
+fn save_manifest(dir: &Path, manifest: &Manifest) -> io::Result<()> {
+    let mut contents = serde_json::to_string_pretty(manifest)?;
+    contents.push('\n');
+    write(dir.join(MANIFEST_FILENAME), contents)
+}
+
+// The filename for a playlist, assigning a new unique sanitized one if the playlist has none yet.
+fn manifest_filename(manifest: &mut Manifest, name: &str) -> String {
+    if let Some(filename) = manifest.playlists.get(name) {
+        return filename.clone();
+    }
+
+    let base: String = name
+        .chars()
+        .map(|c| if c.is_alphanumeric() || c == '-' || c == '_' || c == ' ' { c } else { '_' })
+        .collect();
+    let base = base.trim().to_string();
+    let base = if base.is_empty() { "playlist".to_string() } else { base };
+
+    let mut filename = format!("{}.json", base);
+    let mut suffix = 2;
+    while filename.eq_ignore_ascii_case(MANIFEST_FILENAME) || manifest.playlists.values().any(|f| f.eq_ignore_ascii_case(&filename)) {
+        filename = format!("{} ({}).json", base, suffix);
+        suffix += 1;
+    }
+    manifest.playlists.insert(name.to_string(), filename.clone());
+    filename
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn playlists_never_share_a_file_with_the_manifest() {
+        let mut manifest = Manifest::default();
+        assert_eq!(manifest_filename(&mut manifest, "Manifest"), "Manifest (2).json");
+        assert_eq!(manifest_filename(&mut manifest, "MANIFEST"), "MANIFEST (3).json");
+        assert_eq!(manifest_filename(&mut manifest, "Road"), "Road.json");
+
+        let dir = std::env::temp_dir().join(format!("music-man-playlist-store-{}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        let store = PlaylistStore::PerFile(dir.clone());
+        let playlists = Playlists::from([("Manifest".to_string(), Arc::new(vec![AudioInfo::from_filename("Muse - Uprising.mp3")]))]);
+        store.save(&playlists, &["Manifest"]).unwrap();
+        // Version control and the OS keep their own files alongside.
+        std::fs::create_dir_all(dir.join(".git")).unwrap();
+        std::fs::write(dir.join(".DS_Store"), b"\0\0\0\x01Bud1").unwrap();
+        assert_eq!(store.load()["Manifest"].len(), 1);
+        assert_eq!(store.validate(), Ok(1));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,87 @@
+// Preview -> Hear a short clip of each search result before committing to a full download. Clips are downloaded to
+// their own staging directory and removed as soon as there's an answer, so they never reach the cache.
+
+use std::{
+    io::Write,
+    path::Path,
+    process::Command,
+};
+
+use crate::{
+    audio::{AudioError, AudioInfo},
+    cache::StagingDir,
+    source::YtDlpSource,
+};
+
+pub const DEFAULT_PLAYER: &str = "afplay";
+// Search results offered for preview.
+pub const PREVIEW_CANDIDATES: usize = 5;
+
+// What to do after hearing a preview.
+enum PreviewChoice {
+    Download,
+    Next,
+    // Preview another candidate, by index.
+    Preview(usize),
+    Abort,
+}
+
+/// Preview candidates, starting with the first, until one is picked for download. None if aborted.
+pub fn choose_by_preview(
+    source: &YtDlpSource,
+    candidates: &[(AudioInfo, String)],
+    player: &str,
+) -> Result<Option<AudioInfo>, AudioError> {
+    let mut current = 0;
+    loop {
+        let (info, title) = &candidates[current];
+        println!("Previewing {}/{}: {}", current + 1, candidates.len(), title);
+        let staging = StagingDir::new("preview")?;
+        let clip = source.preview(info, staging.path())?;
+        if let Err(e) = play(player, &clip) {
+            println!("Couldn't play the preview: {}", e);
+        }
+        let choice = prompt_preview(candidates.len());
+        drop(staging);
+        match choice {
+            PreviewChoice::Download => return Ok(Some(info.clone())),
+            PreviewChoice::Next => current = (current + 1) % candidates.len(),
+            PreviewChoice::Preview(index) => current = index,
+            PreviewChoice::Abort => return Ok(None),
+        }
+    }
+}
+
+// Play a clip, waiting for it to finish. The player may carry its own arguments, e.g. "mpv --no-video".
+fn play(player: &str, clip: &Path) -> Result<(), AudioError> {
+    let mut words = player.split_whitespace();
+    let program = words.next().ok_or_else(|| AudioError::Config("player is empty".to_string()))?;
+    let status = Command::new(program).args(words).arg(clip).status()?;
+    if !status.success() {
+        return Err(AudioError::ExportFailed(format!("{} exited with status: {}", program, status)));
+    }
+    Ok(())
+}
+
+fn prompt_preview(candidates: usize) -> PreviewChoice {
+    loop {
+        print!("[d]ownload, [n]ext candidate, [p] <1-{}> preview that one, [a]bort? ", candidates);
+        std::io::stdout().flush().ok();
+        let mut answer = String::new();
+        if std::io::stdin().read_line(&mut answer).unwrap_or(0) == 0 {
+            // Input closed, never download something nobody agreed to.
+            return PreviewChoice::Abort;
+        }
+        let answer = answer.trim().to_lowercase();
+        match answer.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["d" | "download"] => return PreviewChoice::Download,
+            ["n" | "next"] => return PreviewChoice::Next,
+            ["a" | "abort"] => return PreviewChoice::Abort,
+            ["p" | "preview", n] => match n.parse::<usize>() {
+                Ok(n) if (1..=candidates).contains(&n) => return PreviewChoice::Preview(n - 1),
+                _ => println!("Pick a candidate from 1 to {}.", candidates),
+            },
+            _ => println!("Please answer d, n, p <number> or a."),
+        }
+    }
+}
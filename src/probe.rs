@@ -0,0 +1,136 @@
+// Probe -> Technical details about an audio file (codec, bitrate, sample rate, duration) read with ffprobe.
+// Probing means spawning a process per file, so results are persisted in the data dir keyed by path, and reused until
+// the file's size or mtime changes.
+
+use std::{
+    collections::HashMap,
+    fs::{metadata, read_to_string, write},
+    path::{Path, PathBuf},
+    process::Command,
+    time::UNIX_EPOCH,
+};
+
+use serde_json::Value;
+
+use crate::{audio::AudioError, cache::get_data_dir};
+
+pub fn probe_cache() -> PathBuf {
+    get_data_dir().join("probes.json")
+}
+
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct AudioProbe {
+    pub codec: Option<String>,
+    pub bitrate_kbps: Option<u32>,
+    pub sample_rate: Option<u32>,
+    pub duration_secs: Option<u32>,
+}
+
+impl AudioProbe {
+    pub fn is_lossless(&self) -> bool {
+        self.codec.as_deref().is_some_and(|codec| {
+            matches!(codec, "flac" | "alac" | "ape" | "wavpack" | "tta") || codec.starts_with("pcm_")
+        })
+    }
+
+    fn parse(json: &str) -> Option<Self> {
+        let value: Value = serde_json::from_str(json).ok()?;
+        let stream = value
+            .get("streams")?
+            .as_array()?
+            .iter()
+            .find(|s| s.get("codec_type").and_then(Value::as_str) == Some("audio"))?;
+        let format = value.get("format");
+        // ffprobe reports numbers as strings.
+        let number = |v: Option<&Value>, field: &str| {
+            v?.get(field)?.as_str()?.parse::<f64>().ok()
+        };
+
+        Some(Self {
+            codec: stream.get("codec_name").and_then(Value::as_str).map(str::to_string),
+            bitrate_kbps: number(Some(stream), "bit_rate")
+                .or_else(|| number(format, "bit_rate"))
+                .map(|bps| (bps / 1000.0).round() as u32),
+            sample_rate: number(Some(stream), "sample_rate").map(|r| r as u32),
+            duration_secs: number(format, "duration")
+                .or_else(|| number(Some(stream), "duration"))
+                .map(|d| d.round() as u32),
+        })
+    }
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+struct ProbeEntry {
+    size: u64,
+    mtime: u64,
+    probe: AudioProbe,
+}
+
+// Persisted probe results, path -> probe.
+#[derive(Debug, Default)]
+pub struct ProbeCache {
+    entries: HashMap<PathBuf, ProbeEntry>,
+    dirty: bool,
+}
+
+impl ProbeCache {
+    pub fn load() -> Self {
+        Self {
+            entries: read_to_string(probe_cache())
+                .ok()
+                .and_then(|s| serde_json::from_str(&s).ok())
+                .unwrap_or_default(),
+            dirty: false,
+        }
+    }
+
+    pub fn save(&mut self) -> std::io::Result<()> {
+        if self.dirty {
+            write(probe_cache(), serde_json::to_string(&self.entries)?)?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Probe a file, only running ffprobe when we have no result for the file's current size and mtime.
+    pub fn probe(&mut self, path: &Path) -> Result<AudioProbe, AudioError> {
+        let meta = metadata(path)?;
+        let size = meta.len();
+        let mtime = meta
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        if let Some(entry) = self.entries.get(path)
+            && entry.size == size
+            && entry.mtime == mtime
+        {
+            return Ok(entry.probe.clone());
+        }
+
+        let probe = run_ffprobe(path)?;
+        self.entries.insert(
+            path.to_path_buf(),
+            ProbeEntry { size, mtime, probe: probe.clone() },
+        );
+        self.dirty = true;
+        Ok(probe)
+    }
+}
+
+fn run_ffprobe(path: &Path) -> Result<AudioProbe, AudioError> {
+    let output = Command::new("ffprobe")
+        .args(["-v", "error", "-print_format", "json", "-show_format", "-show_streams"])
+        .arg(path)
+        .output()?;
+    if !output.status.success() {
+        return Err(AudioError::ExportFailed(format!(
+            "ffprobe failed on {}: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    AudioProbe::parse(&String::from_utf8_lossy(&output.stdout)).ok_or(AudioError::MissingInfo)
+}
@@ -0,0 +1,140 @@
+// Process -> Running user configured shell commands (command sources, hooks) and yt-dlp searches with a timeout, so a
+// hung command can't hang music-man with it, and stopping them (and yt-dlp) when cancelled.
+
+use std::{
+    io::Read,
+    process::{Child, Command, ExitStatus},
+    sync::{Arc, Mutex, PoisonError},
+    thread::{JoinHandle, sleep},
+    time::{Duration, Instant},
+};
+
+use crate::{audio::AudioError, cancel::CancelToken};
+
+// How long piped output may stay open after the command exits, e.g. held by something it left running in the
+// background, before we stop waiting for the rest of it.
+const PIPE_CLOSE_GRACE: Duration = Duration::from_secs(1);
+
+/// A `sh -c` command in its own process group, so a timeout takes down everything it started, not just sh.
+pub fn shell_command(command_line: &str) -> Command {
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(command_line);
+    own_process_group(&mut command);
+    command
+}
+
+/// Start a command in its own process group. It can then be killed along with everything it started, and Ctrl-C in
+/// the terminal reaches only us, so we decide how it's stopped.
+pub fn own_process_group(command: &mut Command) {
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(command, 0);
+    #[cfg(not(unix))]
+    let _ = command;
+}
+
+/// Kill a process group started with own_process_group, by its leader's pid.
+pub fn kill_process_group(pid: u32) {
+    #[cfg(unix)]
+    unsafe {
+        libc::kill(-(pid as libc::pid_t), libc::SIGKILL);
+    }
+    #[cfg(not(unix))]
+    let _ = pid;
+}
+
+pub struct CommandOutput {
+    pub status: ExitStatus,
+    // Whatever was piped, empty for inherited streams.
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// Run a command, killing it once it's taken longer than timeout or is cancelled. Piped output is drained while
+/// waiting, so a chatty command can't block on a full pipe.
+pub fn run_with_timeout(
+    command: &mut Command,
+    name: &str,
+    timeout: Duration,
+    cancel: &CancelToken,
+) -> Result<CommandOutput, AudioError> {
+    let mut child = command.spawn()?;
+    let stdout = drain(child.stdout.take());
+    let stderr = drain(child.stderr.take());
+
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if cancel.is_cancelled() {
+            kill_group(&mut child);
+            return Err(AudioError::Cancelled);
+        }
+        if started.elapsed() > timeout {
+            kill_group(&mut child);
+            return Err(AudioError::ExportFailed(format!("{} timed out after {}s", name, timeout.as_secs())));
+        }
+        sleep(Duration::from_millis(100));
+    };
+    let closed_by = Instant::now() + PIPE_CLOSE_GRACE;
+    Ok(CommandOutput { status, stdout: stdout.collect(closed_by), stderr: stderr.collect(closed_by) })
+}
+
+// A piped stream being read on its own thread, with what's been read so far.
+struct Drain {
+    output: Arc<Mutex<Vec<u8>>>,
+    reader: JoinHandle<()>,
+}
+
+impl Drain {
+    // Everything read once the pipe closes, or whatever was read by the deadline if something else still holds it
+    // open. The reader is then left to finish whenever that does.
+    fn collect(self, deadline: Instant) -> String {
+        while !self.reader.is_finished() && Instant::now() < deadline {
+            sleep(Duration::from_millis(10));
+        }
+        let output = self.output.lock().unwrap_or_else(PoisonError::into_inner);
+        String::from_utf8_lossy(&output).into_owned()
+    }
+}
+
+fn drain(pipe: Option<impl Read + Send + 'static>) -> Drain {
+    let output = Arc::new(Mutex::new(Vec::new()));
+    let read_into = Arc::clone(&output);
+    let reader = std::thread::spawn(move || {
+        let Some(mut pipe) = pipe else {
+            return;
+        };
+        let mut buf = [0; 8192];
+        while let Ok(read) = pipe.read(&mut buf)
+            && read > 0
+        {
+            read_into.lock().unwrap_or_else(PoisonError::into_inner).extend_from_slice(&buf[..read]);
+        }
+    });
+    Drain { output, reader }
+}
+
+fn kill_group(child: &mut Child) {
+    kill_process_group(child.id());
+    child.kill().ok();
+    child.wait().ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Stdio;
+
+    #[cfg(unix)]
+    #[test]
+    fn output_is_returned_while_a_background_process_holds_the_pipe() {
+        let mut command = shell_command("sleep 5 & echo done");
+        command.stdout(Stdio::piped()).stderr(Stdio::piped());
+        let started = Instant::now();
+        let output = run_with_timeout(&mut command, "test", Duration::from_secs(10), &CancelToken::new()).unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, "done\n");
+        assert!(started.elapsed() < Duration::from_secs(4));
+    }
+}
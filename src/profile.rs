@@ -0,0 +1,227 @@
+// DeviceProfile -> Per-device settings, stored in the device's manifest area so they travel with the device.
+
+use std::{
+    collections::HashMap,
+    fs::{create_dir_all, read_to_string, write},
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    audio::{AudioError, audio_extensions, nfc, normalize_extensions},
+    fsutil::{CopyOptions, parse_size},
+    junk::DEFAULT_JUNK_PATTERNS,
+    layout::DeviceLayout,
+    manifest::manifest_dir,
+};
+
+pub const DEFAULT_PARALLEL_IMPORTS: usize = 2;
+
+// Each copy allocates its buffer up front, once per parallel import, so anything past this only risks running out of
+// memory for no faster copies.
+pub const MAX_COPY_BUFFER: u64 = 64 * 1024 * 1024;
+
+/// A copy buffer size, e.g. "4M", between a byte and MAX_COPY_BUFFER.
+pub fn parse_copy_buffer(size: &str) -> Option<u64> {
+    parse_size(size).filter(|size| (1..=MAX_COPY_BUFFER).contains(size))
+}
+
+fn profile_path(device_root: &Path) -> PathBuf {
+    manifest_dir(device_root).join("profile.json")
+}
+
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct DeviceProfile {
+    // Playlist name -> max bytes synced, keeping the most recently added tracks.
+    pub budgets: HashMap<String, u64>,
+    // Playlist name -> device relative directory, for playlists that shouldn't land in <root>/<playlist name>.
+    pub destinations: HashMap<String, String>,
+    // Copies run at once during a sync, cheap flash can get slower with more.
+    pub parallel_imports: Option<usize>,
+    // Chunk size for copies to the device, e.g. "4M", defaults to CopyOptions::removable.
+    pub copy_buffer: Option<String>,
+    // Size files up front before copying them to the device.
+    pub preallocate: Option<bool>,
+    // How tracks are arranged on the device, playlist directories unless set.
+    pub layout: DeviceLayout,
+    // Names `device clean-junk` removes, replacing DEFAULT_JUNK_PATTERNS.
+    pub junk_patterns: Option<Vec<String>>,
+    // Clean junk off the device at the end of every sync.
+    pub clean_junk_after_sync: bool,
+    // Extensions the device's player plays, e.g. ["mp3", "m4a"]. Cached audio in any other format is transcoded to
+    // mp3 on sync. Defaults to every extension the cache indexes.
+    pub extensions: Option<Vec<String>>,
+    // Whether names differing only by case are the same file on the device, detected from the volume unless set.
+    pub case_insensitive: Option<bool>,
+}
+
+impl DeviceProfile {
+    /// Load the device's profile, a device without one gets the default profile. A profile that exists but is
+    /// unreadable or inconsistent is an error, rather than silently syncing to the wrong places.
+    pub fn load(device_root: &Path) -> Result<Self, AudioError> {
+        let path = profile_path(device_root);
+        let profile: Self = match read_to_string(&path) {
+            Ok(s) => serde_json::from_str(&s)
+                .map_err(|e| AudioError::Config(format!("{}: {}", path.display(), e)))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Self::default(),
+            Err(e) => return Err(e.into()),
+        };
+        profile.validate()?;
+        Ok(profile)
+    }
+
+    /// Destinations must stay inside the device, and no two playlists may share one. Devices are commonly
+    /// FAT formatted, so destinations differing only by case are the same folder.
+    pub fn validate(&self) -> Result<(), AudioError> {
+        if let Some(buffer) = &self.copy_buffer
+            && parse_copy_buffer(buffer).is_none()
+        {
+            return Err(AudioError::Config(format!("Invalid copy_buffer: {}, expected up to 64M", buffer)));
+        }
+        // A pattern that's nothing but wildcards would match every file on the device.
+        for pattern in self.junk_patterns.iter().flatten() {
+            if pattern.trim_matches('*').is_empty() || pattern.contains('/') {
+                return Err(AudioError::Config(format!("Invalid junk pattern: {:?}", pattern)));
+            }
+        }
+        if let Some(extensions) = &self.extensions
+            && normalize_extensions(extensions).is_empty()
+        {
+            return Err(AudioError::Config("A device must accept at least one extension".to_string()));
+        }
+        for (playlist, destination) in &self.destinations {
+            let dest = Path::new(destination);
+            if dest.is_absolute() || dest.components().any(|c| matches!(c, std::path::Component::ParentDir)) {
+                return Err(AudioError::Config(format!(
+                    "Destination {} for playlist {} must be relative to the device root",
+                    destination, playlist
+                )));
+            }
+        }
+        self.check_destinations(std::iter::empty())
+    }
+
+    /// No two of the mapped playlists and the given unmapped ones may share a directory on the device, nor may one's
+    /// be inside another's, e.g. a playlist mapped to "Road/Live" when the unmapped playlist "Road" syncs to "Road".
+    pub fn check_destinations<'a>(&'a self, playlists: impl IntoIterator<Item = &'a str>) -> Result<(), AudioError> {
+        let mut dirs: Vec<(String, &str)> = Vec::new();
+        let playlists = self.destinations.keys().map(String::as_str).chain(playlists);
+        for playlist in playlists {
+            let dir = normalize_destination(&self.playlist_destination(playlist).to_string_lossy());
+            if dirs.iter().any(|(_, other)| *other == playlist) {
+                continue;
+            }
+            for (other_dir, other) in &dirs {
+                let (inner, outer) = if dir.len() >= other_dir.len() { (&dir, other_dir) } else { (other_dir, &dir) };
+                if inner == outer || inner.strip_prefix(outer.as_str()).is_some_and(|rest| rest.starts_with('/')) {
+                    return Err(AudioError::Config(format!(
+                        "Playlists {} and {} would sync to {} and {} on the device, which overlap",
+                        other,
+                        playlist,
+                        self.playlist_destination(other).display(),
+                        self.playlist_destination(playlist).display()
+                    )));
+                }
+            }
+            dirs.push((dir, playlist));
+        }
+        Ok(())
+    }
+
+    pub fn copy_options(&self) -> CopyOptions {
+        let removable = CopyOptions::removable();
+        CopyOptions {
+            buffer_size: self.copy_buffer.as_deref().and_then(parse_copy_buffer).map_or(removable.buffer_size, |size| size as usize),
+            preallocate: self.preallocate.unwrap_or(removable.preallocate),
+            ..removable
+        }
+    }
+
+    pub fn junk_patterns(&self) -> Vec<String> {
+        match &self.junk_patterns {
+            Some(patterns) => patterns.clone(),
+            None => DEFAULT_JUNK_PATTERNS.iter().map(|pattern| pattern.to_string()).collect(),
+        }
+    }
+
+    /// Extensions the device accepts, lowercase and without dots.
+    pub fn extensions(&self) -> Vec<String> {
+        match &self.extensions {
+            Some(extensions) => normalize_extensions(extensions),
+            None => audio_extensions(),
+        }
+    }
+
+    pub fn parallel_imports(&self) -> usize {
+        self.parallel_imports.unwrap_or(DEFAULT_PARALLEL_IMPORTS).max(1)
+    }
+
+    /// Device relative directory a playlist is synced to.
+    pub fn playlist_destination(&self, playlist: &str) -> PathBuf {
+        match self.destinations.get(playlist) {
+            Some(destination) => PathBuf::from(destination.trim_matches('/')),
+            None => PathBuf::from(playlist),
+        }
+    }
+
+    /// Whether a device relative directory is the destination of some mapped playlist, or holds one deeper down, e.g.
+    /// "MUSIC" when a playlist is mapped to "MUSIC/Podcasts".
+    pub fn is_mapped_destination(&self, dir: &str) -> bool {
+        let dir = normalize_destination(dir);
+        self.destinations.values().map(|d| normalize_destination(d)).any(|destination| {
+            destination == dir || destination.strip_prefix(dir.as_str()).is_some_and(|rest| rest.starts_with('/'))
+        })
+    }
+
+    pub fn save(&self, device_root: &Path) -> io::Result<()> {
+        create_dir_all(manifest_dir(device_root))?;
+        write(profile_path(device_root), serde_json::to_string_pretty(self)?)
+    }
+}
+
+// Devices are commonly FAT formatted, so compared case folded, and NFC since the name may have come from macOS.
+fn normalize_destination(destination: &str) -> String {
+    let components: Vec<_> = destination.split(['/', '\\']).filter(|c| !c.is_empty() && *c != ".").collect();
+    nfc(&components.join("/")).to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn copy_buffers_past_the_maximum_are_rejected() {
+        assert_eq!(parse_copy_buffer("4M"), Some(4 * 1024 * 1024));
+        assert_eq!(parse_copy_buffer("64M"), Some(MAX_COPY_BUFFER));
+        assert_eq!(parse_copy_buffer("10G"), None);
+        assert_eq!(parse_copy_buffer("0"), None);
+
+        let profile = DeviceProfile { copy_buffer: Some("10G".to_string()), ..Default::default() };
+        assert!(profile.validate().is_err());
+        assert_eq!(profile.copy_options().buffer_size, CopyOptions::removable().buffer_size);
+    }
+
+    #[test]
+    fn destinations_are_compared_as_whole_paths() {
+        let profile = DeviceProfile {
+            destinations: HashMap::from([("Podcasts".to_string(), "MUSIC//Podcasts/".to_string())]),
+            ..Default::default()
+        };
+        assert!(profile.is_mapped_destination("music/podcasts"));
+        // Holds the mapped playlist, so it isn't a playlist of its own.
+        assert!(profile.is_mapped_destination("MUSIC"));
+        assert!(!profile.is_mapped_destination("Podcasts"));
+        assert!(!profile.is_mapped_destination("MUS"));
+        assert!(profile.check_destinations(["Road", "Podcasts"]).is_ok());
+        // The unmapped playlist "Music" syncs to the directory holding Podcasts.
+        assert!(profile.check_destinations(["Music"]).is_err());
+
+        let mut nested = profile.clone();
+        nested.destinations.insert("Live".to_string(), "music/podcasts/live".to_string());
+        assert!(nested.validate().is_err());
+        let mut shared = profile;
+        shared.destinations.insert("Road".to_string(), "Music/./Podcasts".to_string());
+        assert!(shared.validate().is_err());
+    }
+}
@@ -0,0 +1,479 @@
+// Remote indexes -> Playlists on a Subsonic server, Spotify, or Last.fm, listed through AudioIndex so `missing` can
+// compare them with the cache. Only metadata comes from them, the audio is still resolved through the source chain.
+// Each is configured by its own table in config.toml, e.g. [subsonic] with url, user and password.
+
+use std::{
+    borrow::Cow,
+    sync::{Arc, OnceLock},
+};
+
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::{
+    audio::{AudioError, AudioInfo, Playlist, PlaylistName},
+    config::Config,
+    http::{get_text, get_text_with_headers, post_form},
+    index::AudioIndex,
+};
+
+fn unexpected(service: &str, e: impl std::fmt::Display) -> AudioError {
+    AudioError::Unavailable(format!("Unexpected response from {}: {}", service, e))
+}
+
+fn parse<T: DeserializeOwned>(service: &str, value: Value) -> Result<T, AudioError> {
+    serde_json::from_value(value).map_err(|e| unexpected(service, e))
+}
+
+/// The remote index called name, built from its table in config.toml, or None when name isn't a remote index.
+pub fn remote_index(config: &Config, name: &str) -> Option<Result<Box<dyn AudioIndex>, AudioError>> {
+    let missing = |table: &str| AudioError::Config(format!("No [{}] table in config.toml", table));
+    let index: Result<Box<dyn AudioIndex>, AudioError> = match name {
+        "subsonic" => config.subsonic.clone().map(|c| Box::new(SubsonicIndex::new(c)) as _).ok_or_else(|| missing(name)),
+        "spotify" => config.spotify.clone().map(|c| Box::new(SpotifyIndex::new(c)) as _).ok_or_else(|| missing(name)),
+        "lastfm" => config.lastfm.clone().map(|c| Box::new(LastFmIndex::new(c)) as _).ok_or_else(|| missing(name)),
+        _ => return None,
+    };
+    Some(index)
+}
+
+// Every remote index lists playlists by name, fetching each one's tracks only when asked for it.
+fn list_by_name(index: &dyn AudioIndex) -> Result<Vec<Playlist>, AudioError> {
+    index
+        .list_playlist_names()?
+        .into_iter()
+        .map(|name| {
+            let audio = Arc::new(index.get_playlist(&name)?.into_owned());
+            Ok(Playlist { name, audio })
+        })
+        .collect()
+}
+
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct SubsonicConfig {
+    // Server root, e.g. "https://music.example.com", for Subsonic or anything speaking its API, like Navidrome.
+    pub url: String,
+    pub user: String,
+    pub password: String,
+}
+
+// Subsonic API version asked for, the oldest with everything used here.
+const SUBSONIC_API_VERSION: &str = "1.13.0";
+
+pub struct SubsonicIndex {
+    config: SubsonicConfig,
+}
+
+#[derive(serde::Deserialize)]
+struct SubsonicPlaylists {
+    #[serde(default)]
+    playlist: Vec<SubsonicPlaylist>,
+}
+
+#[derive(serde::Deserialize)]
+struct SubsonicPlaylist {
+    id: String,
+    name: String,
+}
+
+#[derive(serde::Deserialize)]
+struct SubsonicEntries {
+    #[serde(default)]
+    entry: Vec<SubsonicSong>,
+}
+
+#[derive(serde::Deserialize)]
+struct SubsonicSong {
+    title: Option<String>,
+    artist: Option<String>,
+    album: Option<String>,
+    track: Option<u32>,
+    duration: Option<u32>,
+}
+
+impl From<SubsonicSong> for AudioInfo {
+    fn from(song: SubsonicSong) -> Self {
+        AudioInfo {
+            artist: song.artist,
+            title: song.title,
+            album: song.album,
+            track_number: song.track,
+            duration_secs: song.duration,
+            ..Default::default()
+        }
+    }
+}
+
+// The body of a Subsonic JSON response under key, or the error the server gave instead.
+fn subsonic_body(body: &str, key: &str) -> Result<Value, AudioError> {
+    let mut response: Value = serde_json::from_str(body).map_err(|e| unexpected("Subsonic", e))?;
+    let response = response["subsonic-response"].take();
+    if response["status"] != "ok" {
+        let message = response["error"]["message"].as_str().unwrap_or("request failed");
+        return Err(AudioError::Unavailable(format!("Subsonic: {}", message)));
+    }
+    Ok(response[key].clone())
+}
+
+impl SubsonicIndex {
+    pub fn new(config: SubsonicConfig) -> Self {
+        Self { config }
+    }
+
+    fn get(&self, method: &str, key: &str, params: &[(&str, &str)]) -> Result<Value, AudioError> {
+        let url = format!("{}/rest/{}.view", self.config.url.trim_end_matches('/'), method);
+        // The hex encoded form of the password, which every server accepts, unlike salted tokens.
+        let password: String = self.config.password.bytes().map(|b| format!("{:02x}", b)).collect();
+        let password = format!("enc:{}", password);
+        let mut all = vec![
+            ("u", self.config.user.as_str()),
+            ("p", password.as_str()),
+            ("v", SUBSONIC_API_VERSION),
+            ("c", "music-man"),
+            ("f", "json"),
+        ];
+        all.extend_from_slice(params);
+        subsonic_body(&get_text(&url, &all)?, key)
+    }
+
+    fn playlists(&self) -> Result<Vec<SubsonicPlaylist>, AudioError> {
+        let playlists: SubsonicPlaylists = parse("Subsonic", self.get("getPlaylists", "playlists", &[])?)?;
+        Ok(playlists.playlist)
+    }
+}
+
+impl AudioIndex for SubsonicIndex {
+    fn name(&self) -> &str {
+        "Subsonic"
+    }
+
+    fn list_playlists(&self) -> Result<Vec<Playlist>, AudioError> {
+        list_by_name(self)
+    }
+
+    fn list_playlist_names(&self) -> Result<Vec<PlaylistName>, AudioError> {
+        Ok(self.playlists()?.into_iter().map(|playlist| PlaylistName::Named(playlist.name)).collect())
+    }
+
+    fn get_playlist(&self, name: &PlaylistName) -> Result<Cow<'_, [AudioInfo]>, AudioError> {
+        let playlist = self
+            .playlists()?
+            .into_iter()
+            .find(|playlist| playlist.name == name.disp_name())
+            .ok_or(AudioError::NotFound)?;
+        let entries: SubsonicEntries = parse("Subsonic", self.get("getPlaylist", "playlist", &[("id", &playlist.id)])?)?;
+        Ok(Cow::Owned(entries.entry.into_iter().map(AudioInfo::from).collect()))
+    }
+}
+
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct SpotifyConfig {
+    // An app's credentials from the Spotify developer dashboard, which can read public playlists.
+    pub client_id: String,
+    pub client_secret: String,
+    // Whose public playlists are listed and can be named. Any public playlist can be named by its URL or ID.
+    pub user: Option<String>,
+}
+
+const SPOTIFY_API: &str = "https://api.spotify.com/v1";
+const SPOTIFY_TOKEN_URL: &str = "https://accounts.spotify.com/api/token";
+// Only the fields the tracks are built from.
+const SPOTIFY_TRACK_FIELDS: &str = "next,items(track(type,name,artists(name),album(name),track_number,duration_ms,external_ids(isrc)))";
+
+pub struct SpotifyIndex {
+    config: SpotifyConfig,
+    // Fetched on first use, it outlasts any one command.
+    token: OnceLock<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct SpotifyToken {
+    access_token: String,
+}
+
+#[derive(serde::Deserialize)]
+struct SpotifyPage<T> {
+    items: Vec<T>,
+    // The next page's full URL.
+    next: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct SpotifyPlaylist {
+    id: String,
+    name: String,
+}
+
+#[derive(serde::Deserialize)]
+struct SpotifyItem {
+    // None for tracks since removed from Spotify.
+    track: Option<SpotifyTrack>,
+}
+
+#[derive(serde::Deserialize)]
+struct SpotifyTrack {
+    // "track", or "episode" for podcasts, which aren't music to cache.
+    #[serde(rename = "type", default)]
+    kind: String,
+    name: String,
+    #[serde(default)]
+    artists: Vec<SpotifyNamed>,
+    album: Option<SpotifyNamed>,
+    track_number: Option<u32>,
+    duration_ms: Option<u64>,
+    external_ids: Option<SpotifyExternalIds>,
+}
+
+#[derive(serde::Deserialize)]
+struct SpotifyNamed {
+    name: String,
+}
+
+#[derive(serde::Deserialize)]
+struct SpotifyExternalIds {
+    isrc: Option<String>,
+}
+
+impl SpotifyTrack {
+    fn into_info(self) -> Option<AudioInfo> {
+        if self.kind != "track" {
+            return None;
+        }
+        let artists: Vec<String> = self.artists.into_iter().map(|artist| artist.name).collect();
+        Some(AudioInfo {
+            artist: (!artists.is_empty()).then(|| artists.join(", ")),
+            artists,
+            title: Some(self.name),
+            album: self.album.map(|album| album.name),
+            track_number: self.track_number,
+            duration_secs: self.duration_ms.map(|ms| (ms / 1000) as u32),
+            isrc: self.external_ids.and_then(|ids| ids.isrc),
+            ..Default::default()
+        })
+    }
+}
+
+/// The playlist ID in a Spotify playlist URL or URI, or the name itself when it's already an ID.
+pub fn spotify_playlist_id(name: &str) -> Option<&str> {
+    let id = if let Some((_, id)) = name.split_once("playlist/") {
+        id.split(['?', '/', '#']).next().unwrap_or(id)
+    } else if let Some(id) = name.strip_prefix("spotify:playlist:") {
+        id
+    } else {
+        name
+    };
+    (id.len() == 22 && id.chars().all(|c| c.is_ascii_alphanumeric())).then_some(id)
+}
+
+impl SpotifyIndex {
+    pub fn new(config: SpotifyConfig) -> Self {
+        Self { config, token: OnceLock::new() }
+    }
+
+    fn token(&self) -> Result<&str, AudioError> {
+        if let Some(token) = self.token.get() {
+            return Ok(token);
+        }
+        let credentials = format!("{}:{}", self.config.client_id, self.config.client_secret);
+        let body = post_form(SPOTIFY_TOKEN_URL, &[("grant_type", "client_credentials")], Some(&credentials))?;
+        let token: SpotifyToken = serde_json::from_str(&body).map_err(|e| unexpected("Spotify", e))?;
+        Ok(self.token.get_or_init(|| token.access_token))
+    }
+
+    // Every page of a paged endpoint, following each page's next URL.
+    fn get_all<T: DeserializeOwned>(&self, url: &str, params: &[(&str, &str)]) -> Result<Vec<T>, AudioError> {
+        let headers = [format!("Authorization: Bearer {}", self.token()?)];
+        let mut items = Vec::new();
+        let mut page: SpotifyPage<T> = parse("Spotify", parse_json(&get_text_with_headers(url, params, &headers)?)?)?;
+        loop {
+            items.append(&mut page.items);
+            let Some(next) = page.next.take() else {
+                return Ok(items);
+            };
+            // The next URL carries its own query parameters.
+            page = parse("Spotify", parse_json(&get_text_with_headers(&next, &[], &headers)?)?)?;
+        }
+    }
+
+    fn user_playlists(&self) -> Result<Vec<SpotifyPlaylist>, AudioError> {
+        let Some(user) = &self.config.user else {
+            return Err(AudioError::Config(
+                "Set user in [spotify] to list playlists, or name a playlist by its URL".to_string(),
+            ));
+        };
+        self.get_all(&format!("{}/users/{}/playlists", SPOTIFY_API, user), &[("limit", "50")])
+    }
+}
+
+fn parse_json(body: &str) -> Result<Value, AudioError> {
+    serde_json::from_str(body).map_err(|e| unexpected("Spotify", e))
+}
+
+impl AudioIndex for SpotifyIndex {
+    fn name(&self) -> &str {
+        "Spotify"
+    }
+
+    fn list_playlists(&self) -> Result<Vec<Playlist>, AudioError> {
+        list_by_name(self)
+    }
+
+    fn list_playlist_names(&self) -> Result<Vec<PlaylistName>, AudioError> {
+        Ok(self.user_playlists()?.into_iter().map(|playlist| PlaylistName::Named(playlist.name)).collect())
+    }
+
+    fn get_playlist(&self, name: &PlaylistName) -> Result<Cow<'_, [AudioInfo]>, AudioError> {
+        let id = match spotify_playlist_id(name.disp_name()) {
+            Some(id) => id.to_string(),
+            None => {
+                self.user_playlists()?
+                    .into_iter()
+                    .find(|playlist| playlist.name == name.disp_name())
+                    .ok_or(AudioError::NotFound)?
+                    .id
+            }
+        };
+        let url = format!("{}/playlists/{}/tracks", SPOTIFY_API, id);
+        let items: Vec<SpotifyItem> = self.get_all(&url, &[("limit", "100"), ("fields", SPOTIFY_TRACK_FIELDS)])?;
+        Ok(Cow::Owned(items.into_iter().filter_map(|item| item.track?.into_info()).collect()))
+    }
+}
+
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct LastFmConfig {
+    pub api_key: String,
+    pub user: String,
+}
+
+const LASTFM_API: &str = "https://ws.audioscrobbler.com/2.0/";
+// Last.fm has no playlists, these stand in for them.
+const LASTFM_LOVED: &str = "Loved";
+const LASTFM_TOP: &str = "Top";
+// How many of the user's most played tracks make up Top.
+const LASTFM_TOP_TRACKS: &str = "100";
+
+pub struct LastFmIndex {
+    config: LastFmConfig,
+}
+
+#[derive(serde::Deserialize)]
+struct LastFmTracks {
+    #[serde(default)]
+    track: Vec<LastFmTrack>,
+    #[serde(rename = "@attr")]
+    attr: Option<LastFmPages>,
+}
+
+#[derive(serde::Deserialize)]
+struct LastFmTrack {
+    name: String,
+    artist: SpotifyNamed,
+}
+
+// Last.fm gives its numbers as strings.
+#[derive(serde::Deserialize)]
+struct LastFmPages {
+    #[serde(rename = "totalPages")]
+    total_pages: String,
+}
+
+impl LastFmIndex {
+    pub fn new(config: LastFmConfig) -> Self {
+        Self { config }
+    }
+
+    // One page of a user.get* method's tracks, under key, and how many pages there are.
+    fn page(&self, method: &str, key: &str, params: &[(&str, &str)]) -> Result<(Vec<AudioInfo>, usize), AudioError> {
+        let mut all = vec![
+            ("method", method),
+            ("user", self.config.user.as_str()),
+            ("api_key", self.config.api_key.as_str()),
+            ("format", "json"),
+        ];
+        all.extend_from_slice(params);
+        let mut response: Value = serde_json::from_str(&get_text(LASTFM_API, &all)?).map_err(|e| unexpected("Last.fm", e))?;
+        if let Some(message) = response["message"].as_str().filter(|_| response.get("error").is_some()) {
+            return Err(AudioError::Unavailable(format!("Last.fm: {}", message)));
+        }
+        let tracks: LastFmTracks = parse("Last.fm", response[key].take())?;
+        let pages = tracks.attr.and_then(|attr| attr.total_pages.parse().ok()).unwrap_or(1);
+        let tracks = tracks
+            .track
+            .into_iter()
+            .map(|track| AudioInfo { artist: Some(track.artist.name), title: Some(track.name), ..Default::default() })
+            .collect();
+        Ok((tracks, pages))
+    }
+}
+
+impl AudioIndex for LastFmIndex {
+    fn name(&self) -> &str {
+        "Last.fm"
+    }
+
+    fn list_playlists(&self) -> Result<Vec<Playlist>, AudioError> {
+        list_by_name(self)
+    }
+
+    fn list_playlist_names(&self) -> Result<Vec<PlaylistName>, AudioError> {
+        Ok(vec![PlaylistName::Named(LASTFM_LOVED.to_string()), PlaylistName::Named(LASTFM_TOP.to_string())])
+    }
+
+    fn get_playlist(&self, name: &PlaylistName) -> Result<Cow<'_, [AudioInfo]>, AudioError> {
+        match name.disp_name() {
+            LASTFM_LOVED => {
+                let (mut tracks, pages) = self.page("user.getlovedtracks", "lovedtracks", &[("limit", "200")])?;
+                for page in 2..=pages {
+                    let page = page.to_string();
+                    tracks.extend(self.page("user.getlovedtracks", "lovedtracks", &[("limit", "200"), ("page", &page)])?.0);
+                }
+                Ok(Cow::Owned(tracks))
+            }
+            LASTFM_TOP => {
+                let params = [("limit", LASTFM_TOP_TRACKS), ("period", "overall")];
+                Ok(Cow::Owned(self.page("user.gettoptracks", "toptracks", &params)?.0))
+            }
+            _ => Err(AudioError::NotFound),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remote_playlists_become_audio_info() {
+        let body = r#"{"subsonic-response": {"status": "ok", "playlist": {"id": "7", "name": "Road", "entry": [
+            {"id": "1", "title": "Uprising", "artist": "Muse", "album": "The Resistance", "track": 1, "duration": 305}
+        ]}}}"#;
+        let entries: SubsonicEntries = parse("Subsonic", subsonic_body(body, "playlist").unwrap()).unwrap();
+        let info = AudioInfo::from(entries.entry.into_iter().next().unwrap());
+        assert_eq!((info.artist.as_deref(), info.title.as_deref()), (Some("Muse"), Some("Uprising")));
+        assert_eq!((info.track_number, info.duration_secs), (Some(1), Some(305)));
+        let failed = r#"{"subsonic-response": {"status": "failed", "error": {"code": 40, "message": "Wrong username or password"}}}"#;
+        assert!(subsonic_body(failed, "playlist").unwrap_err().to_string().contains("Wrong username or password"));
+
+        let page = r#"{"next": null, "items": [
+            {"track": {"type": "track", "name": "Work", "artists": [{"name": "Rihanna"}, {"name": "Drake"}],
+                "album": {"name": "Anti"}, "track_number": 4, "duration_ms": 219320, "external_ids": {"isrc": "QMJMT1600001"}}},
+            {"track": {"type": "episode", "name": "A podcast"}},
+            {"track": null}
+        ]}"#;
+        let page: SpotifyPage<SpotifyItem> = parse("Spotify", parse_json(page).unwrap()).unwrap();
+        let tracks: Vec<_> = page.items.into_iter().filter_map(|item| item.track?.into_info()).collect();
+        assert_eq!(tracks.len(), 1);
+        assert_eq!(tracks[0].artist.as_deref(), Some("Rihanna, Drake"));
+        assert_eq!(tracks[0].artists, ["Rihanna", "Drake"]);
+        assert_eq!((tracks[0].isrc.as_deref(), tracks[0].duration_secs), (Some("QMJMT1600001"), Some(219)));
+    }
+
+    #[test]
+    fn spotify_playlists_are_named_by_url_uri_or_id() {
+        let id = "37i9dQZF1DXcBWIGoYBM5M";
+        assert_eq!(spotify_playlist_id(&format!("https://open.spotify.com/playlist/{}?si=abc", id)), Some(id));
+        assert_eq!(spotify_playlist_id(&format!("spotify:playlist:{}", id)), Some(id));
+        assert_eq!(spotify_playlist_id(id), Some(id));
+        assert_eq!(spotify_playlist_id("Road Trip"), None);
+    }
+}
@@ -0,0 +1,662 @@
+// Reports built by cross-referencing indexes by AudioKey. These only ever compare in-memory maps that the cache and
+// devices already maintain (and the device manifest, loaded by the caller), so they are cheap to build and never touch
+// the disk.
+
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    path::PathBuf,
+};
+
+use crate::{
+    audio::{AudioError, AudioInfo, AudioKey, AudioLocation, PlaylistName, nfc},
+    cache::LocalCache,
+    device::AttachedDevice,
+    fsutil::format_size,
+    manifest::DeviceManifest,
+    fuzzy,
+    index::AudioIndex,
+    probe::{AudioProbe, ProbeCache},
+    sidecar::Sidecar,
+    sort,
+    table::{OutputOptions, Table, format_duration},
+};
+
+// Indexes that can be included in a cross-index report.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IndexKind {
+    Cache,
+    Device,
+}
+
+impl IndexKind {
+    /// Parse a comma separated list of index names e.g. "cache,device".
+    pub fn parse_list(s: &str) -> Result<Vec<Self>, AudioError> {
+        s.split(',')
+            .map(|name| match name.trim().to_lowercase().as_str() {
+                "cache" => Ok(IndexKind::Cache),
+                "device" => Ok(IndexKind::Device),
+                other => Err(AudioError::Unavailable(format!("Unknown index: {}", other))),
+            })
+            .collect()
+    }
+}
+
+// Where each track lives across the cache, the device, and playlist entries.
+#[derive(Debug, Default, serde::Serialize)]
+pub struct DupesReport {
+    pub cache_only: Vec<AudioKey>,
+    pub device_only: Vec<AudioKey>,
+    pub both: Vec<AudioKey>,
+    // In the cache and on the device under the same key, but the device's copy was synced from audio with another ISRC
+    // than the cache has now, e.g. a re-recording downloaded since. Kept apart rather than counted as both.
+    pub other_recording: Vec<AudioKey>,
+    // Referenced by a playlist, but with no file in any of the compared indexes.
+    pub playlist_only: Vec<AudioKey>,
+    // Cached under different keys but with the same ISRC, so probably the same recording twice. Matching is by ISRC
+    // only, the same key with different ISRCs is a re-recording and not a duplicate.
+    pub same_isrc: Vec<IsrcDuplicate>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct IsrcDuplicate {
+    pub isrc: String,
+    pub keys: Vec<AudioKey>,
+}
+
+impl DupesReport {
+    pub fn build(cache: &LocalCache, device: &AttachedDevice, manifest: &DeviceManifest, across: &[IndexKind]) -> Self {
+        let cache_keys: BTreeSet<&AudioKey> = if across.contains(&IndexKind::Cache) {
+            cache.index_keys().collect()
+        } else {
+            BTreeSet::new()
+        };
+        let device_keys: BTreeSet<&AudioKey> = if across.contains(&IndexKind::Device) {
+            device.index_keys().collect()
+        } else {
+            BTreeSet::new()
+        };
+        let playlist_keys: BTreeSet<AudioKey> = cache
+            .playlist_entries()
+            .filter_map(AudioKey::from_info)
+            .collect();
+        let mut cached_isrcs: HashMap<&AudioKey, Vec<&str>> = HashMap::new();
+        for (isrc, key) in cache.cached_isrcs() {
+            cached_isrcs.entry(key).or_default().push(isrc);
+        }
+        let device_isrc = |key: &AudioKey| match device.location(key) {
+            Some(AudioLocation::LocalPath(path)) => manifest.isrc(&device.path, path),
+            _ => None,
+        };
+        let (both, other_recording): (Vec<AudioKey>, Vec<AudioKey>) =
+            cache_keys.intersection(&device_keys).map(|k| (*k).clone()).partition(|key| {
+                !different_recording(cached_isrcs.get(key).map_or(&[], Vec::as_slice), device_isrc(key))
+            });
+
+        let mut report = Self {
+            cache_only: cache_keys.difference(&device_keys).map(|k| (*k).clone()).collect(),
+            device_only: device_keys.difference(&cache_keys).map(|k| (*k).clone()).collect(),
+            both,
+            other_recording,
+            playlist_only: playlist_keys
+                .into_iter()
+                .filter(|k| !cache_keys.contains(k) && !device_keys.contains(k))
+                .collect(),
+            same_isrc: if across.contains(&IndexKind::Cache) {
+                cache.isrc_conflicts().into_iter().map(|(isrc, keys)| IsrcDuplicate { isrc, keys }).collect()
+            } else {
+                Vec::new()
+            },
+        };
+        for keys in [
+            &mut report.cache_only,
+            &mut report.device_only,
+            &mut report.both,
+            &mut report.other_recording,
+            &mut report.playlist_only,
+        ] {
+            keys.sort_by(sort::key_cmp);
+        }
+        report
+    }
+
+    pub fn print(&self, detailed: bool, output: OutputOptions) {
+        let categories = [
+            ("Cache only", &self.cache_only),
+            ("Device only", &self.device_only),
+            ("Cache and device", &self.both),
+            ("Cache and device, different recordings (ISRC)", &self.other_recording),
+            ("Playlists only (no file)", &self.playlist_only),
+        ];
+        if detailed {
+            let mut table = Table::new(&["Category", "Artist", "Title"]);
+            for (label, keys) in categories {
+                for key in keys {
+                    table.row(vec![label.to_string(), key.artist.clone(), key.title.clone()]);
+                }
+            }
+            table.print(output);
+        }
+        let mut counts = Table::new(&["Category", "Tracks"]);
+        for (label, keys) in categories {
+            counts.row(vec![label.to_string(), keys.len().to_string()]);
+        }
+        counts.row(vec!["Same ISRC in cache (probable duplicate)".to_string(), self.same_isrc.len().to_string()]);
+        counts.print(output);
+        // Always listed, unlike the categories above these are worth acting on.
+        if !self.same_isrc.is_empty() {
+            let mut table = Table::new(&["ISRC", "Artist", "Title"]);
+            for duplicate in &self.same_isrc {
+                for key in &duplicate.keys {
+                    table.row(vec![duplicate.isrc.clone(), key.artist.clone(), key.title.clone()]);
+                }
+            }
+            table.print(output);
+        }
+    }
+}
+
+// Whether a device file is a different recording from the cached audio with its key: both sides know their ISRC, and
+// the device's isn't one of the cache's.
+fn different_recording(cached: &[&str], device: Option<&str>) -> bool {
+    device.is_some_and(|isrc| !cached.is_empty() && !cached.contains(&isrc))
+}
+
+// Bitrate below which lossy audio is reported as low quality.
+pub const DEFAULT_MIN_KBPS: u32 = 160;
+
+#[derive(Debug, serde::Serialize)]
+pub struct QualityEntry {
+    pub key: AudioKey,
+    pub path: PathBuf,
+    pub probe: AudioProbe,
+}
+
+// Cached audio below the quality threshold, worst first.
+#[derive(Debug, Default, serde::Serialize)]
+pub struct QualityReport {
+    pub min_kbps: u32,
+    pub flagged: Vec<QualityEntry>,
+    // Files ffprobe couldn't read at all, these are likely corrupt.
+    pub unreadable: Vec<(AudioKey, String)>,
+}
+
+impl QualityReport {
+    /// Probe the whole cache, or just one playlist's audio, flagging lossy audio below min_kbps.
+    pub fn build(
+        cache: &LocalCache,
+        probes: &mut ProbeCache,
+        playlist: Option<&str>,
+        min_kbps: u32,
+    ) -> Result<Self, AudioError> {
+        let selected: Vec<(AudioKey, PathBuf)> = match playlist {
+            Some(name) => cache
+                .playlist_tracks(name)
+                .ok_or(AudioError::NotFound)?
+                .iter()
+                .filter_map(|info| {
+                    let key = AudioKey::from_info(info)?;
+                    match cache.search(info).ok()? {
+                        AudioLocation::LocalPath(path) => Some((key, path)),
+                        AudioLocation::RemoteUrl(_) => None,
+                    }
+                })
+                .collect(),
+            None => cache.indexed().map(|(k, p)| (k.clone(), p.clone())).collect(),
+        };
+
+        let mut report = Self {
+            min_kbps,
+            ..Default::default()
+        };
+        for (key, path) in selected {
+            match probes.probe(&path) {
+                Ok(probe) => {
+                    let low = !probe.is_lossless() && probe.bitrate_kbps.is_none_or(|kbps| kbps < min_kbps);
+                    if low {
+                        report.flagged.push(QualityEntry { key, path, probe });
+                    }
+                }
+                Err(e) => report.unreadable.push((key, e.to_string())),
+            }
+        }
+        report.flagged.sort_by(|a, b| {
+            a.probe
+                .bitrate_kbps
+                .unwrap_or_default()
+                .cmp(&b.probe.bitrate_kbps.unwrap_or_default())
+                .then_with(|| sort::key_cmp(&a.key, &b.key))
+        });
+        report.unreadable.sort_by(|a, b| sort::key_cmp(&a.0, &b.0));
+        Ok(report)
+    }
+
+    // The flagged audio as AudioInfo, for handing to LocalCache::flag.
+    pub fn flagged_info(&self) -> impl Iterator<Item = AudioInfo> {
+        self.flagged
+            .iter()
+            .map(|e| &e.key)
+            .chain(self.unreadable.iter().map(|(key, _)| key))
+            .map(|key| AudioInfo {
+                artist: Some(key.artist.clone()),
+                title: Some(key.title.clone()),
+                ..Default::default()
+            })
+    }
+
+    pub fn print(&self, output: OutputOptions) {
+        let mut table = Table::new(&["kbps", "Codec", "Hz", "Artist", "Title"]);
+        for entry in &self.flagged {
+            table.row(vec![
+                entry.probe.bitrate_kbps.map(|k| k.to_string()).unwrap_or("?".to_string()),
+                entry.probe.codec.clone().unwrap_or("?".to_string()),
+                entry.probe.sample_rate.map(|r| r.to_string()).unwrap_or("?".to_string()),
+                entry.key.artist.clone(),
+                entry.key.title.clone(),
+            ]);
+        }
+        if !table.is_empty() {
+            table.print(output);
+        }
+        for (key, error) in &self.unreadable {
+            println!("UNREADABLE {} - {}: {}", key.artist, key.title, error);
+        }
+        println!(
+            "{} below {} kbps, {} unreadable",
+            self.flagged.len(),
+            self.min_kbps,
+            self.unreadable.len()
+        );
+    }
+}
+
+// A single place a track was found by `where`.
+#[derive(Debug, serde::Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum TrackLocation {
+    Cache { path: PathBuf },
+    // Indexed in a cache root that isn't currently mounted.
+    CacheOffline { reason: String },
+    Device { device: String, path: PathBuf },
+    Playlist { name: String },
+    // Where the cached copy was fetched from.
+    SourceUrl { url: String },
+    // Marked for re-download by quality or verify.
+    Flagged,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct WhereMatch {
+    pub key: AudioKey,
+    // Set when the key was found by fuzzy matching rather than exactly.
+    pub fuzzy_score: Option<f64>,
+    pub locations: Vec<TrackLocation>,
+}
+
+// Every location a track exists, across the cache, the device and playlists. Nothing is fetched or modified.
+#[derive(Debug, serde::Serialize)]
+pub struct WhereReport {
+    pub query: AudioKey,
+    pub matches: Vec<WhereMatch>,
+}
+
+impl WhereReport {
+    /// Locate the exact key, or when it isn't known anywhere, the closest fuzzy matches. A query missing its artist or
+    /// title locates every track matching the field it has, so one title by several artists lists each of them.
+    pub fn build(cache: &LocalCache, device: &AttachedDevice, query: &AudioInfo) -> Self {
+        let playlist_keys: Vec<AudioKey> = cache.playlist_entries().filter_map(AudioKey::from_info).collect();
+        let candidates = || cache.index_keys().chain(device.index_keys()).chain(&playlist_keys);
+        let matches = match AudioKey::from_info(query) {
+            Some(key) => {
+                let exact = Self::locate(cache, device, &key, None);
+                if !exact.locations.is_empty() {
+                    vec![exact]
+                } else {
+                    fuzzy::best_matches(&key, candidates(), fuzzy::DEFAULT_THRESHOLD, 5)
+                        .into_iter()
+                        .map(|(key, score)| Self::locate(cache, device, &key, Some(score)))
+                        .collect()
+                }
+            }
+            None => fuzzy::partial_matches(
+                query.artist.as_deref(),
+                query.title.as_deref(),
+                candidates(),
+                fuzzy::DEFAULT_THRESHOLD,
+            )
+            .into_iter()
+            .map(|(key, score)| Self::locate(cache, device, key, score))
+            .collect(),
+        };
+        let query = AudioKey {
+            artist: query.artist.as_deref().map(str::to_lowercase).unwrap_or_default(),
+            title: query.title.as_deref().map(str::to_lowercase).unwrap_or_default(),
+        };
+        Self { query, matches }
+    }
+
+    pub fn locate(cache: &LocalCache, device: &AttachedDevice, key: &AudioKey, fuzzy_score: Option<f64>) -> WhereMatch {
+        let info = AudioInfo {
+            artist: Some(key.artist.clone()),
+            title: Some(key.title.clone()),
+            ..Default::default()
+        };
+        let mut locations = Vec::new();
+        match cache.search(&info) {
+            Ok(AudioLocation::LocalPath(path)) => {
+                if let Some(url) = Sidecar::load(&path).and_then(|sidecar| sidecar.source_url) {
+                    locations.push(TrackLocation::SourceUrl { url });
+                }
+                locations.insert(0, TrackLocation::Cache { path });
+            }
+            Err(AudioError::Unavailable(reason)) => locations.push(TrackLocation::CacheOffline { reason }),
+            _ => {}
+        }
+        if let Ok(AudioLocation::LocalPath(path)) = device.search(&info) {
+            locations.push(TrackLocation::Device {
+                device: device.name.clone(),
+                path: path.clone(),
+            });
+        }
+        for name in cache.playlist_names() {
+            let in_playlist = cache
+                .playlist_tracks(name)
+                .is_some_and(|tracks| tracks.iter().any(|t| AudioKey::from_info(t).as_ref() == Some(key)));
+            if in_playlist {
+                locations.push(TrackLocation::Playlist { name: name.to_string() });
+            }
+        }
+        if cache.flagged().iter().any(|t| AudioKey::from_info(t).as_ref() == Some(key)) {
+            locations.push(TrackLocation::Flagged);
+        }
+        WhereMatch {
+            key: key.clone(),
+            fuzzy_score,
+            locations,
+        }
+    }
+
+    pub fn print(&self, output: OutputOptions) {
+        if self.matches.is_empty() {
+            println!("{} - {} not found anywhere", self.query.artist, self.query.title);
+            return;
+        }
+        let mut table = Table::new(&["Track", "Match", "Location", "Path/URL"]);
+        for m in &self.matches {
+            let track = format!("{} - {}", m.key.artist, m.key.title);
+            let score = m.fuzzy_score.map(|s| format!("fuzzy {:.2}", s)).unwrap_or("exact".to_string());
+            for location in &m.locations {
+                let (kind, detail) = location.describe();
+                table.row(vec![track.clone(), score.clone(), kind.to_string(), detail]);
+            }
+        }
+        table.print(output);
+    }
+}
+
+impl TrackLocation {
+    // The location's kind and its path, URL or other detail, for tables.
+    fn describe(&self) -> (&'static str, String) {
+        match self {
+            TrackLocation::Cache { path } => ("cache", path.display().to_string()),
+            TrackLocation::CacheOffline { reason } => ("cache (offline)", reason.clone()),
+            TrackLocation::Device { device, path } => ("device", format!("{}: {}", device, path.display())),
+            TrackLocation::Playlist { name } => ("playlist", name.clone()),
+            TrackLocation::SourceUrl { url } => ("source", url.clone()),
+            TrackLocation::Flagged => ("flagged", String::new()),
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct ArtistEntry {
+    pub artist: String,
+    pub tracks: usize,
+    pub size_bytes: u64,
+    // Playlists with at least one track crediting the artist.
+    pub playlists: usize,
+}
+
+// Every credited artist in the cache, featured artists included, most tracks first.
+#[derive(Debug, Default, serde::Serialize)]
+pub struct ArtistsReport {
+    pub artists: Vec<ArtistEntry>,
+}
+
+impl ArtistsReport {
+    pub fn build(cache: &LocalCache, min_tracks: usize) -> Self {
+        // Sorted so the spelling an artist is shown with doesn't depend on index order.
+        let mut indexed: Vec<(&AudioKey, &PathBuf)> = cache.indexed().collect();
+        indexed.sort_by(|a, b| sort::key_cmp(a.0, b.0));
+
+        let mut by_artist: HashMap<String, ArtistEntry> = HashMap::new();
+        for (_, path) in indexed {
+            let Some(filename) = path.file_name() else { continue };
+            let size = path.metadata().map(|m| m.len()).unwrap_or_default();
+            let mut seen = HashSet::new();
+            for artist in AudioInfo::from_filename(filename).credited_artists() {
+                let normalized = nfc(&artist).to_lowercase();
+                if !seen.insert(normalized.clone()) {
+                    continue;
+                }
+                let entry = by_artist.entry(normalized).or_insert_with(|| ArtistEntry {
+                    artist,
+                    tracks: 0,
+                    size_bytes: 0,
+                    playlists: 0,
+                });
+                entry.tracks += 1;
+                entry.size_bytes += size;
+            }
+        }
+        for name in cache.playlist_names() {
+            let credited: HashSet<String> = cache
+                .playlist_tracks(name)
+                .unwrap_or_default()
+                .iter()
+                .flat_map(|info| info.credited_artists())
+                .map(|artist| nfc(&artist).to_lowercase())
+                .collect();
+            for artist in credited {
+                if let Some(entry) = by_artist.get_mut(&artist) {
+                    entry.playlists += 1;
+                }
+            }
+        }
+
+        let mut artists: Vec<ArtistEntry> = by_artist.into_values().filter(|e| e.tracks >= min_tracks).collect();
+        artists.sort_by(|a, b| b.tracks.cmp(&a.tracks).then_with(|| sort::natural_cmp(&a.artist, &b.artist)));
+        Self { artists }
+    }
+
+    pub fn print(&self, output: OutputOptions) {
+        let mut table = Table::new(&["Artist", "Tracks", "Size", "Playlists"]);
+        for entry in &self.artists {
+            table.row(vec![
+                entry.artist.clone(),
+                entry.tracks.to_string(),
+                format_size(entry.size_bytes),
+                entry.playlists.to_string(),
+            ]);
+        }
+        if !table.is_empty() {
+            table.print(output);
+        }
+        println!("{} artists", self.artists.len());
+    }
+}
+
+// One artist's tracks, cached or only in playlists, and everywhere each of them is.
+#[derive(Debug, serde::Serialize)]
+pub struct ArtistTracksReport {
+    pub artist: String,
+    pub tracks: Vec<WhereMatch>,
+}
+
+impl ArtistTracksReport {
+    pub fn build(cache: &LocalCache, device: &AttachedDevice, artist: &str) -> Self {
+        let cached = cache
+            .indexed()
+            .filter_map(|(_, path)| path.file_name().map(AudioInfo::from_filename))
+            .filter(|info| info.credits(artist));
+        let listed = cache.playlist_entries().filter(|info| info.credits(artist)).cloned();
+        let mut keys: Vec<AudioKey> = cached.chain(listed).filter_map(|info| AudioKey::from_info(&info)).collect();
+        keys.sort_by(sort::key_cmp);
+        keys.dedup();
+        Self {
+            artist: artist.to_string(),
+            tracks: keys.iter().map(|key| WhereReport::locate(cache, device, key, None)).collect(),
+        }
+    }
+
+    pub fn print(&self, output: OutputOptions) {
+        if self.tracks.is_empty() {
+            println!("No tracks credit {}", self.artist);
+            return;
+        }
+        let mut table = Table::new(&["Track", "Location", "Path/URL"]);
+        for m in &self.tracks {
+            let track = format!("{} - {}", m.key.artist, m.key.title);
+            for location in &m.locations {
+                let (kind, detail) = location.describe();
+                table.row(vec![track.clone(), kind.to_string(), detail]);
+            }
+        }
+        table.print(output);
+        println!("{} tracks credit {}", self.tracks.len(), self.artist);
+    }
+}
+
+// How a playlist track was matched to cached audio.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchKind {
+    Isrc,
+    Exact,
+    Fuzzy,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct MatchedTrack {
+    pub track: AudioKey,
+    pub cached: AudioKey,
+    pub kind: MatchKind,
+    // Only for fuzzy matches.
+    pub score: Option<f64>,
+}
+
+// Which tracks of another index's playlist are already cached, and which would need downloading.
+#[derive(Debug, serde::Serialize)]
+pub struct MissingReport {
+    pub index: String,
+    pub playlist: String,
+    pub matched: Vec<MatchedTrack>,
+    pub missing: Vec<AudioInfo>,
+}
+
+impl MissingReport {
+    /// Match each track by ISRC when both sides have one, then by exact key, then fuzzily. A cached file known to have
+    /// a different ISRC is a different recording, e.g. a re-recording under the same title, and never matches by key.
+    /// Tracks with neither an artist nor a title can't be matched, and are counted as missing.
+    pub fn build(cache: &LocalCache, index: &dyn AudioIndex, playlist: &PlaylistName) -> Result<Self, AudioError> {
+        let tracks = index.get_playlist(playlist)?;
+        let cached: HashSet<&AudioKey> = cache.index_keys().collect();
+        let mut isrcs_of: HashMap<&AudioKey, Vec<&str>> = HashMap::new();
+        for (isrc, key) in cache.cached_isrcs() {
+            isrcs_of.entry(key).or_default().push(isrc);
+        }
+        // Another recording when both sides know their ISRC and they differ.
+        let other_recording = |isrc: Option<&str>, key: &AudioKey| {
+            isrc.zip(isrcs_of.get(key)).is_some_and(|(isrc, known)| !known.contains(&isrc))
+        };
+
+        let mut matched = Vec::new();
+        let mut missing = Vec::new();
+        for info in tracks.iter() {
+            let Some(key) = AudioKey::from_info(info) else {
+                missing.push(info.clone());
+                continue;
+            };
+            let isrc = info.normalized_isrc();
+            let by_isrc = isrc.as_deref().map(|isrc| cache.cached_by_isrc(isrc)).unwrap_or_default();
+            let found = if let Some(cached_key) = by_isrc.iter().find(|k| ***k == key).or(by_isrc.first()) {
+                Some(((*cached_key).clone(), MatchKind::Isrc, None))
+            } else if cached.contains(&key) && !other_recording(isrc.as_deref(), &key) {
+                Some((key.clone(), MatchKind::Exact, None))
+            } else {
+                let candidates = cached.iter().copied().filter(|k| !other_recording(isrc.as_deref(), k));
+                fuzzy::best_matches(&key, candidates, fuzzy::DEFAULT_THRESHOLD, 1)
+                    .pop()
+                    .map(|(cached_key, score)| (cached_key, MatchKind::Fuzzy, Some(score)))
+            };
+            match found {
+                Some((cached, kind, score)) => matched.push(MatchedTrack { track: key, cached, kind, score }),
+                None => missing.push(info.clone()),
+            }
+        }
+        Ok(Self {
+            index: index.name().to_string(),
+            playlist: playlist.disp_name().to_string(),
+            matched,
+            missing,
+        })
+    }
+
+    fn count(&self, kind: MatchKind) -> usize {
+        self.matched.iter().filter(|m| m.kind == kind).count()
+    }
+
+    pub fn print(&self, output: OutputOptions) {
+        println!(
+            "{} / {}: {} of {} tracks cached ({} exact, {} fuzzy, {} ISRC), {} to download",
+            self.index,
+            self.playlist,
+            self.matched.len(),
+            self.matched.len() + self.missing.len(),
+            self.count(MatchKind::Exact),
+            self.count(MatchKind::Fuzzy),
+            self.count(MatchKind::Isrc),
+            self.missing.len()
+        );
+        // Fuzzy matches are listed so a bad one can be spotted, they'd otherwise hide a track that's really missing.
+        if self.count(MatchKind::Fuzzy) > 0 {
+            let mut table = Table::new(&["Track", "Matched", "Score"]);
+            for m in self.matched.iter().filter(|m| m.kind == MatchKind::Fuzzy) {
+                table.row(vec![
+                    format!("{} - {}", m.track.artist, m.track.title),
+                    format!("{} - {}", m.cached.artist, m.cached.title),
+                    m.score.map(|s| format!("{:.2}", s)).unwrap_or_default(),
+                ]);
+            }
+            table.print(output);
+        }
+        if !self.missing.is_empty() {
+            let mut table = Table::new(&["Missing", "Duration"]);
+            for info in &self.missing {
+                table.row(vec![
+                    format!(
+                        "{} - {}",
+                        info.artist.as_deref().unwrap_or("?"),
+                        info.title.as_deref().unwrap_or("?")
+                    ),
+                    format_duration(info.duration_secs),
+                ]);
+            }
+            table.print(output);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn re_recordings_on_the_device_are_not_the_cached_track() {
+        // Synced from the cached file, or from a since re-downloaded re-recording.
+        assert!(!different_recording(&["GBAYE8500077"], Some("GBAYE8500077")));
+        assert!(different_recording(&["GBAYE1200411"], Some("GBAYE8500077")));
+        // Either side without an ISRC can only be matched by key.
+        assert!(!different_recording(&[], Some("GBAYE8500077")));
+        assert!(!different_recording(&["GBAYE1200411"], None));
+    }
+}
@@ -0,0 +1,49 @@
+// Sidecar -> Metadata about a cached audio file that isn't part of AudioInfo, e.g. where and when it was fetched from.
+// Stored as one JSON file per cached file under the cache's meta directory, keyed by the cached filename, so the flat
+// audio directory only ever contains audio.
+
+use std::{
+    fs::{create_dir_all, read_to_string, write},
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::cache::sidecar_dir;
+
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct Sidecar {
+    // Name of the AudioSource the file was fetched from.
+    pub source: Option<String>,
+    pub source_url: Option<String>,
+    // Title of the video/upload as the source presented it, before any cleanup.
+    pub source_title: Option<String>,
+    pub uploader: Option<String>,
+    // YYYYMMDD as reported by the source.
+    pub upload_date: Option<String>,
+    pub duration_secs: Option<u32>,
+    // Unix seconds.
+    pub fetched_at: Option<u64>,
+    // Filename of the kept pre-transcode download, under the cache's originals directory.
+    pub original: Option<String>,
+    // Favourited with `star`, listed in the cache's virtual Starred playlist.
+    pub starred: bool,
+}
+
+impl Sidecar {
+    pub fn path_for(audio_path: &Path) -> Option<PathBuf> {
+        let filename = audio_path.file_name()?.to_string_lossy();
+        Some(sidecar_dir().join(format!("{}.json", filename)))
+    }
+
+    pub fn load(audio_path: &Path) -> Option<Self> {
+        let path = Self::path_for(audio_path)?;
+        serde_json::from_str(&read_to_string(path).ok()?).ok()
+    }
+
+    pub fn save(&self, audio_path: &Path) -> io::Result<()> {
+        let path = Self::path_for(audio_path).ok_or(io::ErrorKind::InvalidInput)?;
+        create_dir_all(sidecar_dir())?;
+        write(path, serde_json::to_string_pretty(self)?)
+    }
+}
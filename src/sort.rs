@@ -0,0 +1,123 @@
+// Display order for user-facing listings, so output (and diffs of --json output) doesn't change from run to run.
+// Comparisons are case-folded, and runs of digits compare by value so "Track 2" sorts before "Track 10". Only what's
+// printed is sorted, persisted playlists keep their insertion order.
+
+use std::{cmp::Ordering, iter::Peekable, str::Chars};
+
+use crate::audio::{AudioInfo, AudioKey, PlaylistName};
+
+/// Case-folded comparison with digit runs ordered by value. Strings that differ only in case fall back to a plain
+/// comparison, so the order is total.
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a_chars, mut b_chars) = (a.chars().peekable(), b.chars().peekable());
+    loop {
+        let ord = match (a_chars.peek().copied(), b_chars.peek().copied()) {
+            (None, None) => return a.cmp(b),
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                cmp_digits(&take_digits(&mut a_chars), &take_digits(&mut b_chars))
+            }
+            (Some(x), Some(y)) => {
+                a_chars.next();
+                b_chars.next();
+                x.to_lowercase().cmp(y.to_lowercase())
+            }
+        };
+        if ord != Ordering::Equal {
+            return ord;
+        }
+    }
+}
+
+fn take_digits(chars: &mut Peekable<Chars>) -> String {
+    let mut digits = String::new();
+    while let Some(c) = chars.next_if(char::is_ascii_digit) {
+        digits.push(c);
+    }
+    digits
+}
+
+// Ignoring leading zeros a longer run is a larger number, so runs too long for a u64 still compare.
+fn cmp_digits(a: &str, b: &str) -> Ordering {
+    let (a, b) = (a.trim_start_matches('0'), b.trim_start_matches('0'));
+    a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+}
+
+/// Playlists by name, Uncategorized last.
+pub fn playlist_name_cmp(a: &PlaylistName, b: &PlaylistName) -> Ordering {
+    match (a, b) {
+        (PlaylistName::Named(a), PlaylistName::Named(b)) => natural_cmp(a, b),
+        (PlaylistName::Named(_), PlaylistName::Uncategorized) => Ordering::Less,
+        (PlaylistName::Uncategorized, PlaylistName::Named(_)) => Ordering::Greater,
+        (PlaylistName::Uncategorized, PlaylistName::Uncategorized) => Ordering::Equal,
+    }
+}
+
+/// Tracks by artist, then title.
+pub fn track_cmp(a: &AudioInfo, b: &AudioInfo) -> Ordering {
+    natural_cmp(field(&a.artist), field(&b.artist)).then_with(|| natural_cmp(field(&a.title), field(&b.title)))
+}
+
+fn field(value: &Option<String>) -> &str {
+    value.as_deref().unwrap_or_default()
+}
+
+pub fn key_cmp(a: &AudioKey, b: &AudioKey) -> Ordering {
+    natural_cmp(&a.artist, &b.artist).then_with(|| natural_cmp(&a.title, &b.title))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cache::LocalCache, index::AudioIndex};
+    use std::collections::HashMap;
+
+    fn sorted(mut names: Vec<&str>) -> Vec<&str> {
+        names.sort_by(|a, b| natural_cmp(a, b));
+        names
+    }
+
+    #[test]
+    fn numerals_sort_by_value_and_case_is_folded() {
+        assert_eq!(sorted(vec!["Track 10", "track 2", "Track 1"]), ["Track 1", "track 2", "Track 10"]);
+        assert_eq!(sorted(vec!["b", "B", "a"]), ["a", "B", "b"]);
+        assert_eq!(sorted(vec!["Mix 007", "Mix 7", "Mix 06"]), ["Mix 06", "Mix 007", "Mix 7"]);
+        // Too long for a u64, still by value.
+        assert_eq!(natural_cmp("99999999999999999999999", "100000000000000000000000"), Ordering::Less);
+        assert_eq!(sorted(vec!["Ö", "Z", "o"]), ["o", "Z", "Ö"]);
+
+        let track = |artist: &str, title: &str| AudioInfo {
+            artist: Some(artist.to_string()),
+            title: Some(title.to_string()),
+            ..Default::default()
+        };
+        let mut tracks = [track("muse", "Uprising"), track("Blur", "Song 2"), track("Muse", "Hysteria"), track("Blur", "Song 10")];
+        tracks.sort_by(track_cmp);
+        let titles: Vec<_> = tracks.iter().map(|info| info.title.as_deref().unwrap()).collect();
+        assert_eq!(titles, ["Song 2", "Song 10", "Hysteria", "Uprising"]);
+    }
+
+    #[test]
+    fn listings_are_sorted_but_playlists_keep_their_order() {
+        let dir = std::env::temp_dir().join(format!("music-man-sort-{}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("Muse - Uprising.mp3"), b"audio").unwrap();
+        let road = vec![AudioInfo::from_filename("Muse - Uprising.mp3"), AudioInfo::from_filename("Blur - Song 2.mp3")];
+        let playlists = HashMap::from([
+            ("gym 10".to_string(), Vec::new()),
+            ("Road".to_string(), road),
+            ("Gym 9".to_string(), Vec::new()),
+        ]);
+        let cache = LocalCache::in_dir(&dir, playlists);
+
+        assert_eq!(cache.playlist_names().collect::<Vec<_>>(), ["Gym 9", "gym 10", "Road"]);
+        let names: Vec<_> = cache.list_playlist_names().unwrap().iter().map(|name| name.disp_name().to_string()).collect();
+        assert_eq!(names.last().map(String::as_str), Some(PlaylistName::Uncategorized.disp_name()));
+        assert_eq!(names[..3], ["Gym 9", "gym 10", "Road"]);
+        let titles: Vec<_> = cache.playlist_tracks("Road").unwrap().iter().map(|info| info.title.clone().unwrap()).collect();
+        assert_eq!(titles, ["Uprising", "Song 2"]);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -1,7 +1,26 @@
-use crate::{AudioError, AudioInfo, audio::AudioLocation};
+use crate::{
+    AudioError, AudioInfo,
+    audio::{AudioLocation, has_audio_extension, is_various_artists, split_artists},
+    blocklist::Blocklist,
+    command_source::CommandSource,
+    cancel::{CancelToken, interrupt_token},
+    config::Config,
+    events::{Event, NoProgress, ProgressReporter},
+    fsutil::parse_size,
+    naming::{DestNaming, FilenameRules, sanitize},
+    process::{kill_process_group, own_process_group, run_with_timeout},
+};
+use serde_json::Value;
 use std::{
+    collections::HashSet,
+    io::{BufRead, BufReader},
     path::{Path, PathBuf},
-    process::Command,
+    process::{Command, Stdio},
+    sync::{
+        Arc, Mutex, PoisonError,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
 };
 
 // TRAIT: AudioSource, e.g. an open-source mp3 library, an attached drive, the local file cache etc.
@@ -13,143 +32,817 @@ use std::{
 // Another trait should be implemented by any "audio source".
 //
 // Other AudioSource could include e.g. ytb-dl based sourcing.
+//
+// fetch writes into dest, naming the file as the DestNaming says, and returns a FetchResult saying where it actually
+// landed and what was learned along the way.
 pub trait AudioSource {
     fn name(&self) -> &str;
     fn search(&self, info: &AudioInfo) -> Result<AudioInfo, AudioError>;
-    fn fetch(&self, info: &AudioInfo, dest: PathBuf) -> Result<AudioLocation, AudioError>;
+    fn fetch(&self, info: &AudioInfo, dest: PathBuf, naming: &DestNaming) -> Result<FetchResult, AudioError>;
+
+    /// Fetch audio, searching for it first if there's no URL yet, reporting download progress for sources that have it.
+    fn fetch_with_progress(
+        &self,
+        info: &AudioInfo,
+        dest: PathBuf,
+        naming: &DestNaming,
+        _reporter: &mut dyn ProgressReporter,
+    ) -> Result<FetchResult, AudioError> {
+        let full_info = if info.youtube_url.is_some() { info.clone() } else { self.search(info)? };
+        self.fetch(&full_info, dest, naming)
+    }
+}
+
+/// What a fetch (or an import to a target) produced, so callers don't have to re-stat files.
+#[derive(Clone, Debug)]
+pub struct FetchResult {
+    pub location: AudioLocation,
+    // The AudioInfo as fetched, filled in with whatever the source learned, e.g. yt-dlp's extracted artist and track.
+    pub info: AudioInfo,
+    // Bytes written to the destination, 0 when nothing had to be, e.g. the audio was already there.
+    pub bytes: u64,
+    // The source that fetched it, or the target that imported it.
+    pub source_name: String,
+    // Where the audio was actually fetched from, after any search or redirects.
+    pub url: Option<String>,
+    // yt-dlp's raw metadata, for sources that run it.
+    pub metadata: Option<YtDlpMetadata>,
+    // blake3 hash of the file written, when it was computed while writing it, e.g. by a chunked import to a device.
+    pub hash: Option<String>,
 }
 
+impl FetchResult {
+    /// A local file written by source_name.
+    pub fn local(path: PathBuf, info: AudioInfo, bytes: u64, source_name: &str) -> Self {
+        Self {
+            location: AudioLocation::LocalPath(path),
+            url: info.youtube_url.clone(),
+            info,
+            bytes,
+            source_name: source_name.to_string(),
+            metadata: None,
+            hash: None,
+        }
+    }
+}
+
+impl From<FetchResult> for AudioLocation {
+    fn from(result: FetchResult) -> Self {
+        result.location
+    }
+}
+
+// Where yt-dlp sits among the configured command sources.
+pub const YTDLP_PRIORITY: i32 = 50;
+
+// Every source, yt-dlp plus a CommandSource per enabled config entry.
+pub struct SourceChain {
+    pub ytdlp: YtDlpSource,
+    pub commands: Vec<CommandSource>,
+}
+
+impl SourceChain {
+    /// Command sources were already checked when the config loaded.
+    pub fn from_config(config: &Config) -> Self {
+        let mut ytdlp = YtDlpSource::new("ytdlp");
+        ytdlp.binary = config.ytdlp_binary();
+        if let Some(secs) = config.ytdlp_search_timeout_secs {
+            ytdlp.search_timeout = Duration::from_secs(secs);
+        }
+        if let Some(secs) = config.ytdlp_stall_timeout_secs {
+            ytdlp.stall_timeout = Duration::from_secs(secs);
+        }
+        ytdlp.network = NetworkOptions::from_config(config);
+        ytdlp.blocked = Blocklist::load();
+        let commands = config
+            .sources
+            .iter()
+            .filter(|source| source.enabled)
+            .filter_map(|source| CommandSource::from_config(source).ok())
+            .collect();
+        Self { ytdlp, commands }
+    }
+
+    /// Sources in the order they're tried, lowest priority first.
+    pub fn ordered(&self) -> Vec<&dyn AudioSource> {
+        let mut sources: Vec<(i32, &dyn AudioSource)> = vec![(YTDLP_PRIORITY, &self.ytdlp)];
+        sources.extend(self.commands.iter().map(|c| (c.priority, c as &dyn AudioSource)));
+        sources.sort_by_key(|(priority, _)| *priority);
+        sources.into_iter().map(|(_, source)| source).collect()
+    }
+}
+
+/// How downloads reach the network: through a proxy, appearing to be from some other country, and how fast.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NetworkOptions {
+    // e.g. "socks5://127.0.0.1:1080".
+    pub proxy: Option<String>,
+    // Two letter ISO country code.
+    pub geo_bypass_country: Option<String>,
+    // Bytes per second each download is capped at.
+    pub limit_rate: Option<u64>,
+}
+
+impl NetworkOptions {
+    /// limit_rate was already checked when the config loaded.
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            proxy: config.proxy.clone(),
+            geo_bypass_country: config.geo_bypass_country.clone(),
+            limit_rate: config.limit_rate_bytes().unwrap_or_default(),
+        }
+    }
+
+    /// Take the per-command overrides (--proxy <url>, --geo-bypass-country <code>, --limit-rate <size>) out of a
+    /// command's args, on top of these. Err names a flag given without a valid value.
+    pub fn take_from(&self, args: &mut Vec<&str>) -> Result<Self, String> {
+        let mut options = self.clone();
+        for (flag, field) in [("--proxy", &mut options.proxy), ("--geo-bypass-country", &mut options.geo_bypass_country)] {
+            if let Some(i) = args.iter().position(|arg| *arg == flag) {
+                let value = args.get(i + 1).ok_or_else(|| format!("{} needs a value", flag))?;
+                *field = Some(value.to_string());
+                args.drain(i..=i + 1);
+            }
+        }
+        if let Some(i) = args.iter().position(|arg| *arg == "--limit-rate") {
+            let rate = args.get(i + 1).and_then(|rate| parse_size(rate)).filter(|rate| *rate > 0);
+            options.limit_rate = Some(rate.ok_or("--limit-rate needs a rate, e.g. 2M")?);
+            args.drain(i..=i + 1);
+        }
+        Ok(options)
+    }
+
+    fn apply(&self, command: &mut Command) {
+        if let Some(proxy) = &self.proxy {
+            command.args(["--proxy", proxy]);
+        }
+        if let Some(country) = &self.geo_bypass_country {
+            command.args(["--geo-bypass-country", country]);
+        }
+        if let Some(rate) = self.limit_rate {
+            command.arg("--limit-rate").arg(rate.to_string());
+        }
+    }
+}
+
+// The part of a track previews are cut from, in yt-dlp's --download-sections syntax.
+const PREVIEW_SECTION: &str = "*0:30-1:00";
+
+// Default yt-dlp search query, placeholders are substituted by build_search_query.
+pub const DEFAULT_QUERY_TEMPLATE: &str = "{title} {artist}";
+
+pub const DEFAULT_SEARCH_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_STALL_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
 pub struct YtDlpSource {
     pub name: String,
+    // Search query template supporting {artist} and {title} placeholders, e.g. "{artist} - {title} audio".
+    pub query_template: String,
+    // Keep the originally extracted audio (e.g. opus/webm) alongside the mp3 conversion.
+    pub keep_original: bool,
+    // The yt-dlp executable to run.
+    pub binary: PathBuf,
+    // Kills a running download, defaults to the Ctrl-C token.
+    pub cancel: CancelToken,
+    // How long a search may take.
+    pub search_timeout: Duration,
+    // How long a download may go without output before it's killed, progress lines reset it.
+    pub stall_timeout: Duration,
+    pub network: NetworkOptions,
+    // Videos searches skip, and recorded URLs are searched past.
+    pub blocked: Blocklist,
 }
 
+// Search results looked through for one that isn't blocked.
+const SEARCH_CANDIDATES: usize = 5;
+
 impl AudioSource for YtDlpSource {
     fn name(&self) -> &str {
         &self.name
     }
 
     fn search(&self, info: &AudioInfo) -> Result<AudioInfo, AudioError> {
-        if let (Some(artist), Some(title)) = (&info.artist, &info.title) {
-            let url = self.search_audio(title, artist)?;
-            let mut extended_info = info.clone();
-            extended_info.youtube_url = Some(url);
-            return Ok(extended_info);
+        let url = if self.blocked.is_empty() {
+            self.search_audio(&self.search_query(info)?)?
+        } else {
+            // The best result that isn't blocked.
+            let candidates = self.search_candidates(info, SEARCH_CANDIDATES)?;
+            candidates.into_iter().find_map(|(candidate, _)| candidate.youtube_url).ok_or(AudioError::NotFound)?
+        };
+        let mut extended_info = info.clone();
+        extended_info.youtube_url = Some(url);
+        Ok(extended_info)
+    }
+
+    fn fetch(&self, info: &AudioInfo, dest: PathBuf, naming: &DestNaming) -> Result<FetchResult, AudioError> {
+        self.fetch_with_progress(info, dest, naming, &mut NoProgress)
+    }
+
+    fn fetch_with_progress(
+        &self,
+        info: &AudioInfo,
+        dest: PathBuf,
+        naming: &DestNaming,
+        reporter: &mut dyn ProgressReporter,
+    ) -> Result<FetchResult, AudioError> {
+        let mut full_info = match &info.youtube_url {
+            Some(url) if !self.blocked.contains(url) => info.clone(),
+            _ => self.search(info)?,
+        };
+        let (dest_file, metadata) = self.download_audio(&full_info, &dest, naming, reporter)?;
+        if let Some(metadata) = &metadata {
+            metadata.merge_into(&mut full_info);
         }
-        Err(AudioError::MissingInfo)
+        // yt-dlp doesn't report the size of the converted file, only of what it downloaded.
+        let bytes = std::fs::metadata(&dest_file)?.len();
+        let mut result = FetchResult::local(dest_file, full_info, bytes, &self.name);
+        result.url = metadata.as_ref().and_then(|m| m.webpage_url.clone()).or(result.url);
+        result.metadata = metadata;
+        Ok(result)
     }
+}
 
-    fn fetch(&self, info: &AudioInfo, dest: PathBuf) -> Result<AudioLocation, AudioError> {
-        let full_info = if info.youtube_url.is_some() {
-            info
-        } else {
-            &self.search(info)?
+// The subset of yt-dlp's info JSON we use. Every field is optional, and fields are picked out of the raw JSON
+// individually so that unknown fields, missing fields, or a field changing type never fail the whole parse.
+#[derive(Clone, Debug, Default)]
+pub struct YtDlpMetadata {
+    pub id: Option<String>,
+    pub title: Option<String>,
+    pub uploader: Option<String>,
+    pub duration_secs: Option<u32>,
+    pub upload_date: Option<String>,
+    pub filepath: Option<String>,
+    pub webpage_url: Option<String>,
+    // Extracted for music videos, and usually more accurate than anything parsed out of the video title.
+    pub artist: Option<String>,
+    // Individually credited artists, when the extractor lists them.
+    pub artists: Vec<String>,
+    pub track: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    // The pre-conversion download, when yt-dlp was asked to keep it. Not part of yt-dlp's JSON.
+    pub original_filepath: Option<PathBuf>,
+}
+
+impl YtDlpMetadata {
+    pub fn parse(json: &str) -> Option<Self> {
+        let value: Value = serde_json::from_str(json).ok()?;
+        let string = |field: &str| {
+            value
+                .get(field)
+                .and_then(Value::as_str)
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(str::to_string)
         };
-        let dest_file = self.download_audio(full_info, &dest)?;
-        Ok(AudioLocation::LocalPath(dest_file))
+        Some(Self {
+            id: string("id"),
+            title: string("title"),
+            uploader: string("uploader"),
+            duration_secs: value.get("duration").and_then(Value::as_f64).map(|d| d.round() as u32),
+            upload_date: string("upload_date"),
+            // After post-processing "filepath" is the final (converted) file.
+            filepath: string("filepath").or_else(|| string("_filename")),
+            webpage_url: string("webpage_url"),
+            artist: string("artist").or_else(|| string("creator")),
+            artists: value
+                .get("artists")
+                .and_then(Value::as_array)
+                .map(|artists| {
+                    artists
+                        .iter()
+                        .filter_map(Value::as_str)
+                        .map(str::trim)
+                        .filter(|a| !a.is_empty())
+                        .map(str::to_string)
+                        .collect()
+                })
+                .unwrap_or_default(),
+            track: string("track"),
+            album: string("album"),
+            album_artist: string("album_artist"),
+            original_filepath: None,
+        })
+    }
+
+    /// Fill in whatever the AudioInfo is missing. Existing fields are kept since they're what the user asked for.
+    pub fn merge_into(&self, info: &mut AudioInfo) {
+        if info.artist.is_none() && info.title.is_none() {
+            if let (Some(artist), Some(track)) = (&self.artist, &self.track) {
+                info.artist = Some(artist.clone());
+                info.artists = if self.artists.is_empty() { split_artists(artist) } else { self.artists.clone() };
+                info.title = Some(track.clone());
+            } else if let Some(title) = &self.title {
+                // Fall back to the same "Artist - Title" convention used for filenames, else credit the uploader.
+                let title = normalize_title(title);
+                match title.split_once(" - ") {
+                    Some((artist, track)) => {
+                        info.artist = Some(artist.trim().to_string());
+                        info.artists = split_artists(artist);
+                        info.title = Some(track.trim().to_string());
+                    }
+                    None => {
+                        info.artist = self.uploader.clone();
+                        info.title = Some(title);
+                    }
+                }
+            }
+        }
+        if info.duration_secs.is_none() {
+            info.duration_secs = self.duration_secs;
+        }
+        if info.album.is_none() {
+            info.album = self.album.clone();
+        }
+        // Per-track artists are kept for compilations, the album artist only marks them as such.
+        if self.album_artist.as_deref().is_some_and(is_various_artists) {
+            info.compilation = true;
+        }
+        if info.youtube_url.is_none() {
+            info.youtube_url = self.webpage_url.clone();
+        }
+        if let Some(filename) = self.filepath.as_deref().and_then(|p| Path::new(p).file_name()) {
+            info.filename = Some(filename.to_string_lossy().to_string());
+        }
     }
 }
 
 impl YtDlpSource {
-    fn download_audio(&self, info: &AudioInfo, output_dir: &Path) -> Result<PathBuf, AudioError> {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            query_template: DEFAULT_QUERY_TEMPLATE.to_string(),
+            keep_original: false,
+            binary: PathBuf::from("yt-dlp"),
+            cancel: interrupt_token(),
+            search_timeout: DEFAULT_SEARCH_TIMEOUT,
+            stall_timeout: DEFAULT_STALL_TIMEOUT,
+            network: NetworkOptions::default(),
+            blocked: Blocklist::default(),
+        }
+    }
+
+
+    // With only one of artist/title there's no template to fill, so just search for the raw string.
+    fn search_query(&self, info: &AudioInfo) -> Result<String, AudioError> {
+        match (&info.artist, &info.title) {
+            (Some(artist), Some(title)) => Ok(build_search_query(&self.query_template, artist, title)),
+            (Some(raw), None) | (None, Some(raw)) => Ok(raw.trim().to_string()),
+            (None, None) => Err(AudioError::MissingInfo),
+        }
+    }
+
+    /// The top search results for some audio that aren't blocked, best first, each with its video title. Only the URL is
+    /// filled in, the rest of the AudioInfo is as given.
+    pub fn search_candidates(&self, info: &AudioInfo, count: usize) -> Result<Vec<(AudioInfo, String)>, AudioError> {
+        let mut command = Command::new(&self.binary);
+        command
+            .args(["--flat-playlist", "--print", "%(id)s\t%(title)s"])
+            .arg(format!("ytsearch{}:{}", count, self.search_query(info)?))
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        self.network.apply(&mut command);
+        own_process_group(&mut command);
+        let output = run_with_timeout(&mut command, "yt-dlp search", self.search_timeout, &self.cancel)?;
+        if !output.status.success() {
+            return Err(ytdlp_error(output.status, &output.stderr));
+        }
+        Ok(output
+            .stdout
+            .lines()
+            .filter_map(|line| line.split_once('\t'))
+            .filter(|(id, _)| !self.blocked.contains(id))
+            .map(|(id, title)| {
+                let mut candidate = info.clone();
+                candidate.youtube_url = Some(format!("https://www.youtube.com/watch?v={}", id.trim()));
+                (candidate, title.trim().to_string())
+            })
+            .collect())
+    }
+
+    /// Download just PREVIEW_SECTION of some audio into dir, to hear before committing to the full download.
+    pub fn preview(&self, info: &AudioInfo, dir: &Path) -> Result<PathBuf, AudioError> {
+        let url = info.youtube_url.as_ref().ok_or(AudioError::MissingInfo)?;
+        let mut command = Command::new(&self.binary);
+        command.args(["-x", "--audio-format", "mp3", "--download-sections", PREVIEW_SECTION, "-o"]);
+        command.arg(dir.join("preview.%(ext)s")).arg(url);
+        self.network.apply(&mut command);
+        let output = run_with_progress(&mut command, &mut NoProgress, &self.cancel, self.stall_timeout)?;
+        self.cancel.check()?;
+        if output.stalled {
+            return Err(AudioError::ExportFailed(format!(
+                "yt-dlp timed out after {}s without progress",
+                self.stall_timeout.as_secs()
+            )));
+        }
+        if !output.status.success() {
+            // Section downloads arrived in yt-dlp 2022.06, older ones reject the option outright.
+            if output.stderr.contains("no such option: --download-sections") {
+                return Err(AudioError::Unavailable(
+                    "this yt-dlp can't download sections, update it to preview tracks".to_string(),
+                ));
+            }
+            return Err(ytdlp_error(output.status, &output.stderr));
+        }
+        dir_files(dir)
+            .into_iter()
+            .find(|path| has_audio_extension(path))
+            .ok_or_else(|| AudioError::ExportFailed("yt-dlp didn't write a preview".to_string()))
+    }
+
+    fn download_audio(
+        &self,
+        info: &AudioInfo,
+        output_dir: &Path,
+        naming: &DestNaming,
+        reporter: &mut dyn ProgressReporter,
+    ) -> Result<(PathBuf, Option<YtDlpMetadata>), AudioError> {
         let url = info
             .youtube_url
             .as_ref()
             .expect("Must provide a youtube URL to download audio.");
-        let dest_filename = match (&info.artist, &info.title) {
-            (Some(artist), Some(title)) => {
-                // Use clean file format based on AudioInfo, instead of the URL
-                let san_artist = sanitize_filename(artist);
-                let san_title = sanitize_filename(title);
-                format!(
-                    "{}/{} - {}.%(ext)s",
-                    output_dir.display(),
-                    san_artist,
-                    san_title
-                )
+        let dest_filename = format!(
+            "{}/{}",
+            output_dir.display(),
+            naming.ytdlp_template(info, FilenameRules::Local)
+        );
+        // Print the info JSON once the file has been post-processed and moved, so it includes the final filepath.
+        let mut command = Command::new(&self.binary);
+        command.args([
+            "-x",
+            "--audio-format",
+            "mp3",
+            "--extractor-args",
+            "youtube:player_client=android",
+            "--print",
+            "after_move:%()j",
+            // --print implies quiet, but progress is still wanted, one parseable line per update.
+            "--progress",
+            "--newline",
+            "--progress-template",
+            PROGRESS_TEMPLATE,
+            "-o",
+            &dest_filename,
+            url,
+        ]);
+        if self.keep_original {
+            command.arg("--keep-video");
+        }
+        self.network.apply(&mut command);
+        let existing = dir_files(output_dir);
+        let mut attempt = 1;
+        let output = loop {
+            let output = run_with_progress(&mut command, reporter, &self.cancel, self.stall_timeout);
+            let retryable = output.as_ref().is_ok_and(|run| {
+                run.stalled || (!run.status.success() && YtDlpFailure::classify(&run.stderr) == YtDlpFailure::Retryable)
+            });
+            if !retryable || attempt >= MAX_DOWNLOAD_ATTEMPTS || self.cancel.is_cancelled() {
+                break output;
             }
-            _ => format!("{}/%(title)s.%(ext)s", output_dir.display()),
+            // Left in place, yt-dlp resumes from its .part file.
+            let backoff = RETRY_BACKOFF * 2u32.pow(attempt - 1);
+            tracing::warn!("yt-dlp was throttled, stalled or lost its connection for {:?}, retrying in {:?}", info, backoff);
+            sleep_unless_cancelled(backoff, &self.cancel);
+            attempt += 1;
         };
-        let status = Command::new("yt-dlp")
-            .args([
-                "-x",
-                "--audio-format",
-                "mp3",
-                "--extractor-args",
-                "youtube:player_client=android",
-                "-o",
-                &dest_filename,
-                url,
-            ])
-            .status();
+        if self.cancel.is_cancelled() {
+            // Whatever yt-dlp had written so far, .part files and unconverted downloads, is of no use to anyone.
+            for partial in dir_files(output_dir).difference(&existing) {
+                tracing::info!("removing partial download {}", partial.display());
+                std::fs::remove_file(partial).ok();
+            }
+            return Err(AudioError::Cancelled);
+        }
 
-        match status {
-            Ok(status) => {
+        match output {
+            Ok(RunOutput { stalled: true, stderr, .. }) => {
+                tracing::error!("yt-dlp stalled for {:?}, stderr:\n{}", info, stderr_tail(&stderr));
+                Err(AudioError::ExportFailed(format!(
+                    "yt-dlp timed out after {}s without progress",
+                    self.stall_timeout.as_secs()
+                )))
+            }
+            Ok(RunOutput { status, stdout, stderr, .. }) => {
                 if !status.success() {
-                    return Err(AudioError::ExportFailed(format!(
-                        "ytb-dl exited with status: {}",
-                        status
-                    )));
-                } else {
-                    let dest_path = PathBuf::from(&dest_filename);
-                    if dest_path.exists() {
-                        return Ok(dest_path.to_path_buf());
-                    } else {
-                        return Err(AudioError::ExportFailed(format!(
-                            "ytb-dl failed to write output file: {}",
-                            dest_filename
-                        )));
+                    tracing::error!("yt-dlp failed for {:?} with {}, stderr:\n{}", info, status, stderr_tail(&stderr));
+                    return Err(ytdlp_error(status, &stderr));
+                }
+                let metadata = stdout
+                    .lines()
+                    .rev()
+                    .find(|line| line.trim_start().starts_with('{'))
+                    .and_then(YtDlpMetadata::parse);
+
+                // Prefer the path yt-dlp reports, otherwise audio is always extracted to mp3, so resolve the
+                // extension placeholder to find the output.
+                let dest_path = metadata
+                    .as_ref()
+                    .and_then(|m| m.filepath.as_ref())
+                    .map(PathBuf::from)
+                    .unwrap_or_else(|| PathBuf::from(dest_filename.replace("%(ext)s", "mp3").replace("%%", "%")));
+                if dest_path.exists() {
+                    let mut metadata = metadata;
+                    if self.keep_original {
+                        let original = find_original(&dest_path);
+                        metadata.get_or_insert_default().original_filepath = original;
                     }
+                    Ok((dest_path, metadata))
+                } else {
+                    Err(AudioError::ExportFailed(format!(
+                        "ytb-dl failed to write output file: {}",
+                        dest_filename
+                    )))
                 }
             }
-            Err(e) => Err(AudioError::ExportFailed(e.to_string())),
+            // Keep the io error, so a missing yt-dlp is reported as such.
+            Err(e) => Err(AudioError::Io(e)),
         }
     }
 
     // Start by connecting song name and artist to youtube, see what we
     // can search by.
-    fn search_audio(&self, artist: &str, title: &str) -> Result<String, AudioError> {
-        // Trim whitespace and "+" separate name and artist word by word.
-        let title_and_artist = title
-            .trim()
-            .split_whitespace()
-            .chain(artist.trim().split_whitespace())
-            .collect::<Vec<_>>()
-            .join(" ");
-
-        let output = Command::new("yt-dlp")
+    fn search_audio(&self, query: &str) -> Result<String, AudioError> {
+        let mut command = Command::new(&self.binary);
+        command
             .args([
                 "--get-id",
                 "--default-search",
                 "ytsearch1",
-                &title_and_artist,
+                query,
             ])
-            .output();
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        self.network.apply(&mut command);
+        own_process_group(&mut command);
+        let output = run_with_timeout(&mut command, "yt-dlp search", self.search_timeout, &self.cancel)?;
+        if !output.status.success() {
+            tracing::error!("yt-dlp search for {} failed with {}, stderr:\n{}", query, output.status, stderr_tail(&output.stderr));
+            return Err(ytdlp_error(output.status, &output.stderr));
+        }
+        let video_id = output.stdout.trim().to_string();
+        Ok(format!("https://www.youtube.com/watch?v={}", video_id))
+    }
+}
 
-        match output {
-            Ok(output) => {
-                if !output.status.success() {
-                    return Err(AudioError::ExportFailed(format!(
-                        "ytb-dl exited with status: {}",
-                        output.status
-                    )));
+// Marks yt-dlp's progress lines on stdout, apart from the info JSON.
+const PROGRESS_MARKER: &str = "music-man-progress";
+const PROGRESS_TEMPLATE: &str = "download:music-man-progress %(progress._percent_str)s %(progress.speed)s";
+
+const MAX_DOWNLOAD_ATTEMPTS: u32 = 3;
+// Doubled after each retry.
+const RETRY_BACKOFF: Duration = Duration::from_secs(5);
+// How much of yt-dlp's stderr makes it into errors, the cause is almost always in the last few lines.
+const STDERR_TAIL_LINES: usize = 20;
+
+// Why a yt-dlp run failed, going by what it printed on stderr.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum YtDlpFailure {
+    // Rate limited or a network blip, worth trying again after a pause.
+    Retryable,
+    // The video itself can't be had: removed, private or behind a sign-in. Retrying won't help.
+    Unavailable,
+    // Blocked in the country we appear to be in, which geo_bypass_country or a proxy might get around.
+    GeoBlocked,
+    // ffmpeg isn't installed, so nothing will work until it is.
+    MissingTool,
+    // YouTube changed and this yt-dlp can't keep up, only updating it helps.
+    Outdated,
+    Other,
+}
+
+impl YtDlpFailure {
+    fn classify(stderr: &str) -> Self {
+        // Warnings, e.g. "nsig extraction failed: Some formats may be missing", don't stop a download, so they say
+        // nothing about why one failed.
+        let stderr: String = stderr
+            .to_lowercase()
+            .lines()
+            .filter(|line| !line.trim_start().starts_with("warning:"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let any = |patterns: &[&str]| patterns.iter().any(|p| stderr.contains(p));
+        // Geo-blocked videos are also reported as "Video unavailable", so those patterns go first.
+        if any(&[
+            "not available in your country",
+            "not made this video available in your country",
+            "geo restriction",
+            "geo-restricted",
+            "from your location",
+        ]) {
+            YtDlpFailure::GeoBlocked
+        } else if any(&[
+            "video unavailable",
+            "private video",
+            "sign in to confirm",
+            "has been removed",
+            "members-only",
+            "unsupported url",
+        ]) {
+            YtDlpFailure::Unavailable
+        } else if any(&["ffmpeg not found", "ffprobe not found", "ffprobe and ffmpeg not found"]) {
+            YtDlpFailure::MissingTool
+        } else if any(&[
+            "signature extraction failed",
+            "nsig extraction failed",
+            "unable to extract uploader id",
+            "unable to extract initial player response",
+        ]) {
+            YtDlpFailure::Outdated
+        } else if any(&[
+            "http error 429",
+            "too many requests",
+            "http error 5",
+            "timed out",
+            "connection reset",
+            "temporary failure in name resolution",
+            "remote end closed connection",
+            "incomplete read",
+        ]) {
+            YtDlpFailure::Retryable
+        } else {
+            YtDlpFailure::Other
+        }
+    }
+}
+
+// The last few lines of yt-dlp's stderr.
+fn stderr_tail(stderr: &str) -> String {
+    let lines: Vec<&str> = stderr.lines().filter(|line| !line.trim().is_empty()).collect();
+    lines[lines.len().saturating_sub(STDERR_TAIL_LINES)..].join("\n")
+}
+
+// The error for a failed yt-dlp run, carrying the tail of its stderr since that's where the actual cause is.
+fn ytdlp_error(status: std::process::ExitStatus, stderr: &str) -> AudioError {
+    let tail = stderr_tail(stderr);
+    match YtDlpFailure::classify(stderr) {
+        YtDlpFailure::MissingTool => AudioError::Unavailable(format!("yt-dlp needs ffmpeg installed\n{}", tail)),
+        YtDlpFailure::GeoBlocked => AudioError::ExportFailed(format!(
+            "ytb-dl exited with status: {}\n{}\nThis looks geo-blocked, try setting geo_bypass_country in config.toml, \
+             or --geo-bypass-country <code> for one download",
+            status, tail
+        )),
+        YtDlpFailure::Outdated => AudioError::ExportFailed(format!(
+            "ytb-dl exited with status: {}\n{}\nThis looks like yt-dlp is out of date, run `ytdlp update`",
+            status, tail
+        )),
+        _ if tail.is_empty() => AudioError::ExportFailed(format!("ytb-dl exited with status: {}", status)),
+        _ => AudioError::ExportFailed(format!("ytb-dl exited with status: {}\n{}", status, tail)),
+    }
+}
+
+// Sleep, waking early if cancelled.
+fn sleep_unless_cancelled(duration: Duration, cancel: &CancelToken) {
+    let step = Duration::from_millis(100);
+    let mut slept = Duration::ZERO;
+    while slept < duration && !cancel.is_cancelled() {
+        std::thread::sleep(step);
+        slept += step;
+    }
+}
+
+// What a finished yt-dlp run printed, with its progress lines taken out of stdout.
+struct RunOutput {
+    status: std::process::ExitStatus,
+    stdout: String,
+    stderr: String,
+    // Killed after going stall_timeout without printing anything.
+    stalled: bool,
+}
+
+// Run yt-dlp, reporting its progress lines as they arrive. stderr is still shown as it comes, and also kept, since it
+// usually says why a download failed. yt-dlp runs in its own process group, killed along with its ffmpeg children if
+// cancelled, or if it goes stall_timeout without printing a line on either stream.
+fn run_with_progress(
+    command: &mut Command,
+    reporter: &mut dyn ProgressReporter,
+    cancel: &CancelToken,
+    stall_timeout: Duration,
+) -> std::io::Result<RunOutput> {
+    tracing::debug!("running {:?}", command);
+    own_process_group(command);
+    let mut child = command.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+    let last_output = Arc::new(Mutex::new(Instant::now()));
+    let stalled = Arc::new(AtomicBool::new(false));
+    // Only killed while unreaped, so the pid can't have been reused by then.
+    let reaped = Arc::new(Mutex::new(false));
+    let watcher = {
+        let (reaped, cancel, pid) = (reaped.clone(), cancel.clone(), child.id());
+        let (last_output, stalled) = (last_output.clone(), stalled.clone());
+        std::thread::spawn(move || {
+            loop {
+                let reaped = reaped.lock().unwrap_or_else(PoisonError::into_inner);
+                if *reaped {
+                    break;
+                }
+                if cancel.is_cancelled() {
+                    kill_process_group(pid);
+                    break;
+                }
+                if last_output.lock().unwrap_or_else(PoisonError::into_inner).elapsed() > stall_timeout {
+                    stalled.store(true, Ordering::SeqCst);
+                    kill_process_group(pid);
+                    break;
+                }
+                drop(reaped);
+                std::thread::sleep(Duration::from_millis(100));
+            }
+        })
+    };
+    let touch = |last_output: &Mutex<Instant>| *last_output.lock().unwrap_or_else(PoisonError::into_inner) = Instant::now();
+    let stderr_pipe = child.stderr.take();
+    // Echoed for the user, unless that would land in the middle of an event stream.
+    let echo_stderr = !reporter.on_stderr();
+    let stderr_reader = {
+        let last_output = last_output.clone();
+        std::thread::spawn(move || {
+            let mut stderr = String::new();
+            for line in stderr_pipe.into_iter().flat_map(|pipe| BufReader::new(pipe).lines()).map_while(Result::ok) {
+                touch(&last_output);
+                if echo_stderr {
+                    eprintln!("{}", line);
+                } else {
+                    tracing::debug!("yt-dlp: {}", line);
+                }
+                stderr.push_str(&line);
+                stderr.push('\n');
+            }
+            stderr
+        })
+    };
+    let mut stdout = String::new();
+    if let Some(pipe) = child.stdout.take() {
+        for line in BufReader::new(pipe).lines() {
+            let line = line?;
+            touch(&last_output);
+            match line.trim().strip_prefix(PROGRESS_MARKER) {
+                Some(progress) => {
+                    // The speed is NA until yt-dlp has measured it.
+                    let mut fields = progress.split_whitespace();
+                    let percent = fields.next().and_then(|percent| percent.trim_end_matches('%').parse::<f32>().ok());
+                    let bytes_per_sec = fields.next().and_then(|speed| speed.parse::<f64>().ok()).map(|speed| speed as u64);
+                    if let Some(percent) = percent {
+                        reporter.report(Event::DownloadProgress { percent, bytes_per_sec });
+                    }
+                }
+                None => {
+                    stdout.push_str(&line);
+                    stdout.push('\n');
                 }
-                let video_id = String::from_utf8_lossy(&output.stdout).trim().to_string();
-                Ok(format!("https://www.youtube.com/watch?v={}", video_id))
             }
-            Err(e) => Err(AudioError::Io(e)),
         }
     }
+    *reaped.lock().unwrap_or_else(PoisonError::into_inner) = true;
+    watcher.join().ok();
+    let status = child.wait()?;
+    let stderr = stderr_reader.join().unwrap_or_default();
+    Ok(RunOutput { status, stdout, stderr, stalled: stalled.load(Ordering::SeqCst) })
 }
 
-fn sanitize_filename(s: &str) -> String {
-    s.chars()
-        .map(|c| match c {
-            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
-            _ => c,
-        })
+// Every file directly in a directory.
+fn dir_files(dir: &Path) -> HashSet<PathBuf> {
+    std::fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|path| path.is_file())
         .collect()
 }
 
+// yt-dlp keeps the original download next to the converted file, with the same stem and a different extension.
+fn find_original(converted: &Path) -> Option<PathBuf> {
+    let stem = converted.file_stem()?;
+    std::fs::read_dir(converted.parent()?)
+        .ok()?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .find(|path| path != converted && path.is_file() && path.file_stem() == Some(stem))
+}
+
+/// Expand a search query template, substituting {artist} and {title} in a single pass so that placeholder-like
+/// text inside the values is never expanded again. Whitespace is collapsed, so empty fields don't leave gaps.
+pub fn build_search_query(template: &str, artist: &str, title: &str) -> String {
+    let mut query = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        query.push_str(&rest[..start]);
+        let placeholder = &rest[start..];
+        if let Some(stripped) = placeholder.strip_prefix("{artist}") {
+            query.push_str(artist);
+            rest = stripped;
+        } else if let Some(stripped) = placeholder.strip_prefix("{title}") {
+            query.push_str(title);
+            rest = stripped;
+        } else {
+            query.push('{');
+            rest = &placeholder[1..];
+        }
+    }
+    query.push_str(rest);
+
+    query.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
 fn normalize_title(title: &str) -> String {
     // Remove common YouTube suffixes
     let patterns = [
@@ -191,8 +884,131 @@ fn parse_and_rename(filename: &str) -> Option<String> {
     let (artist, title) = stem.split_once(" - ")
         .or_else(|| stem.split_once(" – "))?;
     
-    let clean_artist = sanitize_filename(artist.trim());
-    let clean_title = sanitize_filename(&normalize_title(title.trim()));
+    let clean_artist = sanitize(artist.trim(), FilenameRules::Local);
+    let clean_title = sanitize(&normalize_title(title.trim()), FilenameRules::Local);
     
     Some(format!("{} - {}.{}", clean_artist, clean_title, ext))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn search_queries_put_artist_and_title_where_the_template_says() {
+        assert_eq!(build_search_query("{title} {artist}", "Muse", "Uprising"), "Uprising Muse");
+        assert_eq!(build_search_query("{artist} - {title} audio", "Muse", "Uprising"), "Muse - Uprising audio");
+        // Placeholders inside the values are left alone, and empty values don't leave gaps.
+        assert_eq!(build_search_query("{artist} {title}", "{title}", "x"), "{title} x");
+        assert_eq!(build_search_query("{artist}  {title} {official}", "", "Uprising"), "Uprising {official}");
+
+        let mut source = YtDlpSource::new("ytdlp");
+        source.query_template = "{artist} - {title}".to_string();
+        let info = AudioInfo { artist: Some("Muse".to_string()), title: Some("Uprising".to_string()), ..Default::default() };
+        assert_eq!(source.search_query(&info).unwrap(), "Muse - Uprising");
+        let title_only = AudioInfo { title: Some(" Uprising ".to_string()), ..Default::default() };
+        assert_eq!(source.search_query(&title_only).unwrap(), "Uprising");
+        assert!(matches!(source.search_query(&AudioInfo::default()), Err(AudioError::MissingInfo)));
+    }
+
+    // Recorded from `yt-dlp --print-json`, trimmed.
+    const YTDLP_INFO: &str = include_str!("../tests/fixtures/ytdlp_info.json");
+
+    #[test]
+    fn ytdlp_metadata_fills_in_what_the_info_is_missing() {
+        let metadata = YtDlpMetadata::parse(YTDLP_INFO).unwrap();
+        assert_eq!(metadata.id.as_deref(), Some("w8KQmps-Sog"));
+        assert_eq!(metadata.duration_secs, Some(305));
+        assert_eq!(metadata.upload_date.as_deref(), Some("20090911"));
+        assert_eq!(metadata.filepath.as_deref(), Some("/tmp/music-man/Muse - Uprising.mp3"));
+        assert_eq!(metadata.album_artist, None);
+
+        let mut info = AudioInfo::default();
+        metadata.merge_into(&mut info);
+        assert_eq!((info.artist.as_deref(), info.title.as_deref()), (Some("Muse"), Some("Uprising")));
+        assert_eq!(info.album.as_deref(), Some("The Resistance"));
+        assert_eq!(info.duration_secs, Some(305));
+        assert_eq!(info.filename.as_deref(), Some("Muse - Uprising.mp3"));
+        assert_eq!(info.youtube_url.as_deref(), Some("https://www.youtube.com/watch?v=w8KQmps-Sog"));
+        // What the user asked for is kept.
+        let mut asked = AudioInfo { artist: Some("MUSE".to_string()), title: Some("Uprising (Live)".to_string()), ..Default::default() };
+        metadata.merge_into(&mut asked);
+        assert_eq!(asked.title.as_deref(), Some("Uprising (Live)"));
+    }
+
+    #[test]
+    fn ytdlp_metadata_survives_schema_drift() {
+        // Without artist and track, the video title is split, then the uploader credited.
+        let metadata = YtDlpMetadata::parse(r#"{"title": "Muse - Uprising (Official Video)", "duration": "unknown"}"#).unwrap();
+        assert_eq!(metadata.duration_secs, None);
+        let mut info = AudioInfo::default();
+        metadata.merge_into(&mut info);
+        assert_eq!((info.artist.as_deref(), info.title.as_deref()), (Some("Muse"), Some("Uprising")));
+
+        let metadata = YtDlpMetadata::parse(r#"{"title": 42, "uploader": "MuseVEVO", "artists": "Muse"}"#).unwrap();
+        assert_eq!((metadata.title, metadata.artists.len()), (None, 0));
+        assert!(YtDlpMetadata::parse("{}").is_some());
+        assert!(YtDlpMetadata::parse("[download] 100%").is_none());
+    }
+
+    // A stand-in for yt-dlp that runs script, whatever it's asked to do.
+    #[cfg(unix)]
+    fn fake_ytdlp(name: &str, script: &str) -> PathBuf {
+        use std::os::unix::fs::PermissionsExt;
+        let path = std::env::temp_dir().join(format!("music-man-fake-ytdlp-{}-{}", name, std::process::id()));
+        std::fs::write(&path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hung_ytdlp_runs_are_killed() {
+        let mut source = YtDlpSource::new("ytdlp");
+        source.binary = fake_ytdlp("hang", "exec sleep 600");
+        source.search_timeout = Duration::from_secs(1);
+        source.cancel = CancelToken::new();
+        let info = AudioInfo { artist: Some("Muse".to_string()), title: Some("Uprising".to_string()), ..Default::default() };
+        let started = Instant::now();
+        match source.search_candidates(&info, 1) {
+            Err(AudioError::ExportFailed(message)) => assert!(message.contains("timed out"), "{}", message),
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+        assert!(started.elapsed() < Duration::from_secs(5));
+        std::fs::remove_file(&source.binary).ok();
+
+        // Downloads are only killed once they stop printing anything, however long they take overall.
+        let mut moving = Command::new("sh");
+        moving.args(["-c", "for i in 1 2 3 4 5 6; do echo downloading >&2; sleep 0.3; done"]);
+        let output = run_with_progress(&mut moving, &mut NoProgress, &CancelToken::new(), Duration::from_secs(1)).unwrap();
+        assert!(!output.stalled && output.status.success());
+
+        let mut stalled = Command::new("sh");
+        stalled.args(["-c", "echo downloading; exec sleep 600"]);
+        let started = Instant::now();
+        let output = run_with_progress(&mut stalled, &mut NoProgress, &CancelToken::new(), Duration::from_secs(1)).unwrap();
+        assert!(output.stalled && !output.status.success());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn geo_blocks_are_not_mistaken_for_unavailable_videos() {
+        let stderr = "ERROR: [youtube] dQw4w9WgXcQ: Video unavailable. The uploader has not made this video available \
+                      in your country";
+        assert_eq!(YtDlpFailure::classify(stderr), YtDlpFailure::GeoBlocked);
+        let stderr = "ERROR: [youtube] dQw4w9WgXcQ: Video unavailable. This video is not available in your country";
+        assert_eq!(YtDlpFailure::classify(stderr), YtDlpFailure::GeoBlocked);
+        let stderr = "ERROR: [youtube] dQw4w9WgXcQ: Video unavailable. This video has been removed by the uploader";
+        assert_eq!(YtDlpFailure::classify(stderr), YtDlpFailure::Unavailable);
+    }
+
+    #[test]
+    fn format_warnings_are_not_an_outdated_ytdlp() {
+        // Only a warning, the failure that follows says what actually went wrong.
+        let stderr = "WARNING: [youtube] dQw4w9WgXcQ: nsig extraction failed: Some formats may be missing\n\
+                      ERROR: unable to download video data: HTTP Error 429: Too Many Requests";
+        assert_eq!(YtDlpFailure::classify(stderr), YtDlpFailure::Retryable);
+        let stderr = "WARNING: [youtube] Some formats may be missing\nERROR: Private video";
+        assert_eq!(YtDlpFailure::classify(stderr), YtDlpFailure::Unavailable);
+    }
+}
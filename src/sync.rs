@@ -0,0 +1,1055 @@
+// Sync -> Bring a cache playlist onto an AttachedDevice. Audio already on the device is compared by content, so that
+// "already present and identical" is reported distinctly from "present but different", and only the latter is ever
+// rewritten, and only when the collision policy allows it.
+
+use std::{
+    collections::{HashSet, hash_map::RandomState},
+    hash::{BuildHasher, Hasher},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc,
+    },
+    time::Instant,
+};
+
+use crate::{
+    audio::{AudioError, AudioInfo, AudioKey, AudioLocation, PlaylistName, audio_extension, is_audio_file_in, nfc, strip_number_prefix},
+    cache::LocalCache,
+    checksums::DeviceChecksums,
+    device::AttachedDevice,
+    events::{Event, ProgressReporter},
+    fsutil::{files_identical, format_size, hash_file, same_file, smart_copy_hashed, sync_dir},
+    fuzzy,
+    manifest::{DeviceManifest, trash_on_device},
+    naming::{DestNaming, FilenameRules},
+    target::AudioTarget,
+};
+
+// What to do with one collision, where the device already has a different copy of the audio.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum ConflictChoice {
+    Overwrite,
+    Skip,
+    // Copy alongside the existing file under a numbered name.
+    KeepBoth,
+}
+
+impl ConflictChoice {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "overwrite" => Some(ConflictChoice::Overwrite),
+            "skip" => Some(ConflictChoice::Skip),
+            "keep-both" => Some(ConflictChoice::KeepBoth),
+            _ => None,
+        }
+    }
+}
+
+// How collisions are resolved for a sync run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CollisionPolicy {
+    // Prompt for each collision, until an "always" answer.
+    Ask,
+    Always(ConflictChoice),
+}
+
+impl Default for CollisionPolicy {
+    fn default() -> Self {
+        CollisionPolicy::Always(ConflictChoice::Skip)
+    }
+}
+
+// What a collision is about.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictKind {
+    // A different file is already on the device under the track's name.
+    #[default]
+    Differs,
+    // Nothing on the device under the track's name, but a file whose name nearly matches it. Overwriting replaces that
+    // file, keeping both copies the track alongside it.
+    FuzzyMatch,
+    // The cached audio's length is far from the playlist entry's. Overwriting or keeping both copies it anyway.
+    DurationMismatch { expected_secs: u32, cached_secs: u32 },
+}
+
+// A collision to resolve, by policy or by asking.
+#[derive(Clone, Copy, Debug)]
+pub struct Conflict<'a> {
+    pub playlist: &'a str,
+    pub info: &'a AudioInfo,
+    // The device file for Differs and FuzzyMatch, the cached file for DurationMismatch.
+    pub path: &'a Path,
+    pub kind: ConflictKind,
+}
+
+// An answer to a collision prompt, "always" answers apply to the rest of the run only.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PromptAnswer {
+    Once(ConflictChoice),
+    Always(ConflictChoice),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum ResolvedBy {
+    Policy,
+    Prompt,
+    // An earlier "always" answer in the same run.
+    EarlierAnswer,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ConflictResolution {
+    #[serde(default)]
+    pub kind: ConflictKind,
+    pub choice: ConflictChoice,
+    pub resolved_by: ResolvedBy,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum SyncOutcome {
+    Copied,
+    Identical,
+    // Present on the device with different content, left alone by the collision policy.
+    Differs,
+    Overwritten,
+    // Present with different content, and copied alongside it.
+    KeptBoth,
+    // Left off the device by the playlist's size budget.
+    OverBudget,
+    // Previously synced, but fell out of the playlist's size budget and was moved to the device trash.
+    RotatedOut,
+    // Marked to never be synced to this device.
+    Skipped,
+    Failed(String),
+    // Not synced, the sync was cancelled before or while copying it.
+    Cancelled,
+}
+
+impl SyncOutcome {
+    // A track's failure, unless it failed because the sync was cancelled.
+    fn from_error(e: AudioError) -> Self {
+        if e.is_cancelled() { SyncOutcome::Cancelled } else { SyncOutcome::Failed(e.to_string()) }
+    }
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct TrackReport {
+    pub info: AudioInfo,
+    pub outcome: SyncOutcome,
+    // How a collision was resolved, if there was one.
+    pub resolution: Option<ConflictResolution>,
+}
+
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct SyncReport {
+    pub playlist: String,
+    pub tracks: Vec<TrackReport>,
+    // Bytes written to the device, and how long the whole sync took, for the effective throughput.
+    #[serde(default)]
+    pub copied_bytes: u64,
+    #[serde(default)]
+    pub elapsed_secs: f64,
+}
+
+impl SyncReport {
+    pub fn count(&self, outcome: &SyncOutcome) -> usize {
+        self.tracks
+            .iter()
+            .filter(|t| std::mem::discriminant(&t.outcome) == std::mem::discriminant(outcome))
+            .count()
+    }
+
+    pub fn cancelled(&self) -> bool {
+        self.count(&SyncOutcome::Cancelled) > 0
+    }
+
+    /// Bytes per second written to the device over the whole sync, None if nothing was copied.
+    pub fn throughput(&self) -> Option<u64> {
+        (self.copied_bytes > 0 && self.elapsed_secs > 0.0).then(|| (self.copied_bytes as f64 / self.elapsed_secs) as u64)
+    }
+
+    /// One line of counts by outcome.
+    pub fn summary(&self) -> String {
+        format!(
+            "{} copied, {} overwritten, {} kept both, {} identical, {} differ (skipped), {} over budget, {} rotated out, \
+             {} skipped on this device, {} failed",
+            self.count(&SyncOutcome::Copied),
+            self.count(&SyncOutcome::Overwritten),
+            self.count(&SyncOutcome::KeptBoth),
+            self.count(&SyncOutcome::Identical),
+            self.count(&SyncOutcome::Differs),
+            self.count(&SyncOutcome::OverBudget),
+            self.count(&SyncOutcome::RotatedOut),
+            self.count(&SyncOutcome::Skipped),
+            self.count(&SyncOutcome::Failed(String::new())),
+        )
+    }
+
+    pub fn print(&self) {
+        for track in &self.tracks {
+            match &track.outcome {
+                SyncOutcome::Failed(e) => println!("FAILED {:?}: {}", track.info, e),
+                SyncOutcome::RotatedOut => println!("Rotated out {:?}", track.info),
+                _ => {}
+            }
+        }
+        println!("Synced {}: {}", self.playlist, self.summary());
+        if let Some(throughput) = self.throughput() {
+            println!(
+                "Wrote {} in {:.1}s ({}/s)",
+                format_size(self.copied_bytes),
+                self.elapsed_secs,
+                format_size(throughput)
+            );
+        }
+        if self.cancelled() {
+            println!("Cancelled, {} tracks not synced:", self.count(&SyncOutcome::Cancelled));
+            for track in self.tracks.iter().filter(|t| t.outcome == SyncOutcome::Cancelled) {
+                println!("  {:?}", track.info);
+            }
+        }
+    }
+}
+
+/// Sync a cache playlist to the device, in playlist order. Each collision is reported as an Event::Conflict, and with
+/// CollisionPolicy::Ask put to `prompt`, until it answers "always". Cancelling the device's token stops the sync between
+/// tracks (and mid-copy), the tracks not synced are reported as Cancelled, and what was synced is still recorded.
+pub fn sync_playlist(
+    cache: &LocalCache,
+    device: &mut AttachedDevice,
+    playlist: &str,
+    mut policy: CollisionPolicy,
+    prompt: &mut dyn FnMut(&Conflict) -> PromptAnswer,
+    reporter: &mut dyn ProgressReporter,
+) -> Result<SyncReport, AudioError> {
+    device.ensure_writable()?;
+    let tracks = cache.playlist_tracks(playlist).ok_or(AudioError::NotFound)?.into_owned();
+    // A mapping onto another playlist's directory would mix the two, checked against every cached playlist since
+    // unmapped ones take a directory of their own name.
+    device.profile.check_destinations(cache.playlist_names())?;
+    let mut manifest = DeviceManifest::load(&device.path, device.case_insensitive());
+    let mut checksums = DeviceChecksums::load(&device.path);
+    let mut report = SyncReport {
+        playlist: playlist.to_string(),
+        ..Default::default()
+    };
+
+    let skipped: Vec<bool> = tracks.iter().map(|info| device.skips(info)).collect();
+    let playlist_keys = playlist_keys(cache, device, playlist);
+    let in_budget = match device.profile.budgets.get(playlist) {
+        Some(budget) => within_budget(cache, &tracks, &skipped, *budget),
+        None => skipped.iter().map(|skipped| !skipped).collect(),
+    };
+    if let Some(budget) = device.profile.budgets.get(playlist) {
+        let pinned: u64 = tracks.iter().zip(&skipped).filter(|(t, s)| t.pinned && !**s).map(|(t, _)| cached_size(cache, t)).sum();
+        if pinned > *budget {
+            println!(
+                "Warning: pinned tracks in {} take {}, over its {} budget on {}",
+                playlist,
+                format_size(pinned),
+                format_size(*budget),
+                device.name
+            );
+        }
+    }
+
+    // Cached formats the device doesn't play are transcoded as they're copied, which takes a while.
+    let transcodes = tracks
+        .iter()
+        .zip(&in_budget)
+        .filter(|(info, included)| **included && needs_transcode(cache, device, &manifest, info))
+        .count();
+    if transcodes > 0 {
+        println!("{} tracks in {} will be transcoded to mp3 for {}", transcodes, playlist, device.name);
+    }
+
+    reporter.report(Event::PlanComputed {
+        playlist,
+        tracks: tracks.len(),
+        in_budget: in_budget.iter().filter(|included| **included).count(),
+    });
+    let total_bytes: u64 = tracks
+        .iter()
+        .zip(&in_budget)
+        .filter(|(_, included)| **included)
+        .map(|(info, _)| cached_size(cache, info))
+        .sum();
+    let mut progress = SyncProgress {
+        reporter,
+        playlist,
+        started: Instant::now(),
+        copied_bytes: 0,
+        total_bytes,
+        tracks: vec![None; tracks.len()],
+    };
+
+    // New tracks only need a plain copy, so are queued for the parallel import below. Everything else, including
+    // collisions that may prompt, is handled here one at a time.
+    let mut copies = Vec::new();
+    let mut queued_keys = HashSet::new();
+    let mut deferred = Vec::new();
+    let mut answered_always = false;
+    for (index, (info, in_budget)) in tracks.iter().zip(&in_budget).enumerate() {
+        if device.cancel.is_cancelled() {
+            progress.finish(index, info, SyncOutcome::Cancelled, 0, None);
+            continue;
+        }
+        progress.reporter.report(Event::TrackStarted { playlist, index, info });
+        if *in_budget && needs_transcode(cache, device, &manifest, info) {
+            progress.reporter.report(Event::Transcoding { playlist, index, info, format: "mp3" });
+        }
+        if *in_budget
+            && let Some((key, source_path)) = new_on_device(cache, device, info)
+            && duration_mismatch(cache, info, &source_path).is_none()
+            && near_match(device, info, &playlist_keys).is_none()
+        {
+            // A repeat of a queued track is only known to be on the device once the queued copy has finished.
+            if queued_keys.insert(key) {
+                copies.push((index, source_path));
+            } else {
+                deferred.push(index);
+            }
+            continue;
+        }
+
+        let mut resolution = None;
+        let reporter = &mut *progress.reporter;
+        let mut resolve = |conflict: &Conflict| {
+            reporter.report(Event::Conflict {
+                playlist,
+                info,
+                path: conflict.path,
+                kind: conflict.kind,
+            });
+            let (choice, resolved_by) = match policy {
+                CollisionPolicy::Always(choice) if answered_always => (choice, ResolvedBy::EarlierAnswer),
+                CollisionPolicy::Always(choice) => (choice, ResolvedBy::Policy),
+                CollisionPolicy::Ask => match prompt(conflict) {
+                    PromptAnswer::Once(choice) => (choice, ResolvedBy::Prompt),
+                    PromptAnswer::Always(choice) => {
+                        policy = CollisionPolicy::Always(choice);
+                        answered_always = true;
+                        (choice, ResolvedBy::Prompt)
+                    }
+                },
+            };
+            resolution = Some(ConflictResolution { kind: conflict.kind, choice, resolved_by });
+            choice
+        };
+        let (outcome, bytes) = if *in_budget {
+            sync_track(cache, device, &mut manifest, &mut checksums, playlist, info, &mut resolve)
+        } else {
+            // A skipped track is taken off the device like one over budget, but reported as skipped.
+            rotate_out(device, &mut manifest, &mut checksums, playlist, info).map(|outcome| match outcome {
+                SyncOutcome::OverBudget if skipped[index] => (SyncOutcome::Skipped, 0),
+                outcome => (outcome, 0),
+            })
+        }
+        .unwrap_or_else(|e| (SyncOutcome::from_error(e), 0));
+        progress.finish(index, info, outcome, bytes, resolution);
+    }
+
+    // Copies run on up to parallel_imports threads, while the manifest and checksums are only ever updated from this
+    // thread, in whatever order copies complete.
+    let mut imported = Vec::new();
+    let root = device.path.clone();
+    let workers = device.profile.parallel_imports();
+    import_parallel(cache, device, playlist, &tracks, &copies, workers, |index, result| {
+        let info = &tracks[index];
+        let (outcome, bytes) = result
+            .and_then(|(dest_path, bytes, hash)| {
+                manifest.record_synced(&root, playlist, &dest_path);
+                let source_path = &copies.iter().find(|(i, _)| *i == index).unwrap().1;
+                record_checksum(cache, &mut manifest, &mut checksums, &root, source_path, &dest_path, Some(hash))?;
+                imported.push((index, dest_path));
+                Ok((SyncOutcome::Copied, bytes))
+            })
+            .unwrap_or_else(|e| (SyncOutcome::from_error(e), 0));
+        progress.finish(index, info, outcome, bytes, None);
+    });
+    for (index, dest_path) in imported {
+        device.update_index(&tracks[index], &AudioLocation::LocalPath(dest_path)).ok();
+    }
+    for index in deferred {
+        let info = &tracks[index];
+        if device.cancel.is_cancelled() {
+            progress.finish(index, info, SyncOutcome::Cancelled, 0, None);
+            continue;
+        }
+        let (outcome, bytes) = sync_track(cache, device, &mut manifest, &mut checksums, playlist, info, &mut |_| {
+            ConflictChoice::Skip
+        })
+        .unwrap_or_else(|e| (SyncOutcome::from_error(e), 0));
+        progress.finish(index, info, outcome, bytes, None);
+    }
+    // Files are synced as they're copied, the directory entries once for the whole playlist.
+    let synced_dir = if device.profile.layout.has_playlist_dirs() {
+        device.playlist_dir(&PlaylistName::Named(playlist.to_string()))
+    } else {
+        device.path.clone()
+    };
+    if progress.copied_bytes > 0
+        && let Err(e) = sync_dir(&synced_dir)
+    {
+        tracing::warn!("sync {}: failed to flush the playlist directory: {}", playlist, e);
+    }
+    // Reported in playlist order, whatever order tracks finished in.
+    report.copied_bytes = progress.copied_bytes;
+    report.elapsed_secs = progress.started.elapsed().as_secs_f64();
+    report.tracks = progress.tracks.into_iter().flatten().collect();
+
+    // Whatever was synced is recorded, cancelled or not, and the checksums and index are saved even when saving the
+    // manifest fails.
+    let saved = manifest.save(&device.path);
+    let saved_checksums = checksums.save(&device.path);
+    device.refresh_index();
+    // Hashes of cached files worked out for the comparisons and checksums, so the next sync doesn't redo them.
+    cache.save_file_index();
+    saved.and(saved_checksums)?;
+    // Without playlist directories the playlist is its M3U file, rewritten to match what's now on the device.
+    if !device.profile.layout.has_playlist_dirs()
+        && let Err(e) = device.write_m3u(playlist, &tracks)
+    {
+        println!("Failed to write {}: {}", device.m3u_path(playlist).display(), e);
+    }
+    tracing::info!(
+        "sync {} finished: {} tracks, {} failed",
+        playlist,
+        report.tracks.len(),
+        report.count(&SyncOutcome::Failed(String::new()))
+    );
+    reporter.report(Event::SyncFinished { report: &report });
+    Ok(report)
+}
+
+// Per track results of a sync in progress, reporting each track as it finishes.
+struct SyncProgress<'a> {
+    reporter: &'a mut dyn ProgressReporter,
+    playlist: &'a str,
+    started: Instant,
+    copied_bytes: u64,
+    total_bytes: u64,
+    // Indexed by playlist position.
+    tracks: Vec<Option<TrackReport>>,
+}
+
+impl SyncProgress<'_> {
+    // bytes is what the track's copy wrote, 0 if nothing was copied.
+    fn finish(
+        &mut self,
+        index: usize,
+        info: &AudioInfo,
+        outcome: SyncOutcome,
+        bytes: u64,
+        resolution: Option<ConflictResolution>,
+    ) {
+        let playlist = self.playlist;
+        match &outcome {
+            SyncOutcome::Failed(e) => tracing::warn!("sync {}: failed {:?}: {}", playlist, info, e),
+            outcome => tracing::debug!("sync {}: {:?} {:?}", playlist, outcome, info),
+        }
+        if matches!(outcome, SyncOutcome::Copied | SyncOutcome::Overwritten | SyncOutcome::KeptBoth) {
+            self.copied_bytes += bytes;
+            let bytes_per_sec = (self.copied_bytes as f64 / self.started.elapsed().as_secs_f64().max(0.001)) as u64;
+            self.reporter.report(Event::CopyProgress {
+                copied_bytes: self.copied_bytes,
+                total_bytes: self.total_bytes,
+                bytes_per_sec,
+            });
+        }
+        self.reporter.report(Event::TrackFinished { playlist, index, info, outcome: &outcome });
+        self.tracks[index] = Some(TrackReport { info: info.clone(), outcome, resolution });
+    }
+}
+
+// The key and cached file of a track that's cached but not yet on the device at all, so needs nothing but a copy.
+fn new_on_device(cache: &LocalCache, device: &AttachedDevice, info: &AudioInfo) -> Option<(AudioKey, PathBuf)> {
+    let key = AudioKey::from_info(info)?;
+    let Ok(AudioLocation::LocalPath(source_path)) = cache.search(info) else {
+        return None;
+    };
+    match device.contains(info) {
+        Err(AudioError::NotFound) => Some((key, source_path)),
+        _ => None,
+    }
+}
+
+// Import the given (playlist index, cached file) copies on up to workers threads. on_done is called on the calling
+// thread as each copy completes, with where it went, the bytes written, and the source's hash.
+fn import_parallel(
+    cache: &LocalCache,
+    device: &AttachedDevice,
+    playlist: &str,
+    tracks: &[AudioInfo],
+    copies: &[(usize, PathBuf)],
+    workers: usize,
+    mut on_done: impl FnMut(usize, Result<(PathBuf, u64, String), AudioError>),
+) {
+    let next = AtomicUsize::new(0);
+    let (done, completed) = mpsc::channel();
+    std::thread::scope(|scope| {
+        for _ in 0..workers.clamp(1, copies.len().max(1)) {
+            let (next, done) = (&next, done.clone());
+            scope.spawn(move || {
+                while let Some((index, source_path)) = copies.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    let location = AudioLocation::LocalPath(source_path.clone());
+                    // Once cancelled, the rest of the queue is drained without copying.
+                    let result = device
+                        .cancel
+                        .check()
+                        .and_then(|_| {
+                            device.import(
+                                &location,
+                                &tracks[*index],
+                                Some(PlaylistName::Named(playlist.to_string())),
+                                &DestNaming::KeepSource,
+                            )
+                        })
+                        .and_then(|imported| match imported.location {
+                            // The hash the copy computed as it went, the source's is only looked up for clones.
+                            AudioLocation::LocalPath(dest_path) => {
+                                let hash = match imported.hash {
+                                    Some(hash) => hash,
+                                    None => copy_hash(cache, source_path, &dest_path)?,
+                                };
+                                Ok((dest_path, imported.bytes, hash))
+                            }
+                            AudioLocation::RemoteUrl(_) => Err(AudioError::Unexpected),
+                        });
+                    if done.send((*index, result)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(done);
+        for (index, result) in completed {
+            on_done(index, result);
+        }
+    });
+}
+
+/// Give every audio file in the playlist's device directory a fresh random numeric prefix ("017 - Artist - Title"),
+/// for players that only play in filename order, or strip previously applied prefixes when `shuffle` is false.
+/// Files are renamed in place, never recopied, and only the device side is touched.
+pub fn apply_shuffle_order(device: &mut AttachedDevice, playlist: &str, shuffle: bool) -> Result<usize, AudioError> {
+    device.ensure_writable()?;
+    let mut manifest = DeviceManifest::load(&device.path, device.case_insensitive());
+    let was_shuffled = manifest.shuffled.iter().any(|p| p == playlist);
+    if !shuffle && !was_shuffled {
+        // Never strip prefixes we didn't add, e.g. real track numbers.
+        return Ok(0);
+    }
+    if !device.profile.layout.has_playlist_dirs() {
+        return Err(AudioError::Config(format!(
+            "Shuffle order renames a playlist's directory, but {} uses the {} layout",
+            device.name,
+            device.profile.layout.name()
+        )));
+    }
+    let mut checksums = DeviceChecksums::load(&device.path);
+
+    let dir = device.playlist_dir(&PlaylistName::Named(playlist.to_string()));
+    let extensions = device.profile.extensions();
+    let mut files: Vec<_> = std::fs::read_dir(&dir)?
+        .filter_map(|e| e.ok())
+        .filter(|entry| is_audio_file_in(entry, &extensions))
+        .map(|e| e.path())
+        .collect();
+    shuffle_in_place(&mut files);
+
+    let width = files.len().to_string().len().max(3);
+    let mut renamed = 0;
+    for (position, path) in files.iter().enumerate() {
+        let (Some(stem), Some(ext)) = (path.file_stem(), path.extension()) else {
+            continue;
+        };
+        let stem = nfc(&stem.to_string_lossy());
+        let base = PathBuf::from(format!("{}.{}", strip_number_prefix(&stem), ext.to_string_lossy()));
+        let naming = if shuffle {
+            DestNaming::Prefixed { prefix: format!("{:0width$} - ", position + 1), base: Box::new(DestNaming::KeepSource) }
+        } else {
+            DestNaming::KeepSource
+        };
+        let Some(new_name) = naming.file_name(&AudioInfo::default(), &base, FilenameRules::Fat) else {
+            continue;
+        };
+        let new_path = dir.join(&new_name);
+        // Compare normalized names, a rename that only changes the normalization form is a no-op here. On a
+        // case-insensitive volume a name differing only by case exists as the file itself, and renaming onto it only
+        // changes the case, so only a different file is a collision.
+        if nfc(&path.to_string_lossy()) == nfc(&new_path.to_string_lossy())
+            || (new_path.exists() && !same_file(path, &new_path))
+        {
+            continue;
+        }
+
+        std::fs::rename(path, &new_path)?;
+        manifest.rename_path(&device.path, path, &new_path);
+        checksums.rename_path(&device.path, path, &new_path);
+        let info = AudioInfo::from_filename(Path::new(&new_name));
+        device.update_index(&info, &AudioLocation::LocalPath(new_path)).ok();
+        renamed += 1;
+    }
+
+    manifest.shuffled.retain(|p| p != playlist);
+    if shuffle {
+        manifest.shuffled.push(playlist.to_string());
+    }
+    manifest.save(&device.path)?;
+    checksums.save(&device.path)?;
+    device.refresh_index();
+    Ok(renamed)
+}
+
+// Fisher-Yates shuffle, seeded from the randomly keyed std hasher so we don't need an RNG dependency.
+pub fn shuffle_in_place<T>(items: &mut [T]) {
+    let mut state = RandomState::new().build_hasher().finish() | 1;
+    for i in (1..items.len()).rev() {
+        // xorshift64
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        items.swap(i, (state % (i as u64 + 1)) as usize);
+    }
+}
+
+// Which tracks fit in a size budget, taking pinned tracks first, even past the budget, then the most recently added.
+// Entries without an added_at predate tracking it, so are treated as oldest, with later playlist positions assumed to
+// be more recent. Skipped tracks are never included.
+fn within_budget(cache: &LocalCache, tracks: &[AudioInfo], skipped: &[bool], budget: u64) -> Vec<bool> {
+    let mut order: Vec<usize> = (0..tracks.len()).filter(|&i| !skipped[i]).collect();
+    order.sort_by_key(|&i| std::cmp::Reverse((tracks[i].pinned, tracks[i].added_at.unwrap_or_default(), i)));
+
+    let mut included = vec![false; tracks.len()];
+    let mut used = 0;
+    for i in order {
+        let size = cached_size(cache, &tracks[i]);
+        if tracks[i].pinned || used + size <= budget {
+            used += size;
+            included[i] = true;
+        }
+    }
+    included
+}
+
+// Size of the cached copy of a track, 0 when it isn't cached.
+fn cached_size(cache: &LocalCache, info: &AudioInfo) -> u64 {
+    match cache.search(info) {
+        Ok(AudioLocation::LocalPath(path)) => std::fs::metadata(path).map(|m| m.len()).unwrap_or_default(),
+        _ => 0,
+    }
+}
+
+// Remove a track from the device if we previously synced it for this playlist, leaving anything else alone.
+fn rotate_out(
+    device: &mut AttachedDevice,
+    manifest: &mut DeviceManifest,
+    checksums: &mut DeviceChecksums,
+    playlist: &str,
+    info: &AudioInfo,
+) -> Result<SyncOutcome, AudioError> {
+    let path = match device.contains(info) {
+        Ok(AudioLocation::LocalPath(path)) => path.clone(),
+        Ok(AudioLocation::RemoteUrl(_)) | Err(AudioError::NotFound) => return Ok(SyncOutcome::OverBudget),
+        Err(e) => return Err(e),
+    };
+    if !manifest.was_synced(&device.path, playlist, &path) {
+        return Ok(SyncOutcome::OverBudget);
+    }
+
+    trash_on_device(&device.path, &path)?;
+    manifest.forget_synced(&device.path, playlist, &path);
+    checksums.forget(&device.path, &path);
+    device.remove_from_index(info);
+    Ok(SyncOutcome::RotatedOut)
+}
+
+fn sync_track(
+    cache: &LocalCache,
+    device: &mut AttachedDevice,
+    manifest: &mut DeviceManifest,
+    checksums: &mut DeviceChecksums,
+    playlist: &str,
+    info: &AudioInfo,
+    resolve: &mut dyn FnMut(&Conflict) -> ConflictChoice,
+) -> Result<(SyncOutcome, u64), AudioError> {
+    AudioKey::from_info(info).ok_or(AudioError::MissingInfo)?;
+    let source = cache.search(info)?;
+    let AudioLocation::LocalPath(source_path) = &source else {
+        return Err(AudioError::Unexpected);
+    };
+
+    let existing = match device.contains(info) {
+        Ok(AudioLocation::LocalPath(path)) => Some(path.clone()),
+        Ok(AudioLocation::RemoteUrl(_)) => return Err(AudioError::Unexpected),
+        Err(AudioError::NotFound) => None,
+        Err(e) => return Err(e),
+    };
+
+    let root = device.path.clone();
+    let conflict = |path, kind| Conflict { playlist, info, path, kind };
+    match existing {
+        None => {
+            if let Some((expected_secs, cached_secs)) = duration_mismatch(cache, info, source_path) {
+                let kind = ConflictKind::DurationMismatch { expected_secs, cached_secs };
+                if resolve(&conflict(source_path, kind)) == ConflictChoice::Skip {
+                    return Ok((SyncOutcome::Differs, 0));
+                }
+            }
+            if let Some(near_path) = near_match(device, info, &playlist_keys(cache, device, playlist)) {
+                match resolve(&conflict(&near_path, ConflictKind::FuzzyMatch)) {
+                    ConflictChoice::Skip => return Ok((SyncOutcome::Differs, 0)),
+                    // Replaced by the track, like an overwrite, but under the track's own name.
+                    ConflictChoice::Overwrite => {
+                        trash_on_device(&root, &near_path)?;
+                        manifest.forget_path(&root, &near_path);
+                        checksums.forget(&root, &near_path);
+                        device.remove_location(&near_path);
+                    }
+                    ConflictChoice::KeepBoth => {}
+                }
+            }
+            let imported = device.import(&source, info, Some(PlaylistName::Named(playlist.to_string())), &DestNaming::KeepSource)?;
+            device.update_index(info, &imported.location)?;
+            if let AudioLocation::LocalPath(dest_path) = &imported.location {
+                manifest.record_synced(&root, playlist, dest_path);
+                record_checksum(cache, manifest, checksums, &root, source_path, dest_path, imported.hash.clone())?;
+            }
+            Ok((SyncOutcome::Copied, imported.bytes))
+        }
+        // Audio the device can't play is on it transcoded, which can't be compared with the cached original, only
+        // with the hash the original had when it was transcoded. Transcodes from before that was recorded are taken
+        // to be of the cached file as it is now.
+        Some(dest_path) if !accepts(device, source_path) => {
+            let source_hash = cache.content_hash(source_path)?;
+            if manifest.transcoded_from(&root, &dest_path).is_some_and(|recorded| recorded != source_hash) {
+                let imported =
+                    device.import(&source, info, Some(PlaylistName::Named(playlist.to_string())), &DestNaming::KeepSource)?;
+                device.update_index(info, &imported.location)?;
+                if let AudioLocation::LocalPath(new_path) = &imported.location {
+                    record_checksum(cache, manifest, checksums, &root, source_path, new_path, imported.hash.clone())?;
+                }
+                return Ok((SyncOutcome::Overwritten, imported.bytes));
+            }
+            let hash = manifest.hash_file(&root, &dest_path)?;
+            record_checksum(cache, manifest, checksums, &root, source_path, &dest_path, Some(hash))?;
+            Ok((SyncOutcome::Identical, 0))
+        }
+        Some(dest_path) => {
+            let identical =
+                files_identical(source_path, &dest_path, &mut |p| cache.content_hash(p), &mut |p| manifest.hash_file(&root, p))?;
+            if identical {
+                let hash = manifest.hash_file(&root, &dest_path)?;
+                record_checksum(cache, manifest, checksums, &root, source_path, &dest_path, Some(hash))?;
+                return Ok((SyncOutcome::Identical, 0));
+            }
+            // A copy kept alongside the existing file by an earlier sync may already be this audio.
+            for kept in manifest.kept_copies(&root, &dest_path) {
+                if kept.exists()
+                    && files_identical(source_path, &kept, &mut |p| cache.content_hash(p), &mut |p| manifest.hash_file(&root, p))?
+                {
+                    let hash = manifest.hash_file(&root, &kept)?;
+                    record_checksum(cache, manifest, checksums, &root, source_path, &kept, Some(hash))?;
+                    return Ok((SyncOutcome::Identical, 0));
+                }
+            }
+            let outcome = match resolve(&conflict(&dest_path, ConflictKind::Differs)) {
+                ConflictChoice::Skip => (SyncOutcome::Differs, 0),
+                ConflictChoice::Overwrite => {
+                    let copied = smart_copy_hashed(source_path, &dest_path, device.copy_options())?;
+                    record_checksum(cache, manifest, checksums, &root, source_path, &dest_path, copied.hash)?;
+                    (SyncOutcome::Overwritten, copied.bytes)
+                }
+                ConflictChoice::KeepBoth => {
+                    let both_path = numbered_path(&dest_path);
+                    let copied = smart_copy_hashed(source_path, &both_path, device.copy_options())?;
+                    manifest.record_synced(&root, playlist, &both_path);
+                    manifest.record_kept_copy(&root, &dest_path, &both_path);
+                    record_checksum(cache, manifest, checksums, &root, source_path, &both_path, copied.hash)?;
+                    (SyncOutcome::KeptBoth, copied.bytes)
+                }
+            };
+            Ok(outcome)
+        }
+    }
+}
+
+// Record the checksum of a file just placed on (or confirmed on) the device, using a hash already computed while copying
+// it or for the comparison, or else the source's, so the device copy isn't reread.
+fn record_checksum(
+    cache: &LocalCache,
+    manifest: &mut DeviceManifest,
+    checksums: &mut DeviceChecksums,
+    root: &Path,
+    source_path: &Path,
+    dest_path: &Path,
+    known_hash: Option<String>,
+) -> Result<(), AudioError> {
+    let hash = match known_hash {
+        Some(hash) => hash,
+        None => copy_hash(cache, source_path, dest_path)?,
+    };
+    let size = std::fs::metadata(dest_path)?.len();
+    manifest.record_hash(root, dest_path, hash.clone())?;
+    checksums.record(root, dest_path, size, hash);
+    if audio_extension(source_path) != audio_extension(dest_path) {
+        manifest.record_transcode(root, dest_path, cache.content_hash(source_path)?);
+    }
+    manifest.record_isrc(root, dest_path, cache.isrcs_of_file(source_path).first().copied());
+    Ok(())
+}
+
+// Whether the device plays a cached file as it is, rather than needing it transcoded.
+fn accepts(device: &AttachedDevice, source_path: &Path) -> bool {
+    device.accepts_extension(&audio_extension(source_path))
+}
+
+// Whether syncing a track transcodes it: the device doesn't play its cached format, and it's either new to the device
+// or the cached file changed since the device's copy was transcoded from it.
+fn needs_transcode(cache: &LocalCache, device: &AttachedDevice, manifest: &DeviceManifest, info: &AudioInfo) -> bool {
+    let Ok(AudioLocation::LocalPath(source_path)) = cache.search(info) else {
+        return false;
+    };
+    if accepts(device, &source_path) {
+        return false;
+    }
+    match device.contains(info) {
+        Ok(AudioLocation::LocalPath(dest_path)) => manifest
+            .transcoded_from(&device.path, dest_path)
+            .is_some_and(|recorded| cache.content_hash(&source_path).is_ok_and(|hash| hash != recorded)),
+        _ => true,
+    }
+}
+
+// The hash a device copy's checksum is recorded with: the cached source's, from the cache's file index when it's been
+// hashed before, unless the copy was transcoded on import.
+fn copy_hash(cache: &LocalCache, source_path: &Path, dest_path: &Path) -> std::io::Result<String> {
+    if audio_extension(source_path) == audio_extension(dest_path) {
+        cache.content_hash(source_path)
+    } else {
+        hash_file(dest_path)
+    }
+}
+
+// Similarity a device file's name needs to the track's to be taken for it, stricter than search's so that tracks
+// differing by a digit, like "Song 1" and "Song 2", aren't.
+const NEAR_MATCH_THRESHOLD: f64 = 0.9;
+
+// The playlist's tracks keyed as on the device. They're never near matches for each other, however alike their names.
+fn playlist_keys(cache: &LocalCache, device: &AttachedDevice, playlist: &str) -> HashSet<AudioKey> {
+    let tracks = cache.playlist_tracks(playlist).unwrap_or_default();
+    tracks.iter().filter_map(|info| device.profile.layout.key(info)).collect()
+}
+
+// A device file, not one of the playlist's own tracks, whose name nearly matches a track not on the device.
+fn near_match(device: &AttachedDevice, info: &AudioInfo, playlist_keys: &HashSet<AudioKey>) -> Option<PathBuf> {
+    let key = device.profile.layout.key(info)?;
+    let candidates = device.index_keys().filter(|candidate| !playlist_keys.contains(*candidate));
+    let (near, _) = fuzzy::best_matches(&key, candidates, NEAR_MATCH_THRESHOLD, 1).into_iter().next()?;
+    match device.location(&near)? {
+        AudioLocation::LocalPath(path) => Some(path.clone()),
+        AudioLocation::RemoteUrl(_) => None,
+    }
+}
+
+// How far a cached file's length may be from the playlist entry's before it's taken for different audio, at least
+// DURATION_TOLERANCE_SECS and otherwise a tenth of the expected length.
+const DURATION_TOLERANCE_SECS: u32 = 10;
+
+// The playlist entry's and the cached file's lengths, when both are known and too far apart.
+fn duration_mismatch(cache: &LocalCache, info: &AudioInfo, source_path: &Path) -> Option<(u32, u32)> {
+    let expected = info.duration_secs?;
+    let cached = cache.duration_secs(source_path)?;
+    (expected.abs_diff(cached) > DURATION_TOLERANCE_SECS.max(expected / 10)).then_some((expected, cached))
+}
+
+// The first free "<stem> (N).<ext>" next to a path.
+fn numbered_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let ext = path.extension().map(|e| format!(".{}", e.to_string_lossy())).unwrap_or_default();
+    (2..)
+        .map(|n| path.with_file_name(format!("{} ({}){}", stem, n, ext)))
+        .find(|candidate| !candidate.exists())
+        .unwrap()
+}
+
+/// Ask on the terminal how to resolve a collision.
+pub fn prompt_on_stdin(conflict: &Conflict) -> PromptAnswer {
+    let track = format!(
+        "{} - {}",
+        conflict.info.artist.as_deref().unwrap_or_default(),
+        conflict.info.title.as_deref().unwrap_or_default()
+    );
+    let question = match conflict.kind {
+        ConflictKind::Differs => format!(
+            "{} differs from {}. [o]verwrite, [s]kip, [k]eep both, [a]lways overwrite this run? ",
+            track,
+            conflict.path.display()
+        ),
+        ConflictKind::FuzzyMatch => format!(
+            "{} isn't on the device, but {} nearly matches it. [o]verwrite it, [s]kip, [k]eep both, [a]lways overwrite \
+             this run? ",
+            track,
+            conflict.path.display()
+        ),
+        ConflictKind::DurationMismatch { expected_secs, cached_secs } => format!(
+            "{} is cached at {}s, but the playlist expects {}s. [o]/[k] copy anyway, [s]kip, [a]lways copy this run? ",
+            track, cached_secs, expected_secs
+        ),
+    };
+    loop {
+        print!("{}", question);
+        std::io::stdout().flush().ok();
+        let mut answer = String::new();
+        if std::io::stdin().read_line(&mut answer).unwrap_or(0) == 0 {
+            // Input closed, never block, just leave the existing file alone.
+            return PromptAnswer::Once(ConflictChoice::Skip);
+        }
+        match answer.trim().to_lowercase().as_str() {
+            "o" | "overwrite" => return PromptAnswer::Once(ConflictChoice::Overwrite),
+            "s" | "skip" | "" => return PromptAnswer::Once(ConflictChoice::Skip),
+            "k" | "keep both" => return PromptAnswer::Once(ConflictChoice::KeepBoth),
+            "a" | "always" => return PromptAnswer::Always(ConflictChoice::Overwrite),
+            _ => println!("Please answer o, s, k or a."),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::events::NoProgress;
+
+    #[test]
+    fn parallel_imports_copy_every_track_and_list_them_in_playlist_order() {
+        let dir = std::env::temp_dir().join(format!("music-man-parallel-imports-{}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        let (cache_dir, device_dir) = (dir.join("cache"), dir.join("device"));
+        std::fs::create_dir_all(&cache_dir).unwrap();
+        std::fs::create_dir_all(&device_dir).unwrap();
+
+        let mut tracks = Vec::new();
+        let mut copies = Vec::new();
+        for i in 0..12 {
+            let info = AudioInfo::from_filename(Path::new(&format!("Band{} - Song{}.mp3", i, i)));
+            let source_path = cache_dir.join(info.filename.clone().unwrap());
+            // Sizes differ so copies finish out of order.
+            std::fs::write(&source_path, vec![i as u8; (12 - i) * 50_000]).unwrap();
+            copies.push((i, source_path));
+            tracks.push(info);
+        }
+        let cache = LocalCache::in_dir(&cache_dir, HashMap::new());
+        let mut device = AttachedDevice::new("test".to_string(), device_dir.clone()).unwrap();
+
+        let mut completed = Vec::new();
+        import_parallel(&cache, &device, "Road", &tracks, &copies, 4, |index, result| {
+            completed.push((index, result.unwrap()));
+        });
+        assert_eq!(completed.len(), tracks.len());
+        let mut indexes: Vec<_> = completed.iter().map(|(index, _)| *index).collect();
+        indexes.sort_unstable();
+        assert_eq!(indexes, (0..tracks.len()).collect::<Vec<_>>());
+        for (index, (dest_path, bytes, hash)) in &completed {
+            let source_path = &copies[*index].1;
+            assert_eq!(std::fs::read(dest_path).unwrap(), std::fs::read(source_path).unwrap());
+            assert_eq!(*bytes, std::fs::metadata(source_path).unwrap().len());
+            assert_eq!(*hash, hash_file(dest_path).unwrap());
+            device.update_index(&tracks[*index], &AudioLocation::LocalPath(dest_path.clone())).unwrap();
+        }
+
+        // The playlist file follows the playlist, whatever order the copies finished in.
+        assert_eq!(device.write_m3u("Road", &tracks).unwrap(), tracks.len());
+        let m3u = std::fs::read_to_string(device.m3u_path("Road")).unwrap();
+        let listed: Vec<_> = m3u.lines().filter(|line| !line.starts_with('#')).collect();
+        let expected: Vec<_> = tracks.iter().map(|info| format!("Road/{}", info.filename.clone().unwrap())).collect();
+        assert_eq!(listed, expected);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn decomposed_and_composed_spellings_are_one_track() {
+        let dir = std::env::temp_dir().join(format!("music-man-nfc-{}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        let (cache_dir, device_dir) = (dir.join("cache"), dir.join("device"));
+        std::fs::create_dir_all(&cache_dir).unwrap();
+        std::fs::create_dir_all(device_dir.join("Road")).unwrap();
+        // Written on macOS, so decomposed, and tagged composed.
+        let joga = "Bjo\u{308}rk - Jo\u{301}ga.mp3";
+        let hoppipolla = "Sigur Ro\u{301}s - Ho\u{301}ppi\u{301}polla.mp3";
+        std::fs::write(cache_dir.join(joga), b"joga").unwrap();
+        std::fs::write(cache_dir.join(hoppipolla), b"hoppipolla").unwrap();
+        std::fs::write(device_dir.join("Road").join(joga), b"joga").unwrap();
+        let tracks = vec![
+            AudioInfo { artist: Some("Björk".to_string()), title: Some("Jóga".to_string()), ..Default::default() },
+            AudioInfo { artist: Some("Sigur Rós".to_string()), title: Some("Hóppípolla".to_string()), ..Default::default() },
+        ];
+        let cache = LocalCache::in_dir(&cache_dir, HashMap::from([("Road".to_string(), tracks.clone())]));
+        let mut device = AttachedDevice::new("test".to_string(), device_dir.clone()).unwrap();
+
+        assert!(cache.search(&tracks[0]).is_ok() && cache.search(&tracks[1]).is_ok());
+        assert!(device.search(&tracks[0]).is_ok());
+        assert!(new_on_device(&cache, &device, &tracks[0]).is_none());
+        assert!(new_on_device(&cache, &device, &tracks[1]).is_some());
+
+        let mut prompt = |_: &Conflict| PromptAnswer::Once(ConflictChoice::Skip);
+        let report =
+            sync_playlist(&cache, &mut device, "Road", CollisionPolicy::default(), &mut prompt, &mut NoProgress).unwrap();
+        assert_eq!(report.count(&SyncOutcome::Identical), 1);
+        assert_eq!(report.count(&SyncOutcome::Copied), 1);
+        let mut names: Vec<_> = std::fs::read_dir(device_dir.join("Road"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .filter(|name| name.ends_with(".mp3"))
+            .collect();
+        names.sort();
+        // The new copy is written composed, the file already there is left alone.
+        assert_eq!(names, [joga.to_string(), "Sigur Rós - Hóppípolla.mp3".to_string()]);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn each_device_gets_the_formats_it_plays() {
+        let dir = std::env::temp_dir().join(format!("music-man-multi-device-{}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        let cache_dir = dir.join("cache");
+        std::fs::create_dir_all(&cache_dir).unwrap();
+        std::fs::create_dir_all(dir.join("clip")).unwrap();
+        std::fs::create_dir_all(dir.join("shokz")).unwrap();
+        std::fs::write(cache_dir.join("Band - Song.flac"), b"not really flac").unwrap();
+        std::fs::write(cache_dir.join("Band - Other.mp3"), b"not really mp3 either").unwrap();
+        let tracks: Vec<_> =
+            ["Band - Song.flac", "Band - Other.mp3"].iter().map(|name| AudioInfo::from_filename(Path::new(name))).collect();
+        let cache = LocalCache::in_dir(&cache_dir, HashMap::from([("Gym".to_string(), tracks.clone())]));
+
+        let mut plays_flac = AttachedDevice::new("clip".to_string(), dir.join("clip")).unwrap();
+        let mut mp3_only = AttachedDevice::new("shokz".to_string(), dir.join("shokz")).unwrap();
+        mp3_only.profile.extensions = Some(vec!["mp3".to_string()]);
+        for (device, transcodes) in [(&plays_flac, false), (&mp3_only, true)] {
+            let manifest = DeviceManifest::load(&device.path, false);
+            assert_eq!(needs_transcode(&cache, device, &manifest, &tracks[0]), transcodes);
+            assert!(!needs_transcode(&cache, device, &manifest, &tracks[1]));
+        }
+
+        let mut prompt = |_: &Conflict| PromptAnswer::Once(ConflictChoice::Skip);
+        let report =
+            sync_playlist(&cache, &mut plays_flac, "Gym", CollisionPolicy::default(), &mut prompt, &mut NoProgress).unwrap();
+        assert_eq!(report.count(&SyncOutcome::Copied), 2);
+        assert!(dir.join("clip/Gym/Band - Song.flac").is_file());
+
+        // The flac isn't audio, so transcoding it fails whether or not ffmpeg is installed, but only that track fails
+        // and only on the device that needed it transcoded.
+        let report =
+            sync_playlist(&cache, &mut mp3_only, "Gym", CollisionPolicy::default(), &mut prompt, &mut NoProgress).unwrap();
+        assert_eq!(report.count(&SyncOutcome::Copied), 1);
+        assert_eq!(report.count(&SyncOutcome::Failed(String::new())), 1);
+        assert!(dir.join("shokz/Gym/Band - Other.mp3").is_file());
+        assert!(!dir.join("shokz/Gym/Band - Song.flac").exists());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
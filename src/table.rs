@@ -0,0 +1,163 @@
+// Table -> Columnar output for listings and reports, sized to the terminal and paged when it doesn't fit on screen.
+// Plain output is tab separated and never truncated or paged, so it's stable for scripts.
+
+use std::{
+    io::{IsTerminal, Write, stdout},
+    process::{Command, Stdio},
+};
+
+use ratatui::crossterm::terminal;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+// Columns are never shrunk below this, past it the table is left to wrap.
+const MIN_COLUMN_WIDTH: usize = 6;
+const COLUMN_GAP: &str = "  ";
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OutputOptions {
+    // Never page, even when output overflows the terminal.
+    pub no_pager: bool,
+    // Tab separated, untruncated, unpaged output.
+    pub plain: bool,
+}
+
+impl OutputOptions {
+    /// Take the output flags (--no-pager, --plain) out of a command's args.
+    pub fn take_from(args: &mut Vec<&str>) -> Self {
+        let mut options = Self::default();
+        args.retain(|arg| match *arg {
+            "--no-pager" => {
+                options.no_pager = true;
+                false
+            }
+            "--plain" => {
+                options.plain = true;
+                false
+            }
+            _ => true,
+        });
+        options
+    }
+}
+
+pub struct Table {
+    headers: Vec<String>,
+    rows: Vec<Vec<String>>,
+}
+
+impl Table {
+    pub fn new(headers: &[&str]) -> Self {
+        Self {
+            headers: headers.iter().map(|h| h.to_string()).collect(),
+            rows: Vec::new(),
+        }
+    }
+
+    pub fn row(&mut self, cells: Vec<String>) {
+        self.rows.push(cells);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// Render as aligned columns fitting within width, ellipsizing the widest columns first when they don't fit.
+    pub fn render(&self, width: Option<usize>) -> String {
+        let mut widths: Vec<usize> = self.headers.iter().map(|h| h.width()).collect();
+        for row in &self.rows {
+            for (i, cell) in row.iter().enumerate() {
+                widths[i] = widths[i].max(cell.width());
+            }
+        }
+        if let Some(width) = width {
+            let gaps = COLUMN_GAP.len() * widths.len().saturating_sub(1);
+            while widths.iter().sum::<usize>() + gaps > width {
+                let (widest, &current) = widths.iter().enumerate().max_by_key(|(_, w)| **w).unwrap();
+                if current <= MIN_COLUMN_WIDTH {
+                    break;
+                }
+                widths[widest] = current - 1;
+            }
+        }
+
+        let mut out = String::new();
+        for row in std::iter::once(&self.headers).chain(&self.rows) {
+            let cells: Vec<String> = row
+                .iter()
+                .zip(&widths)
+                .map(|(cell, &width)| {
+                    let cell = ellipsize(cell, width);
+                    let padding = width - cell.width();
+                    format!("{}{}", cell, " ".repeat(padding))
+                })
+                .collect();
+            out.push_str(cells.join(COLUMN_GAP).trim_end());
+            out.push('\n');
+        }
+        out
+    }
+
+    pub fn render_plain(&self) -> String {
+        std::iter::once(&self.headers)
+            .chain(&self.rows)
+            .map(|row| row.join("\t") + "\n")
+            .collect()
+    }
+
+    pub fn print(&self, options: OutputOptions) {
+        if options.plain {
+            print!("{}", self.render_plain());
+            return;
+        }
+        let interactive = stdout().is_terminal();
+        let size = terminal::size().ok().filter(|_| interactive);
+        let rendered = self.render(size.map(|(columns, _)| columns as usize));
+        match size {
+            Some((_, rows)) if !options.no_pager && rendered.lines().count() >= rows as usize => page(&rendered),
+            _ => print!("{}", rendered),
+        }
+    }
+}
+
+/// Send output through $PAGER (less by default), falling back to printing it if the pager can't be run.
+pub fn page(output: &str) {
+    let pager = std::env::var("PAGER")
+        .ok()
+        .filter(|p| !p.trim().is_empty())
+        .unwrap_or("less -FRX".to_string());
+    let child = Command::new("sh").arg("-c").arg(&pager).stdin(Stdio::piped()).spawn();
+    match child {
+        Ok(mut child) => {
+            // The pager exiting early (e.g. quitting less) closes the pipe, which isn't an error.
+            if let Some(mut stdin) = child.stdin.take() {
+                stdin.write_all(output.as_bytes()).ok();
+            }
+            child.wait().ok();
+        }
+        Err(_) => print!("{}", output),
+    }
+}
+
+// Truncate to a display width, ending in an ellipsis when anything was cut.
+fn ellipsize(s: &str, width: usize) -> String {
+    if s.width() <= width {
+        return s.to_string();
+    }
+    let mut out = String::new();
+    let mut used = 0;
+    for c in s.chars() {
+        let w = c.width().unwrap_or(0);
+        if used + w + 1 > width {
+            break;
+        }
+        out.push(c);
+        used += w;
+    }
+    out.push('…');
+    out
+}
+
+/// Format seconds as m:ss, or blank when unknown.
+pub fn format_duration(secs: Option<u32>) -> String {
+    secs.map(|s| format!("{}:{:02}", s / 60, s % 60)).unwrap_or_default()
+}
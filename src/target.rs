@@ -1,22 +1,33 @@
 use crate::{
     AudioInfo,
-    audio::{AudioError, AudioLocation, PlaylistName},
+    audio::{AudioError, AudioLocation, PlaylistName, audio_extension, transcode_to_mp3},
+    cache::StagingDir,
     device::AttachedDevice,
+    fsutil::smart_copy_hashed,
+    http::stage_remote,
+    naming::{DestNaming, FilenameRules},
+    source::FetchResult,
 };
 
 // TRAIT: AudioTarget, e.g. an attached drive, the local file cache etc.
 // AudioTarget impls are able to be written to, and can be used as a target for exporting audio from an AudioSource:
 // 1. contains -> Look for existing AudioInfo in the target.
-// 2. import -> Import audio to this target into a specified playist (if any), from a provided source location.
+// 2. import -> Import audio to this target into a specified playist (if any), from a provided source location, named as
+//    the DestNaming says. Remote locations are downloaded to staging first, and the staged copy is removed once imported.
+//    Returns a FetchResult for the imported copy, the same as fetching from a source.
+// 3. accepts_extension -> Whether the target plays audio with an extension (lowercase, no dot). Imports of any other
+//    format are transcoded to mp3.
 pub trait AudioTarget {
     fn name(&self) -> &str;
     fn contains(&self, info: &AudioInfo) -> Result<&AudioLocation, AudioError>;
+    fn accepts_extension(&self, ext: &str) -> bool;
     fn import(
         &self,
         source_location: &AudioLocation,
         info: &AudioInfo,
         playlist: Option<PlaylistName>,
-    ) -> Result<AudioLocation, AudioError>;
+        naming: &DestNaming,
+    ) -> Result<FetchResult, AudioError>;
 }
 
 impl AudioTarget for AttachedDevice {
@@ -28,47 +39,74 @@ impl AudioTarget for AttachedDevice {
         self.search(info)
     }
 
-    // As of now we don't need AudioInfo to import to an AttachedDevice, for the currently support option of device local transfer,
-    // we just re-use the existing location filename when copying to the target.
+    fn accepts_extension(&self, ext: &str) -> bool {
+        self.profile.extensions().iter().any(|accepted| accepted == ext)
+    }
+
+    // Devices are assumed FAT formatted, so names are sanitized for FAT whatever the naming.
     fn import(
         &self,
         source_location: &AudioLocation,
-        _info: &AudioInfo,
+        info: &AudioInfo,
         playlist: Option<PlaylistName>,
-    ) -> Result<AudioLocation, AudioError> {
+        naming: &DestNaming,
+    ) -> Result<FetchResult, AudioError> {
+        self.ensure_writable()?;
         match source_location {
+            AudioLocation::LocalPath(source_path) if !self.accepts_extension(&audio_extension(source_path)) => {
+                if !self.accepts_extension("mp3") {
+                    return Err(AudioError::ExportFailed(format!(
+                        "{} accepts neither {} nor mp3 to transcode it to",
+                        self.name,
+                        source_path.display()
+                    )));
+                }
+                let staging = StagingDir::new("transcode")?;
+                let stem = source_path.file_stem().ok_or(AudioError::NotFound)?.to_string_lossy();
+                let transcoded = staging.path().join(format!("{}.mp3", stem));
+                // Syncs report it as an event, this call may be on any of the import threads.
+                tracing::info!("transcoding {} to mp3 for {}", source_path.display(), self.name);
+                transcode_to_mp3(source_path, &transcoded)?;
+                self.import(&AudioLocation::local(&transcoded), info, playlist, naming)
+            }
             AudioLocation::LocalPath(source_path) => {
-                let dirpath = match &playlist.unwrap_or(PlaylistName::Uncategorized) {
-                    PlaylistName::Uncategorized => self.path.clone(),
-                    PlaylistName::Named(name) => self.path.join(name),
+                let dirpath = match self.profile.layout.track_dir(info) {
+                    Some(track_dir) => self.path.join(track_dir),
+                    None => self.playlist_dir(&playlist.unwrap_or(PlaylistName::Uncategorized)),
                 };
 
                 // Ensure the playlist directory exists.
                 std::fs::create_dir_all(&dirpath)?;
 
-                // File name at destination will be same as source.
-                let filename = source_path
-                    .file_name()
-                    .unwrap()
-                    .to_string_lossy()
-                    .to_string();
+                // FAT names are written NFC, since the source may be a decomposed macOS filename.
+                let filename = self
+                    .profile
+                    .layout
+                    .naming(info, naming)
+                    .file_name(info, source_path, FilenameRules::Fat)
+                    .ok_or(AudioError::NotFound)?;
                 let dest_path = dirpath.join(filename);
-                match std::fs::copy(&source_path, &dest_path) {
-                    Ok(num_bytes) => {
+                match smart_copy_hashed(source_path, &dest_path, self.copy_options()) {
+                    Ok(copied) => {
                         println!(
                             "Copied {} bytes from {} to {}",
-                            num_bytes,
+                            copied.bytes,
                             source_path.display().to_string(),
                             dest_path.display().to_string()
                         );
-                        Ok(AudioLocation::LocalPath(dest_path))
+                        let mut result = FetchResult::local(dest_path, info.clone(), copied.bytes, &self.name);
+                        result.hash = copied.hash;
+                        Ok(result)
                     }
                     Err(e) => Err(AudioError::Io(e)),
                 }
             }
-            _ => Err(AudioError::ExportFailed(format!(
-                "Currently do not support import to AttachedDevice from non-LocalPath."
-            ))),
+            AudioLocation::RemoteUrl(url) => {
+                let staged = stage_remote(url)?;
+                let mut result = self.import(&AudioLocation::local(staged.path()), info, playlist, naming)?;
+                result.url = Some(url.clone());
+                Ok(result)
+            }
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,159 @@
+// yt-dlp binary management. yt-dlp breaks whenever YouTube changes, and an old one fails with errors that don't say
+// so, so its version is checked against the oldest one known to work. `ytdlp update` self-updates installs that can,
+// and otherwise downloads the latest release to a managed location in the data dir, which is then preferred over PATH.
+// A downloaded release is checked against the SHA-256 the release publishes before it's made runnable.
+
+use std::{
+    fs::{File, copy, create_dir_all, rename, set_permissions},
+    io::{self, Read},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::{
+    audio::AudioError,
+    cache::get_data_dir,
+    http::{get_text, stage_remote},
+};
+
+/// The oldest yt-dlp release known to still extract from YouTube, bump it when older ones stop working.
+pub const MIN_YTDLP_VERSION: &str = "2025.01.15";
+
+const RELEASE_URL: &str = "https://github.com/yt-dlp/yt-dlp/releases/latest/download";
+#[cfg(target_os = "macos")]
+const RELEASE_ASSET: &str = "yt-dlp_macos";
+#[cfg(not(target_os = "macos"))]
+const RELEASE_ASSET: &str = "yt-dlp_linux";
+// "<sha256>  <asset>" for every asset of the release.
+const RELEASE_CHECKSUMS: &str = "SHA2-256SUMS";
+
+/// Where `ytdlp update` puts a downloaded yt-dlp.
+pub fn managed_binary() -> PathBuf {
+    get_data_dir().join("bin").join("yt-dlp")
+}
+
+/// The installed version, e.g. "2025.01.15".
+pub fn installed_version(binary: &Path) -> Result<String, AudioError> {
+    let output = Command::new(binary)
+        .arg("--version")
+        .output()
+        .map_err(|e| AudioError::Unavailable(format!("{}: {}", binary.display(), e)))?;
+    if !output.status.success() {
+        return Err(AudioError::Unavailable(format!("{} --version exited with {}", binary.display(), output.status)));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).lines().next().unwrap_or_default().trim().to_string())
+}
+
+/// Whether a version is older than MIN_YTDLP_VERSION. Versions that don't parse, e.g. nightly builds named after a
+/// commit, are given the benefit of the doubt.
+pub fn is_outdated(version: &str) -> bool {
+    match (parse_version(version), parse_version(MIN_YTDLP_VERSION)) {
+        (Some(version), Some(min)) => version < min,
+        _ => false,
+    }
+}
+
+// yt-dlp versions are dates, YYYY.MM.DD, with an optional .N for a second release that day.
+fn parse_version(version: &str) -> Option<Vec<u32>> {
+    let parts: Vec<u32> = version.split('.').map(|part| part.parse().ok()).collect::<Option<_>>()?;
+    (parts.len() >= 3).then_some(parts)
+}
+
+/// A warning to show at startup when the installed yt-dlp is too old. Nothing when it can't be run at all, doctor
+/// covers that.
+pub fn version_warning(binary: &Path) -> Option<String> {
+    let version = installed_version(binary).ok()?;
+    is_outdated(&version).then(|| {
+        format!(
+            "yt-dlp {} is older than {}, downloads are likely to fail. Run `ytdlp update`",
+            version, MIN_YTDLP_VERSION
+        )
+    })
+}
+
+/// Update yt-dlp, returning the binary to use from now on. Self-updating installs update in place, anything else,
+/// e.g. pip or a package manager's, gets the latest release downloaded to managed_binary().
+pub fn update(binary: &Path) -> Result<PathBuf, AudioError> {
+    if let Ok(output) = Command::new(binary).arg("-U").output() {
+        let text = format!("{}{}", String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr));
+        if output.status.success() && (text.contains("Updated yt-dlp to") || text.contains("is up to date")) {
+            return Ok(binary.to_path_buf());
+        }
+        tracing::info!("{} can't update itself: {}", binary.display(), text.trim());
+    }
+
+    let sums = get_text(&format!("{}/{}", RELEASE_URL, RELEASE_CHECKSUMS), &[])?;
+    let expected = release_checksum(&sums, RELEASE_ASSET).ok_or_else(|| {
+        AudioError::Unavailable(format!("{} lists no checksum for {}", RELEASE_CHECKSUMS, RELEASE_ASSET))
+    })?;
+    let staged = stage_remote(&format!("{}/{}", RELEASE_URL, RELEASE_ASSET))?;
+    let actual = sha256_file(staged.path())?;
+    if actual != expected {
+        return Err(AudioError::Unavailable(format!(
+            "downloaded {} has SHA-256 {}, but the release says {}, not installing it",
+            RELEASE_ASSET, actual, expected
+        )));
+    }
+    set_permissions(staged.path(), PermissionsExt::from_mode(0o755))?;
+    // Make sure what was downloaded runs before it replaces anything.
+    installed_version(staged.path())?;
+    let managed = managed_binary();
+    create_dir_all(managed.parent().ok_or(AudioError::NotFound)?)?;
+    let part = managed.with_extension("part");
+    copy(staged.path(), &part)?;
+    rename(&part, &managed)?;
+    Ok(managed)
+}
+
+// An asset's checksum from a SHA2-256SUMS listing, lowercase hex.
+fn release_checksum(sums: &str, asset: &str) -> Option<String> {
+    sums.lines().find_map(|line| {
+        let (hash, name) = line.trim().split_once(char::is_whitespace)?;
+        // sha256sum marks binary mode with a "*" before the name.
+        (name.trim().trim_start_matches('*') == asset).then(|| hash.to_lowercase())
+    })
+}
+
+fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_assets_release_checksum() {
+        let sums = "\
+            3f0e3f3a9f5c4b2c8d1e6f7a8b9c0d1e2f3a4b5c6d7e8f9a0b1c2d3e4f5a6b7c  yt-dlp\n\
+            A1B2C3D4E5F60718293A4B5C6D7E8F90A1B2C3D4E5F60718293A4B5C6D7E8F90  yt-dlp_linux\n\
+            0000000000000000000000000000000000000000000000000000000000000000 *yt-dlp_macos\n";
+        assert_eq!(
+            release_checksum(sums, "yt-dlp_linux").as_deref(),
+            Some("a1b2c3d4e5f60718293a4b5c6d7e8f90a1b2c3d4e5f60718293a4b5c6d7e8f90")
+        );
+        assert_eq!(release_checksum(sums, "yt-dlp_macos").as_deref(), Some(&"0".repeat(64)[..]));
+        assert_eq!(release_checksum(sums, "yt-dlp_linux_aarch64"), None);
+    }
+
+    #[test]
+    fn hashes_files_with_sha256() {
+        let path = std::env::temp_dir().join(format!("music-man-sha256-{}", std::process::id()));
+        std::fs::write(&path, b"abc").unwrap();
+        assert_eq!(sha256_file(&path).unwrap(), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        std::fs::remove_file(&path).ok();
+    }
+}
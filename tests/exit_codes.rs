@@ -0,0 +1,70 @@
+// The exit status contract, checked against the binary in script mode: commands piped in, the worst status of any of
+// them once input ends. Each run gets its own HOME, so the app directories start empty.
+
+use std::{
+    io::Write,
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("music-man-exit-{}-{}", name, std::process::id()));
+    std::fs::remove_dir_all(&dir).ok();
+    std::fs::create_dir_all(dir.join("home")).unwrap();
+    std::fs::create_dir_all(dir.join("device")).unwrap();
+    dir
+}
+
+// Attach device, then run commands, returning the exit code.
+fn run(name: &str, device: Option<&str>, commands: &[&str]) -> i32 {
+    let dir = scratch_dir(name);
+    let device = device.map_or_else(|| dir.join("device"), PathBuf::from);
+    let mut child = Command::new(env!("CARGO_BIN_EXE_music-man"))
+        .env("HOME", dir.join("home"))
+        .env_remove("XDG_DATA_HOME")
+        .env_remove("XDG_CACHE_HOME")
+        .env_remove("XDG_CONFIG_HOME")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    writeln!(stdin, "{}", device.display()).unwrap();
+    for command in commands {
+        writeln!(stdin, "{}", command).unwrap();
+    }
+    drop(stdin);
+    let code = child.wait().unwrap().code().unwrap();
+    std::fs::remove_dir_all(&dir).ok();
+    code
+}
+
+#[test]
+fn commands_that_succeed_exit_0() {
+    assert_eq!(run("success", None, &["queue", "device reindex"]), 0);
+}
+
+#[test]
+fn usage_errors_exit_1() {
+    assert_eq!(run("unknown", None, &["frobnicate"]), 1);
+    assert_eq!(run("device-usage", None, &["device parallel 99"]), 1);
+    assert_eq!(run("playlist-usage", None, &["playlist diff Road 1 2"]), 1);
+    assert_eq!(run("show-usage", None, &["show_playlist"]), 1);
+}
+
+#[test]
+fn a_missing_device_exits_2() {
+    assert_eq!(run("no-device", Some("/nonexistent/music-man-device"), &[]), 2);
+}
+
+#[test]
+fn hard_failures_exit_4() {
+    assert_eq!(run("import", None, &["import Nobody Nothing"]), 4);
+    assert_eq!(run("search", None, &["search Nobody - Nothing"]), 4);
+}
+
+#[test]
+fn the_worst_status_of_a_script_wins() {
+    assert_eq!(run("worst", None, &["import Nobody Nothing", "frobnicate", "queue"]), 4);
+}